@@ -41,62 +41,250 @@ CREATE EXTENSION IF NOT EXISTS citext;
 CREATE TABLE IF NOT EXISTS public.Perms (
   perm_id INT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
   perm VARCHAR(50) UNIQUE NOT NULL,
-  description TEXT
+  description TEXT,
+  is_system BOOLEAN NOT NULL DEFAULT false
 );
 
 CREATE TABLE IF NOT EXISTS public.Roles (
   role_id INT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
-  role_name VARCHAR(50) UNIQUE NOT NULL
+  role_name VARCHAR(50) UNIQUE NOT NULL,
+  is_system BOOLEAN NOT NULL DEFAULT false
 );
 
+-- Older databases predate the system flags
+ALTER TABLE public.Perms ADD COLUMN IF NOT EXISTS is_system BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE public.Roles ADD COLUMN IF NOT EXISTS is_system BOOLEAN NOT NULL DEFAULT false;
+
 CREATE TABLE IF NOT EXISTS public.Role_Perms (
   role_id INT REFERENCES public.Roles(role_id) ON DELETE CASCADE,
   perm_id INT REFERENCES public.Perms(perm_id) ON DELETE CASCADE,
   PRIMARY KEY (role_id, perm_id)
 );
 
+-- A perm grants every perm it implies, resolved transitively by the rbac crate
+CREATE TABLE IF NOT EXISTS public.Perm_Implies (
+  perm_id INT REFERENCES public.Perms(perm_id) ON DELETE CASCADE,
+  implied_perm_id INT REFERENCES public.Perms(perm_id) ON DELETE CASCADE,
+  PRIMARY KEY (perm_id, implied_perm_id),
+  CONSTRAINT no_self_implication CHECK (perm_id <> implied_perm_id)
+);
+
 -- Create Users table if not exists
 CREATE TABLE IF NOT EXISTS public.Users (
   uid UUID PRIMARY KEY DEFAULT gen_random_uuid(),
   Email CITEXT UNIQUE NOT NULL,
   Password_Hash TEXT NULL,
-  OAuth_Provider VARCHAR(50) NULL,
-  OAuth_Provider_ID VARCHAR(255) NULL, -- Unique ID from OAuth provider
+  Password_Pepper_Id VARCHAR(64) NULL, -- Pepper key the hash was made with, NULL when unpeppered
   Creation_Time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  Deleted_At TIMESTAMP WITH TIME ZONE NULL, -- Soft deleted, hard deleted once the grace period ends
+  Email_Verified_At TIMESTAMP WITH TIME ZONE NULL,
   CONSTRAINT first_email_check CHECK (
     Email ~* '^[^[:space:]]+@[^[:space:]]+\.[^[:space:]]+$'
-  ),
-  CONSTRAINT oauth_unique_per_provider UNIQUE (OAuth_Provider, OAuth_Provider_ID)
+  )
+);
+
+-- Older databases predate soft deletion
+ALTER TABLE public.Users ADD COLUMN IF NOT EXISTS Deleted_At TIMESTAMP WITH TIME ZONE NULL;
+ALTER TABLE public.Users ADD COLUMN IF NOT EXISTS Password_Pepper_Id VARCHAR(64) NULL;
+
+-- Accounts from before email verification are trusted as they are
+DO \$\$
+BEGIN
+  IF NOT EXISTS (
+    SELECT 1 FROM information_schema.columns
+    WHERE table_schema = 'public' AND table_name = 'users' AND column_name = 'email_verified_at'
+  ) THEN
+    ALTER TABLE public.Users ADD COLUMN Email_Verified_At TIMESTAMP WITH TIME ZONE NULL;
+    UPDATE public.Users SET Email_Verified_At = Creation_Time;
+  END IF;
+END
+\$\$;
+
+-- Accounts at OAuth/OpenID providers a user can sign in with, at most one per provider
+CREATE TABLE IF NOT EXISTS public.User_Identities (
+  provider VARCHAR(50) NOT NULL,
+  provider_user_id VARCHAR(255) NOT NULL, -- Unique ID from the provider, e.g. the sub claim
+  user_uid UUID NOT NULL REFERENCES public.Users(uid) ON DELETE CASCADE,
+  email CITEXT NULL, -- As the provider reported it, may differ from the user's
+  linked_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_login_at TIMESTAMP WITH TIME ZONE NULL,
+  PRIMARY KEY (provider, provider_user_id),
+  CONSTRAINT one_identity_per_provider UNIQUE (user_uid, provider)
+);
+
+-- Older databases kept a single identity on the user
+DO \$\$
+BEGIN
+  IF EXISTS (
+    SELECT 1 FROM information_schema.columns
+    WHERE table_schema = 'public' AND table_name = 'users' AND column_name = 'oauth_provider'
+  ) THEN
+    INSERT INTO public.User_Identities (provider, provider_user_id, user_uid, email)
+    SELECT OAuth_Provider, OAuth_Provider_ID, uid, Email FROM public.Users
+    WHERE OAuth_Provider IS NOT NULL AND OAuth_Provider_ID IS NOT NULL
+    ON CONFLICT DO NOTHING;
+    DROP INDEX IF EXISTS idx_users_oauth;
+    ALTER TABLE public.Users DROP COLUMN OAuth_Provider, DROP COLUMN OAuth_Provider_ID;
+  END IF;
+END
+\$\$;
+
+-- TOTP second factor. The secret has to be readable to check codes, so it can't be hashed.
+-- Confirmed_At stays NULL until the first code is entered, only then is it enforced.
+CREATE TABLE IF NOT EXISTS public.User_Totp (
+  user_uid UUID PRIMARY KEY REFERENCES public.Users(uid) ON DELETE CASCADE,
+  secret VARCHAR(64) NOT NULL, -- Base32, as shown to authenticator apps
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  confirmed_at TIMESTAMP WITH TIME ZONE NULL
+);
+
+-- One-time codes for when the authenticator is lost, stored as SHA-256 hashes
+CREATE TABLE IF NOT EXISTS public.User_Recovery_Codes (
+  user_uid UUID REFERENCES public.Users(uid) ON DELETE CASCADE,
+  code_hash VARCHAR(64) NOT NULL,
+  used_at TIMESTAMP WITH TIME ZONE NULL,
+  PRIMARY KEY (user_uid, code_hash)
+);
+
+-- WebAuthn credentials, so users can log in with a passkey instead of a password
+CREATE TABLE IF NOT EXISTS public.User_Passkeys (
+  credential_id VARCHAR(1400) PRIMARY KEY, -- base64url, as the browser reports it
+  user_uid UUID NOT NULL REFERENCES public.Users(uid) ON DELETE CASCADE,
+  public_key BYTEA NOT NULL, -- COSE_Key
+  sign_count BIGINT NOT NULL DEFAULT 0,
+  transports TEXT[] NOT NULL DEFAULT '{}',
+  name VARCHAR(64) NOT NULL,
+  backed_up BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at TIMESTAMP WITH TIME ZONE NULL
 );
 
 -- Link users to roles
 CREATE TABLE IF NOT EXISTS public.User_Roles (
   user_uid UUID REFERENCES public.Users(uid) ON DELETE CASCADE,
   role_id INT REFERENCES public.Roles(role_id) ON DELETE CASCADE,
-  PRIMARY KEY (user_uid, role_id)
+  valid_from TIMESTAMP WITH TIME ZONE NULL, -- NULL means no bound
+  valid_until TIMESTAMP WITH TIME ZONE NULL,
+  PRIMARY KEY (user_uid, role_id),
+  CONSTRAINT role_validity CHECK (valid_until IS NULL OR valid_from IS NULL OR valid_until > valid_from)
 );
 
--- Allow directly providing permissions to users
+-- Allow directly providing permissions to users, optionally scoped to one resource
+-- ('' means unscoped, see rbac::scope_perm for how the two are combined)
 CREATE TABLE IF NOT EXISTS public.User_Perms (
   user_uid UUID REFERENCES public.Users(uid) ON DELETE CASCADE,
   perm_id INT REFERENCES public.Perms(perm_id) ON DELETE CASCADE,
-  PRIMARY KEY (user_uid, perm_id)
+  resource VARCHAR(64) NOT NULL DEFAULT '',
+  valid_from TIMESTAMP WITH TIME ZONE NULL,
+  valid_until TIMESTAMP WITH TIME ZONE NULL,
+  PRIMARY KEY (user_uid, perm_id, resource),
+  CONSTRAINT resource_format CHECK (resource ~ '^[A-Za-z0-9_-]*$'),
+  CONSTRAINT perm_validity CHECK (valid_until IS NULL OR valid_from IS NULL OR valid_until > valid_from)
+);
+
+-- Older databases predate scoped grants
+ALTER TABLE public.User_Perms ADD COLUMN IF NOT EXISTS resource VARCHAR(64) NOT NULL DEFAULT '';
+DO \$\$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.key_column_usage
+        WHERE table_name = 'user_perms' AND constraint_name = 'user_perms_pkey' AND column_name = 'resource'
+    ) THEN
+        ALTER TABLE public.User_Perms DROP CONSTRAINT user_perms_pkey;
+        ALTER TABLE public.User_Perms ADD PRIMARY KEY (user_uid, perm_id, resource);
+    END IF;
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'resource_format') THEN
+        ALTER TABLE public.User_Perms ADD CONSTRAINT resource_format CHECK (resource ~ '^[A-Za-z0-9_-]*$');
+    END IF;
+END
+\$\$;
+
+-- Older databases predate time-limited grants
+ALTER TABLE public.User_Roles ADD COLUMN IF NOT EXISTS valid_from TIMESTAMP WITH TIME ZONE NULL;
+ALTER TABLE public.User_Roles ADD COLUMN IF NOT EXISTS valid_until TIMESTAMP WITH TIME ZONE NULL;
+ALTER TABLE public.User_Perms ADD COLUMN IF NOT EXISTS valid_from TIMESTAMP WITH TIME ZONE NULL;
+ALTER TABLE public.User_Perms ADD COLUMN IF NOT EXISTS valid_until TIMESTAMP WITH TIME ZONE NULL;
+DO \$\$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'role_validity') THEN
+        ALTER TABLE public.User_Roles ADD CONSTRAINT role_validity CHECK (valid_until IS NULL OR valid_from IS NULL OR valid_until > valid_from);
+    END IF;
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'perm_validity') THEN
+        ALTER TABLE public.User_Perms ADD CONSTRAINT perm_validity CHECK (valid_until IS NULL OR valid_from IS NULL OR valid_until > valid_from);
+    END IF;
+END
+\$\$;
+
+-- Grants removed by rbac::purge_expired_grants, kept so access can be audited later.
+-- No foreign keys: the history outlives the user, role or perm it mentions.
+CREATE TABLE IF NOT EXISTS public.Expired_Grants (
+  expired_id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+  user_uid UUID NOT NULL,
+  kind VARCHAR(8) NOT NULL CHECK (kind IN ('role', 'perm')),
+  name VARCHAR(255) NOT NULL, -- Role name or perm
+  resource VARCHAR(64) NOT NULL DEFAULT '',
+  valid_from TIMESTAMP WITH TIME ZONE NULL,
+  valid_until TIMESTAMP WITH TIME ZONE NOT NULL,
+  removed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Explicit per-user denies, checked ahead of role grants
+CREATE TABLE IF NOT EXISTS public.User_Perm_Denies (
+  user_uid UUID REFERENCES public.Users(uid) ON DELETE CASCADE,
+  perm_id INT REFERENCES public.Perms(perm_id) ON DELETE CASCADE,
+  resource VARCHAR(64) NOT NULL DEFAULT '',
+  reason TEXT,
+  PRIMARY KEY (user_uid, perm_id, resource),
+  CONSTRAINT deny_resource_format CHECK (resource ~ '^[A-Za-z0-9_-]*$')
 );
 
-INSERT INTO public.Roles (role_name) VALUES 
-('admin'),
-('mod'),
-('user')
-ON CONFLICT (role_name) DO NOTHING;
+-- Append-only record of account and privilege changes, written by the audit crate.
+-- No foreign keys: entries must outlive the users they mention.
+CREATE TABLE IF NOT EXISTS public.Audit_Log (
+  audit_id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+  at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  actor_uid UUID NULL, -- NULL when nobody was logged in, e.g. registration
+  target_uid UUID NULL,
+  action VARCHAR(32) NOT NULL,
+  diff JSONB NOT NULL DEFAULT '{}',
+  ip VARCHAR(64) NULL
+);
 
-INSERT INTO public.Perms (perm, description) VALUES
-('admin:access', 'Can manage users, access admin panel'),
-('users:manage', 'Can create, edit, search, and delete users'),
-('users:create', 'Can create users'),
-('users:edit', 'Can edit users'),
-('users:delete', 'Can delete users'),
-('users:search', 'Can search through users')
-ON CONFLICT (perm) DO NOTHING;
+CREATE OR REPLACE FUNCTION public.audit_log_append_only() RETURNS trigger AS \$\$
+BEGIN
+    RAISE EXCEPTION 'Audit_Log is append-only';
+END
+\$\$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_log_no_change ON public.Audit_Log;
+CREATE TRIGGER audit_log_no_change BEFORE UPDATE OR DELETE ON public.Audit_Log
+FOR EACH ROW EXECUTE FUNCTION public.audit_log_append_only();
+DROP TRIGGER IF EXISTS audit_log_no_truncate ON public.Audit_Log;
+CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON public.Audit_Log
+FOR EACH STATEMENT EXECUTE FUNCTION public.audit_log_append_only();
+
+INSERT INTO public.Roles (role_name, is_system) VALUES 
+('admin', true),
+('mod', true),
+('user', true)
+ON CONFLICT (role_name) DO UPDATE SET is_system = true;
+
+INSERT INTO public.Perms (perm, description, is_system) VALUES
+('admin:access', 'Can manage users, access admin panel', true),
+('users:manage', 'Can create, edit, search, and delete users', true),
+('users:create', 'Can create users', true),
+('users:edit', 'Can edit users', true),
+('users:delete', 'Can delete users', true),
+('users:search', 'Can search through users', true)
+ON CONFLICT (perm) DO UPDATE SET is_system = true;
+
+-- Permission hierarchy
+INSERT INTO public.Perm_Implies (perm_id, implied_perm_id)
+SELECT p.perm_id, c.perm_id
+FROM public.Perms p, public.Perms c
+WHERE (p.perm = 'admin:access' AND c.perm = 'users:manage')
+OR (p.perm = 'users:manage' AND c.perm IN ('users:create', 'users:edit', 'users:delete', 'users:search'))
+ON CONFLICT DO NOTHING;
 
 -- Give admin role permissions
 INSERT INTO public.Role_Perms (role_id, perm_id)
@@ -114,10 +302,16 @@ AND p.perm IN ('users:search', 'users:edit')
 ON CONFLICT DO NOTHING;
 
 -- Create index for faster lookups
-CREATE INDEX IF NOT EXISTS idx_users_oauth ON public.Users(OAuth_Provider, OAuth_Provider_ID);
 CREATE INDEX IF NOT EXISTS idx_users_email ON public.Users(Email);
 CREATE INDEX IF NOT EXISTS idx_users_role ON public.User_Roles(role_id);
 CREATE INDEX IF NOT EXISTS idx_user_perms_user ON public.User_Perms(user_uid);
+CREATE INDEX IF NOT EXISTS idx_user_perm_denies_user ON public.User_Perm_Denies(user_uid);
+CREATE INDEX IF NOT EXISTS idx_user_passkeys_user ON public.User_Passkeys(user_uid);
+CREATE INDEX IF NOT EXISTS idx_expired_grants_user ON public.Expired_Grants(user_uid);
+CREATE INDEX IF NOT EXISTS idx_audit_log_actor ON public.Audit_Log(actor_uid);
+CREATE INDEX IF NOT EXISTS idx_audit_log_target ON public.Audit_Log(target_uid);
+CREATE INDEX IF NOT EXISTS idx_audit_log_at ON public.Audit_Log(at);
+CREATE INDEX IF NOT EXISTS idx_users_deleted_at ON public.Users(Deleted_At) WHERE Deleted_At IS NOT NULL;
 EOF
 
 # Configure GRIDS
@@ -0,0 +1,384 @@
+use db::get_uidb_pool;
+use deadpool_postgres::GenericClient;
+use napi_derive::napi;
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use shared_types::Row;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+/// Entries per export chunk, big enough to be quick to page through, small enough to hold
+const EXPORT_PAGE_SIZE: u32 = 5000;
+
+/// Stands in for secrets such as password hashes in a diff
+pub const REDACTED: &str = "[redacted]";
+
+/// Who made a change and from where, passed down from the request
+#[derive(Debug, Clone, Default)]
+#[napi(object)]
+pub struct AuditContext {
+    /// Unset for changes nobody was logged in for, e.g. registration
+    pub actor_uid: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[napi(string_enum = "snake_case")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    UserCreate,
+    UserUpdate,
+    UserDelete,
+    UserRestore,
+    UserPurge,
+    RoleAssign,
+    RoleRemove,
+    PermGrant,
+    PermRevoke,
+    PermDeny,
+    DenyRemove,
+    EmailVerify,
+    TotpEnable,
+    TotpDisable,
+    RecoveryCodesRegenerate,
+    PasskeyAdd,
+    PasskeyRemove,
+    IdentityLink,
+    IdentityUnlink,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::UserCreate => "user_create",
+            AuditAction::UserUpdate => "user_update",
+            AuditAction::UserDelete => "user_delete",
+            AuditAction::UserRestore => "user_restore",
+            AuditAction::UserPurge => "user_purge",
+            AuditAction::RoleAssign => "role_assign",
+            AuditAction::RoleRemove => "role_remove",
+            AuditAction::PermGrant => "perm_grant",
+            AuditAction::PermRevoke => "perm_revoke",
+            AuditAction::PermDeny => "perm_deny",
+            AuditAction::DenyRemove => "deny_remove",
+            AuditAction::EmailVerify => "email_verify",
+            AuditAction::TotpEnable => "totp_enable",
+            AuditAction::TotpDisable => "totp_disable",
+            AuditAction::RecoveryCodesRegenerate => "recovery_codes_regenerate",
+            AuditAction::PasskeyAdd => "passkey_add",
+            AuditAction::PasskeyRemove => "passkey_remove",
+            AuditAction::IdentityLink => "identity_link",
+            AuditAction::IdentityUnlink => "identity_unlink",
+        }
+    }
+
+    fn parse(action: &str) -> Option<Self> {
+        Some(match action {
+            "user_create" => AuditAction::UserCreate,
+            "user_update" => AuditAction::UserUpdate,
+            "user_delete" => AuditAction::UserDelete,
+            "user_restore" => AuditAction::UserRestore,
+            "user_purge" => AuditAction::UserPurge,
+            "role_assign" => AuditAction::RoleAssign,
+            "role_remove" => AuditAction::RoleRemove,
+            "perm_grant" => AuditAction::PermGrant,
+            "perm_revoke" => AuditAction::PermRevoke,
+            "perm_deny" => AuditAction::PermDeny,
+            "deny_remove" => AuditAction::DenyRemove,
+            "email_verify" => AuditAction::EmailVerify,
+            "totp_enable" => AuditAction::TotpEnable,
+            "totp_disable" => AuditAction::TotpDisable,
+            "recovery_codes_regenerate" => AuditAction::RecoveryCodesRegenerate,
+            "passkey_add" => AuditAction::PasskeyAdd,
+            "passkey_remove" => AuditAction::PasskeyRemove,
+            "identity_link" => AuditAction::IdentityLink,
+            "identity_unlink" => AuditAction::IdentityUnlink,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[napi(object)]
+pub struct AuditEntry {
+    pub audit_id: i64,
+    /// Epoch seconds
+    pub at: f64,
+    pub actor_uid: Option<String>,
+    pub target_uid: Option<String>,
+    pub action: AuditAction,
+    /// Changed fields as `{ field: { before, after } }`
+    pub diff: Value,
+    pub ip: Option<String>,
+}
+
+#[derive(Default)]
+#[napi(object)]
+pub struct AuditQuery {
+    pub actor_uid: Option<String>,
+    pub target_uid: Option<String>,
+    pub action: Option<AuditAction>,
+    /// Epoch seconds, inclusive
+    pub since: Option<f64>,
+    /// Epoch seconds, exclusive
+    pub until: Option<f64>,
+    /// Page size, 50 by default. The export always pages by 5000.
+    pub limit: Option<u32>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<i64>,
+}
+
+#[napi(object)]
+pub struct AuditPage {
+    /// Newest first
+    pub entries: Vec<AuditEntry>,
+    pub next_cursor: Option<i64>,
+}
+
+#[napi(object)]
+pub struct AuditExport {
+    /// One entry per line, newest first
+    pub jsonl: String,
+    /// Pass back as `cursor` for the next chunk, unset after the last one
+    pub next_cursor: Option<i64>,
+}
+
+fn entry_from_row(row: Row) -> napi::Result<AuditEntry> {
+    let action: &str = row.get("action");
+    let action = AuditAction::parse(action)
+        .ok_or_else(|| napi::Error::from_reason(format!("Unknown audit action: {action}")))?;
+    Ok(AuditEntry {
+        audit_id: row.get("audit_id"),
+        at: row.get("at"),
+        actor_uid: row.get("actor_uid"),
+        target_uid: row.get("target_uid"),
+        action,
+        diff: serde_json::from_str(row.get("diff")).unwrap_or(Value::Null),
+        ip: row.get("ip"),
+    })
+}
+
+/// Fields that differ between two snapshots. Non-object snapshots, such as a missing
+/// `before` on creation, are diffed field by field against nothing.
+pub fn diff(before: &Value, after: &Value) -> Value {
+    let empty = Map::new();
+    let before = before.as_object().unwrap_or(&empty);
+    let after = after.as_object().unwrap_or(&empty);
+
+    let mut changes = Map::new();
+    for key in before.keys().chain(after.keys()) {
+        if changes.contains_key(key) {
+            continue;
+        }
+        let b = before.get(key).unwrap_or(&Value::Null);
+        let a = after.get(key).unwrap_or(&Value::Null);
+        if b != a {
+            changes.insert(key.clone(), json!({ "before": b, "after": a }));
+        }
+    }
+    Value::Object(changes)
+}
+
+/// Appends an entry. Pass the transaction making the change so both commit together.
+pub async fn record(
+    client: &impl GenericClient,
+    ctx: &AuditContext,
+    action: AuditAction,
+    target_uid: Option<&str>,
+    diff: Value,
+) -> napi::Result<()> {
+    let stmt = client
+        .prepare_cached(
+            "INSERT INTO public.Audit_Log (actor_uid, target_uid, action, diff, ip)
+             VALUES (CAST($1 AS TEXT)::uuid, CAST($2 AS TEXT)::uuid, $3, CAST($4 AS TEXT)::jsonb, $5)",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(
+            &stmt,
+            &[
+                &ctx.actor_uid,
+                &target_uid,
+                &action.as_str(),
+                &diff.to_string(),
+                &ctx.ip,
+            ],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Audit insert failed: {e}")))?;
+    Ok(())
+}
+
+async fn query_entries(query: &AuditQuery, limit: Option<i64>) -> napi::Result<Vec<AuditEntry>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let mut filters = Vec::new();
+    let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = Vec::new();
+    let mut param_counter = 1;
+
+    if let Some(actor) = &query.actor_uid {
+        filters.push(format!("actor_uid::text = ${}", param_counter));
+        params.push(actor);
+        param_counter += 1;
+    }
+
+    if let Some(target) = &query.target_uid {
+        filters.push(format!("target_uid::text = ${}", param_counter));
+        params.push(target);
+        param_counter += 1;
+    }
+
+    let action = query.action.map(|a| a.as_str());
+    if let Some(action) = &action {
+        filters.push(format!("action = ${}", param_counter));
+        params.push(action);
+        param_counter += 1;
+    }
+
+    if let Some(since) = &query.since {
+        filters.push(format!("at >= to_timestamp(${})", param_counter));
+        params.push(since);
+        param_counter += 1;
+    }
+
+    if let Some(until) = &query.until {
+        filters.push(format!("at < to_timestamp(${})", param_counter));
+        params.push(until);
+        param_counter += 1;
+    }
+
+    if let Some(cursor) = &query.cursor {
+        filters.push(format!("audit_id < ${}", param_counter));
+        params.push(cursor);
+        param_counter += 1;
+    }
+
+    let where_clause = if filters.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", filters.join(" AND "))
+    };
+    let limit_clause = match &limit {
+        Some(limit) => {
+            params.push(limit);
+            format!("LIMIT ${}", param_counter)
+        }
+        None => String::new(),
+    };
+
+    let sql = format!(
+        "SELECT audit_id, date_part('epoch', at) as at, actor_uid::text as actor_uid,
+            target_uid::text as target_uid, action::text as action, diff::text as diff, ip::text as ip
+         FROM public.Audit_Log {where_clause}
+         ORDER BY audit_id DESC {limit_clause}"
+    );
+
+    let rows = client
+        .query(&sql, &params)
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    rows.into_iter().map(entry_from_row).collect()
+}
+
+/// Up to `limit` entries after the query's cursor, and the cursor for the rest if any
+async fn query_page(
+    query: &AuditQuery,
+    limit: u32,
+) -> napi::Result<(Vec<AuditEntry>, Option<i64>)> {
+    let limit = limit as usize;
+    // One extra row tells us whether another page exists
+    let mut entries = query_entries(query, Some(limit as i64 + 1)).await?;
+    let next_cursor = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|e| e.audit_id)
+    } else {
+        None
+    };
+    Ok((entries, next_cursor))
+}
+
+pub async fn list_audit(query: AuditQuery) -> napi::Result<AuditPage> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let (entries, next_cursor) = query_page(&query, limit).await?;
+    Ok(AuditPage {
+        entries,
+        next_cursor,
+    })
+}
+
+/// Matching entries as JSON lines, newest first, a chunk at a time so a big log never
+/// has to fit in memory at once. Repeat with `next_cursor` until it comes back unset.
+pub async fn export_audit_jsonl(query: AuditQuery) -> napi::Result<AuditExport> {
+    let (entries, next_cursor) = query_page(&query, EXPORT_PAGE_SIZE).await?;
+    let mut jsonl = String::new();
+    for entry in entries {
+        let line =
+            serde_json::to_string(&entry).map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        jsonl.push_str(&line);
+        jsonl.push('\n');
+    }
+    Ok(AuditExport { jsonl, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_lists_added_removed_and_changed_fields() {
+        let before = json!({ "name": "Ann", "email": "a@x.io", "role": "user", "age": 30 });
+        let after = json!({ "name": "Ann", "email": "ann@x.io", "age": 30, "verified": true });
+        assert_eq!(
+            diff(&before, &after),
+            json!({
+                "email": { "before": "a@x.io", "after": "ann@x.io" },
+                "role": { "before": "user", "after": null },
+                "verified": { "before": null, "after": true },
+            })
+        );
+    }
+
+    #[test]
+    fn unchanged_snapshots_have_an_empty_diff() {
+        let same = json!({ "name": "Ann", "tags": ["a", "b"], "meta": { "k": 1 } });
+        assert_eq!(diff(&same, &same), json!({}));
+        assert_eq!(diff(&Value::Null, &Value::Null), json!({}));
+    }
+
+    #[test]
+    fn nested_values_are_compared_whole() {
+        let before = json!({ "meta": { "k": 1, "l": 2 } });
+        let after = json!({ "meta": { "k": 1, "l": 3 } });
+        assert_eq!(
+            diff(&before, &after),
+            json!({ "meta": { "before": { "k": 1, "l": 2 }, "after": { "k": 1, "l": 3 } } })
+        );
+    }
+
+    #[test]
+    fn non_object_before_diffs_against_nothing() {
+        let after = json!({ "name": "Ann", "email": "a@x.io" });
+        let expected = json!({
+            "name": { "before": null, "after": "Ann" },
+            "email": { "before": null, "after": "a@x.io" },
+        });
+        assert_eq!(diff(&Value::Null, &after), expected);
+        assert_eq!(diff(&json!("gone"), &after), expected);
+        assert_eq!(
+            diff(&after, &Value::Null),
+            json!({
+                "name": { "before": "Ann", "after": null },
+                "email": { "before": "a@x.io", "after": null },
+            })
+        );
+    }
+}
@@ -0,0 +1,224 @@
+mod pdf;
+pub mod worksheet;
+
+use db::get_grids_pool;
+use napi_derive::napi;
+
+// Mirrors the grid model in web/GridBuilder.ts so both sides agree on the shape
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct Term {
+    pub en: String,
+    pub de: String,
+}
+
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct Section {
+    pub terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct Phrase {
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct Grid {
+    pub title: String,
+    pub description: String,
+    pub phrases: Vec<Phrase>,
+}
+
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct Sentence {
+    pub en: String,
+    pub de: String,
+}
+
+impl Phrase {
+    /// Number of sentences this phrase can realise (one term picked from each section)
+    pub fn sentence_count(&self) -> usize {
+        if self.sections.is_empty() {
+            return 0;
+        }
+        self.sections
+            .iter()
+            .fold(1usize, |acc, s| acc.saturating_mul(s.terms.len()))
+    }
+
+    /// Picks the terms for the n-th sentence, in the same order `assemble_sentences` yields them
+    pub fn terms_at(&self, mut index: usize) -> Vec<&Term> {
+        let mut picked = Vec::with_capacity(self.sections.len());
+        for section in self.sections.iter().rev() {
+            let len = section.terms.len();
+            picked.push(&section.terms[index % len]);
+            index /= len;
+        }
+        picked.reverse();
+        picked
+    }
+}
+
+pub fn sentence_from_terms(terms: &[&Term]) -> Sentence {
+    Sentence {
+        en: terms
+            .iter()
+            .map(|t| t.en.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        de: terms
+            .iter()
+            .map(|t| t.de.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+pub fn assemble_sentences(phrase: &Phrase) -> Vec<Sentence> {
+    (0..phrase.sentence_count())
+        .map(|i| sentence_from_terms(&phrase.terms_at(i)))
+        .collect()
+}
+
+pub async fn load_grid(grid_id: i32) -> napi::Result<Grid> {
+    let client = get_grids_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "SELECT
+                g.grid_name,
+                g.grid_desc,
+                p.phrase_id,
+                s.section_id,
+                t.en_text,
+                t.de_text
+             FROM public.Grids g
+             LEFT JOIN public.Phrases p ON p.grid_id = g.grid_id
+             LEFT JOIN public.Sections s ON s.phrase_id = p.phrase_id
+             LEFT JOIN public.Terms t ON t.section_id = s.section_id
+             WHERE g.grid_id = $1
+             ORDER BY p.phrase_order, p.phrase_id, s.section_order, s.section_id, t.term_id",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let rows = client
+        .query(&stmt, &[&grid_id])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let first = rows
+        .first()
+        .ok_or(napi::Error::from_reason("Grid not found"))?;
+
+    let mut grid = Grid {
+        title: first
+            .get::<_, Option<String>>("grid_name")
+            .unwrap_or_default(),
+        description: first
+            .get::<_, Option<String>>("grid_desc")
+            .unwrap_or_default(),
+        phrases: Vec::new(),
+    };
+
+    // Rows come back flattened, so rebuild the tree by watching the ids change
+    let mut last_phrase: Option<i32> = None;
+    let mut last_section: Option<i32> = None;
+    for row in &rows {
+        let Some(phrase_id) = row.get::<_, Option<i32>>("phrase_id") else {
+            continue;
+        };
+        if last_phrase != Some(phrase_id) {
+            grid.phrases.push(Phrase {
+                sections: Vec::new(),
+            });
+            last_phrase = Some(phrase_id);
+            last_section = None;
+        }
+        let phrase = grid.phrases.last_mut().expect("phrase pushed above");
+
+        let Some(section_id) = row.get::<_, Option<i32>>("section_id") else {
+            continue;
+        };
+        if last_section != Some(section_id) {
+            phrase.sections.push(Section { terms: Vec::new() });
+            last_section = Some(section_id);
+        }
+        let section = phrase.sections.last_mut().expect("section pushed above");
+
+        if let (Some(en), Some(de)) = (
+            row.get::<_, Option<String>>("en_text"),
+            row.get::<_, Option<String>>("de_text"),
+        ) {
+            section.terms.push(Term { en, de });
+        }
+    }
+
+    Ok(grid)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn section(terms: &[(&str, &str)]) -> Section {
+        Section {
+            terms: terms
+                .iter()
+                .map(|(en, de)| Term {
+                    en: en.to_string(),
+                    de: de.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn phrase() -> Phrase {
+        Phrase {
+            sections: vec![
+                section(&[("the dog", "der Hund"), ("the cat", "die Katze")]),
+                section(&[("eats", "frisst"), ("sees", "sieht"), ("likes", "mag")]),
+                section(&[("fish", "Fisch")]),
+            ],
+        }
+    }
+
+    #[test]
+    fn terms_at_counts_like_an_odometer() {
+        let phrase = phrase();
+        assert_eq!(phrase.sentence_count(), 6);
+        let german: Vec<String> = (0..6)
+            .map(|i| sentence_from_terms(&phrase.terms_at(i)).de)
+            .collect();
+        assert_eq!(
+            german,
+            [
+                "der Hund frisst Fisch",
+                "der Hund sieht Fisch",
+                "der Hund mag Fisch",
+                "die Katze frisst Fisch",
+                "die Katze sieht Fisch",
+                "die Katze mag Fisch",
+            ]
+        );
+        let sentences = assemble_sentences(&phrase);
+        assert_eq!(sentences.len(), 6);
+        assert_eq!(sentences[4].en, "the cat sees fish");
+    }
+
+    #[test]
+    fn empty_sections_realise_nothing() {
+        assert_eq!(Phrase { sections: vec![] }.sentence_count(), 0);
+        let mut phrase = phrase();
+        phrase.sections.push(section(&[]));
+        assert_eq!(phrase.sentence_count(), 0);
+        assert!(assemble_sentences(&phrase).is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+// Just enough PDF to lay out worksheets with the standard 14 fonts,
+// so no font files or external renderers are needed.
+
+const PAGE_WIDTH: f32 = 595.0; // A4 in points
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 56.0;
+
+const BODY_SIZE: f32 = 12.0;
+const NOTE_SIZE: f32 = 10.0;
+const HEADING_SIZE: f32 = 18.0;
+
+#[derive(Clone, Copy)]
+enum Font {
+    Regular,
+    Bold,
+    Oblique,
+}
+
+impl Font {
+    fn resource(self) -> &'static str {
+        match self {
+            Font::Regular => "F1",
+            Font::Bold => "F2",
+            Font::Oblique => "F3",
+        }
+    }
+}
+
+pub struct Document {
+    pages: Vec<String>,
+    y: f32,
+}
+
+impl Document {
+    pub fn new() -> Self {
+        Document {
+            pages: vec![String::new()],
+            y: PAGE_HEIGHT - MARGIN,
+        }
+    }
+
+    pub fn new_page(&mut self) {
+        self.pages.push(String::new());
+        self.y = PAGE_HEIGHT - MARGIN;
+    }
+
+    pub fn heading(&mut self, text: &str) {
+        self.paragraph(text, Font::Bold, HEADING_SIZE, 0.0);
+    }
+
+    pub fn line(&mut self, text: &str) {
+        self.paragraph(text, Font::Regular, BODY_SIZE, 0.0);
+    }
+
+    /// Smaller, slanted text indented under the previous line
+    pub fn note(&mut self, text: &str) {
+        self.paragraph(text, Font::Oblique, NOTE_SIZE, 14.0);
+    }
+
+    pub fn spacer(&mut self) {
+        self.y -= BODY_SIZE * 0.6;
+    }
+
+    fn paragraph(&mut self, text: &str, font: Font, size: f32, indent: f32) {
+        let leading = size * 1.35;
+        let max_width = PAGE_WIDTH - 2.0 * MARGIN - indent;
+        for line in wrap(text, size, max_width) {
+            if self.y - leading < MARGIN {
+                self.new_page();
+            }
+            self.y -= leading;
+            let page = self.pages.last_mut().expect("document always has a page");
+            page.push_str(&format!(
+                "BT /{} {size} Tf {:.2} {:.2} Td ({}) Tj ET\n",
+                font.resource(),
+                MARGIN + indent,
+                self.y,
+                encode(&line)
+            ));
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let page_count = self.pages.len();
+        let mut objects: Vec<Vec<u8>> = Vec::new();
+
+        // Fixed objects: 1 catalog, 2 page tree, 3-5 fonts, then a page and its content per page
+        let kids = (0..page_count)
+            .map(|i| format!("{} 0 R", 6 + i * 2))
+            .collect::<Vec<_>>()
+            .join(" ");
+        objects.push(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+        objects.push(format!("<< /Type /Pages /Kids [{kids}] /Count {page_count} >>").into_bytes());
+        for base in ["Helvetica", "Helvetica-Bold", "Helvetica-Oblique"] {
+            objects.push(
+                format!(
+                    "<< /Type /Font /Subtype /Type1 /BaseFont /{base} /Encoding /WinAnsiEncoding >>"
+                )
+                .into_bytes(),
+            );
+        }
+        for (i, content) in self.pages.iter().enumerate() {
+            objects.push(
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {PAGE_WIDTH} {PAGE_HEIGHT}] \
+                     /Resources << /Font << /F1 3 0 R /F2 4 0 R /F3 5 0 R >> >> /Contents {} 0 R >>",
+                    7 + i * 2
+                )
+                .into_bytes(),
+            );
+            let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+            stream.extend_from_slice(content.as_bytes());
+            stream.extend_from_slice(b"endstream");
+            objects.push(stream);
+        }
+
+        let mut out = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+        let mut offsets = Vec::with_capacity(objects.len());
+        for (i, obj) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+            out.extend_from_slice(obj);
+            out.extend_from_slice(b"\nendobj\n");
+        }
+
+        let xref_at = out.len();
+        out.extend_from_slice(format!("xref\n0 {}\n", objects.len() + 1).as_bytes());
+        out.extend_from_slice(b"0000000000 65535 f \n");
+        for offset in offsets {
+            out.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+        }
+        out.extend_from_slice(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref_at}\n%%EOF\n",
+                objects.len() + 1
+            )
+            .as_bytes(),
+        );
+        out
+    }
+}
+
+fn wrap(text: &str, size: f32, max_width: f32) -> Vec<String> {
+    let space = char_width(' ') * size;
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut width = 0.0;
+    for word in text
+        .split_whitespace()
+        .flat_map(|word| split_long(word, size, max_width))
+    {
+        let w = text_width(word, size);
+        if !current.is_empty() && width + space + w > max_width {
+            lines.push(std::mem::take(&mut current));
+            width = 0.0;
+        }
+        if !current.is_empty() {
+            current.push(' ');
+            width += space;
+        }
+        current.push_str(word);
+        width += w;
+    }
+    if !current.is_empty() || lines.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Hard splits a word too wide for a line of its own into pieces that fit, at least one
+/// character each
+fn split_long(word: &str, size: f32, max_width: f32) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let (mut start, mut width) = (0, 0.0);
+    for (i, c) in word.char_indices() {
+        let w = char_width(c) * size;
+        if i > start && width + w > max_width {
+            pieces.push(&word[start..i]);
+            (start, width) = (i, 0.0);
+        }
+        width += w;
+    }
+    pieces.push(&word[start..]);
+    pieces
+}
+
+fn text_width(text: &str, size: f32) -> f32 {
+    text.chars().map(char_width).sum::<f32>() * size
+}
+
+// Helvetica advance widths from the standard AFM, in 1/1000 em
+fn char_width(c: char) -> f32 {
+    let w = match c {
+        ' ' | '!' | ',' | '.' | '/' | ':' | ';' | '[' | '\\' | ']' | 'I' | 'f' | 't' => 278,
+        '\'' => 191,
+        '"' => 355,
+        '(' | ')' | '-' | '`' | 'r' => 333,
+        '*' => 389,
+        '+' | '<' | '=' | '>' | '~' => 584,
+        '%' => 889,
+        '&' | 'A' | 'B' | 'E' | 'K' | 'P' | 'S' | 'V' | 'X' | 'Y' | 'Ä' => 667,
+        '@' => 1015,
+        'C' | 'D' | 'H' | 'N' | 'R' | 'U' | 'Ü' => 722,
+        'F' | 'T' | 'Z' | 'ß' => 611,
+        'G' | 'O' | 'Q' | 'Ö' => 778,
+        'J' | 'c' | 'k' | 's' | 'v' | 'x' | 'y' | 'z' => 500,
+        'M' | 'm' => 833,
+        'W' => 944,
+        '^' => 469,
+        'i' | 'j' | 'l' => 222,
+        'w' => 722,
+        '{' | '}' => 334,
+        '|' => 260,
+        _ => 556,
+    };
+    w as f32 / 1000.0
+}
+
+/// Escapes a string for a PDF literal, mapping to WinAnsi and falling back to '?'
+fn encode(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        let byte = match c {
+            '\u{20}'..='\u{7e}' | '\u{a0}'..='\u{ff}' => c as u32 as u8,
+            '€' => 0x80,
+            '…' => 0x85,
+            '‘' => 0x91,
+            '’' => 0x92,
+            '“' => 0x93,
+            '”' => 0x94,
+            '–' => 0x96,
+            '—' => 0x97,
+            _ => b'?',
+        };
+        match byte {
+            b'(' | b')' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{byte:03o}")),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_at_word_boundaries() {
+        let max = text_width("der Hund", 12.0) + 0.01;
+        assert_eq!(
+            wrap("der Hund der Katze", 12.0, max),
+            ["der Hund", "der", "Katze"]
+        );
+        assert_eq!(wrap("  der   Hund  ", 12.0, 1000.0), ["der Hund"]);
+        assert_eq!(wrap("", 12.0, 100.0), [""]);
+    }
+
+    #[test]
+    fn splits_words_wider_than_a_line() {
+        let word = "Donaudampfschifffahrtsgesellschaftskapitän";
+        let max = 100.0;
+        let lines = wrap(&format!("ein {word} kommt"), 12.0, max);
+        assert!(lines.len() > 3, "{lines:?}");
+        assert!(
+            lines.iter().all(|l| text_width(l, 12.0) <= max),
+            "{lines:?}"
+        );
+        assert_eq!(lines[0], "ein");
+        assert_eq!(lines.concat().replace(' ', ""), format!("ein{word}kommt"));
+
+        // A character wider than the line still gets a line of its own
+        assert_eq!(wrap("WW", 12.0, 1.0), ["W", "W"]);
+    }
+
+    #[test]
+    fn escapes_and_maps_to_winansi() {
+        assert_eq!(encode("(a) \\ b"), "\\(a\\) \\\\ b");
+        assert_eq!(encode("Grüße €"), "Gr\\374\\337e \\200");
+        assert_eq!(encode("日本"), "??");
+    }
+
+    /// Checks the header, and that `startxref` points at the xref table
+    pub(crate) fn assert_valid_pdf(pdf: &[u8]) {
+        assert!(pdf.starts_with(b"%PDF-"));
+        let text = String::from_utf8_lossy(pdf);
+        assert!(text.ends_with("%%EOF\n"));
+        let offset: usize = text
+            .rsplit("startxref\n")
+            .next()
+            .and_then(|rest| rest.lines().next())
+            .and_then(|line| line.parse().ok())
+            .expect("startxref offset");
+        assert!(pdf[offset..].starts_with(b"xref\n"));
+        // And every object entry at its object
+        let entries = text[offset..].lines().skip(3);
+        for (n, entry) in entries.take_while(|l| l.ends_with(" n ")).enumerate() {
+            let at: usize = entry[..10].parse().unwrap();
+            assert!(pdf[at..].starts_with(format!("{} 0 obj", n + 1).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn writes_a_well_formed_pdf() {
+        let mut doc = Document::new();
+        doc.heading("Titel");
+        for n in 0..80 {
+            doc.line(&format!("{n}. Zeile mit (Klammern) und Umlauten: äöü"));
+        }
+        let pdf = doc.finish();
+        assert_valid_pdf(&pdf);
+        assert!(String::from_utf8_lossy(&pdf).contains("/Count 2"));
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{Grid, pdf};
+use napi_derive::napi;
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::{Rng, SeedableRng};
+
+const DEFAULT_SENTENCE_COUNT: u32 = 10;
+const MIN_BLANK_WIDTH: usize = 8;
+
+#[derive(Debug, Clone, Default)]
+#[napi(object)]
+pub struct WorksheetOptions {
+    /// Number of exercises on the sheet, capped by how many sentences the grid can realise
+    pub sentence_count: Option<u32>,
+    /// Section indices to blank in every sentence, a random section is blanked when unset
+    pub blank_sections: Option<Vec<u32>>,
+    /// Fixed seed so the same sheet can be printed again
+    pub seed: Option<i64>,
+    /// Print the English sentence under each exercise as a hint (defaults to true)
+    pub show_translation: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Gap {
+    Text(String),
+    Blank(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Exercise {
+    pub hint: Option<String>,
+    pub parts: Vec<Gap>,
+}
+
+impl Exercise {
+    pub fn prompt(&self) -> String {
+        self.parts
+            .iter()
+            .map(|p| match p {
+                Gap::Text(t) => t.clone(),
+                Gap::Blank(answer) => blank_for(answer),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn answers(&self) -> Vec<&str> {
+        self.parts
+            .iter()
+            .filter_map(|p| match p {
+                Gap::Blank(answer) => Some(answer.as_str()),
+                Gap::Text(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn solution(&self) -> String {
+        self.parts
+            .iter()
+            .map(|p| match p {
+                Gap::Text(t) | Gap::Blank(t) => t.as_str(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Worksheet {
+    pub title: String,
+    pub description: String,
+    pub exercises: Vec<Exercise>,
+}
+
+pub fn blank_for(answer: &str) -> String {
+    "_".repeat(answer.chars().count().max(MIN_BLANK_WIDTH))
+}
+
+pub fn build_worksheet(grid: &Grid, opts: &WorksheetOptions) -> Worksheet {
+    let mut rng = match opts.seed {
+        Some(seed) => StdRng::seed_from_u64(seed as u64),
+        None => StdRng::from_entropy(),
+    };
+
+    // Every realisable sentence gets a global index so we can sample without building them all
+    let counts: Vec<usize> = grid.phrases.iter().map(|p| p.sentence_count()).collect();
+    let total = counts.iter().fold(0usize, |acc, c| acc.saturating_add(*c));
+    let wanted = (opts.sentence_count.unwrap_or(DEFAULT_SENTENCE_COUNT) as usize).min(total);
+    let show_translation = opts.show_translation.unwrap_or(true);
+
+    let exercises = index::sample(&mut rng, total, wanted)
+        .into_iter()
+        .map(|mut i| {
+            let mut phrase_idx = 0;
+            while i >= counts[phrase_idx] {
+                i -= counts[phrase_idx];
+                phrase_idx += 1;
+            }
+            let terms = grid.phrases[phrase_idx].terms_at(i);
+
+            let requested: Vec<usize> = opts
+                .blank_sections
+                .iter()
+                .flatten()
+                .map(|s| *s as usize)
+                .filter(|s| *s < terms.len())
+                .collect();
+            let blanks = if requested.is_empty() {
+                vec![rng.gen_range(0..terms.len())]
+            } else {
+                requested
+            };
+
+            Exercise {
+                hint: show_translation.then(|| crate::sentence_from_terms(&terms).en),
+                parts: terms
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, t)| {
+                        if blanks.contains(&idx) {
+                            Gap::Blank(t.de.clone())
+                        } else {
+                            Gap::Text(t.de.clone())
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    Worksheet {
+        title: grid.title.clone(),
+        description: grid.description.clone(),
+        exercises,
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+const HTML_STYLE: &str = "
+body { font-family: Helvetica, Arial, sans-serif; max-width: 48rem; margin: 2rem auto; color: #111; }
+h1 { margin-bottom: 0.25rem; }
+.desc { color: #555; margin-top: 0; }
+.meta { display: flex; gap: 3rem; margin: 1.5rem 0; }
+ol li { margin-bottom: 1.25rem; font-size: 1.1rem; }
+.blank { letter-spacing: 0.05em; }
+.hint { display: block; color: #666; font-style: italic; font-size: 0.9rem; }
+.answer-key { break-before: page; page-break-before: always; }
+.answer-key li { margin-bottom: 0.5rem; }
+@media print { body { margin: 0 auto; } }
+";
+
+pub fn render_html(ws: &Worksheet) -> String {
+    let title = escape_html(&ws.title);
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html lang=\"de\">\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!(
+        "<title>{title}</title>\n<style>{HTML_STYLE}</style>\n"
+    ));
+    out.push_str("</head>\n<body>\n<section class=\"worksheet\">\n");
+    out.push_str(&format!("<h1>{title}</h1>\n"));
+    if !ws.description.is_empty() {
+        out.push_str(&format!(
+            "<p class=\"desc\">{}</p>\n",
+            escape_html(&ws.description)
+        ));
+    }
+    out.push_str("<div class=\"meta\"><span>Name: ____________________</span><span>Date: ____________</span></div>\n<ol>\n");
+    for ex in &ws.exercises {
+        let prompt = ex
+            .parts
+            .iter()
+            .map(|p| match p {
+                Gap::Text(t) => escape_html(t),
+                Gap::Blank(a) => format!("<span class=\"blank\">{}</span>", blank_for(a)),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        out.push_str(&format!("<li>{prompt}"));
+        if let Some(hint) = &ex.hint {
+            out.push_str(&format!(
+                "<span class=\"hint\">{}</span>",
+                escape_html(hint)
+            ));
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str("</ol>\n</section>\n");
+
+    out.push_str(&format!(
+        "<section class=\"answer-key\">\n<h1>{title} - Answer key</h1>\n<ol>\n"
+    ));
+    for ex in &ws.exercises {
+        let solution = ex
+            .parts
+            .iter()
+            .map(|p| match p {
+                Gap::Text(t) => escape_html(t),
+                Gap::Blank(a) => format!("<strong>{}</strong>", escape_html(a)),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        out.push_str(&format!("<li>{solution}</li>\n"));
+    }
+    out.push_str("</ol>\n</section>\n</body>\n</html>\n");
+    out
+}
+
+pub fn render_pdf(ws: &Worksheet) -> Vec<u8> {
+    let mut doc = pdf::Document::new();
+
+    doc.heading(&ws.title);
+    if !ws.description.is_empty() {
+        doc.note(&ws.description);
+    }
+    doc.spacer();
+    doc.line("Name: ____________________        Date: ____________");
+    doc.spacer();
+    for (n, ex) in ws.exercises.iter().enumerate() {
+        doc.line(&format!("{}. {}", n + 1, ex.prompt()));
+        if let Some(hint) = &ex.hint {
+            doc.note(hint);
+        }
+        doc.spacer();
+    }
+
+    doc.new_page();
+    doc.heading(&format!("{} - Answer key", ws.title));
+    doc.spacer();
+    for (n, ex) in ws.exercises.iter().enumerate() {
+        doc.line(&format!("{}. {}", n + 1, ex.solution()));
+        doc.note(&ex.answers().join(", "));
+    }
+
+    doc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> Grid {
+        Grid {
+            title: "Tiere <1>".to_string(),
+            description: "Fill in the gaps & check".to_string(),
+            phrases: vec![crate::tests::phrase()],
+        }
+    }
+
+    fn options(seed: i64) -> WorksheetOptions {
+        WorksheetOptions {
+            seed: Some(seed),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_seed_same_sheet() {
+        let grid = grid();
+        let a = build_worksheet(&grid, &options(7));
+        let b = build_worksheet(&grid, &options(7));
+        let prompts = |ws: &Worksheet| {
+            ws.exercises
+                .iter()
+                .map(Exercise::prompt)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(prompts(&a), prompts(&b));
+        // Capped by the 6 sentences the grid can make, each used once
+        assert_eq!(a.exercises.len(), 6);
+        let mut solutions: Vec<String> = a.exercises.iter().map(Exercise::solution).collect();
+        solutions.sort();
+        solutions.dedup();
+        assert_eq!(solutions.len(), 6);
+    }
+
+    #[test]
+    fn blanks_the_requested_sections() {
+        let opts = WorksheetOptions {
+            sentence_count: Some(3),
+            blank_sections: Some(vec![1, 9]),
+            show_translation: Some(false),
+            seed: Some(1),
+        };
+        let ws = build_worksheet(&grid(), &opts);
+        assert_eq!(ws.exercises.len(), 3);
+        for ex in &ws.exercises {
+            assert!(ex.hint.is_none());
+            assert!(matches!(ex.parts[1], Gap::Blank(_)));
+            assert_eq!(ex.answers().len(), 1);
+            assert!(ex.prompt().contains(&"_".repeat(MIN_BLANK_WIDTH)));
+        }
+    }
+
+    #[test]
+    fn html_escapes_grid_text() {
+        let html = render_html(&build_worksheet(&grid(), &options(3)));
+        assert!(html.contains("<h1>Tiere &lt;1&gt;</h1>"));
+        assert!(html.contains("Fill in the gaps &amp; check"));
+        assert!(html.contains("<span class=\"hint\">the "));
+        assert_eq!(html.matches("<li>").count(), 12);
+    }
+
+    #[test]
+    fn pdf_is_well_formed() {
+        let pdf = render_pdf(&build_worksheet(&grid(), &options(3)));
+        crate::pdf::tests::assert_valid_pdf(&pdf);
+    }
+}
@@ -0,0 +1,623 @@
+use crate::JwtError;
+use aws_lc_rs::encoding::{AsDer, PublicKeyX509Der};
+use aws_lc_rs::rsa::KeyPair as RsaKeyPair;
+use aws_lc_rs::signature::{
+    ECDSA_P256_SHA256_FIXED_SIGNING, ECDSA_P384_SHA384_FIXED_SIGNING, EcdsaKeyPair,
+    EcdsaSigningAlgorithm, Ed25519KeyPair, KeyPair,
+};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use serde_json::{Map, Value, json};
+
+// Private keys come as PEM (PKCS#8, or PKCS#1 for RSA and SEC1 for EC, as openssl
+// writes them) or as a private JWK. Only the private key is configured, the public half
+// is derived from it.
+
+/// The public half of an asymmetric key, as the JWK parameters describe it
+#[derive(Debug, Clone)]
+pub enum PublicKey {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    Ec {
+        curve: &'static str,
+        x: Vec<u8>,
+        y: Vec<u8>,
+    },
+    Ed {
+        x: Vec<u8>,
+    },
+}
+
+/// A key tokens are signed and verified with
+pub struct SigningKey {
+    pub algorithm: Algorithm,
+    pub(crate) encoding: EncodingKey,
+    pub(crate) decoding: DecodingKey,
+    /// None for HMAC secrets, which can't be shared
+    public: Option<(PublicKey, Vec<u8>)>,
+}
+
+enum Family {
+    Hmac,
+    Rsa,
+    Ec(&'static EcdsaSigningAlgorithm, &'static str),
+    Ed,
+}
+
+fn family(algorithm: Algorithm) -> Family {
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Family::Hmac,
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => Family::Rsa,
+        Algorithm::ES256 => Family::Ec(&ECDSA_P256_SHA256_FIXED_SIGNING, "P-256"),
+        Algorithm::ES384 => Family::Ec(&ECDSA_P384_SHA384_FIXED_SIGNING, "P-384"),
+        Algorithm::EdDSA => Family::Ed,
+    }
+}
+
+/// The label and DER contents of the first PEM block
+fn pem_decode(pem: &str) -> Result<(String, Vec<u8>), JwtError> {
+    let start = pem
+        .find("-----BEGIN ")
+        .ok_or_else(|| invalid("Not a PEM key"))?;
+    let rest = &pem[start + "-----BEGIN ".len()..];
+    let label_end = rest
+        .find("-----")
+        .ok_or_else(|| invalid("Malformed PEM header"))?;
+    let label = &rest[..label_end];
+    let body = &rest[label_end + "-----".len()..];
+    let end = body
+        .find(&format!("-----END {label}-----"))
+        .ok_or_else(|| invalid(format!("PEM key without an END {label} line")))?;
+    let base64: String = body[..end].chars().filter(|c| !c.is_whitespace()).collect();
+    let der = STANDARD
+        .decode(base64)
+        .map_err(|e| invalid(format!("Malformed PEM body: {e}")))?;
+    Ok((label.to_string(), der))
+}
+
+fn der_length(len: usize, out: &mut Vec<u8>) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+}
+
+/// An `RSAPrivateKey` (RFC 8017) from big-endian integers, in the order the structure
+/// lists them
+fn rsa_private_key_der(integers: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = vec![0x02, 0x01, 0x00];
+    for int in integers {
+        let trimmed: &[u8] = match int.iter().position(|b| *b != 0) {
+            Some(i) => &int[i..],
+            None => &[0],
+        };
+        body.push(0x02);
+        let pad = trimmed[0] & 0x80 != 0;
+        der_length(trimmed.len() + pad as usize, &mut body);
+        if pad {
+            body.push(0);
+        }
+        body.extend_from_slice(trimmed);
+    }
+    let mut der = vec![0x30];
+    der_length(body.len(), &mut der);
+    der.extend(body);
+    der
+}
+
+fn invalid(message: impl Into<String>) -> JwtError {
+    JwtError::Key(message.into())
+}
+
+fn key_rejected(e: impl std::fmt::Display) -> JwtError {
+    invalid(format!("Invalid private key: {e}"))
+}
+
+fn spki(key: &impl AsDer<PublicKeyX509Der<'static>>) -> Result<Vec<u8>, JwtError> {
+    Ok(key.as_der().map_err(key_rejected)?.as_ref().to_vec())
+}
+
+impl SigningKey {
+    /// A shared secret, for the HMAC algorithms
+    pub fn hmac(algorithm: Algorithm, secret: &[u8]) -> Result<Self, JwtError> {
+        if !matches!(family(algorithm), Family::Hmac) {
+            return Err(invalid(format!(
+                "{algorithm:?} needs a private key, not a secret"
+            )));
+        }
+        Ok(SigningKey {
+            algorithm,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            public: None,
+        })
+    }
+
+    fn rsa(
+        algorithm: Algorithm,
+        pair: RsaKeyPair,
+        encoding: EncodingKey,
+    ) -> Result<Self, JwtError> {
+        let public = pair.public_key();
+        let n = public.modulus().big_endian_without_leading_zero().to_vec();
+        let e = public.exponent().big_endian_without_leading_zero().to_vec();
+        Ok(SigningKey {
+            algorithm,
+            encoding,
+            decoding: DecodingKey::from_rsa_raw_components(&n, &e),
+            public: Some((PublicKey::Rsa { n, e }, spki(public)?)),
+        })
+    }
+
+    fn ec(algorithm: Algorithm, curve: &'static str, pair: EcdsaKeyPair) -> Result<Self, JwtError> {
+        let pkcs8 = pair.to_pkcs8v1().map_err(key_rejected)?;
+        // Uncompressed point, 0x04 || x || y
+        let point = pair.public_key().as_ref();
+        let (x, y) = point[1..].split_at((point.len() - 1) / 2);
+        Ok(SigningKey {
+            algorithm,
+            encoding: EncodingKey::from_ec_der(pkcs8.as_ref()),
+            decoding: DecodingKey::from_ec_der(point),
+            public: Some((
+                PublicKey::Ec {
+                    curve,
+                    x: x.to_vec(),
+                    y: y.to_vec(),
+                },
+                spki(pair.public_key())?,
+            )),
+        })
+    }
+
+    fn ed(algorithm: Algorithm, pair: Ed25519KeyPair) -> Result<Self, JwtError> {
+        let pkcs8 = pair.to_pkcs8v1().map_err(key_rejected)?;
+        let x = pair.public_key().as_ref().to_vec();
+        Ok(SigningKey {
+            algorithm,
+            encoding: EncodingKey::from_ed_der(pkcs8.as_ref()),
+            decoding: DecodingKey::from_ed_der(&x),
+            public: Some((PublicKey::Ed { x }, spki(pair.public_key())?)),
+        })
+    }
+
+    /// A PEM private key: `PRIVATE KEY` (PKCS#8), `RSA PRIVATE KEY` or `EC PRIVATE KEY`
+    pub fn from_pem(algorithm: Algorithm, pem: &str) -> Result<Self, JwtError> {
+        let (label, der) = pem_decode(pem)?;
+        match (family(algorithm), label.as_str()) {
+            (Family::Hmac, _) => Err(invalid(format!("{algorithm:?} takes a secret, not a key"))),
+            (Family::Rsa, "PRIVATE KEY") => Self::rsa(
+                algorithm,
+                RsaKeyPair::from_pkcs8(&der).map_err(key_rejected)?,
+                EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(key_rejected)?,
+            ),
+            (Family::Rsa, "RSA PRIVATE KEY") => Self::rsa(
+                algorithm,
+                RsaKeyPair::from_der(&der).map_err(key_rejected)?,
+                EncodingKey::from_rsa_der(&der),
+            ),
+            (Family::Ec(alg, curve), "PRIVATE KEY" | "EC PRIVATE KEY") => Self::ec(
+                algorithm,
+                curve,
+                EcdsaKeyPair::from_private_key_der(alg, &der)
+                    .map_err(|_| invalid(format!("Not a {curve} private key")))?,
+            ),
+            (Family::Ed, "PRIVATE KEY") => Self::ed(
+                algorithm,
+                Ed25519KeyPair::from_pkcs8(&der).map_err(key_rejected)?,
+            ),
+            (_, label) => Err(invalid(format!(
+                "A {label} PEM can't be used for {algorithm:?}"
+            ))),
+        }
+    }
+
+    /// A private JWK, with `d` and the rest of the private parameters
+    pub fn from_jwk(algorithm: Algorithm, jwk: &str) -> Result<Self, JwtError> {
+        let jwk: Value =
+            serde_json::from_str(jwk).map_err(|e| invalid(format!("Malformed JWK: {e}")))?;
+        let param = |name: &str| -> Result<Vec<u8>, JwtError> {
+            let value = jwk[name]
+                .as_str()
+                .ok_or_else(|| invalid(format!("JWK without {name}")))?;
+            URL_SAFE_NO_PAD
+                .decode(value.trim_end_matches('='))
+                .map_err(|_| invalid(format!("JWK {name} isn't base64url")))
+        };
+        if let Some(alg) = jwk["alg"].as_str()
+            && alg != format!("{algorithm:?}")
+        {
+            return Err(invalid(format!("JWK is for {alg}, not {algorithm:?}")));
+        }
+
+        match (family(algorithm), jwk["kty"].as_str()) {
+            (Family::Hmac, _) => Err(invalid(format!("{algorithm:?} takes a secret, not a key"))),
+            (Family::Rsa, Some("RSA")) => {
+                let integers = ["n", "e", "d", "p", "q", "dp", "dq", "qi"]
+                    .iter()
+                    .map(|name| param(name))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let der = rsa_private_key_der(&integers);
+                Self::rsa(
+                    algorithm,
+                    RsaKeyPair::from_der(&der).map_err(key_rejected)?,
+                    EncodingKey::from_rsa_der(&der),
+                )
+            }
+            (Family::Ec(alg, curve), Some("EC")) => {
+                if jwk["crv"].as_str() != Some(curve) {
+                    return Err(invalid(format!("{algorithm:?} needs a {curve} key")));
+                }
+                let mut point = vec![0x04];
+                point.extend(param("x")?);
+                point.extend(param("y")?);
+                Self::ec(
+                    algorithm,
+                    curve,
+                    EcdsaKeyPair::from_private_key_and_public_key(alg, &param("d")?, &point)
+                        .map_err(key_rejected)?,
+                )
+            }
+            (Family::Ed, Some("OKP")) => {
+                if jwk["crv"].as_str() != Some("Ed25519") {
+                    return Err(invalid("EdDSA needs an Ed25519 key"));
+                }
+                Self::ed(
+                    algorithm,
+                    Ed25519KeyPair::from_seed_and_public_key(&param("d")?, &param("x")?)
+                        .map_err(key_rejected)?,
+                )
+            }
+            (_, kty) => Err(invalid(format!(
+                "A {} JWK can't be used for {algorithm:?}",
+                kty.unwrap_or("typeless")
+            ))),
+        }
+    }
+
+    /// A private key as PEM or JWK, told apart by the leading `{`
+    pub fn from_private_key(algorithm: Algorithm, key: &str) -> Result<Self, JwtError> {
+        if key.trim_start().starts_with('{') {
+            Self::from_jwk(algorithm, key)
+        } else {
+            Self::from_pem(algorithm, key)
+        }
+    }
+
+    pub fn public_key(&self) -> Option<&PublicKey> {
+        self.public.as_ref().map(|(key, _)| key)
+    }
+
+    /// The public key as a `PUBLIC KEY` PEM, for services that verify our tokens
+    pub fn public_key_pem(&self) -> Option<String> {
+        self.public.as_ref().map(|(_, spki)| {
+            let base64 = STANDARD.encode(spki);
+            let lines: Vec<&str> = base64
+                .as_bytes()
+                .chunks(64)
+                .map(|line| std::str::from_utf8(line).expect("base64 is ASCII"))
+                .collect();
+            format!(
+                "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+                lines.join("\n")
+            )
+        })
+    }
+}
+
+impl PublicKey {
+    /// The key's JWK parameters (RFC 7518)
+    fn jwk(&self) -> Map<String, Value> {
+        let b64 = |bytes: &[u8]| Value::String(URL_SAFE_NO_PAD.encode(bytes));
+        let mut jwk = Map::new();
+        match self {
+            PublicKey::Rsa { n, e } => {
+                jwk.insert("kty".into(), "RSA".into());
+                jwk.insert("n".into(), b64(n));
+                jwk.insert("e".into(), b64(e));
+            }
+            PublicKey::Ec { curve, x, y } => {
+                jwk.insert("kty".into(), "EC".into());
+                jwk.insert("crv".into(), (*curve).into());
+                jwk.insert("x".into(), b64(x));
+                jwk.insert("y".into(), b64(y));
+            }
+            PublicKey::Ed { x } => {
+                jwk.insert("kty".into(), "OKP".into());
+                jwk.insert("crv".into(), "Ed25519".into());
+                jwk.insert("x".into(), b64(x));
+            }
+        }
+        jwk
+    }
+}
+
+/// Keys by `kid`. The first one signs, the rest only verify what they signed before they
+/// were retired, until it expires.
+pub struct KeyRing {
+    keys: Vec<(String, SigningKey)>,
+}
+
+impl KeyRing {
+    pub fn new(keys: Vec<(String, SigningKey)>) -> Result<Self, JwtError> {
+        if keys.is_empty() {
+            return Err(invalid("A key ring needs at least one key"));
+        }
+        for (i, (kid, _)) in keys.iter().enumerate() {
+            if kid.is_empty() {
+                return Err(invalid("Keys need a kid"));
+            }
+            if keys[..i].iter().any(|(other, _)| other == kid) {
+                return Err(invalid(format!("Duplicate key id: {kid}")));
+            }
+        }
+        Ok(KeyRing { keys })
+    }
+
+    /// The key new tokens are signed with, and its kid
+    pub fn active(&self) -> (&str, &SigningKey) {
+        let (kid, key) = &self.keys[0];
+        (kid, key)
+    }
+
+    pub fn get(&self, kid: &str) -> Option<&SigningKey> {
+        self.keys.iter().find(|(k, _)| k == kid).map(|(_, key)| key)
+    }
+
+    /// The public keys as a JWKS document (RFC 7517). HMAC secrets are left out.
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<Value> = self
+            .keys
+            .iter()
+            .filter_map(|(kid, key)| {
+                let mut jwk = key.public_key()?.jwk();
+                jwk.insert("kid".into(), kid.as_str().into());
+                jwk.insert("use".into(), "sig".into());
+                jwk.insert("alg".into(), format!("{:?}", key.algorithm).into());
+                Some(Value::Object(jwk))
+            })
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{Header, Validation, decode, encode};
+
+    const RSA_PKCS8: &str = include_str!("../testdata/rsa_pkcs8.pem");
+    const RSA_PKCS1: &str = include_str!("../testdata/rsa_pkcs1.pem");
+    const RSA_JWK: &str = include_str!("../testdata/rsa.jwk");
+    const EC_SEC1: &str = include_str!("../testdata/ec_sec1.pem");
+    const EC_PKCS8: &str = include_str!("../testdata/ec_pkcs8.pem");
+    const EC_JWK: &str = include_str!("../testdata/ec.jwk");
+    const EC384_SEC1: &str = include_str!("../testdata/ec384_sec1.pem");
+    const ED_PKCS8: &str = include_str!("../testdata/ed_pkcs8.pem");
+    const ED_JWK: &str = include_str!("../testdata/ed.jwk");
+
+    /// Signs a token with `key` and checks it with both its own decoding key and one
+    /// built from the public PEM, the way another service would. Returns the PEM.
+    fn round_trip(key: &SigningKey) -> String {
+        let claims = json!({ "sub": "u1", "exp": 4_000_000_000u64 });
+        let token = encode(&Header::new(key.algorithm), &claims, &key.encoding).unwrap();
+        let validation = Validation::new(key.algorithm);
+        let own = decode::<Value>(&token, &key.decoding, &validation).unwrap();
+        assert_eq!(own.claims, claims);
+
+        let pem = key.public_key_pem().unwrap();
+        let public = match family(key.algorithm) {
+            Family::Rsa => DecodingKey::from_rsa_pem(pem.as_bytes()),
+            Family::Ec(..) => DecodingKey::from_ec_pem(pem.as_bytes()),
+            Family::Ed => DecodingKey::from_ed_pem(pem.as_bytes()),
+            Family::Hmac => unreachable!(),
+        }
+        .unwrap();
+        assert_eq!(
+            decode::<Value>(&token, &public, &validation)
+                .unwrap()
+                .claims,
+            claims
+        );
+        pem
+    }
+
+    fn error(result: Result<SigningKey, JwtError>) -> String {
+        match result {
+            Err(JwtError::Key(message)) => message,
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("key was accepted"),
+        }
+    }
+
+    #[test]
+    fn loads_rsa_keys() {
+        let pkcs8 = round_trip(&SigningKey::from_pem(Algorithm::RS256, RSA_PKCS8).unwrap());
+        let pkcs1 = round_trip(&SigningKey::from_pem(Algorithm::RS256, RSA_PKCS1).unwrap());
+        let jwk = round_trip(&SigningKey::from_jwk(Algorithm::RS256, RSA_JWK).unwrap());
+        assert_eq!(pkcs8, pkcs1);
+        assert_eq!(pkcs8, jwk);
+        round_trip(&SigningKey::from_pem(Algorithm::PS256, RSA_PKCS8).unwrap());
+    }
+
+    #[test]
+    fn loads_ec_keys() {
+        let sec1 = round_trip(&SigningKey::from_pem(Algorithm::ES256, EC_SEC1).unwrap());
+        let pkcs8 = round_trip(&SigningKey::from_pem(Algorithm::ES256, EC_PKCS8).unwrap());
+        let jwk = round_trip(&SigningKey::from_jwk(Algorithm::ES256, EC_JWK).unwrap());
+        assert_eq!(sec1, pkcs8);
+        assert_eq!(sec1, jwk);
+        round_trip(&SigningKey::from_pem(Algorithm::ES384, EC384_SEC1).unwrap());
+    }
+
+    #[test]
+    fn loads_ed25519_keys() {
+        let pkcs8 = round_trip(&SigningKey::from_pem(Algorithm::EdDSA, ED_PKCS8).unwrap());
+        let jwk = round_trip(&SigningKey::from_jwk(Algorithm::EdDSA, ED_JWK).unwrap());
+        assert_eq!(pkcs8, jwk);
+    }
+
+    #[test]
+    fn picks_the_format_from_the_key() {
+        let pem = SigningKey::from_private_key(Algorithm::ES256, EC_SEC1).unwrap();
+        let jwk = SigningKey::from_private_key(Algorithm::ES256, EC_JWK).unwrap();
+        assert_eq!(pem.public_key_pem(), jwk.public_key_pem());
+    }
+
+    #[test]
+    fn derives_the_public_jwk() {
+        let key = SigningKey::from_pem(Algorithm::ES256, EC_SEC1).unwrap();
+        let private: Value = serde_json::from_str(EC_JWK).unwrap();
+        let public = key.public_key().unwrap().jwk();
+        for name in ["kty", "crv", "x", "y"] {
+            assert_eq!(public[name], private[name], "{name}");
+        }
+        assert!(!public.contains_key("d"));
+    }
+
+    #[test]
+    fn rejects_keys_for_another_algorithm() {
+        assert!(error(SigningKey::from_pem(Algorithm::RS256, EC_SEC1)).contains("can't be used"));
+        assert!(error(SigningKey::from_pem(Algorithm::EdDSA, RSA_PKCS1)).contains("can't be used"));
+        assert!(error(SigningKey::from_pem(Algorithm::ES256, RSA_PKCS8)).contains("P-256"));
+        assert!(error(SigningKey::from_jwk(Algorithm::EdDSA, RSA_JWK)).contains("RS256"));
+        assert!(error(SigningKey::from_jwk(Algorithm::ES256, ED_JWK)).contains("can't be used"));
+        assert!(error(SigningKey::from_pem(Algorithm::HS256, RSA_PKCS8)).contains("secret"));
+        assert!(error(SigningKey::hmac(Algorithm::RS256, b"secret")).contains("private key"));
+    }
+
+    #[test]
+    fn rejects_keys_on_another_curve() {
+        assert!(error(SigningKey::from_pem(Algorithm::ES256, EC384_SEC1)).contains("P-256"));
+        assert!(error(SigningKey::from_pem(Algorithm::ES384, EC_SEC1)).contains("P-384"));
+
+        let mut jwk: Value = serde_json::from_str(EC_JWK).unwrap();
+        jwk["crv"] = "P-384".into();
+        assert!(error(SigningKey::from_jwk(Algorithm::ES256, &jwk.to_string())).contains("P-256"));
+
+        let mut jwk: Value = serde_json::from_str(ED_JWK).unwrap();
+        jwk["crv"] = "Ed448".into();
+        assert!(
+            error(SigningKey::from_jwk(Algorithm::EdDSA, &jwk.to_string())).contains("Ed25519")
+        );
+    }
+
+    #[test]
+    fn rejects_a_jwk_for_another_alg() {
+        assert!(error(SigningKey::from_jwk(Algorithm::RS384, RSA_JWK)).contains("is for RS256"));
+        let mut jwk: Value = serde_json::from_str(RSA_JWK).unwrap();
+        jwk.as_object_mut().unwrap().remove("alg");
+        round_trip(&SigningKey::from_jwk(Algorithm::RS384, &jwk.to_string()).unwrap());
+    }
+
+    #[test]
+    fn rejects_an_inconsistent_rsa_jwk() {
+        let mut jwk: Value = serde_json::from_str(RSA_JWK).unwrap();
+        jwk["q"] = jwk["p"].clone();
+        assert!(
+            error(SigningKey::from_jwk(Algorithm::RS256, &jwk.to_string())).contains("Invalid")
+        );
+        jwk.as_object_mut().unwrap().remove("dp");
+        assert!(error(SigningKey::from_jwk(Algorithm::RS256, &jwk.to_string())).contains("dp"));
+    }
+
+    #[test]
+    fn encodes_der_lengths() {
+        let encode = |len| {
+            let mut out = Vec::new();
+            der_length(len, &mut out);
+            out
+        };
+        assert_eq!(encode(0x7f), [0x7f]);
+        assert_eq!(encode(0x80), [0x81, 0x80]);
+        assert_eq!(encode(0x100), [0x82, 0x01, 0x00]);
+        // Positive integers with the top bit set get a leading zero
+        assert_eq!(
+            rsa_private_key_der(&[vec![0x00, 0x80]]),
+            [0x30, 0x07, 0x02, 0x01, 0x00, 0x02, 0x02, 0x00, 0x80]
+        );
+    }
+
+    fn key_ring(keys: Vec<(&str, SigningKey)>) -> Result<KeyRing, JwtError> {
+        KeyRing::new(
+            keys.into_iter()
+                .map(|(kid, key)| (kid.to_string(), key))
+                .collect(),
+        )
+    }
+
+    fn hmac() -> SigningKey {
+        SigningKey::hmac(Algorithm::HS256, b"secret").unwrap()
+    }
+
+    #[test]
+    fn signs_with_the_first_key() {
+        let ring = key_ring(vec![
+            (
+                "new",
+                SigningKey::from_pem(Algorithm::ES256, EC_SEC1).unwrap(),
+            ),
+            ("old", hmac()),
+        ])
+        .unwrap();
+        let (kid, key) = ring.active();
+        assert_eq!(kid, "new");
+        assert_eq!(key.algorithm, Algorithm::ES256);
+        assert_eq!(ring.get("old").unwrap().algorithm, Algorithm::HS256);
+        assert!(ring.get("other").is_none());
+    }
+
+    #[test]
+    fn jwks_lists_only_public_keys() {
+        let ring = key_ring(vec![
+            ("hmac", hmac()),
+            (
+                "ec",
+                SigningKey::from_pem(Algorithm::ES256, EC_SEC1).unwrap(),
+            ),
+            (
+                "ed",
+                SigningKey::from_pem(Algorithm::EdDSA, ED_PKCS8).unwrap(),
+            ),
+        ])
+        .unwrap();
+        let jwks = ring.jwks();
+        let keys = jwks["keys"].as_array().unwrap();
+        let kids: Vec<&str> = keys.iter().map(|k| k["kid"].as_str().unwrap()).collect();
+        assert_eq!(kids, ["ec", "ed"]);
+        assert_eq!(keys[0]["alg"], "ES256");
+        assert_eq!(keys[0]["use"], "sig");
+        assert_eq!(keys[1]["kty"], "OKP");
+        assert!(keys.iter().all(|k| k.get("d").is_none()));
+
+        let secrets_only = key_ring(vec![("hmac", hmac())]).unwrap();
+        assert_eq!(secrets_only.jwks(), json!({ "keys": [] }));
+    }
+
+    #[test]
+    fn rejects_empty_and_duplicate_kids() {
+        assert!(matches!(key_ring(vec![]), Err(JwtError::Key(_))));
+        assert!(matches!(
+            key_ring(vec![("", hmac())]),
+            Err(JwtError::Key(_))
+        ));
+        let duplicate = key_ring(vec![("a", hmac()), ("b", hmac()), ("a", hmac())]);
+        assert!(matches!(duplicate, Err(JwtError::Key(m)) if m.contains("Duplicate key id: a")));
+    }
+}
@@ -1,9 +1,74 @@
+mod keys;
+
+pub use keys::{KeyRing, PublicKey, SigningKey};
+
 use chrono::{Duration, Utc};
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use jsonwebtoken::{Algorithm, Header, Validation, decode, decode_header, encode};
 use napi_derive::napi;
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::str::FromStr;
 use std::sync::OnceLock;
+use thiserror::Error;
+
+/// Clock skew allowed on `exp` and `nbf` unless `JWT_LEEWAY_SECONDS` says otherwise
+const DEFAULT_LEEWAY_SECONDS: u64 = 60;
+
+#[derive(Error, Debug, Clone)]
+pub enum JwtError {
+    /// Missing or invalid `JWT_*` settings
+    #[error("JWT configuration error: {0}")]
+    Config(String),
+    #[error("Invalid JWT key: {0}")]
+    Key(String),
+    #[error("Failed to sign token: {0}")]
+    Signing(String),
+    /// Not a JWT, or claims that don't parse
+    #[error("Malformed token: {0}")]
+    Malformed(String),
+    #[error("Token signed with an unknown key")]
+    UnknownKey,
+    /// Signed with another algorithm than its key is for
+    #[error("Token signed with the wrong algorithm")]
+    InvalidAlgorithm,
+    #[error("Invalid token signature")]
+    InvalidSignature,
+    #[error("Token expired")]
+    Expired,
+    /// `nbf` is still in the future
+    #[error("Token not valid yet")]
+    NotYetValid,
+    #[error("Token from another issuer")]
+    InvalidIssuer,
+    #[error("Token for another audience")]
+    InvalidAudience,
+    #[error("Token without the {0} claim")]
+    MissingClaim(String),
+    #[error("Wrong token type, expected {expected}")]
+    WrongTokenType { expected: &'static str },
+}
+
+impl From<jsonwebtoken::errors::Error> for JwtError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        use jsonwebtoken::errors::ErrorKind;
+        match err.kind() {
+            ErrorKind::InvalidSignature => JwtError::InvalidSignature,
+            ErrorKind::InvalidAlgorithm => JwtError::InvalidAlgorithm,
+            ErrorKind::ExpiredSignature => JwtError::Expired,
+            ErrorKind::ImmatureSignature => JwtError::NotYetValid,
+            ErrorKind::InvalidIssuer => JwtError::InvalidIssuer,
+            ErrorKind::InvalidAudience => JwtError::InvalidAudience,
+            ErrorKind::MissingRequiredClaim(claim) => JwtError::MissingClaim(claim.clone()),
+            _ => JwtError::Malformed(err.to_string()),
+        }
+    }
+}
+
+impl From<JwtError> for napi::Error {
+    fn from(err: JwtError) -> Self {
+        napi::Error::from_reason(err.to_string())
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[napi(object)]
@@ -12,6 +77,15 @@ pub struct AccessTokenClaims {
     pub email: String,
     pub iat: i64,
     pub exp: i64,
+    /// Missing on tokens issued before `nbf` was set, see `verify_access_token`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    /// `JWT_ISSUER`, if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// `JWT_AUDIENCE`, if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
     pub token_type: String, // "access"
 }
 
@@ -27,40 +101,158 @@ pub struct RefreshTokenClaims {
 }
 
 pub struct JwtManager {
-    access_secret: Vec<u8>,
-    refresh_secret: Vec<u8>,
+    access_keys: KeyRing,
+    /// Refresh tokens never leave us, so they stay on a shared secret
+    refresh_key: SigningKey,
     access_exp_hours: i64,
     refresh_exp_days: i64,
+    /// Set on access tokens and required of them when verifying
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    /// Tokens issued from this unix time on must carry `nbf`
+    nbf_required_from: i64,
 }
 
-impl JwtManager {
-    pub fn new() -> Result<Self, String> {
-        let access_secret =
-            env::var("JWT_ACCESS_SECRET").map_err(|_| "JWT_ACCESS_SECRET not set".to_string())?;
+/// The kid of the key configured without `JWT_ACCESS_KEYS`. Tokens from before there were
+/// kids are verified with it too.
+const DEFAULT_KID: &str = "default";
+
+fn algorithm_from_env(var: &str) -> Result<Option<Algorithm>, JwtError> {
+    match env::var(var) {
+        Ok(alg) if !alg.is_empty() => Algorithm::from_str(&alg)
+            .map(Some)
+            .map_err(|_| JwtError::Config(format!("Unsupported {var}: {alg}"))),
+        _ => Ok(None),
+    }
+}
+
+/// A key from `<prefix>_SECRET` for the HMAC algorithms, or for the others (RS256, ES256,
+/// EdDSA and the like) a private key, PEM or JWK, from `<prefix>_PRIVATE_KEY` or the file
+/// `<prefix>_PRIVATE_KEY_FILE` names
+fn access_key(prefix: &str, algorithm: Algorithm) -> Result<SigningKey, JwtError> {
+    if matches!(
+        algorithm,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        let secret = env::var(format!("{prefix}_SECRET"))
+            .map_err(|_| JwtError::Config(format!("{prefix}_SECRET not set")))?;
+        return SigningKey::hmac(algorithm, secret.as_bytes());
+    }
+
+    let pem = match (
+        env::var(format!("{prefix}_PRIVATE_KEY")),
+        env::var(format!("{prefix}_PRIVATE_KEY_FILE")),
+    ) {
+        // Env files often hold PEM on one line with escaped newlines
+        (Ok(key), _) if !key.is_empty() => key.replace("\\n", "\n"),
+        (_, Ok(path)) if !path.is_empty() => std::fs::read_to_string(&path).map_err(|e| {
+            JwtError::Config(format!(
+                "Failed to read {prefix}_PRIVATE_KEY_FILE {path}: {e}"
+            ))
+        })?,
+        _ => {
+            return Err(JwtError::Config(format!(
+                "{prefix}_PRIVATE_KEY or {prefix}_PRIVATE_KEY_FILE must be set for {algorithm:?}"
+            )));
+        }
+    };
+    SigningKey::from_private_key(algorithm, &pem)
+        .map_err(|e| JwtError::Config(format!("{prefix}: {e}")))
+}
 
-        let refresh_secret =
-            env::var("JWT_REFRESH_SECRET").map_err(|_| "JWT_REFRESH_SECRET not set".to_string())?;
+/// `JWT_ALGORITHM` picks how access tokens are signed, HS256 by default, with the key from
+/// `JWT_ACCESS_SECRET` or `JWT_ACCESS_PRIVATE_KEY(_FILE)`. Asymmetric keys let other services
+/// verify access tokens with the public key.
+///
+/// To rotate, `JWT_ACCESS_KEYS` lists kids (comma separated), each configured by
+/// `JWT_ACCESS_KEY_<KID>_SECRET`, `_PRIVATE_KEY` or `_PRIVATE_KEY_FILE`, and optionally
+/// `_ALGORITHM`. The first one signs new tokens, the rest keep verifying old ones and can
+/// go once those have expired. The key from before is `default`.
+fn access_keys() -> Result<KeyRing, JwtError> {
+    let algorithm = algorithm_from_env("JWT_ALGORITHM")?.unwrap_or(Algorithm::HS256);
+    let configured = env::var("JWT_ACCESS_KEYS").unwrap_or_default();
+    let kids: Vec<&str> = configured
+        .split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .collect();
+    if kids.is_empty() {
+        return KeyRing::new(vec![(
+            DEFAULT_KID.to_string(),
+            access_key("JWT_ACCESS", algorithm)?,
+        )]);
+    }
+
+    let keys = kids
+        .into_iter()
+        .map(|kid| {
+            if !kid
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(JwtError::Config(format!(
+                    "Invalid JWT_ACCESS_KEYS kid {kid}, use letters, digits, - and _"
+                )));
+            }
+            let prefix = format!("JWT_ACCESS_KEY_{}", kid.to_uppercase().replace('-', "_"));
+            let algorithm =
+                algorithm_from_env(&format!("{prefix}_ALGORITHM"))?.unwrap_or(algorithm);
+            Ok((kid.to_string(), access_key(&prefix, algorithm)?))
+        })
+        .collect::<Result<Vec<_>, JwtError>>()?;
+    KeyRing::new(keys)
+}
+
+impl JwtManager {
+    /// Access tokens carry `JWT_ISSUER` and `JWT_AUDIENCE` when they're set, and only
+    /// verify when they match. `JWT_LEEWAY_SECONDS` is the clock skew allowed on `exp` and
+    /// `nbf`, 60 by default. Tokens issued before `JWT_NBF_REQUIRED_FROM`, a unix time that
+    /// defaults to startup, may lack `nbf`. Set it past the last old instance's shutdown in
+    /// a rolling deploy.
+    pub fn new() -> Result<Self, JwtError> {
+        let access_keys = access_keys()?;
+
+        let refresh_secret = env::var("JWT_REFRESH_SECRET")
+            .map_err(|_| JwtError::Config("JWT_REFRESH_SECRET not set".to_string()))?;
+        let refresh_key = SigningKey::hmac(Algorithm::HS256, refresh_secret.as_bytes())?;
 
         // Different expiration times for access vs refresh tokens
         let access_exp_hours = env::var("JWT_ACCESS_EXPIRY_HRS")
             .unwrap_or_else(|_| "1".to_string()) // 1 hour for access
             .parse::<i64>()
-            .map_err(|e| format!("Invalid JWT_ACCESS_EXPIRY_HRS: {}", e))?;
+            .map_err(|e| JwtError::Config(format!("Invalid JWT_ACCESS_EXPIRY_HRS: {}", e)))?;
 
         let refresh_exp_days = env::var("JWT_REFRESH_EXPIRY_DAYS")
             .unwrap_or_else(|_| "30".to_string()) // 30 days for refresh
             .parse::<i64>()
-            .map_err(|e| format!("Invalid JWT_REFRESH_EXPIRY_DAYS: {}", e))?;
+            .map_err(|e| JwtError::Config(format!("Invalid JWT_REFRESH_EXPIRY_DAYS: {}", e)))?;
+
+        let setting = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let leeway = setting("JWT_LEEWAY_SECONDS")
+            .map(|v| v.parse::<u64>())
+            .transpose()
+            .map_err(|e| JwtError::Config(format!("Invalid JWT_LEEWAY_SECONDS: {}", e)))?
+            .unwrap_or(DEFAULT_LEEWAY_SECONDS);
+        let nbf_required_from = setting("JWT_NBF_REQUIRED_FROM")
+            .map(|v| v.parse::<i64>())
+            .transpose()
+            .map_err(|e| JwtError::Config(format!("Invalid JWT_NBF_REQUIRED_FROM: {}", e)))?
+            .unwrap_or_else(|| Utc::now().timestamp());
 
         Ok(JwtManager {
-            access_secret: access_secret.into_bytes(),
-            refresh_secret: refresh_secret.into_bytes(),
+            access_keys,
+            refresh_key,
             access_exp_hours,
             refresh_exp_days,
+            issuer: setting("JWT_ISSUER"),
+            audience: setting("JWT_AUDIENCE"),
+            leeway,
+            nbf_required_from,
         })
     }
 
-    pub async fn gen_access_token(&self, uid: &str, email: &str) -> Result<String, String> {
+    pub async fn gen_access_token(&self, uid: &str, email: &str) -> Result<String, JwtError> {
         let now = Utc::now();
         let iat = now.timestamp();
         let exp = (now + Duration::hours(self.access_exp_hours)).timestamp();
@@ -70,19 +262,25 @@ impl JwtManager {
             email: email.to_string(),
             iat,
             exp,
+            nbf: Some(iat),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
             token_type: "access".to_string(),
         };
 
-        let encoding_key = EncodingKey::from_secret(&self.access_secret);
-        encode(&Header::new(Algorithm::HS256), &claims, &encoding_key)
-            .map_err(|e| format!("Failed to encode access token: {}", e))
+        let (kid, key) = self.access_keys.active();
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::new(key.algorithm)
+        };
+        encode(&header, &claims, &key.encoding).map_err(|e| JwtError::Signing(e.to_string()))
     }
 
     pub async fn gen_refresh_token(
         &self,
         uid: &str,
         email: &str,
-    ) -> Result<(String, String), String> {
+    ) -> Result<(String, String), JwtError> {
         let now = Utc::now();
         let iat = now.timestamp();
         let exp = (now + Duration::days(self.refresh_exp_days)).timestamp();
@@ -97,50 +295,91 @@ impl JwtManager {
             jti: jti.clone(),
         };
 
-        let encoding_key = EncodingKey::from_secret(&self.refresh_secret);
-        let token = encode(&Header::new(Algorithm::HS256), &claims, &encoding_key)
-            .map_err(|e| format!("Failed to encode refresh token: {}", e))?;
+        let token = encode(
+            &Header::new(self.refresh_key.algorithm),
+            &claims,
+            &self.refresh_key.encoding,
+        )
+        .map_err(|e| JwtError::Signing(e.to_string()))?;
 
         Ok((token, jti))
     }
 
-    pub async fn verify_access_token(&self, token: &str) -> Result<AccessTokenClaims, String> {
-        let decoding_key = DecodingKey::from_secret(&self.access_secret);
-        let mut validation = Validation::new(Algorithm::HS256);
+    pub async fn verify_access_token(&self, token: &str) -> Result<AccessTokenClaims, JwtError> {
+        let header = decode_header(token)?;
+        let key = self
+            .access_keys
+            .get(header.kid.as_deref().unwrap_or(DEFAULT_KID))
+            .ok_or(JwtError::UnknownKey)?;
+        // Only the key's own algorithm, a token can't pick a weaker one
+        let mut validation = Validation::new(key.algorithm);
         validation.validate_exp = true;
-        validation.set_required_spec_claims(&["exp", "iat", "token_type"]);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway;
+        // Not "nbf", tokens issued before it was set are still live, checked below
+        let mut required = vec!["exp", "iat", "token_type"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
 
-        let token_data = decode::<AccessTokenClaims>(token, &decoding_key, &validation)
-            .map_err(|e| format!("Failed to verify access token: {}", e))?;
+        let token_data = decode::<AccessTokenClaims>(token, &key.decoding, &validation)?;
 
         // Validate token type
         if token_data.claims.token_type != "access" {
-            return Err("Invalid token type".to_string());
+            return Err(JwtError::WrongTokenType { expected: "access" });
+        }
+
+        // Only tokens from before nbf was set may lack it, and those all expire within
+        // JWT_ACCESS_EXPIRY_HRS of the upgrade
+        let claims = &token_data.claims;
+        if claims.nbf.is_none() && claims.iat >= self.nbf_required_from {
+            return Err(JwtError::MissingClaim("nbf".to_string()));
         }
 
         Ok(token_data.claims)
     }
 
-    pub async fn verify_refresh_token(&self, token: &str) -> Result<RefreshTokenClaims, String> {
-        let decoding_key = DecodingKey::from_secret(&self.refresh_secret);
-        let mut validation = Validation::new(Algorithm::HS256);
+    pub async fn verify_refresh_token(&self, token: &str) -> Result<RefreshTokenClaims, JwtError> {
+        let mut validation = Validation::new(self.refresh_key.algorithm);
         validation.validate_exp = true;
+        validation.leeway = self.leeway;
         validation.set_required_spec_claims(&["exp", "iat", "token_type", "jti"]);
 
-        let token_data = decode::<RefreshTokenClaims>(token, &decoding_key, &validation)
-            .map_err(|e| format!("Failed to verify refresh token: {}", e))?;
+        let token_data =
+            decode::<RefreshTokenClaims>(token, &self.refresh_key.decoding, &validation)?;
 
         if token_data.claims.token_type != "refresh" {
-            return Err("Invalid token type".to_string());
+            return Err(JwtError::WrongTokenType {
+                expected: "refresh",
+            });
         }
 
         Ok(token_data.claims)
     }
 
+    /// The public key new access tokens verify with, as PEM. None for HMAC algorithms.
+    pub fn access_public_key_pem(&self) -> Option<String> {
+        self.access_keys.active().1.public_key_pem()
+    }
+
+    /// The public keys access tokens verify with, retired ones included, as a JWKS document
+    pub fn access_jwks(&self) -> String {
+        self.access_keys.jwks().to_string()
+    }
+
     pub async fn rotate_refresh_token(
         &self,
         old_refresh_token: &str,
-    ) -> Result<(String, String, String), String> {
+    ) -> Result<(String, String, String), JwtError> {
         // Verify old refresh token
         let old_claims = self.verify_refresh_token(old_refresh_token).await?;
 
@@ -159,9 +398,9 @@ impl JwtManager {
 }
 
 // Singleton instance for better performance
-static JWT_MANAGER: OnceLock<Result<JwtManager, String>> = OnceLock::new();
+static JWT_MANAGER: OnceLock<Result<JwtManager, JwtError>> = OnceLock::new();
 
-pub async fn get_jwt_manager() -> Result<&'static JwtManager, String> {
+pub async fn get_jwt_manager() -> Result<&'static JwtManager, JwtError> {
     JWT_MANAGER
         .get_or_init(JwtManager::new)
         .as_ref()
@@ -169,22 +408,181 @@ pub async fn get_jwt_manager() -> Result<&'static JwtManager, String> {
 }
 
 // Public API functions start here
-pub async fn gen_access_token(uid: &str, email: &str) -> Result<String, String> {
+pub async fn gen_access_token(uid: &str, email: &str) -> Result<String, JwtError> {
     get_jwt_manager().await?.gen_access_token(uid, email).await
 }
 
-pub async fn gen_refresh_token(uid: &str, email: &str) -> Result<(String, String), String> {
+pub async fn gen_refresh_token(uid: &str, email: &str) -> Result<(String, String), JwtError> {
     get_jwt_manager().await?.gen_refresh_token(uid, email).await
 }
 
-pub async fn verify_access_token(token: &str) -> Result<AccessTokenClaims, String> {
+pub async fn verify_access_token(token: &str) -> Result<AccessTokenClaims, JwtError> {
     get_jwt_manager().await?.verify_access_token(token).await
 }
 
-pub async fn verify_refresh_token(token: &str) -> Result<RefreshTokenClaims, String> {
+pub async fn verify_refresh_token(token: &str) -> Result<RefreshTokenClaims, JwtError> {
     get_jwt_manager().await?.verify_refresh_token(token).await
 }
 
-pub async fn rotate_refresh_token(token: &str) -> Result<(String, String, String), String> {
+pub async fn rotate_refresh_token(token: &str) -> Result<(String, String, String), JwtError> {
     get_jwt_manager().await?.rotate_refresh_token(token).await
 }
+
+pub async fn access_public_key_pem() -> Result<Option<String>, JwtError> {
+    Ok(get_jwt_manager().await?.access_public_key_pem())
+}
+
+pub async fn access_jwks() -> Result<String, JwtError> {
+    Ok(get_jwt_manager().await?.access_jwks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::EncodingKey;
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"test-access-secret";
+
+    fn hmac_key(secret: &[u8]) -> SigningKey {
+        SigningKey::hmac(Algorithm::HS256, secret).unwrap()
+    }
+
+    fn manager() -> JwtManager {
+        manager_with(vec![(DEFAULT_KID.to_string(), hmac_key(SECRET))])
+    }
+
+    fn manager_with(keys: Vec<(String, SigningKey)>) -> JwtManager {
+        JwtManager {
+            access_keys: KeyRing::new(keys).unwrap(),
+            refresh_key: SigningKey::hmac(Algorithm::HS256, b"test-refresh-secret").unwrap(),
+            access_exp_hours: 1,
+            refresh_exp_days: 30,
+            issuer: None,
+            audience: None,
+            leeway: DEFAULT_LEEWAY_SECONDS,
+            nbf_required_from: Utc::now().timestamp() - 300,
+        }
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        let header = Header {
+            kid: Some(DEFAULT_KID.to_string()),
+            ..Header::new(Algorithm::HS256)
+        };
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    async fn verify(token: &str) -> Result<AccessTokenClaims, JwtError> {
+        manager().verify_access_token(token).await
+    }
+
+    #[tokio::test]
+    async fn accepts_tokens_issued_before_nbf() {
+        let iat = Utc::now().timestamp() - 600;
+        let token = sign(json!({
+            "uid": "u1", "email": "a@example.com", "iat": iat, "exp": iat + 3600,
+            "token_type": "access",
+        }));
+        assert_eq!(verify(&token).await.unwrap().nbf, None);
+    }
+
+    #[tokio::test]
+    async fn requires_nbf_on_tokens_issued_since_the_upgrade() {
+        let iat = Utc::now().timestamp() - 60;
+        let token = sign(json!({
+            "uid": "u1", "email": "a@example.com", "iat": iat, "exp": iat + 3600,
+            "token_type": "access",
+        }));
+        assert!(matches!(verify(&token).await, Err(JwtError::MissingClaim(c)) if c == "nbf"));
+
+        let token = sign(json!({
+            "uid": "u1", "email": "a@example.com", "iat": iat, "exp": iat + 3600,
+            "nbf": 0, "token_type": "access",
+        }));
+        assert_eq!(verify(&token).await.unwrap().nbf, Some(0));
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_not_valid_yet() {
+        let iat = Utc::now().timestamp();
+        let token = sign(json!({
+            "uid": "u1", "email": "a@example.com", "iat": iat, "exp": iat + 3600,
+            "nbf": iat + 600, "token_type": "access",
+        }));
+        assert!(matches!(verify(&token).await, Err(JwtError::NotYetValid)));
+    }
+
+    #[tokio::test]
+    async fn verifies_issued_tokens() {
+        let manager = manager();
+        let token = manager
+            .gen_access_token("u1", "a@example.com")
+            .await
+            .unwrap();
+        let claims = manager.verify_access_token(&token).await.unwrap();
+        assert_eq!(claims.nbf, Some(claims.iat));
+    }
+
+    #[tokio::test]
+    async fn verifies_tokens_from_retired_keys() {
+        let old = manager_with(vec![("2024".to_string(), hmac_key(b"old secret"))]);
+        let token = old.gen_access_token("u1", "a@example.com").await.unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("2024"));
+
+        let rotated = manager_with(vec![
+            ("2025".to_string(), hmac_key(b"new secret")),
+            ("2024".to_string(), hmac_key(b"old secret")),
+        ]);
+        assert_eq!(rotated.verify_access_token(&token).await.unwrap().uid, "u1");
+        let fresh = rotated
+            .gen_access_token("u1", "a@example.com")
+            .await
+            .unwrap();
+        assert_eq!(decode_header(&fresh).unwrap().kid.as_deref(), Some("2025"));
+
+        // Dropped from the ring once its tokens have expired
+        let retired = manager_with(vec![("2025".to_string(), hmac_key(b"new secret"))]);
+        assert!(matches!(
+            retired.verify_access_token(&token).await,
+            Err(JwtError::UnknownKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_kids() {
+        let iat = Utc::now().timestamp();
+        let header = Header {
+            kid: Some("elsewhere".to_string()),
+            ..Header::new(Algorithm::HS256)
+        };
+        let claims = json!({
+            "uid": "u1", "email": "a@example.com", "iat": iat, "exp": iat + 3600,
+            "nbf": iat, "token_type": "access",
+        });
+        let token = encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap();
+        assert!(matches!(verify(&token).await, Err(JwtError::UnknownKey)));
+    }
+
+    #[tokio::test]
+    async fn tokens_without_a_kid_use_the_default_key() {
+        let iat = Utc::now().timestamp();
+        let claims = json!({
+            "uid": "u1", "email": "a@example.com", "iat": iat, "exp": iat + 3600,
+            "nbf": iat, "token_type": "access",
+        });
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap();
+        assert_eq!(verify(&token).await.unwrap().uid, "u1");
+
+        let named = manager_with(vec![("2025".to_string(), hmac_key(SECRET))]);
+        assert!(matches!(
+            named.verify_access_token(&token).await,
+            Err(JwtError::UnknownKey)
+        ));
+    }
+}
@@ -0,0 +1,97 @@
+mod message;
+mod queue;
+mod template;
+mod transport;
+
+pub use queue::{FailedMail, MailQueueReport, enqueue, failed_mail, process_queue};
+pub use template::{RenderedMail, render};
+pub use transport::{FileSpool, MailTransport, MemoryTransport, SmtpTransport};
+
+use napi_derive::napi;
+use redis_handler::RedisHandlerError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MailError {
+    /// Worth retrying, e.g. the server is down or answered with a 4xx
+    #[error("Mail delivery failed, will retry: {0}")]
+    Transient(String),
+    /// Retrying won't help, e.g. a 5xx or a malformed address
+    #[error("Mail rejected: {0}")]
+    Permanent(String),
+    #[error("Template error: {0}")]
+    Template(String),
+    #[error("Mail queue error: {0}")]
+    Queue(#[from] RedisHandlerError),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl From<MailError> for napi::Error {
+    fn from(err: MailError) -> Self {
+        napi::Error::from_reason(err.to_string())
+    }
+}
+
+/// One message to one recipient. The sender is configured on the transport.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[napi(object)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    /// Sent as an alternative to `text` for clients that show HTML
+    pub html: Option<String>,
+}
+
+static TRANSPORT: OnceLock<Arc<dyn MailTransport>> = OnceLock::new();
+
+/// Replaces the transport picked from the environment. Only once, before any mail is sent.
+/// Keep a clone of the `Arc` to look at what a `MemoryTransport` received.
+pub fn set_transport(transport: Arc<dyn MailTransport>) -> Result<(), MailError> {
+    TRANSPORT
+        .set(transport)
+        .map_err(|_| MailError::Permanent("Mail transport is already set".to_string()))
+}
+
+/// `MAIL_TRANSPORT` picks `smtp`, `memory` or, by default, `file`
+pub fn transport() -> &'static dyn MailTransport {
+    TRANSPORT
+        .get_or_init(|| {
+            match std::env::var("MAIL_TRANSPORT")
+                .unwrap_or_default()
+                .to_lowercase()
+                .as_str()
+            {
+                "smtp" => Arc::new(SmtpTransport::from_env()),
+                "memory" => Arc::new(MemoryTransport::default()),
+                _ => Arc::new(FileSpool::from_env()),
+            }
+        })
+        .as_ref()
+}
+
+/// Queues a mail for delivery. It goes out the next time the queue is processed.
+pub async fn send(mail: Mail) -> Result<(), MailError> {
+    enqueue(mail).await
+}
+
+/// Renders a template for `to` and queues it
+pub async fn send_template(
+    template: &str,
+    locale: Option<&str>,
+    to: &str,
+    vars: &HashMap<String, String>,
+) -> Result<(), MailError> {
+    let rendered = render(template, locale, vars)?;
+    enqueue(Mail {
+        to: to.to_string(),
+        subject: rendered.subject,
+        text: rendered.text,
+        html: rendered.html,
+    })
+    .await
+}
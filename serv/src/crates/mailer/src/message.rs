@@ -0,0 +1,85 @@
+use crate::{Mail, MailError};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rand_core::RngCore;
+
+/// Rejects header values that could smuggle in extra headers or recipients
+pub(crate) fn check_header(name: &str, value: &str) -> Result<(), MailError> {
+    if value.is_empty() || value.contains(['\r', '\n']) {
+        return Err(MailError::Permanent(format!("Invalid {name} header")));
+    }
+    Ok(())
+}
+
+/// Checks the recipient looks like a bare address, which is all the transports accept
+pub(crate) fn check_address(address: &str) -> Result<(), MailError> {
+    check_header("address", address)?;
+    let valid = address
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'))
+        && !address.contains([' ', '<', '>', ',']);
+    if !valid {
+        return Err(MailError::Permanent(format!("Invalid address: {address}")));
+    }
+    Ok(())
+}
+
+/// RFC 2047 encodes non-ASCII header text
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+/// Base64 wrapped at 76 columns, so bodies survive servers without 8BITMIME
+fn encode_body(body: &str) -> String {
+    let encoded = STANDARD.encode(body);
+    encoded
+        .as_bytes()
+        .chunks(76)
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+fn part(content_type: &str, body: &str) -> String {
+    format!(
+        "Content-Type: {content_type}; charset=utf-8\r\n\
+         Content-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+        encode_body(body)
+    )
+}
+
+/// The full RFC 5322 message, CRLF line endings, as spooled or sent over SMTP
+pub(crate) fn format_message(from: &str, mail: &Mail) -> Result<String, MailError> {
+    check_address(from)?;
+    check_address(&mail.to)?;
+    check_header("Subject", &mail.subject)?;
+
+    let domain = from.split_once('@').map_or("localhost", |(_, d)| d);
+    let mut id = [0u8; 12];
+    rand_core::OsRng.fill_bytes(&mut id);
+    let message_id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+
+    let mut message = format!(
+        "From: {from}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{message_id}@{domain}>\r\nMIME-Version: 1.0\r\n",
+        mail.to,
+        encode_header(&mail.subject),
+        chrono::Utc::now().to_rfc2822(),
+    );
+
+    match &mail.html {
+        None => message.push_str(&part("text/plain", &mail.text)),
+        Some(html) => {
+            let boundary = format!("alt-{message_id}");
+            message.push_str(&format!(
+                "Content-Type: multipart/alternative; boundary=\"{boundary}\"\r\n\r\n\
+                 --{boundary}\r\n{}--{boundary}\r\n{}--{boundary}--\r\n",
+                part("text/plain", &mail.text),
+                part("text/html", html),
+            ));
+        }
+    }
+    Ok(message)
+}
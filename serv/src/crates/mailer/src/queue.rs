@@ -0,0 +1,246 @@
+use crate::message::{check_address, check_header};
+use crate::{Mail, MailError, MailTransport, transport};
+use napi_derive::napi;
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Attempts before a mail that keeps failing transiently is given up on
+const MAX_ATTEMPTS: u32 = 8;
+const BASE_BACKOFF_SECONDS: i64 = 30;
+const MAX_BACKOFF_SECONDS: i64 = 60 * 60;
+const DEFAULT_BATCH: u32 = 50;
+
+#[derive(Serialize, Deserialize)]
+struct QueuedMail {
+    id: String,
+    mail: Mail,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+#[napi(object)]
+pub struct MailQueueReport {
+    pub sent: u32,
+    /// Failed for now, queued again with a backoff
+    pub retried: u32,
+    /// Given up on and moved to the failed list
+    pub failed: u32,
+}
+
+#[derive(Debug)]
+#[napi(object)]
+pub struct FailedMail {
+    pub id: String,
+    pub to: String,
+    pub subject: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Checks the mail up front so a bad address fails the caller, not the queue later
+pub async fn enqueue(mail: Mail) -> Result<(), MailError> {
+    check_address(&mail.to)?;
+    check_header("Subject", &mail.subject)?;
+
+    let mut id = [0u8; 8];
+    rand_core::OsRng.fill_bytes(&mut id);
+    let queued = QueuedMail {
+        id: id.iter().map(|b| format!("{b:02x}")).collect(),
+        mail,
+        attempts: 0,
+        last_error: None,
+    };
+    redis_handler::queue_mail(serde_json::to_string(&queued)?, now()).await?;
+    Ok(())
+}
+
+/// What became of one queued mail
+#[derive(Debug, PartialEq)]
+enum Delivery {
+    Sent,
+    /// Try again at `due_at` with the updated entry
+    Retry {
+        payload: String,
+        due_at: i64,
+    },
+    /// Give up, keeping the entry for inspection
+    Dead(String),
+}
+
+async fn deliver(
+    transport: &dyn MailTransport,
+    payload: &str,
+    now: i64,
+) -> Result<Delivery, MailError> {
+    let Ok(mut queued) = serde_json::from_str::<QueuedMail>(payload) else {
+        // Unreadable entries can never be sent
+        return Ok(Delivery::Dead(payload.to_string()));
+    };
+
+    let error = match transport.send(&queued.mail).await {
+        Ok(()) => return Ok(Delivery::Sent),
+        Err(e) => e,
+    };
+    queued.attempts += 1;
+    queued.last_error = Some(error.to_string());
+
+    if matches!(error, MailError::Transient(_)) && queued.attempts < MAX_ATTEMPTS {
+        // 30s before the first retry, doubling from there
+        let backoff =
+            (BASE_BACKOFF_SECONDS << (queued.attempts - 1).min(16)).min(MAX_BACKOFF_SECONDS);
+        Ok(Delivery::Retry {
+            payload: serde_json::to_string(&queued)?,
+            due_at: now + backoff,
+        })
+    } else {
+        Ok(Delivery::Dead(serde_json::to_string(&queued)?))
+    }
+}
+
+/// Sends up to `limit` due mails. Transient failures are retried with exponential backoff,
+/// permanent ones and those out of attempts end up in `failed_mail`. Each mail is settled
+/// in the queue right after its attempt, so if processing stops halfway the rest are
+/// picked up again once their claim runs out. A mail sent just before a crash may go out
+/// twice.
+pub async fn process_queue(limit: Option<u32>) -> Result<MailQueueReport, MailError> {
+    let mut report = MailQueueReport::default();
+    let due = redis_handler::claim_due_mail(limit.unwrap_or(DEFAULT_BATCH).max(1)).await?;
+
+    for claimed in due {
+        match deliver(transport(), &claimed, now()).await? {
+            Delivery::Sent => {
+                redis_handler::ack_mail(claimed).await?;
+                report.sent += 1;
+            }
+            Delivery::Retry { payload, due_at } => {
+                redis_handler::requeue_mail(claimed, payload, due_at).await?;
+                report.retried += 1;
+            }
+            Delivery::Dead(payload) => {
+                redis_handler::dead_letter_mail(claimed, payload).await?;
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// The most recently failed mails, newest first
+pub async fn failed_mail(limit: u32) -> Result<Vec<FailedMail>, MailError> {
+    let entries = redis_handler::list_dead_mail(limit.max(1)).await?;
+    Ok(entries
+        .iter()
+        .filter_map(|payload| serde_json::from_str::<QueuedMail>(payload).ok())
+        .map(|q| FailedMail {
+            id: q.id,
+            to: q.mail.to,
+            subject: q.mail.subject,
+            attempts: q.attempts,
+            last_error: q.last_error,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryTransport;
+    use async_trait::async_trait;
+
+    /// Always fails as if the server were down
+    struct Unreachable;
+
+    #[async_trait]
+    impl MailTransport for Unreachable {
+        async fn send(&self, _mail: &Mail) -> Result<(), MailError> {
+            Err(MailError::Transient("connection refused".to_string()))
+        }
+    }
+
+    fn payload(to: &str, attempts: u32) -> String {
+        serde_json::to_string(&QueuedMail {
+            id: "0011223344556677".to_string(),
+            mail: Mail {
+                to: to.to_string(),
+                subject: "Hello".to_string(),
+                text: "Hi there".to_string(),
+                html: None,
+            },
+            attempts,
+            last_error: None,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_through_the_transport() {
+        let transport = MemoryTransport::default();
+        let outcome = deliver(&transport, &payload("a@example.com", 0), 0)
+            .await
+            .unwrap();
+        assert_eq!(outcome, Delivery::Sent);
+        let sent = transport.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "a@example.com");
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_are_not_retried() {
+        let transport = MemoryTransport::default();
+        let outcome = deliver(&transport, &payload("not an address", 0), 0)
+            .await
+            .unwrap();
+        let Delivery::Dead(dead) = outcome else {
+            panic!("expected a dead letter, got {outcome:?}");
+        };
+        let dead: QueuedMail = serde_json::from_str(&dead).unwrap();
+        assert_eq!(dead.attempts, 1);
+        assert!(dead.last_error.is_some());
+        assert!(transport.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn transient_failures_back_off_then_give_up() {
+        let outcome = deliver(&Unreachable, &payload("a@example.com", 0), 100)
+            .await
+            .unwrap();
+        let Delivery::Retry {
+            payload: retry,
+            due_at,
+        } = outcome
+        else {
+            panic!("expected a retry, got {outcome:?}");
+        };
+        assert_eq!(due_at, 100 + BASE_BACKOFF_SECONDS);
+        assert_eq!(
+            serde_json::from_str::<QueuedMail>(&retry).unwrap().attempts,
+            1
+        );
+        let outcome = deliver(&Unreachable, &payload("a@example.com", 1), 100)
+            .await
+            .unwrap();
+        assert!(
+            matches!(outcome, Delivery::Retry { due_at, .. } if due_at == 100 + BASE_BACKOFF_SECONDS * 2)
+        );
+
+        let last = payload("a@example.com", MAX_ATTEMPTS - 1);
+        let outcome = deliver(&Unreachable, &last, 100).await.unwrap();
+        assert!(matches!(outcome, Delivery::Dead(_)));
+    }
+
+    #[tokio::test]
+    async fn unreadable_entries_are_dead_lettered_as_they_are() {
+        let transport = MemoryTransport::default();
+        let outcome = deliver(&transport, "{not json", 0).await.unwrap();
+        assert_eq!(outcome, Delivery::Dead("{not json".to_string()));
+    }
+}
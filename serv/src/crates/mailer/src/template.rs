@@ -0,0 +1,286 @@
+use crate::MailError;
+use napi_derive::napi;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Templates compiled in, as `(locale, name, text)`. The first line of a template is
+/// `Subject: ...`, everything after the blank line below it is the body.
+const BUILTIN: &[(&str, &str, &str)] = &[
+    (
+        "en",
+        "password_reset",
+        include_str!("../templates/en/password_reset.txt"),
+    ),
+    (
+        "en",
+        "email_verification",
+        include_str!("../templates/en/email_verification.txt"),
+    ),
+    (
+        "de",
+        "password_reset",
+        include_str!("../templates/de/password_reset.txt"),
+    ),
+    (
+        "de",
+        "email_verification",
+        include_str!("../templates/de/email_verification.txt"),
+    ),
+];
+
+const DEFAULT_LOCALE: &str = "en";
+
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct RenderedMail {
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+/// `de-AT` tries `de-at`, then `de`, then the default locale
+fn locale_chain(locale: Option<&str>) -> Vec<String> {
+    let mut chain = Vec::new();
+    if let Some(locale) = locale.map(|l| l.trim().replace('_', "-").to_lowercase()) {
+        // Only plain tags, the locale ends up in a path
+        if !locale.is_empty()
+            && locale
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            if let Some((language, _)) = locale.split_once('-') {
+                chain.push(locale.clone());
+                chain.push(language.to_string());
+            } else {
+                chain.push(locale);
+            }
+        }
+    }
+    if !chain.iter().any(|l| l == DEFAULT_LOCALE) {
+        chain.push(DEFAULT_LOCALE.to_string());
+    }
+    chain
+}
+
+/// Reads `{dir}/{locale}/{name}.txt` and the optional `.html` next to it
+fn load_override(
+    dir: &Path,
+    locale: &str,
+    name: &str,
+) -> Result<Option<(String, Option<String>)>, MailError> {
+    let base = dir.join(locale);
+    let read = |ext: &str| -> Result<Option<String>, MailError> {
+        match std::fs::read_to_string(base.join(format!("{name}.{ext}"))) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(MailError::Template(format!(
+                "Failed to read {locale}/{name}.{ext}: {e}"
+            ))),
+        }
+    };
+    let Some(text) = read("txt")? else {
+        return Ok(None);
+    };
+    Ok(Some((text, read("html")?)))
+}
+
+/// Overrides from `MAIL_TEMPLATE_DIR` first, then the built-in templates
+fn load(
+    dir: Option<&Path>,
+    locale: &str,
+    name: &str,
+) -> Result<Option<(String, Option<String>)>, MailError> {
+    if let Some(dir) = dir
+        && let Some(found) = load_override(dir, locale, name)?
+    {
+        return Ok(Some(found));
+    }
+    Ok(BUILTIN
+        .iter()
+        .find(|(l, n, _)| *l == locale && *n == name)
+        .map(|(_, _, text)| (text.to_string(), None)))
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replaces every `{{var}}`. Unknown variables are an error rather than an empty gap.
+fn substitute(
+    template: &str,
+    vars: &HashMap<String, String>,
+    escape: bool,
+) -> Result<String, MailError> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| MailError::Template("Unclosed {{ in template".to_string()))?;
+        let name = after[..end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| MailError::Template(format!("Missing template variable {name}")))?;
+        if escape {
+            out.push_str(&escape_html(value));
+        } else {
+            out.push_str(value);
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Renders `template` in the closest available locale
+pub fn render(
+    template: &str,
+    locale: Option<&str>,
+    vars: &HashMap<String, String>,
+) -> Result<RenderedMail, MailError> {
+    let dir = std::env::var_os("MAIL_TEMPLATE_DIR");
+    render_from(dir.as_deref().map(Path::new), template, locale, vars)
+}
+
+fn render_from(
+    dir: Option<&Path>,
+    template: &str,
+    locale: Option<&str>,
+    vars: &HashMap<String, String>,
+) -> Result<RenderedMail, MailError> {
+    if template.is_empty()
+        || !template
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(MailError::Template(format!(
+            "Invalid template name: {template}"
+        )));
+    }
+
+    let mut found = None;
+    for locale in locale_chain(locale) {
+        if let Some(t) = load(dir, &locale, template)? {
+            found = Some(t);
+            break;
+        }
+    }
+    let (text, html) =
+        found.ok_or_else(|| MailError::Template(format!("Unknown template: {template}")))?;
+
+    let text = text.replace("\r\n", "\n");
+    let (subject, body) = text
+        .split_once('\n')
+        .and_then(|(first, body)| Some((first.strip_prefix("Subject:")?.trim(), body)))
+        .ok_or_else(|| MailError::Template(format!("Template {template} has no Subject: line")))?;
+
+    Ok(RenderedMail {
+        subject: substitute(subject, vars, false)?,
+        text: substitute(body.trim_start_matches('\n'), vars, false)?,
+        html: html.map(|h| substitute(&h, vars, true)).transpose()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// A template directory under the temp dir, removed again on drop
+    struct TemplateDir(PathBuf);
+
+    impl TemplateDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("mail-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(dir.join("en")).unwrap();
+            TemplateDir(dir)
+        }
+
+        fn write(&self, file: &str, content: &str) {
+            std::fs::write(self.0.join("en").join(file), content).unwrap();
+        }
+    }
+
+    impl Drop for TemplateDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn locale_falls_back_to_language_then_default() {
+        assert_eq!(locale_chain(Some("de_AT")), ["de-at", "de", "en"]);
+        assert_eq!(locale_chain(Some("en-GB")), ["en-gb", "en"]);
+        assert_eq!(locale_chain(Some("../etc")), ["en"]);
+        assert_eq!(locale_chain(None), ["en"]);
+
+        let link = vars(&[("link", "https://example.com/r"), ("ttl_minutes", "15")]);
+        let mail = render_from(None, "password_reset", Some("de-AT"), &link).unwrap();
+        assert_eq!(mail.subject, "Passwort zurücksetzen");
+        assert!(mail.text.contains("innerhalb von 15 Minuten"));
+        let mail = render_from(None, "password_reset", Some("fr"), &link).unwrap();
+        assert!(mail.text.contains("https://example.com/r"));
+        assert!(!mail.text.contains("Passwort"));
+    }
+
+    #[test]
+    fn missing_variables_are_an_error() {
+        let err = render_from(None, "password_reset", None, &vars(&[("link", "x")])).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Missing template variable ttl_minutes")
+        );
+        assert!(substitute("{{ open", &vars(&[]), false).is_err());
+        assert!(render_from(None, "../secret", None, &vars(&[])).is_err());
+        assert!(render_from(None, "no_such_mail", None, &vars(&[])).is_err());
+    }
+
+    #[test]
+    fn only_the_html_part_is_escaped() {
+        let dir = TemplateDir::new("escape");
+        dir.write("hello.txt", "Subject: Hi {{name}}\r\n\r\nHello {{ name }}");
+        dir.write("hello.html", "<p>Hello {{name}}</p>");
+        let mail = render_from(
+            Some(&dir.0),
+            "hello",
+            None,
+            &vars(&[("name", "<Tom & \"Jerry\">")]),
+        )
+        .unwrap();
+        assert_eq!(mail.subject, "Hi <Tom & \"Jerry\">");
+        assert_eq!(mail.text, "Hello <Tom & \"Jerry\">");
+        assert_eq!(
+            mail.html.as_deref(),
+            Some("<p>Hello &lt;Tom &amp; &quot;Jerry&quot;&gt;</p>")
+        );
+    }
+
+    #[test]
+    fn unreadable_html_is_an_error() {
+        let dir = TemplateDir::new("unreadable");
+        dir.write("hello.txt", "Subject: Hi\n\nHello");
+        std::fs::create_dir(dir.0.join("en").join("hello.html")).unwrap();
+        let err = render_from(Some(&dir.0), "hello", None, &vars(&[])).unwrap_err();
+        assert!(err.to_string().contains("Failed to read en/hello.html"));
+    }
+}
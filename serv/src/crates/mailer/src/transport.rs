@@ -0,0 +1,266 @@
+use crate::message::{check_address, format_message};
+use crate::{Mail, MailError};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rand_core::RngCore;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::timeout;
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    /// Delivers one mail. `MailError::Transient` means the queue should try again later.
+    async fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+fn mail_from_env() -> String {
+    std::env::var("MAIL_FROM").unwrap_or_else(|_| "noreply@localhost.localdomain".to_string())
+}
+
+/// Plain SMTP with optional `AUTH PLAIN`. There is no TLS, so point it at a local relay or
+/// a stand-in such as Mailpit, not straight at a provider over the internet.
+pub struct SmtpTransport {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    from: String,
+}
+
+impl SmtpTransport {
+    pub fn new(host: impl Into<String>, port: u16, from: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            credentials: None,
+            from: from.into(),
+        }
+    }
+
+    pub fn with_credentials(mut self, user: impl Into<String>, pass: impl Into<String>) -> Self {
+        self.credentials = Some((user.into(), pass.into()));
+        self
+    }
+
+    /// `SMTP_HOST` (localhost), `SMTP_PORT` (1025), `SMTP_USER`, `SMTP_PASSWD` and `MAIL_FROM`
+    pub fn from_env() -> Self {
+        let transport = Self::new(
+            std::env::var("SMTP_HOST").unwrap_or_else(|_| "localhost".to_string()),
+            std::env::var("SMTP_PORT")
+                .ok()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(1025),
+            mail_from_env(),
+        );
+        match (std::env::var("SMTP_USER"), std::env::var("SMTP_PASSWD")) {
+            (Ok(user), Ok(pass)) => transport.with_credentials(user, pass),
+            _ => transport,
+        }
+    }
+}
+
+struct SmtpSession {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+fn io_error(e: impl std::fmt::Display) -> MailError {
+    MailError::Transient(format!("SMTP connection failed: {e}"))
+}
+
+impl SmtpSession {
+    /// Reads a possibly multiline reply, failing unless its code is one of `expected`
+    async fn expect(&mut self, expected: &[u16]) -> Result<(), MailError> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            let read = timeout(SMTP_TIMEOUT, self.reader.read_line(&mut line))
+                .await
+                .map_err(io_error)?
+                .map_err(io_error)?;
+            if read == 0 {
+                return Err(io_error("server closed the connection"));
+            }
+            reply.push_str(&line);
+            // `250-` continues a reply, `250 ` ends it
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+
+        let code: u16 = reply.get(..3).and_then(|c| c.parse().ok()).unwrap_or(0);
+        if expected.contains(&code) {
+            return Ok(());
+        }
+        let reply = reply.trim_end().to_string();
+        Err(if (400..500).contains(&code) {
+            MailError::Transient(reply)
+        } else {
+            MailError::Permanent(reply)
+        })
+    }
+
+    async fn command(&mut self, command: &str, expected: &[u16]) -> Result<(), MailError> {
+        timeout(
+            SMTP_TIMEOUT,
+            self.writer.write_all(format!("{command}\r\n").as_bytes()),
+        )
+        .await
+        .map_err(io_error)?
+        .map_err(io_error)?;
+        self.expect(expected).await
+    }
+}
+
+/// The DATA section for `message`, ending in the lone dot. Lines starting with a dot get
+/// another one so they can't end the data early.
+fn dot_stuff(message: &str) -> String {
+    let mut data = String::with_capacity(message.len() + 8);
+    for line in message.split("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push('.');
+    data
+}
+
+#[async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let message = format_message(&self.from, mail)?;
+
+        let stream = timeout(
+            SMTP_TIMEOUT,
+            TcpStream::connect((self.host.as_str(), self.port)),
+        )
+        .await
+        .map_err(io_error)?
+        .map_err(io_error)?;
+        let (reader, writer) = stream.into_split();
+        let mut session = SmtpSession {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        session.expect(&[220]).await?;
+        session.command("EHLO localhost", &[250]).await?;
+        if let Some((user, pass)) = &self.credentials {
+            let token = STANDARD.encode(format!("\0{user}\0{pass}"));
+            session
+                .command(&format!("AUTH PLAIN {token}"), &[235])
+                .await?;
+        }
+        session
+            .command(&format!("MAIL FROM:<{}>", self.from), &[250])
+            .await?;
+        session
+            .command(&format!("RCPT TO:<{}>", mail.to), &[250, 251])
+            .await?;
+        session.command("DATA", &[354]).await?;
+
+        session.command(&dot_stuff(&message), &[250]).await?;
+
+        // The mail is accepted at this point, a failed goodbye doesn't change that
+        let _ = session.command("QUIT", &[221]).await;
+        Ok(())
+    }
+}
+
+/// Writes every mail as an `.eml` file instead of sending it, for local testing
+pub struct FileSpool {
+    dir: PathBuf,
+    from: String,
+}
+
+impl FileSpool {
+    pub fn new(dir: impl Into<PathBuf>, from: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            from: from.into(),
+        }
+    }
+
+    /// Writes to `MAIL_OUTBOX_DIR`, `./outbox` by default
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("MAIL_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string()),
+            mail_from_env(),
+        )
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileSpool {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let message = format_message(&self.from, mail)?;
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{millis}-{:08x}.eml", rand_core::OsRng.next_u32());
+
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| MailError::Transient(format!("Failed to create outbox: {e}")))?;
+        tokio::fs::write(self.dir.join(name), message)
+            .await
+            .map_err(|e| MailError::Transient(format!("Failed to write mail: {e}")))
+    }
+}
+
+/// Keeps sent mail in memory, for tests
+#[derive(Default)]
+pub struct MemoryTransport {
+    sent: Mutex<Vec<Mail>>,
+}
+
+impl MemoryTransport {
+    /// Everything sent so far, oldest first
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// Like `sent`, but also forgets it
+    pub fn take(&self) -> Vec<Mail> {
+        self.sent
+            .lock()
+            .map(|mut s| std::mem::take(&mut *s))
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl MailTransport for MemoryTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        check_address(&mail.to)?;
+        self.sent
+            .lock()
+            .map_err(|_| MailError::Transient("Mail store poisoned".to_string()))?
+            .push(mail.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leading_dots_are_doubled() {
+        let message = "Subject: x\r\n\r\n.\r\n..two\r\nmid.dle\r\n. trailing";
+        assert_eq!(
+            dot_stuff(message),
+            "Subject: x\r\n\r\n..\r\n...two\r\nmid.dle\r\n.. trailing\r\n."
+        );
+        assert_eq!(dot_stuff("body\r\n"), "body\r\n\r\n.");
+    }
+}
@@ -1,14 +1,25 @@
+use audit::{AuditContext, AuditExport, AuditPage, AuditQuery};
 use db::initialize_dbs;
+use grid_handler::Grid;
+use grid_handler::worksheet::{WorksheetOptions, build_worksheet, render_html, render_pdf};
 use jwt_handler::{
-    AccessTokenClaims, RefreshTokenClaims, gen_access_token, gen_refresh_token,
-    rotate_refresh_token, verify_access_token, verify_refresh_token,
+    AccessTokenClaims, RefreshTokenClaims, access_jwks, access_public_key_pem, gen_access_token,
+    gen_refresh_token, rotate_refresh_token, verify_access_token, verify_refresh_token,
 };
+use mailer::{FailedMail, Mail, MailQueueReport, RenderedMail};
+use napi::bindgen_prelude::Buffer;
 use napi_derive::napi;
-use redis_handler::RefreshTokenData;
+use rbac::{ExpiredGrant, GrantWindow, Perm, PermDecision, Role, UnverifiedEmailPolicy};
+use redis_handler::{LockoutStatus, RefreshTokenData};
+use serde_json::Value;
 use shared_types::User;
+use std::collections::HashMap;
 use user_handler::{
-    add_user, delete_user as internal_delete_users, search_users as internal_search_users,
-    update_user as internal_update_user, user_from_uid, validate_pass,
+    HashConfig, Identity, LoginResult, OAuthState, OidcAuthorization, OidcProviderConfig,
+    OidcSignIn, Passkey, PasswordCheck, PasswordPolicy, ProviderProfile, TotpEnrolment, TotpStatus,
+    UserListQuery, UserPage, WebauthnConfig, add_user, delete_user as internal_delete_users,
+    list_users as internal_list_users, update_user as internal_update_user, user_from_email,
+    user_from_uid, validate_pass,
 };
 
 #[napi]
@@ -20,12 +31,11 @@ pub async fn init_dbs() {
 pub async fn create_user(
     email: String,
     pass: Option<String>,
-    oauth_provider: Option<String>,
-    oauth_provider_id: Option<String>,
     roles: Option<Vec<String>>,
     perms: Option<Vec<String>>,
+    audit: Option<AuditContext>,
 ) -> napi::Result<User> {
-    add_user(email, pass, oauth_provider, oauth_provider_id, roles, perms)
+    add_user(email, pass, roles, perms, audit.unwrap_or_default())
         .await
         .map_err(|e| napi::Error::from_reason(format!("Failed to create user: {e}")))
 }
@@ -35,38 +45,271 @@ pub async fn update_user(
     uid: String,
     email: Option<String>,
     pass: Option<String>,
-    oauth_provider: Option<String>,
-    oauth_provider_id: Option<String>,
     roles: Option<Vec<String>>,
     perms: Option<Vec<String>>,
+    audit: Option<AuditContext>,
 ) -> napi::Result<User> {
-    internal_update_user(
-        uid,
-        email,
-        pass,
-        oauth_provider,
-        oauth_provider_id,
-        roles,
-        perms,
-    )
-    .await
-    .map_err(|e| napi::Error::from_reason(format!("Failed to update user: {e}")))
+    internal_update_user(uid, email, pass, roles, perms, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to update user: {e}")))
 }
 
+/// Signs in through a provider, linking or creating the account as needed. Fails for an
+/// unverified email while the unverified email policy is `block`.
 #[napi]
-pub async fn search_users(email_str: String) -> napi::Result<Vec<User>> {
-    internal_search_users(email_str)
+pub async fn provider_sign_in(
+    profile: ProviderProfile,
+    audit: Option<AuditContext>,
+) -> napi::Result<User> {
+    user_handler::provider_sign_in(profile, audit.unwrap_or_default())
         .await
-        .map_err(|e| napi::Error::from_reason(format!("Failed to search users: {e}")))
+        .map_err(|e| napi::Error::from_reason(format!("Failed to sign in with provider: {e}")))
+}
+
+#[napi]
+pub async fn link_identity(
+    uid: String,
+    profile: ProviderProfile,
+    audit: Option<AuditContext>,
+) -> napi::Result<User> {
+    user_handler::link_identity(uid, profile, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to link identity: {e}")))
+}
+
+#[napi]
+pub async fn unlink_identity(
+    uid: String,
+    provider: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<User> {
+    user_handler::unlink_identity(uid, provider, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to unlink identity: {e}")))
+}
+
+#[napi]
+pub async fn list_identities(uid: String) -> napi::Result<Vec<Identity>> {
+    user_handler::list_identities(uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list identities: {e}")))
+}
+
+/// Seals `payload` into an OAuth `state`, with a binding to keep in a cookie. The key is
+/// derived from `OAUTH_STATE_SECRET`, which every instance has to share.
+#[napi]
+pub fn mint_oauth_state(payload: Value) -> napi::Result<OAuthState> {
+    user_handler::mint_oauth_state(payload)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to mint OAuth state: {e}")))
 }
 
+/// Returns the payload of a state minted with `mint_oauth_state`, once
 #[napi]
-pub async fn delete_user(email: String) -> napi::Result<User> {
-    internal_delete_users(email)
+pub async fn verify_oauth_state(state: String, binding: String) -> napi::Result<Value> {
+    user_handler::verify_oauth_state(state, binding)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to verify OAuth state: {e}")))
+}
+
+/// Sets the OpenID Connect providers. Optional, the environment is read otherwise.
+#[napi]
+pub fn configure_oidc(providers: Vec<OidcProviderConfig>) -> napi::Result<()> {
+    user_handler::configure_oidc(providers)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to configure OIDC: {e}")))
+}
+
+/// Names of the OpenID Connect providers that can be signed in with
+#[napi]
+pub fn oidc_providers() -> Vec<String> {
+    user_handler::oidc_providers()
+}
+
+/// Where to send the browser to sign in at an OpenID Connect provider. With `link_uid`
+/// the provider account is linked to that user instead.
+#[napi]
+pub async fn begin_oidc_sign_in(
+    provider: String,
+    redirect_uri: String,
+    link_uid: Option<String>,
+) -> napi::Result<OidcAuthorization> {
+    user_handler::begin_oidc_sign_in(provider, redirect_uri, link_uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to begin OIDC sign-in: {e}")))
+}
+
+#[napi]
+pub async fn finish_oidc_sign_in(
+    state: String,
+    code: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<OidcSignIn> {
+    user_handler::finish_oidc_sign_in(state, code, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to finish OIDC sign-in: {e}")))
+}
+
+#[napi]
+pub async fn list_users(query: UserListQuery) -> napi::Result<UserPage> {
+    internal_list_users(query)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list users: {e}")))
+}
+
+#[napi]
+pub async fn delete_user(email: String, audit: Option<AuditContext>) -> napi::Result<User> {
+    internal_delete_users(email, audit.unwrap_or_default())
         .await
         .map_err(|e| napi::Error::from_reason(format!("Failed to delete user: {e}")))
 }
 
+#[napi]
+pub async fn restore_user(uid: String, audit: Option<AuditContext>) -> napi::Result<User> {
+    user_handler::restore_user(uid, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to restore user: {e}")))
+}
+
+#[napi]
+pub async fn purge_deleted_users() -> napi::Result<Vec<String>> {
+    user_handler::purge_deleted_users()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to purge deleted users: {e}")))
+}
+
+/// Overrides the Argon2 parameters for new hashes. Call once at startup, before any
+/// password is hashed or checked. Fails on an invalid `PASSWORD_PEPPERS` too.
+#[napi]
+pub fn configure_password_hashing(config: HashConfig) -> napi::Result<()> {
+    user_handler::configure_hashing(config)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to configure hashing: {e}")))
+}
+
+#[napi]
+pub async fn request_password_reset(
+    email: String,
+    reset_url: String,
+    locale: Option<String>,
+) -> napi::Result<()> {
+    user_handler::request_password_reset(email, reset_url, locale)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to request password reset: {e}")))
+}
+
+#[napi]
+pub async fn reset_password(
+    token: String,
+    pass: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<User> {
+    user_handler::reset_password(token, pass, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to reset password: {e}")))
+}
+
+#[napi]
+pub async fn request_email_verification(
+    email: String,
+    verify_url: String,
+    locale: Option<String>,
+) -> napi::Result<()> {
+    user_handler::request_email_verification(email, verify_url, locale)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to request email verification: {e}")))
+}
+
+#[napi]
+pub async fn verify_email(token: String, audit: Option<AuditContext>) -> napi::Result<User> {
+    user_handler::verify_email(token, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to verify email: {e}")))
+}
+
+/// Queues a mail, sent by the next `process_mail_queue`
+#[napi]
+pub async fn send_mail(mail: Mail) -> napi::Result<()> {
+    mailer::send(mail)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to send mail: {e}")))
+}
+
+#[napi]
+pub async fn send_template_mail(
+    template: String,
+    to: String,
+    vars: HashMap<String, String>,
+    locale: Option<String>,
+) -> napi::Result<()> {
+    mailer::send_template(&template, locale.as_deref(), &to, &vars)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to send mail: {e}")))
+}
+
+/// Renders a template without sending anything
+#[napi]
+pub fn preview_mail(
+    template: String,
+    vars: HashMap<String, String>,
+    locale: Option<String>,
+) -> napi::Result<RenderedMail> {
+    mailer::render(&template, locale.as_deref(), &vars)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to render mail: {e}")))
+}
+
+/// Sends due mail, 50 at a time by default. Meant to be called on an interval.
+#[napi]
+pub async fn process_mail_queue(limit: Option<u32>) -> napi::Result<MailQueueReport> {
+    mailer::process_queue(limit)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to process mail queue: {e}")))
+}
+
+#[napi]
+pub async fn list_failed_mail(limit: u32) -> napi::Result<Vec<FailedMail>> {
+    mailer::failed_mail(limit)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list failed mail: {e}")))
+}
+
+/// Overrides `UNVERIFIED_EMAIL_POLICY`. Call once at startup, before any login.
+#[napi]
+pub fn configure_unverified_email_policy(policy: UnverifiedEmailPolicy) -> napi::Result<()> {
+    rbac::configure_unverified_email_policy(policy).map_err(|e| {
+        napi::Error::from_reason(format!("Failed to configure unverified email policy: {e}"))
+    })
+}
+
+#[napi]
+pub fn unverified_email_policy() -> UnverifiedEmailPolicy {
+    rbac::unverified_email_policy()
+}
+
+/// Overrides the password policy. Call once at startup, before any password is checked.
+#[napi]
+pub fn configure_password_policy(policy: PasswordPolicy) -> napi::Result<()> {
+    user_handler::configure_password_policy(policy)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to configure password policy: {e}")))
+}
+
+#[napi]
+pub async fn check_password(pass: String, email: Option<String>) -> napi::Result<PasswordCheck> {
+    user_handler::check_password(pass, email)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to check password: {e}")))
+}
+
+#[napi]
+pub async fn import_password_hash(
+    uid: String,
+    hash: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<User> {
+    user_handler::import_password_hash(uid, hash, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to import password hash: {e}")))
+}
+
+/// Fails rather than answering false when the hash is peppered with a key no longer in
+/// `PASSWORD_PEPPERS`
 #[napi]
 pub async fn check_pass(email: String, pass: String) -> napi::Result<bool> {
     validate_pass(email, pass)
@@ -109,6 +352,24 @@ pub async fn rotate_refresh_jwt(token: String) -> napi::Result<(String, String,
         .map_err(|e| napi::Error::from_reason(format!("Failed to rotate refresh token: {e}")))
 }
 
+/// The PEM public key access tokens verify with, for other services. Null when they're
+/// signed with a shared secret (HS256).
+#[napi]
+pub async fn access_jwt_public_key() -> napi::Result<Option<String>> {
+    access_public_key_pem()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to read access token key: {e}")))
+}
+
+/// The public keys access tokens verify with as a JWKS document, for
+/// `/.well-known/jwks.json`. Keys still verifying tokens from before a rotation are included.
+#[napi]
+pub async fn access_jwks_json() -> napi::Result<String> {
+    access_jwks()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to read access token keys: {e}")))
+}
+
 #[napi]
 pub async fn init_redis() -> napi::Result<()> {
     redis_handler::init_redis()
@@ -188,6 +449,158 @@ pub async fn get_rate_limit_stats(identifier: String) -> napi::Result<(u32, u32)
         .map_err(|e| napi::Error::from_reason(format!("Failed to get rate limit stats: {e}")))
 }
 
+#[napi]
+pub async fn authenticate(email: String, pass: String) -> napi::Result<LoginResult> {
+    user_handler::authenticate(email, pass)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to authenticate: {e}")))
+}
+
+/// Challenge for a sign-in that skipped the password, for users with TOTP enabled
+#[napi]
+pub async fn begin_second_factor(uid: String) -> napi::Result<String> {
+    user_handler::begin_second_factor(uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to begin second factor: {e}")))
+}
+
+#[napi]
+pub async fn verify_second_factor(challenge: String, code: String) -> napi::Result<LoginResult> {
+    user_handler::verify_second_factor(challenge, code)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to verify second factor: {e}")))
+}
+
+#[napi]
+pub async fn begin_totp_enrolment(uid: String) -> napi::Result<TotpEnrolment> {
+    user_handler::begin_totp_enrolment(uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to begin TOTP enrolment: {e}")))
+}
+
+/// Returns the recovery codes, which can't be shown again. They are stored hashed with
+/// `RECOVERY_CODE_SECRET`, which every instance has to share.
+#[napi]
+pub async fn confirm_totp_enrolment(
+    uid: String,
+    code: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<Vec<String>> {
+    user_handler::confirm_totp_enrolment(uid, code, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to confirm TOTP enrolment: {e}")))
+}
+
+#[napi]
+pub async fn disable_totp(uid: String, audit: Option<AuditContext>) -> napi::Result<User> {
+    user_handler::disable_totp(uid, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to disable TOTP: {e}")))
+}
+
+#[napi]
+pub async fn regenerate_recovery_codes(
+    uid: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<Vec<String>> {
+    user_handler::regenerate_recovery_codes(uid, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to regenerate recovery codes: {e}")))
+}
+
+#[napi]
+pub async fn totp_status(uid: String) -> napi::Result<TotpStatus> {
+    user_handler::totp_status(uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to get TOTP status: {e}")))
+}
+
+/// Checks and uses up a TOTP or recovery code, e.g. before a sensitive change
+#[napi]
+pub async fn verify_totp(uid: String, code: String) -> napi::Result<bool> {
+    user_handler::verify_totp(uid, code)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to verify TOTP: {e}")))
+}
+
+/// Sets the passkey relying party. Optional, the environment is read otherwise.
+#[napi]
+pub fn configure_webauthn(config: WebauthnConfig) -> napi::Result<()> {
+    user_handler::configure_webauthn(config)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to configure WebAuthn: {e}")))
+}
+
+/// Options to pass to `navigator.credentials.create()`
+#[napi]
+pub async fn begin_passkey_registration(uid: String) -> napi::Result<Value> {
+    user_handler::begin_passkey_registration(uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to begin passkey registration: {e}")))
+}
+
+/// `response` is the new credential's `toJSON()`
+#[napi]
+pub async fn finish_passkey_registration(
+    uid: String,
+    response: Value,
+    name: Option<String>,
+    audit: Option<AuditContext>,
+) -> napi::Result<Passkey> {
+    user_handler::finish_passkey_registration(uid, response, name, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to register passkey: {e}")))
+}
+
+/// Options to pass to `navigator.credentials.get()`
+#[napi]
+pub async fn begin_passkey_authentication() -> napi::Result<Value> {
+    user_handler::begin_passkey_authentication()
+        .await
+        .map_err(|e| {
+            napi::Error::from_reason(format!("Failed to begin passkey authentication: {e}"))
+        })
+}
+
+/// `response` is the assertion's `toJSON()`
+#[napi]
+pub async fn finish_passkey_authentication(response: Value) -> napi::Result<LoginResult> {
+    user_handler::finish_passkey_authentication(response)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to log in with passkey: {e}")))
+}
+
+#[napi]
+pub async fn list_passkeys(uid: String) -> napi::Result<Vec<Passkey>> {
+    user_handler::list_passkeys(uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list passkeys: {e}")))
+}
+
+#[napi]
+pub async fn delete_passkey(
+    uid: String,
+    credential_id: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<bool> {
+    user_handler::delete_passkey(uid, credential_id, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to delete passkey: {e}")))
+}
+
+#[napi]
+pub async fn lockout_status(email: String) -> napi::Result<LockoutStatus> {
+    redis_handler::lockout_status(email)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to get lockout status: {e}")))
+}
+
+#[napi]
+pub async fn unlock_account(email: String) -> napi::Result<bool> {
+    redis_handler::unlock_account(email)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to unlock account: {e}")))
+}
+
 #[napi]
 pub async fn redis_health_check() -> napi::Result<bool> {
     redis_handler::health_check()
@@ -229,3 +642,269 @@ pub async fn uid_lookup(uid: String) -> napi::Result<User> {
         .await
         .map_err(|e| napi::Error::from_reason(format!("Failed to lookup user from uid: {e}")))
 }
+
+#[napi]
+pub async fn email_lookup(email: String) -> napi::Result<Option<User>> {
+    user_from_email(&email)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to lookup user from email: {e}")))
+}
+
+#[napi]
+pub async fn get_grid(grid_id: i32) -> napi::Result<Grid> {
+    grid_handler::load_grid(grid_id)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to load grid: {e}")))
+}
+
+#[napi]
+pub async fn gen_worksheet_html(
+    grid_id: i32,
+    options: Option<WorksheetOptions>,
+) -> napi::Result<String> {
+    let grid = grid_handler::load_grid(grid_id)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to load grid: {e}")))?;
+    let worksheet = build_worksheet(&grid, &options.unwrap_or_default());
+    Ok(render_html(&worksheet))
+}
+
+#[napi]
+pub async fn gen_worksheet_pdf(
+    grid_id: i32,
+    options: Option<WorksheetOptions>,
+) -> napi::Result<Buffer> {
+    let grid = grid_handler::load_grid(grid_id)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to load grid: {e}")))?;
+    let worksheet = build_worksheet(&grid, &options.unwrap_or_default());
+    Ok(render_pdf(&worksheet).into())
+}
+
+#[napi]
+pub async fn list_roles() -> napi::Result<Vec<Role>> {
+    rbac::list_roles()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list roles: {e}")))
+}
+
+#[napi]
+pub async fn create_role(role_name: String, perms: Option<Vec<String>>) -> napi::Result<Role> {
+    rbac::create_role(role_name, perms)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to create role: {e}")))
+}
+
+#[napi]
+pub async fn rename_role(role_name: String, new_name: String) -> napi::Result<Role> {
+    rbac::rename_role(role_name, new_name)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to rename role: {e}")))
+}
+
+#[napi]
+pub async fn delete_role(role_name: String) -> napi::Result<Role> {
+    rbac::delete_role(role_name)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to delete role: {e}")))
+}
+
+#[napi]
+pub async fn list_perms() -> napi::Result<Vec<Perm>> {
+    rbac::list_perms()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list permissions: {e}")))
+}
+
+#[napi]
+pub async fn create_perm(perm: String, description: Option<String>) -> napi::Result<Perm> {
+    rbac::create_perm(perm, description)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to create permission: {e}")))
+}
+
+#[napi]
+pub async fn update_perm(
+    perm: String,
+    new_name: Option<String>,
+    description: Option<String>,
+) -> napi::Result<Perm> {
+    rbac::update_perm(perm, new_name, description)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to update permission: {e}")))
+}
+
+#[napi]
+pub async fn delete_perm(perm: String) -> napi::Result<Perm> {
+    rbac::delete_perm(perm)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to delete permission: {e}")))
+}
+
+#[napi]
+pub async fn grant_role_perm(role_name: String, perm: String) -> napi::Result<Role> {
+    rbac::grant_role_perm(role_name, perm)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to grant permission to role: {e}")))
+}
+
+#[napi]
+pub async fn revoke_role_perm(role_name: String, perm: String) -> napi::Result<Role> {
+    rbac::revoke_role_perm(role_name, perm).await.map_err(|e| {
+        napi::Error::from_reason(format!("Failed to revoke permission from role: {e}"))
+    })
+}
+
+#[napi]
+pub async fn set_role_perms(role_name: String, perms: Vec<String>) -> napi::Result<Role> {
+    rbac::set_role_perms(role_name, perms)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to set role permissions: {e}")))
+}
+
+#[napi]
+pub async fn add_perm_implication(perm: String, implied: String) -> napi::Result<Perm> {
+    rbac::add_perm_implication(perm, implied)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to add permission implication: {e}")))
+}
+
+#[napi]
+pub async fn remove_perm_implication(perm: String, implied: String) -> napi::Result<Perm> {
+    rbac::remove_perm_implication(perm, implied)
+        .await
+        .map_err(|e| {
+            napi::Error::from_reason(format!("Failed to remove permission implication: {e}"))
+        })
+}
+
+#[napi]
+pub async fn effective_perms(uid: String) -> napi::Result<Vec<String>> {
+    rbac::effective_perms(&uid)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to resolve permissions: {e}")))
+}
+
+#[napi]
+pub async fn has_perm(uid: String, perm: String, resource: Option<String>) -> napi::Result<bool> {
+    rbac::has_perm(&uid, &perm, resource)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to check permission: {e}")))
+}
+
+#[napi]
+pub async fn explain_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+) -> napi::Result<PermDecision> {
+    rbac::explain_perm(uid, perm, resource)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to explain permission: {e}")))
+}
+
+#[napi]
+pub async fn assign_user_role(
+    uid: String,
+    role_name: String,
+    window: Option<GrantWindow>,
+    audit: Option<AuditContext>,
+) -> napi::Result<Vec<String>> {
+    rbac::assign_user_role(uid, role_name, window, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to assign role to user: {e}")))
+}
+
+#[napi]
+pub async fn remove_user_role(
+    uid: String,
+    role_name: String,
+    audit: Option<AuditContext>,
+) -> napi::Result<Vec<String>> {
+    rbac::remove_user_role(uid, role_name, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to remove role from user: {e}")))
+}
+
+#[napi]
+pub async fn grant_user_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    window: Option<GrantWindow>,
+    audit: Option<AuditContext>,
+) -> napi::Result<Vec<String>> {
+    rbac::grant_user_perm(uid, perm, resource, window, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to grant permission to user: {e}")))
+}
+
+#[napi]
+pub async fn revoke_user_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    audit: Option<AuditContext>,
+) -> napi::Result<Vec<String>> {
+    rbac::revoke_user_perm(uid, perm, resource, audit.unwrap_or_default())
+        .await
+        .map_err(|e| {
+            napi::Error::from_reason(format!("Failed to revoke permission from user: {e}"))
+        })
+}
+
+#[napi]
+pub async fn deny_user_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    reason: Option<String>,
+    audit: Option<AuditContext>,
+) -> napi::Result<PermDecision> {
+    rbac::deny_user_perm(uid, perm, resource, reason, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to deny permission to user: {e}")))
+}
+
+#[napi]
+pub async fn remove_user_deny(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    audit: Option<AuditContext>,
+) -> napi::Result<PermDecision> {
+    rbac::remove_user_deny(uid, perm, resource, audit.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to remove permission deny: {e}")))
+}
+
+#[napi]
+pub async fn purge_expired_grants() -> napi::Result<Vec<ExpiredGrant>> {
+    rbac::purge_expired_grants()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to purge expired grants: {e}")))
+}
+
+#[napi]
+pub async fn list_expired_grants(
+    uid: Option<String>,
+    limit: Option<u32>,
+) -> napi::Result<Vec<ExpiredGrant>> {
+    rbac::list_expired_grants(uid, limit)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list expired grants: {e}")))
+}
+
+#[napi]
+pub async fn list_audit(query: AuditQuery) -> napi::Result<AuditPage> {
+    audit::list_audit(query)
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list audit log: {e}")))
+}
+
+#[napi]
+pub async fn export_audit_jsonl(query: Option<AuditQuery>) -> napi::Result<AuditExport> {
+    audit::export_audit_jsonl(query.unwrap_or_default())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to export audit log: {e}")))
+}
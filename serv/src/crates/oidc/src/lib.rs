@@ -0,0 +1,633 @@
+//! An OpenID Connect relying party for the authorization code flow with PKCE: reads the
+//! provider's discovery document, builds the authorization URL, redeems the code and
+//! validates the ID token against the provider's published keys. Keeping the state,
+//! nonce and code verifier between the two legs is left to the caller, `StateKey` can
+//! seal them into the state itself.
+
+#[cfg(test)]
+mod mock;
+mod state;
+
+pub use state::{OpenedState, SealedState, StateKey};
+
+use aws_lc_rs::digest::{SHA256, digest};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::jwk::{Jwk, JwkSet, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use rand_core::RngCore;
+use reqwest::Url;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+const RANDOM_BYTES: usize = 32;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How far the provider's clock may be off from ours
+const CLOCK_SKEW_SECONDS: u64 = 60;
+/// Keys are refetched this often, so ones the provider withdrew stop being accepted
+const KEYS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+/// A token signed with an unknown key refetches the keys, but no more often than this
+const KEYS_MIN_REFRESH: Duration = Duration::from_secs(60);
+/// Microsoft's multi-tenant endpoints publish an issuer with the tenant left open
+const TENANT_PLACEHOLDER: &str = "{tenantid}";
+
+// Shared secrets would let anyone holding the client secret mint ID tokens
+const ALLOWED_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("Provider unreachable: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid discovery document: {0}")]
+    Discovery(String),
+    /// The provider answered the token request with an error, e.g. an expired code
+    #[error("Provider refused the sign-in: {0}")]
+    Refused(String),
+    #[error("Invalid ID token: {0}")]
+    IdToken(String),
+    #[error("Invalid ID token: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("Invalid state: {0}")]
+    State(String),
+    /// The state couldn't be sealed, e.g. no system randomness
+    #[error("Failed to seal state: {0}")]
+    Seal(String),
+}
+
+impl From<OidcError> for napi::Error {
+    fn from(err: OidcError) -> Self {
+        napi::Error::from_reason(err.to_string())
+    }
+}
+
+/// A fresh random value for a state, nonce or code verifier, base64url encoded
+pub fn random_token() -> String {
+    let mut bytes = [0u8; RANDOM_BYTES];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The S256 PKCE challenge for a code verifier
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, code_verifier.as_bytes()))
+}
+
+/// A client registered with a provider
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    /// The issuer URL, the discovery document is looked up below it
+    pub issuer: String,
+    pub client_id: String,
+    /// None for public clients, which rely on PKCE alone
+    pub client_secret: Option<String>,
+    /// Requested besides `openid`, e.g. `email` and `profile`
+    pub scopes: Vec<String>,
+}
+
+/// The parts of `/.well-known/openid-configuration` the code flow needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+/// Where to send the browser, and what to keep until it comes back
+#[derive(Debug, Clone)]
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+/// Who the ID token says signed in
+#[derive(Debug, Clone)]
+pub struct IdTokenClaims {
+    /// The provider's stable id for the account
+    pub subject: String,
+    pub email: Option<String>,
+    /// Whether the provider vouches that the user controls `email`
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+// Some providers send booleans as strings
+fn flexible_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flexible {
+        Bool(bool),
+        Text(String),
+    }
+    Ok(match Option::<Flexible>::deserialize(deserializer)? {
+        Some(Flexible::Bool(b)) => b,
+        Some(Flexible::Text(s)) => s.eq_ignore_ascii_case("true"),
+        None => false,
+    })
+}
+
+#[derive(Deserialize)]
+struct RawClaims {
+    iss: String,
+    sub: String,
+    /// A string or a list, already checked to include us
+    aud: serde_json::Value,
+    azp: Option<String>,
+    nonce: Option<String>,
+    tid: Option<String>,
+    email: Option<String>,
+    #[serde(default, deserialize_with = "flexible_bool")]
+    email_verified: bool,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+struct KeyCache {
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+/// Plain HTTP is only allowed to the local machine, for development and tests
+fn endpoint(url: &str, what: &str) -> Result<Url, OidcError> {
+    let parsed =
+        Url::parse(url).map_err(|e| OidcError::Discovery(format!("{what} is not a URL: {e}")))?;
+    let local = matches!(parsed.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if parsed.scheme() == "https" || (parsed.scheme() == "http" && local) {
+        Ok(parsed)
+    } else {
+        Err(OidcError::Discovery(format!("{what} must use https")))
+    }
+}
+
+fn http_client() -> Result<reqwest::Client, OidcError> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::aws_lc_rs::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| OidcError::Discovery(e.to_string()))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    Ok(reqwest::Client::builder()
+        .use_preconfigured_tls(tls)
+        .timeout(HTTP_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?)
+}
+
+/// The signing key a token names, or the only one if it names none
+fn signing_key(keys: &JwkSet, kid: Option<&str>) -> Option<Jwk> {
+    let mut usable = keys
+        .keys
+        .iter()
+        .filter(|k| !matches!(k.common.public_key_use, Some(PublicKeyUse::Encryption)));
+    match kid {
+        Some(kid) => usable.find(|k| k.common.key_id.as_deref() == Some(kid)),
+        None => match (usable.next(), usable.next()) {
+            (Some(only), None) => Some(only),
+            _ => None,
+        },
+    }
+    .cloned()
+}
+
+/// A provider whose discovery document has been read. Cheap to share, the signing keys
+/// are cached and refetched as they rotate.
+pub struct Provider {
+    config: ProviderConfig,
+    discovery: Discovery,
+    authorization_endpoint: Url,
+    http: reqwest::Client,
+    keys: RwLock<KeyCache>,
+}
+
+impl Provider {
+    /// Reads the provider's discovery document and signing keys
+    pub async fn discover(config: ProviderConfig) -> Result<Self, OidcError> {
+        let issuer = config.issuer.trim_end_matches('/');
+        let http = http_client()?;
+
+        let url = endpoint(
+            &format!("{issuer}/.well-known/openid-configuration"),
+            "issuer",
+        )?;
+        let discovery: Discovery = http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // A multi-tenant issuer can't match the configured one, the tenant is checked
+        // per token instead
+        if !discovery.issuer.contains(TENANT_PLACEHOLDER)
+            && discovery.issuer.trim_end_matches('/') != issuer
+        {
+            return Err(OidcError::Discovery(format!(
+                "issuer {} doesn't match {issuer}",
+                discovery.issuer
+            )));
+        }
+        let authorization_endpoint =
+            endpoint(&discovery.authorization_endpoint, "authorization_endpoint")?;
+        endpoint(&discovery.token_endpoint, "token_endpoint")?;
+        endpoint(&discovery.jwks_uri, "jwks_uri")?;
+
+        let keys = Self::fetch_keys(&http, &discovery.jwks_uri).await?;
+        Ok(Provider {
+            config,
+            discovery,
+            authorization_endpoint,
+            http,
+            keys: RwLock::new(KeyCache {
+                keys,
+                fetched_at: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn discovery(&self) -> &Discovery {
+        &self.discovery
+    }
+
+    async fn fetch_keys(http: &reqwest::Client, jwks_uri: &str) -> Result<JwkSet, OidcError> {
+        Ok(http
+            .get(jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Starts a sign-in. The browser goes to `url`, and everything else has to be kept
+    /// until it comes back to `redirect_uri` with the same `state`.
+    pub fn authorization_request(&self, redirect_uri: &str) -> AuthorizationRequest {
+        let state = random_token();
+        let nonce = random_token();
+        let code_verifier = random_token();
+
+        let mut scopes = vec!["openid"];
+        scopes.extend(
+            self.config
+                .scopes
+                .iter()
+                .map(String::as_str)
+                .filter(|s| *s != "openid"),
+        );
+
+        let mut url = self.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge(&code_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        AuthorizationRequest {
+            url: url.into(),
+            state,
+            nonce,
+            code_verifier,
+        }
+    }
+
+    /// Redeems the code the browser came back with, returning the raw ID token
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<String, OidcError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        let mut request = self.http.post(&self.discovery.token_endpoint);
+        if let Some(secret) = &self.config.client_secret {
+            // Basic is the default when the provider doesn't say
+            let post = self
+                .discovery
+                .token_endpoint_auth_methods_supported
+                .iter()
+                .any(|m| m == "client_secret_post");
+            if post {
+                form.push(("client_secret", secret));
+            } else {
+                request = request.basic_auth(&self.config.client_id, Some(secret));
+            }
+        }
+
+        let response = request.form(&form).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(OidcError::Refused(
+                match response.json::<TokenError>().await {
+                    Ok(e) => e.error_description.unwrap_or(e.error),
+                    Err(_) => status.to_string(),
+                },
+            ));
+        }
+
+        response
+            .json::<TokenResponse>()
+            .await?
+            .id_token
+            .ok_or_else(|| OidcError::Refused("no ID token in the token response".to_string()))
+    }
+
+    async fn key_for(&self, kid: Option<&str>) -> Result<Jwk, OidcError> {
+        let (cached, age) = {
+            let cache = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            (signing_key(&cache.keys, kid), cache.fetched_at.elapsed())
+        };
+        match cached {
+            Some(jwk) if age < KEYS_MAX_AGE => return Ok(jwk),
+            None if age < KEYS_MIN_REFRESH => {
+                return Err(OidcError::IdToken("signed with an unknown key".to_string()));
+            }
+            _ => {}
+        }
+
+        // Rotated, or due for a refresh
+        let keys = Self::fetch_keys(&self.http, &self.discovery.jwks_uri).await?;
+        let found = signing_key(&keys, kid);
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = KeyCache {
+            keys,
+            fetched_at: Instant::now(),
+        };
+        found.ok_or_else(|| OidcError::IdToken("signed with an unknown key".to_string()))
+    }
+
+    /// Checks the ID token's signature, issuer, audience, lifetime and nonce
+    pub async fn verify_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let header = decode_header(id_token)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(OidcError::IdToken(format!(
+                "{:?} signatures aren't accepted",
+                header.alg
+            )));
+        }
+        let jwk = self.key_for(header.kid.as_deref()).await?;
+        if let Some(alg) = jwk.common.key_algorithm
+            && alg.to_string() != format!("{:?}", header.alg)
+        {
+            return Err(OidcError::IdToken(format!(
+                "key is for {alg}, not {:?}",
+                header.alg
+            )));
+        }
+
+        let templated = self.discovery.issuer.contains(TENANT_PLACEHOLDER);
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = CLOCK_SKEW_SECONDS;
+        validation.validate_nbf = true;
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iat", "iss", "aud", "sub"]);
+        if !templated {
+            validation.set_issuer(&[&self.discovery.issuer]);
+        }
+
+        let claims =
+            decode::<RawClaims>(id_token, &DecodingKey::from_jwk(&jwk)?, &validation)?.claims;
+
+        if templated {
+            let tenant = claims
+                .tid
+                .as_deref()
+                .ok_or_else(|| OidcError::IdToken("missing tenant".to_string()))?;
+            if claims.iss != self.discovery.issuer.replace(TENANT_PLACEHOLDER, tenant) {
+                return Err(OidcError::IdToken("wrong issuer".to_string()));
+            }
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::IdToken("nonce mismatch".to_string()));
+        }
+        // A token for several audiences has to say which one it was issued to
+        let several = claims.aud.as_array().is_some_and(|aud| aud.len() > 1);
+        match claims.azp.as_deref() {
+            Some(azp) if azp != self.config.client_id => {
+                return Err(OidcError::IdToken("issued to another client".to_string()));
+            }
+            None if several => {
+                return Err(OidcError::IdToken("missing authorized party".to_string()));
+            }
+            _ => {}
+        }
+
+        Ok(IdTokenClaims {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            name: claims.name,
+        })
+    }
+
+    /// Finishes a sign-in: redeems the code and verifies the ID token it yields
+    pub async fn sign_in(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let id_token = self
+            .exchange_code(code, redirect_uri, code_verifier)
+            .await?;
+        self.verify_id_token(&id_token, nonce).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockIssuer;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const CLIENT_ID: &str = "serv";
+    const REDIRECT_URI: &str = "http://localhost:8888/auth/oidc/mock/callback";
+
+    async fn setup() -> (MockIssuer, Provider) {
+        let issuer = MockIssuer::start(CLIENT_ID, Some("secret")).await.unwrap();
+        let provider = Provider::discover(issuer.provider_config()).await.unwrap();
+        (issuer, provider)
+    }
+
+    fn claims(issuer: &MockIssuer, nonce: &str) -> serde_json::Value {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        json!({
+            "iss": issuer.issuer(),
+            "sub": "sub-1",
+            "aud": CLIENT_ID,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": nonce,
+        })
+    }
+
+    /// The code and state a callback URL carries
+    fn callback_params(callback: &str) -> HashMap<String, String> {
+        Url::parse(callback)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    /// Lets the next unknown key refetch the keys right away
+    fn expire_key_refresh_limit(provider: &Provider) {
+        let mut cache = provider.keys.write().unwrap();
+        cache.fetched_at = Instant::now()
+            .checked_sub(KEYS_MIN_REFRESH)
+            .unwrap_or(cache.fetched_at);
+    }
+
+    #[tokio::test]
+    async fn discovers_the_provider() {
+        let (issuer, provider) = setup().await;
+        let discovery = provider.discovery();
+        assert_eq!(discovery.issuer, issuer.issuer());
+        assert_eq!(
+            discovery.token_endpoint,
+            format!("{}/token", issuer.issuer())
+        );
+        assert!(endpoint("http://example.com/token", "token_endpoint").is_err());
+        assert!(endpoint("http://127.0.0.1:8080/token", "token_endpoint").is_ok());
+    }
+
+    #[tokio::test]
+    async fn signs_in_with_pkce() {
+        let (issuer, provider) = setup().await;
+        let request = provider.authorization_request(REDIRECT_URI);
+        let callback = issuer
+            .authorize(&request.url, "sub-1", "a@example.com", true)
+            .unwrap();
+        let params = callback_params(&callback);
+        assert_eq!(params["state"], request.state);
+
+        let claims = provider
+            .sign_in(
+                &params["code"],
+                REDIRECT_URI,
+                &request.code_verifier,
+                &request.nonce,
+            )
+            .await
+            .unwrap();
+        assert_eq!(claims.subject, "sub-1");
+        assert_eq!(claims.email.as_deref(), Some("a@example.com"));
+        assert!(claims.email_verified);
+
+        // Codes are single use
+        let reused = provider
+            .exchange_code(&params["code"], REDIRECT_URI, &request.code_verifier)
+            .await;
+        assert!(matches!(reused, Err(OidcError::Refused(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_code_verifier() {
+        let (issuer, provider) = setup().await;
+        let request = provider.authorization_request(REDIRECT_URI);
+        let callback = issuer
+            .authorize(&request.url, "sub-1", "a@example.com", true)
+            .unwrap();
+        let params = callback_params(&callback);
+
+        let result = provider
+            .exchange_code(&params["code"], REDIRECT_URI, &random_token())
+            .await;
+        assert!(matches!(result, Err(OidcError::Refused(r)) if r.contains("PKCE")));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_id_tokens() {
+        let (issuer, provider) = setup().await;
+        let valid = issuer.sign(&claims(&issuer, "n"));
+        assert!(provider.verify_id_token(&valid, "n").await.is_ok());
+
+        let result = provider.verify_id_token(&valid, "other").await;
+        assert!(matches!(result, Err(OidcError::IdToken(r)) if r.contains("nonce")));
+
+        let mut other_client = claims(&issuer, "n");
+        other_client["aud"] = json!("someone-else");
+        let result = provider
+            .verify_id_token(&issuer.sign(&other_client), "n")
+            .await;
+        assert!(matches!(result, Err(OidcError::Jwt(_))));
+
+        // Claims of one token with the signature of another
+        let mut tampered = claims(&issuer, "n");
+        tampered["sub"] = json!("sub-2");
+        let tampered = issuer.sign(&tampered);
+        let forged = format!(
+            "{}.{}",
+            tampered.rsplit_once('.').unwrap().0,
+            valid.rsplit_once('.').unwrap().1
+        );
+        let result = provider.verify_id_token(&forged, "n").await;
+        assert!(matches!(result, Err(OidcError::Jwt(_))));
+    }
+
+    #[tokio::test]
+    async fn follows_key_rotation() {
+        let (issuer, provider) = setup().await;
+        let old = issuer.sign(&claims(&issuer, "n"));
+        issuer.rotate_key();
+        let new = issuer.sign(&claims(&issuer, "n"));
+
+        // Unknown keys don't refetch more than once a minute
+        let result = provider.verify_id_token(&new, "n").await;
+        assert!(matches!(result, Err(OidcError::IdToken(r)) if r.contains("unknown key")));
+
+        expire_key_refresh_limit(&provider);
+        assert!(provider.verify_id_token(&new, "n").await.is_ok());
+        // The withdrawn key went with the refetch
+        let result = provider.verify_id_token(&old, "n").await;
+        assert!(matches!(result, Err(OidcError::IdToken(r)) if r.contains("unknown key")));
+    }
+}
@@ -0,0 +1,351 @@
+use crate::{OidcError, ProviderConfig, code_challenge, random_token};
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use serde_json::{Value as Json, json};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use url::{Url, form_urlencoded};
+
+const ID_TOKEN_TTL_SECONDS: u64 = 5 * 60;
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+struct SigningKey {
+    kid: String,
+    pkcs8: Vec<u8>,
+    /// Uncompressed point, 0x04 || x || y
+    public: Vec<u8>,
+}
+
+impl SigningKey {
+    fn generate() -> Self {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .expect("P-256 key generation");
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+            .expect("generated key parses");
+        SigningKey {
+            kid: random_token()[..16].to_string(),
+            public: pair.public_key().as_ref().to_vec(),
+            pkcs8: pkcs8.as_ref().to_vec(),
+        }
+    }
+
+    fn jwk(&self) -> Json {
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "use": "sig",
+            "alg": "ES256",
+            "kid": self.kid,
+            "x": URL_SAFE_NO_PAD.encode(&self.public[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&self.public[33..65]),
+        })
+    }
+
+    fn sign(&self, claims: &Json) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &EncodingKey::from_ec_der(&self.pkcs8)).expect("ES256 signing")
+    }
+}
+
+/// A consented sign-in waiting for its code to be redeemed
+struct Grant {
+    redirect_uri: String,
+    code_challenge: String,
+    claims: Json,
+}
+
+struct Issuer {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    key: SigningKey,
+    grants: HashMap<String, Grant>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn token_error(error: &str, description: &str) -> (u16, Json) {
+    (
+        400,
+        json!({ "error": error, "error_description": description }),
+    )
+}
+
+impl Issuer {
+    fn discovery(&self) -> Json {
+        json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": format!("{}/authorize", self.issuer),
+            "token_endpoint": format!("{}/token", self.issuer),
+            "jwks_uri": format!("{}/jwks", self.issuer),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["ES256"],
+            "code_challenge_methods_supported": ["S256"],
+            "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+        })
+    }
+
+    fn token(&mut self, body: &[u8], authorization: Option<&str>) -> (u16, Json) {
+        let form: HashMap<String, String> = form_urlencoded::parse(body).into_owned().collect();
+        let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+
+        let basic = authorization
+            .and_then(|a| a.strip_prefix("Basic "))
+            .and_then(|a| STANDARD.decode(a).ok())
+            .and_then(|a| String::from_utf8(a).ok());
+        let (client_id, client_secret) = match basic.as_deref().and_then(|b| b.split_once(':')) {
+            Some((id, secret)) => (id, Some(secret)),
+            None => (
+                field("client_id"),
+                form.get("client_secret").map(String::as_str),
+            ),
+        };
+        if client_id != self.client_id
+            || (self.client_secret.is_some() && client_secret != self.client_secret.as_deref())
+        {
+            return (401, json!({ "error": "invalid_client" }));
+        }
+        if field("grant_type") != "authorization_code" {
+            return token_error("unsupported_grant_type", "only authorization_code");
+        }
+
+        // Codes are single use, a failed attempt burns it too
+        let Some(grant) = self.grants.remove(field("code")) else {
+            return token_error("invalid_grant", "unknown or used code");
+        };
+        if field("redirect_uri") != grant.redirect_uri {
+            return token_error("invalid_grant", "redirect_uri mismatch");
+        }
+        if code_challenge(field("code_verifier")) != grant.code_challenge {
+            return token_error("invalid_grant", "PKCE verification failed");
+        }
+
+        (
+            200,
+            json!({
+                "access_token": random_token(),
+                "token_type": "Bearer",
+                "expires_in": ID_TOKEN_TTL_SECONDS,
+                "id_token": self.key.sign(&grant.claims),
+            }),
+        )
+    }
+
+    fn route(
+        &mut self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        authorization: Option<&str>,
+    ) -> (u16, Json) {
+        match (method, path) {
+            ("GET", "/.well-known/openid-configuration") => (200, self.discovery()),
+            ("GET", "/jwks") => (200, json!({ "keys": [self.key.jwk()] })),
+            ("POST", "/token") => self.token(body, authorization),
+            _ => (404, json!({ "error": "not_found" })),
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, issuer: Arc<Mutex<Issuer>>) -> io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() > MAX_REQUEST_BYTES {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+
+    let length = headers
+        .get("content-length")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(0)
+        .min(MAX_REQUEST_BYTES);
+    while buf.len() < head_end + length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let (status, body) = issuer.lock().unwrap_or_else(|e| e.into_inner()).route(
+        method,
+        path,
+        &buf[head_end..head_end + length],
+        headers.get("authorization").map(String::as_str),
+    );
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        if status == 200 { "OK" } else { "Error" },
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// An OpenID provider on a local port, standing in for Keycloak, Authentik and the like
+/// in tests. Serves discovery, keys and the token endpoint over plain HTTP. The browser
+/// leg is skipped: `authorize` consents on the user's behalf and returns where the
+/// provider would have redirected to.
+pub struct MockIssuer {
+    issuer: String,
+    state: Arc<Mutex<Issuer>>,
+    server: JoinHandle<()>,
+}
+
+impl MockIssuer {
+    /// Listens on a free port of 127.0.0.1. Needs a tokio runtime.
+    pub async fn start(client_id: &str, client_secret: Option<&str>) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let issuer = format!("http://{}", listener.local_addr()?);
+        let state = Arc::new(Mutex::new(Issuer {
+            issuer: issuer.clone(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.map(str::to_string),
+            key: SigningKey::generate(),
+            grants: HashMap::new(),
+        }));
+
+        let shared = state.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, shared.clone()));
+            }
+        });
+        Ok(MockIssuer {
+            issuer,
+            state,
+            server,
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// A config for the client the issuer was started for
+    pub fn provider_config(&self) -> ProviderConfig {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        ProviderConfig {
+            issuer: self.issuer.clone(),
+            client_id: state.client_id.clone(),
+            client_secret: state.client_secret.clone(),
+            scopes: vec!["email".to_string(), "profile".to_string()],
+        }
+    }
+
+    /// Signs `subject` in at an authorization URL and returns the callback URL, with the
+    /// code and state, that the browser would have been sent to
+    pub fn authorize(
+        &self,
+        authorization_url: &str,
+        subject: &str,
+        email: &str,
+        email_verified: bool,
+    ) -> Result<String, OidcError> {
+        let url = Url::parse(authorization_url).map_err(|e| OidcError::Refused(e.to_string()))?;
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let param = |name: &str| {
+            query
+                .get(name)
+                .cloned()
+                .ok_or_else(|| OidcError::Refused(format!("missing {name}")))
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if param("client_id")? != state.client_id {
+            return Err(OidcError::Refused("unknown client".to_string()));
+        }
+        if param("response_type")? != "code" || param("code_challenge_method")? != "S256" {
+            return Err(OidcError::Refused("unsupported request".to_string()));
+        }
+        if !param("scope")?.split(' ').any(|s| s == "openid") {
+            return Err(OidcError::Refused("openid scope missing".to_string()));
+        }
+
+        let redirect_uri = param("redirect_uri")?;
+        let now = now();
+        let claims = json!({
+            "iss": state.issuer,
+            "sub": subject,
+            "aud": state.client_id,
+            "iat": now,
+            "exp": now + ID_TOKEN_TTL_SECONDS,
+            "nonce": param("nonce")?,
+            "email": email,
+            "email_verified": email_verified,
+        });
+        let code = random_token();
+        state.grants.insert(
+            code.clone(),
+            Grant {
+                redirect_uri: redirect_uri.clone(),
+                code_challenge: param("code_challenge")?,
+                claims,
+            },
+        );
+
+        let mut callback =
+            Url::parse(&redirect_uri).map_err(|e| OidcError::Refused(e.to_string()))?;
+        callback
+            .query_pairs_mut()
+            .append_pair("code", &code)
+            .append_pair("state", &param("state")?);
+        Ok(callback.into())
+    }
+
+    /// Signs arbitrary claims with the current key, for tokens the flow wouldn't issue
+    pub fn sign(&self, claims: &Json) -> String {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .key
+            .sign(claims)
+    }
+
+    /// Replaces the signing key. The old one disappears from the published keys.
+    pub fn rotate_key(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).key = SigningKey::generate();
+    }
+}
+
+impl Drop for MockIssuer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{OidcError, random_token};
+use aws_lc_rs::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use aws_lc_rs::constant_time::verify_slices_are_equal;
+use aws_lc_rs::digest::{SHA256, digest};
+use aws_lc_rs::hkdf::{HKDF_SHA256, Salt};
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Keeps sealed states from being mistaken for anything else sealed with a related key
+const STATE_AAD: &[u8] = b"oauth_state:v1";
+const KEY_INFO: &[u8] = b"oauth state key";
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    payload: Value,
+    /// SHA-256 of the binding, base64url
+    binding: String,
+    id: String,
+    expires_at: u64,
+}
+
+/// A state to put in an authorization URL, and the binding to keep in the browser
+pub struct SealedState {
+    pub state: String,
+    /// Goes in a cookie. The state only opens together with it.
+    pub binding: String,
+    /// Unique per state, for single-use bookkeeping
+    pub id: String,
+    pub expires_at: u64,
+}
+
+/// What a state opened to
+pub struct OpenedState {
+    pub payload: Value,
+    pub id: String,
+    pub expires_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn seal_error(what: &str) -> OidcError {
+    OidcError::Seal(what.to_string())
+}
+
+fn binding_hash(binding: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, binding.as_bytes()))
+}
+
+/// Seals OAuth `state` values with AES-256-GCM, so the provider and the browser carry
+/// them without being able to read or change them. Single use is up to the caller.
+pub struct StateKey(LessSafeKey);
+
+impl StateKey {
+    /// Derives the key from a secret of any length
+    pub fn from_secret(secret: &[u8]) -> Result<Self, OidcError> {
+        let prk = Salt::new(HKDF_SHA256, &[]).extract(secret);
+        let okm = prk
+            .expand(&[KEY_INFO], &AES_256_GCM)
+            .map_err(|_| seal_error("key derivation"))?;
+        Ok(StateKey(LessSafeKey::new(UnboundKey::from(okm))))
+    }
+
+    /// A random key. States sealed with it die with the process.
+    pub fn generate() -> Result<Self, OidcError> {
+        let mut secret = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| seal_error("no system randomness"))?;
+        Self::from_secret(&secret)
+    }
+
+    /// Seals `payload` for `ttl_seconds`, bound to a fresh binding
+    pub fn seal(&self, payload: Value, ttl_seconds: u64) -> Result<SealedState, OidcError> {
+        let binding = random_token();
+        let id = random_token();
+        let expires_at = now() + ttl_seconds;
+        let mut sealed = serde_json::to_vec(&Sealed {
+            payload,
+            binding: binding_hash(&binding),
+            id: id.clone(),
+            expires_at,
+        })
+        .map_err(|e| OidcError::Seal(e.to_string()))?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| seal_error("no system randomness"))?;
+        self.0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(STATE_AAD),
+                &mut sealed,
+            )
+            .map_err(|_| seal_error("encryption"))?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&sealed);
+        Ok(SealedState {
+            state: URL_SAFE_NO_PAD.encode(bytes),
+            binding,
+            id,
+            expires_at,
+        })
+    }
+
+    /// Opens a state with the binding the browser kept. Fails if either was changed, the
+    /// binding belongs to another state, or the state expired.
+    pub fn open(&self, state: &str, binding: &str) -> Result<OpenedState, OidcError> {
+        let invalid = || OidcError::State("tampered with or not from this server".to_string());
+        let mut bytes = URL_SAFE_NO_PAD.decode(state).map_err(|_| invalid())?;
+        if bytes.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let mut sealed = bytes.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&bytes).map_err(|_| invalid())?;
+        let opened = self
+            .0
+            .open_in_place(nonce, Aad::from(STATE_AAD), &mut sealed)
+            .map_err(|_| invalid())?;
+        let sealed: Sealed = serde_json::from_slice(opened).map_err(|_| invalid())?;
+
+        verify_slices_are_equal(sealed.binding.as_bytes(), binding_hash(binding).as_bytes())
+            .map_err(|_| OidcError::State("started in another browser".to_string()))?;
+        if sealed.expires_at <= now() {
+            return Err(OidcError::State("expired".to_string()));
+        }
+
+        Ok(OpenedState {
+            payload: sealed.payload,
+            id: sealed.id,
+            expires_at: sealed.expires_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rejection(result: Result<OpenedState, OidcError>) -> String {
+        match result {
+            Err(OidcError::State(reason)) => reason,
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => panic!("state opened"),
+        }
+    }
+
+    #[test]
+    fn opens_with_its_binding() {
+        let key = StateKey::from_secret(b"secret").unwrap();
+        let sealed = key.seal(json!({ "uid": "u1" }), 60).unwrap();
+        let opened = key.open(&sealed.state, &sealed.binding).unwrap();
+        assert_eq!(opened.payload["uid"], "u1");
+        assert_eq!(opened.id, sealed.id);
+
+        // The same secret derives the same key, on another instance or after a restart
+        let again = StateKey::from_secret(b"secret").unwrap();
+        assert!(again.open(&sealed.state, &sealed.binding).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_bindings_keys_and_changes() {
+        let key = StateKey::from_secret(b"secret").unwrap();
+        let sealed = key.seal(json!(null), 60).unwrap();
+        let other = key.seal(json!(null), 60).unwrap();
+        assert!(rejection(key.open(&sealed.state, &other.binding)).contains("another browser"));
+
+        let mut bytes = sealed.state.clone().into_bytes();
+        let i = bytes.len() / 2;
+        bytes[i] = if bytes[i] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(bytes).unwrap();
+        assert!(rejection(key.open(&tampered, &sealed.binding)).contains("tampered"));
+
+        let stranger = StateKey::generate().unwrap();
+        assert!(rejection(stranger.open(&sealed.state, &sealed.binding)).contains("tampered"));
+    }
+
+    #[test]
+    fn rejects_expired_states() {
+        let key = StateKey::generate().unwrap();
+        let sealed = key.seal(json!(null), 0).unwrap();
+        assert_eq!(
+            rejection(key.open(&sealed.state, &sealed.binding)),
+            "expired"
+        );
+    }
+}
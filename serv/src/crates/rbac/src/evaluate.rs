@@ -0,0 +1,457 @@
+use crate::expiry::GrantWindow;
+use crate::hierarchy::PermGraph;
+use crate::pattern::{perm_matches, scope_perm, specificity};
+use crate::verification::grants_need_verified_email;
+use db::get_uidb_pool;
+use napi_derive::napi;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Precedence when deciding a single check:
+//   1. Nothing matches: denied.
+//   2. No deny matches: allowed by the most specific grant.
+//   3. A deny matches: it beats every role grant. A direct grant on the user only wins
+//      when it is strictly more specific than the most specific matching deny, so
+//      `deny users:*` + `allow users:search` still lets the user search.
+// Denies follow the hierarchy like grants do: denying `users:manage` also denies
+// `users:edit`. Grants outside their window are ignored.
+
+#[napi(string_enum = "snake_case")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleSource {
+    Role,
+    Direct,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct PermRule {
+    /// The perm as stored, bound to its resource when scoped
+    pub perm: String,
+    pub source: RuleSource,
+    /// Role that granted it, for role rules
+    pub role: Option<String>,
+    /// Why the deny was put in place, for deny rules
+    pub reason: Option<String>,
+    /// When a timed grant applies, unset for grants that always do and denies
+    pub window: Option<GrantWindow>,
+}
+
+#[napi(object)]
+pub struct PermDecision {
+    pub allowed: bool,
+    pub perm: String,
+    pub resource: Option<String>,
+    /// The rule that settled it, unset when nothing matched
+    pub decided_by: Option<PermRule>,
+    /// Every rule that matched, most specific first
+    pub matched: Vec<PermRule>,
+}
+
+/// Every grant and deny of one user, as of one point in time
+#[derive(Debug, Clone)]
+pub struct PermRules {
+    rules: Vec<PermRule>,
+    /// Epoch seconds windows are checked against
+    at: f64,
+}
+
+impl Default for PermRules {
+    fn default() -> Self {
+        PermRules::new(now())
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+impl PermRules {
+    pub fn new(at: f64) -> Self {
+        PermRules {
+            rules: Vec::new(),
+            at,
+        }
+    }
+
+    pub fn push(&mut self, rule: PermRule) {
+        self.rules.push(rule);
+    }
+
+    /// The rules whose window contains `at`
+    fn active(&self) -> impl Iterator<Item = &PermRule> {
+        self.rules
+            .iter()
+            .filter(|r| r.window.is_none_or(|w| w.contains(self.at)))
+    }
+
+    /// How specifically `rule` covers `perm` (and its resource), if it does at all
+    fn coverage(
+        &self,
+        graph: &PermGraph,
+        rule: &PermRule,
+        required: &[String],
+    ) -> Option<(usize, bool)> {
+        graph
+            .expand([&rule.perm])
+            .iter()
+            .filter(|e| required.iter().any(|r| perm_matches(e, r)))
+            .map(|e| specificity(e))
+            .max()
+    }
+
+    pub fn decide(&self, graph: &PermGraph, perm: &str, resource: Option<&str>) -> PermDecision {
+        // A check on a resource is satisfied by the unscoped perm or the one bound to it
+        let mut required = vec![perm.to_string()];
+        if let Some(r) = resource.filter(|r| !r.is_empty()) {
+            required.push(scope_perm(perm, r));
+        }
+
+        let mut matched: Vec<((usize, bool), &PermRule)> = self
+            .active()
+            .filter_map(|rule| {
+                self.coverage(graph, rule, &required)
+                    .map(|spec| (spec, rule))
+            })
+            .collect();
+        // Most specific first, deny before direct before role on ties
+        let rank = |s: RuleSource| match s {
+            RuleSource::Deny => 0,
+            RuleSource::Direct => 1,
+            RuleSource::Role => 2,
+        };
+        matched.sort_by(|(a_spec, a), (b_spec, b)| {
+            b_spec.cmp(a_spec).then(rank(a.source).cmp(&rank(b.source)))
+        });
+
+        let deny = matched.iter().find(|(_, r)| r.source == RuleSource::Deny);
+        let decided_by = match deny {
+            None => matched.first(),
+            Some((deny_spec, _)) => matched
+                .iter()
+                .find(|(spec, r)| r.source == RuleSource::Direct && spec > deny_spec)
+                .or(deny),
+        };
+
+        PermDecision {
+            allowed: decided_by.is_some_and(|(_, r)| r.source != RuleSource::Deny),
+            perm: perm.to_string(),
+            resource: resource.map(str::to_string),
+            decided_by: decided_by.map(|(_, r)| (*r).clone()),
+            matched: matched.into_iter().map(|(_, r)| r.clone()).collect(),
+        }
+    }
+
+    /// Every perm the user's grants expand to that a deny does not take away
+    pub fn effective(&self, graph: &PermGraph) -> Vec<String> {
+        let granted: BTreeSet<String> = self
+            .active()
+            .filter(|r| r.source != RuleSource::Deny)
+            .flat_map(|r| graph.expand([&r.perm]))
+            .collect();
+        granted
+            .into_iter()
+            .filter(|p| self.decide(graph, p, None).allowed)
+            .collect()
+    }
+}
+
+/// Binds a scoped grant to its resource in SQL, matching `scope_perm`
+macro_rules! bound_perm {
+    ($alias:literal) => {
+        concat!(
+            "CASE
+                WHEN ",
+            $alias,
+            ".resource = '' THEN p.perm
+                WHEN strpos(p.perm, '*') > 0 THEN regexp_replace(p.perm, '\\*', ",
+            $alias,
+            ".resource)
+                ELSE p.perm || ':' || ",
+            $alias,
+            ".resource
+            END"
+        )
+    };
+}
+pub(crate) use bound_perm;
+
+/// Drops a grant row of a user without a verified email while `$2`, a bool parameter, is set
+macro_rules! email_verified {
+    ($alias:literal) => {
+        concat!(
+            "(NOT $2 OR EXISTS (SELECT 1 FROM public.Users vu WHERE vu.uid = ",
+            $alias,
+            ".user_uid AND vu.email_verified_at IS NOT NULL))"
+        )
+    };
+}
+
+/// Restricts a `User_Roles` or `User_Perms` row to grants inside their validity window
+#[macro_export]
+macro_rules! grant_active {
+    ($alias:literal) => {
+        concat!(
+            "(",
+            $alias,
+            ".valid_from IS NULL OR ",
+            $alias,
+            ".valid_from <= now())
+             AND (",
+            $alias,
+            ".valid_until IS NULL OR ",
+            $alias,
+            ".valid_until > now())"
+        )
+    };
+}
+
+pub async fn load_rules(uids: &[String]) -> napi::Result<HashMap<String, PermRules>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(concat!(
+            "SELECT ur.user_uid::text as uid, p.perm::text as perm, 'role' as source,
+                r.role_name::text as role, NULL::text as reason,
+                date_part('epoch', ur.valid_from) as valid_from,
+                date_part('epoch', ur.valid_until) as valid_until
+             FROM public.User_Roles ur
+             JOIN public.Roles r ON r.role_id = ur.role_id
+             JOIN public.Role_Perms rp ON rp.role_id = ur.role_id
+             JOIN public.Perms p ON p.perm_id = rp.perm_id
+             WHERE ur.user_uid::text = ANY($1) AND ",
+            email_verified!("ur"),
+            "
+             UNION ALL
+             SELECT up.user_uid::text, ",
+            bound_perm!("up"),
+            ", 'direct', NULL, NULL,
+                date_part('epoch', up.valid_from), date_part('epoch', up.valid_until)
+             FROM public.User_Perms up
+             JOIN public.Perms p ON p.perm_id = up.perm_id
+             WHERE up.user_uid::text = ANY($1) AND ",
+            email_verified!("up"),
+            "
+             UNION ALL
+             SELECT d.user_uid::text, ",
+            bound_perm!("d"),
+            ", 'deny', NULL, d.reason, NULL, NULL
+             FROM public.User_Perm_Denies d
+             JOIN public.Perms p ON p.perm_id = d.perm_id
+             WHERE d.user_uid::text = ANY($1)"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let rows = client
+        .query(&stmt, &[&uids, &grants_need_verified_email()])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    // Windows are checked by `PermRules`, against one clock reading for all users
+    let at = now();
+    let mut by_user: HashMap<String, PermRules> = uids
+        .iter()
+        .map(|uid| (uid.clone(), PermRules::new(at)))
+        .collect();
+    for row in rows {
+        let source = match row.get::<_, &str>("source") {
+            "role" => RuleSource::Role,
+            "direct" => RuleSource::Direct,
+            _ => RuleSource::Deny,
+        };
+        let window = GrantWindow {
+            valid_from: row.get("valid_from"),
+            valid_until: row.get("valid_until"),
+        };
+        by_user
+            .entry(row.get("uid"))
+            .or_insert_with(|| PermRules::new(at))
+            .push(PermRule {
+                perm: row.get("perm"),
+                source,
+                role: row.get("role"),
+                reason: row.get("reason"),
+                window: (window.valid_from.is_some() || window.valid_until.is_some())
+                    .then_some(window),
+            });
+    }
+    Ok(by_user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: f64 = 1_700_000_000.0;
+
+    fn graph() -> PermGraph {
+        PermGraph::from_edges(
+            [("users:manage".to_string(), "users:edit".to_string())],
+            ["users:manage", "users:edit", "users:search"].map(String::from),
+        )
+    }
+
+    fn rule(perm: &str, source: RuleSource) -> PermRule {
+        PermRule {
+            perm: perm.to_string(),
+            source,
+            role: (source == RuleSource::Role).then(|| "editor".to_string()),
+            reason: (source == RuleSource::Deny).then(|| "abuse".to_string()),
+            window: None,
+        }
+    }
+
+    fn rules(list: impl IntoIterator<Item = PermRule>) -> PermRules {
+        let mut rules = PermRules::new(NOW);
+        for r in list {
+            rules.push(r);
+        }
+        rules
+    }
+
+    fn timed(mut rule: PermRule, valid_from: Option<f64>, valid_until: Option<f64>) -> PermRule {
+        rule.window = Some(GrantWindow {
+            valid_from,
+            valid_until,
+        });
+        rule
+    }
+
+    #[test]
+    fn nothing_matching_is_denied() {
+        let decision = rules([]).decide(&graph(), "users:edit", None);
+        assert!(!decision.allowed);
+        assert!(decision.decided_by.is_none());
+        assert!(decision.matched.is_empty());
+    }
+
+    #[test]
+    fn deny_beats_role_and_equal_direct_grants() {
+        let rules = rules([
+            rule("users:edit", RuleSource::Role),
+            rule("users:edit", RuleSource::Direct),
+            rule("users:edit", RuleSource::Deny),
+        ]);
+        let decision = rules.decide(&graph(), "users:edit", None);
+        assert!(!decision.allowed);
+        let decided_by = decision.decided_by.unwrap();
+        assert_eq!(decided_by.source, RuleSource::Deny);
+        assert_eq!(decided_by.reason.as_deref(), Some("abuse"));
+        let sources: Vec<RuleSource> = decision.matched.iter().map(|r| r.source).collect();
+        assert_eq!(
+            sources,
+            [RuleSource::Deny, RuleSource::Direct, RuleSource::Role]
+        );
+    }
+
+    #[test]
+    fn deny_follows_the_hierarchy() {
+        let rules = rules([
+            rule("users:edit", RuleSource::Role),
+            rule("users:manage", RuleSource::Deny),
+        ]);
+        let decision = rules.decide(&graph(), "users:edit", None);
+        assert!(!decision.allowed);
+        assert_eq!(decision.decided_by.unwrap().perm, "users:manage");
+    }
+
+    #[test]
+    fn more_specific_direct_grant_beats_a_wildcard_deny() {
+        let rules = rules([
+            rule("users:*", RuleSource::Deny),
+            rule("users:search", RuleSource::Direct),
+            rule("users:edit", RuleSource::Role),
+        ]);
+        let graph = graph();
+        let search = rules.decide(&graph, "users:search", None);
+        assert!(search.allowed);
+        assert_eq!(search.decided_by.unwrap().source, RuleSource::Direct);
+        // A role grant never outranks a deny, however specific
+        assert!(!rules.decide(&graph, "users:edit", None).allowed);
+    }
+
+    #[test]
+    fn ties_go_to_the_deny() {
+        let rules = rules([
+            rule("users:*", RuleSource::Direct),
+            rule("users:*", RuleSource::Deny),
+        ]);
+        let decision = rules.decide(&graph(), "users:search", None);
+        assert!(!decision.allowed);
+        assert_eq!(decision.decided_by.unwrap().source, RuleSource::Deny);
+    }
+
+    #[test]
+    fn resource_checks_take_scoped_and_unscoped_grants() {
+        let rules = rules([
+            rule("grids:edit:42", RuleSource::Direct),
+            rule("grids:edit:7", RuleSource::Deny),
+        ]);
+        let graph = graph();
+        assert!(rules.decide(&graph, "grids:edit", Some("42")).allowed);
+        assert!(!rules.decide(&graph, "grids:edit", Some("7")).allowed);
+        assert!(!rules.decide(&graph, "grids:edit", None).allowed);
+    }
+
+    #[test]
+    fn grants_only_count_inside_their_window() {
+        let graph = graph();
+        let allowed = |rule: PermRule| rules([rule]).decide(&graph, "users:edit", None).allowed;
+        let grant = || rule("users:edit", RuleSource::Direct);
+
+        assert!(!allowed(timed(grant(), None, Some(NOW - 1.0))));
+        assert!(!allowed(timed(grant(), Some(NOW + 1.0), None)));
+        assert!(allowed(timed(grant(), Some(NOW - 1.0), Some(NOW + 1.0))));
+        // Starts inclusive, ends exclusive, like grant_active!
+        assert!(allowed(timed(grant(), Some(NOW), None)));
+        assert!(!allowed(timed(grant(), None, Some(NOW))));
+
+        // An expired grant no longer outranks a deny either
+        let rules = rules([
+            rule("users:*", RuleSource::Deny),
+            timed(
+                rule("users:edit", RuleSource::Direct),
+                None,
+                Some(NOW - 1.0),
+            ),
+        ]);
+        let decision = rules.decide(&graph, "users:edit", None);
+        assert!(!decision.allowed);
+        assert_eq!(decision.matched.len(), 1);
+    }
+
+    #[test]
+    fn explains_the_deciding_rule() {
+        let rules = rules([
+            rule("users:manage", RuleSource::Role),
+            rule("users:edit", RuleSource::Direct),
+        ]);
+        let decision = rules.decide(&graph(), "users:edit", Some("9"));
+        assert!(decision.allowed);
+        assert_eq!(decision.perm, "users:edit");
+        assert_eq!(decision.resource.as_deref(), Some("9"));
+        let decided_by = decision.decided_by.unwrap();
+        assert_eq!(decided_by.perm, "users:edit");
+        assert_eq!(decided_by.source, RuleSource::Direct);
+        assert_eq!(decision.matched.len(), 2);
+        assert_eq!(decision.matched[1].role.as_deref(), Some("editor"));
+    }
+
+    #[test]
+    fn effective_perms_leave_out_denied_and_expired_ones() {
+        let rules = rules([
+            rule("users:manage", RuleSource::Role),
+            rule("users:edit", RuleSource::Deny),
+            timed(rule("users:search", RuleSource::Direct), None, Some(NOW)),
+        ]);
+        assert_eq!(rules.effective(&graph()), ["users:manage"]);
+    }
+}
@@ -0,0 +1,143 @@
+use db::get_uidb_pool;
+use napi_derive::napi;
+use shared_types::Row;
+
+/// When a user role or perm grant applies, as epoch seconds. Unset bounds are open.
+#[derive(Debug, Clone, Copy, Default)]
+#[napi(object)]
+pub struct GrantWindow {
+    pub valid_from: Option<f64>,
+    pub valid_until: Option<f64>,
+}
+
+impl GrantWindow {
+    pub fn validate(&self) -> napi::Result<()> {
+        if let (Some(from), Some(until)) = (self.valid_from, self.valid_until)
+            && until <= from
+        {
+            return Err(napi::Error::from_reason("Grant must end after it starts"));
+        }
+        Ok(())
+    }
+
+    /// Whether the grant applies at `at`, like `grant_active!` does in SQL
+    pub fn contains(&self, at: f64) -> bool {
+        self.valid_from.is_none_or(|from| from <= at)
+            && self.valid_until.is_none_or(|until| until > at)
+    }
+}
+
+#[napi(string_enum = "snake_case")]
+pub enum GrantKind {
+    Role,
+    Perm,
+}
+
+/// A grant removed by `purge_expired_grants`
+#[napi(object)]
+pub struct ExpiredGrant {
+    pub expired_id: i64,
+    pub uid: String,
+    pub kind: GrantKind,
+    /// Role name or perm
+    pub name: String,
+    /// Resource the perm was scoped to, empty for roles and unscoped perms
+    pub resource: String,
+    pub valid_from: Option<f64>,
+    pub valid_until: f64,
+    pub removed_at: f64,
+}
+
+fn expired_from_row(row: Row) -> ExpiredGrant {
+    ExpiredGrant {
+        expired_id: row.get("expired_id"),
+        uid: row.get("uid"),
+        kind: match row.get::<_, &str>("kind") {
+            "role" => GrantKind::Role,
+            _ => GrantKind::Perm,
+        },
+        name: row.get("name"),
+        resource: row.get("resource"),
+        valid_from: row.get("valid_from"),
+        valid_until: row.get("valid_until"),
+        removed_at: row.get("removed_at"),
+    }
+}
+
+const EXPIRED_COLUMNS: &str = "
+    expired_id,
+    user_uid::text as uid,
+    kind::text as kind,
+    name::text as name,
+    resource::text as resource,
+    date_part('epoch', valid_from) as valid_from,
+    date_part('epoch', valid_until) as valid_until,
+    date_part('epoch', removed_at) as removed_at";
+
+/// Deletes every user role and perm grant whose window has closed, recording each one
+/// in `Expired_Grants`. Already inactive grants are ignored by every check regardless,
+/// this only keeps the tables small and leaves a trail of who had what.
+pub async fn purge_expired_grants() -> napi::Result<Vec<ExpiredGrant>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "WITH removed_roles AS (
+                DELETE FROM public.User_Roles ur
+                USING public.Roles r
+                WHERE r.role_id = ur.role_id AND ur.valid_until <= now()
+                RETURNING ur.user_uid, r.role_name, ur.valid_from, ur.valid_until
+            ), removed_perms AS (
+                DELETE FROM public.User_Perms up
+                USING public.Perms p
+                WHERE p.perm_id = up.perm_id AND up.valid_until <= now()
+                RETURNING up.user_uid, p.perm, up.resource, up.valid_from, up.valid_until
+            )
+            INSERT INTO public.Expired_Grants (user_uid, kind, name, resource, valid_from, valid_until)
+            SELECT user_uid, 'role', role_name, '', valid_from, valid_until FROM removed_roles
+            UNION ALL
+            SELECT user_uid, 'perm', perm, resource, valid_from, valid_until FROM removed_perms
+            RETURNING {EXPIRED_COLUMNS}"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let rows = client
+        .query(&stmt, &[])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Purge failed: {e}")))?;
+
+    Ok(rows.into_iter().map(expired_from_row).collect())
+}
+
+/// Grants removed so far, newest first, optionally for one user
+pub async fn list_expired_grants(
+    uid: Option<String>,
+    limit: Option<u32>,
+) -> napi::Result<Vec<ExpiredGrant>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT {EXPIRED_COLUMNS} FROM public.Expired_Grants
+             WHERE $1::text IS NULL OR user_uid::text = $1
+             ORDER BY removed_at DESC, expired_id DESC
+             LIMIT $2"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let limit = i64::from(limit.unwrap_or(100));
+    let rows = client
+        .query(&stmt, &[&uid, &limit])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(rows.into_iter().map(expired_from_row).collect())
+}
@@ -0,0 +1,196 @@
+use crate::pattern::{generalizations, perm_matches};
+use db::get_uidb_pool;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+/// Perm implication edges from `Perm_Implies`, e.g. `users:manage -> users:edit`
+#[derive(Debug, Default, Clone)]
+pub struct PermGraph {
+    implies: HashMap<String, Vec<String>>,
+    /// Every perm defined in `Perms`, used to find wildcard grantors
+    known: Vec<String>,
+}
+
+impl PermGraph {
+    pub fn from_edges(
+        edges: impl IntoIterator<Item = (String, String)>,
+        known: impl IntoIterator<Item = String>,
+    ) -> Self {
+        let mut implies: HashMap<String, Vec<String>> = HashMap::new();
+        for (perm, implied) in edges {
+            implies.entry(perm).or_default().push(implied);
+        }
+        PermGraph {
+            implies,
+            known: known.into_iter().collect(),
+        }
+    }
+
+    /// Everything the given perms grant, walked transitively. Cycles are harmless.
+    /// A wildcard grant also picks up whatever the perms it matches imply.
+    pub fn expand<I, S>(&self, perms: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut seen: BTreeSet<String> = BTreeSet::new();
+        let mut queue: VecDeque<String> =
+            perms.into_iter().map(|p| p.as_ref().to_string()).collect();
+        while let Some(perm) = queue.pop_front() {
+            if !seen.insert(perm.clone()) {
+                continue;
+            }
+            for (parent, children) in &self.implies {
+                if *parent == perm || perm_matches(&perm, parent) {
+                    queue.extend(children.iter().filter(|c| !seen.contains(*c)).cloned());
+                }
+            }
+        }
+        seen.into_iter().collect()
+    }
+
+    /// Whether holding `granted` is enough for `required`
+    pub fn grants<I, S>(&self, granted: I, required: &str) -> bool
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.expand(granted)
+            .iter()
+            .any(|g| perm_matches(g, required))
+    }
+
+    /// Every perm string a user could hold that grants `required`, including itself
+    pub fn grantors(&self, required: &str) -> Vec<String> {
+        let mut candidates: BTreeSet<String> = self.known.iter().cloned().collect();
+        candidates.extend(generalizations(required));
+        candidates
+            .into_iter()
+            .filter(|c| self.grants([c], required))
+            .collect()
+    }
+}
+
+pub async fn load_perm_graph() -> napi::Result<PermGraph> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let edges_stmt = client
+        .prepare_cached(
+            "SELECT p.perm, c.perm as implied
+             FROM public.Perm_Implies pi
+             JOIN public.Perms p ON p.perm_id = pi.perm_id
+             JOIN public.Perms c ON c.perm_id = pi.implied_perm_id",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let edges = client
+        .query(&edges_stmt, &[])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let known_stmt = client
+        .prepare_cached("SELECT perm FROM public.Perms")
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let known = client
+        .query(&known_stmt, &[])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(PermGraph::from_edges(
+        edges.into_iter().map(|r| (r.get("perm"), r.get("implied"))),
+        known.into_iter().map(|r| r.get("perm")),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph_of(edges: &[(&str, &str)]) -> PermGraph {
+        let known: BTreeSet<String> = edges
+            .iter()
+            .flat_map(|(a, b)| [a.to_string(), b.to_string()])
+            .collect();
+        PermGraph::from_edges(
+            edges.iter().map(|(a, b)| (a.to_string(), b.to_string())),
+            known,
+        )
+    }
+
+    #[test]
+    fn follows_chains_of_implications() {
+        let graph = graph_of(&[
+            ("docs:admin", "docs:publish"),
+            ("docs:publish", "docs:edit"),
+            ("docs:edit", "docs:read"),
+        ]);
+        assert_eq!(
+            graph.expand(["docs:publish"]),
+            ["docs:edit", "docs:publish", "docs:read"]
+        );
+        assert!(graph.grants(["docs:admin"], "docs:read"));
+        assert!(!graph.grants(["docs:edit"], "docs:publish"));
+        assert_eq!(
+            graph.grantors("docs:edit"),
+            [
+                "*",
+                "*:*",
+                "*:edit",
+                "docs:*",
+                "docs:admin",
+                "docs:edit",
+                "docs:publish"
+            ]
+        );
+    }
+
+    #[test]
+    fn cycles_end_and_list_each_perm_once() {
+        let graph = graph_of(&[
+            ("a:one", "a:two"),
+            ("a:two", "a:three"),
+            ("a:three", "a:one"),
+            ("a:three", "a:four"),
+        ]);
+        let all = ["a:four", "a:one", "a:three", "a:two"];
+        for start in ["a:one", "a:two", "a:three"] {
+            assert_eq!(graph.expand([start]), all);
+        }
+        assert_eq!(graph.expand(["a:four"]), ["a:four"]);
+        assert_eq!(graph.expand(["a:one", "a:two", "a:one"]), all);
+
+        let self_loop = graph_of(&[("b:one", "b:one")]);
+        assert_eq!(self_loop.expand(["b:one"]), ["b:one"]);
+    }
+
+    #[test]
+    fn diamonds_reach_the_bottom_once() {
+        let graph = graph_of(&[
+            ("grids:admin", "grids:edit"),
+            ("grids:admin", "grids:share"),
+            ("grids:edit", "grids:view"),
+            ("grids:share", "grids:view"),
+        ]);
+        assert_eq!(
+            graph.expand(["grids:admin"]),
+            ["grids:admin", "grids:edit", "grids:share", "grids:view"]
+        );
+        let grantors = graph.grantors("grids:view");
+        for perm in ["grids:admin", "grids:edit", "grids:share", "grids:view"] {
+            assert_eq!(grantors.iter().filter(|g| *g == perm).count(), 1, "{perm}");
+        }
+    }
+
+    #[test]
+    fn wildcards_pick_up_what_their_matches_imply() {
+        let graph = graph_of(&[("users:manage", "roles:view")]);
+        assert!(graph.grants(["users:*"], "roles:view"));
+        assert!(graph.grants(["*"], "roles:view"));
+        assert!(!graph.grants(["roles:*"], "users:manage"));
+    }
+}
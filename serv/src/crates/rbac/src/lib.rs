@@ -0,0 +1,1050 @@
+mod evaluate;
+mod expiry;
+mod hierarchy;
+mod pattern;
+mod verification;
+
+pub use evaluate::{PermDecision, PermRule, PermRules, RuleSource, load_rules};
+pub use expiry::{ExpiredGrant, GrantKind, GrantWindow, list_expired_grants, purge_expired_grants};
+pub use hierarchy::{PermGraph, load_perm_graph};
+pub use pattern::{
+    MAX_PERM_LEN, MAX_PERM_SEGMENTS, generalizations, is_valid_pattern, perm_matches, scope_perm,
+    specificity,
+};
+pub use verification::{
+    UnverifiedEmailPolicy, configure_unverified_email_policy, unverified_email_policy,
+};
+
+use audit::{AuditAction, AuditContext};
+use db::get_uidb_pool;
+use napi_derive::napi;
+use serde_json::{Value, json};
+use shared_types::Row;
+use std::collections::HashMap;
+
+use evaluate::bound_perm;
+
+/// Perms a user currently holds directly or through roles, before the hierarchy is applied.
+/// Direct grants scoped to a resource come back bound, following `scope_perm`.
+/// Expects the user row aliased as `u`.
+const GRANTED_PERMS: &str = concat!(
+    "ARRAY(
+    SELECT p.perm
+    FROM public.Perms p
+    JOIN public.Role_Perms rp ON rp.perm_id = p.perm_id
+    JOIN public.User_Roles ur ON ur.role_id = rp.role_id
+    WHERE ur.user_uid = u.uid AND ",
+    grant_active!("ur"),
+    "
+    UNION
+    SELECT ",
+    bound_perm!("up"),
+    "
+    FROM public.Perms p
+    JOIN public.User_Perms up ON up.perm_id = p.perm_id
+    WHERE up.user_uid = u.uid AND ",
+    grant_active!("up"),
+    "
+)"
+);
+
+/// Perms a system role can't lose, or nobody would be left to manage roles and users
+const REQUIRED_ROLE_PERMS: [(&str, &str); 1] = [("admin", "admin:access")];
+
+/// Fails if `role` is a system role and `kept` drops a perm it must keep
+fn check_required_perms(role: &Role, kept: impl Fn(&str) -> bool) -> napi::Result<()> {
+    if !role.is_system {
+        return Ok(());
+    }
+    match REQUIRED_ROLE_PERMS
+        .iter()
+        .find(|(name, perm)| *name == role.role_name && !kept(perm))
+    {
+        Some((_, perm)) => Err(napi::Error::from_reason(format!(
+            "Role {} is a system role and must keep {perm}",
+            role.role_name
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn is_valid_resource(resource: &str) -> bool {
+    !resource.is_empty()
+        && resource.len() <= 64
+        && resource
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[napi(object)]
+pub struct Role {
+    pub role_id: i32,
+    pub role_name: String,
+    /// Seeded by db/init.sh, cannot be renamed or deleted
+    pub is_system: bool,
+    pub perms: Vec<String>,
+}
+
+#[napi(object)]
+pub struct Perm {
+    pub perm_id: i32,
+    pub perm: String,
+    pub description: Option<String>,
+    /// Seeded by db/init.sh, cannot be renamed or deleted
+    pub is_system: bool,
+    /// Perms this one directly implies
+    pub implies: Vec<String>,
+}
+
+pub fn role_from_row(row: Row) -> Role {
+    Role {
+        role_id: row.get("role_id"),
+        role_name: row.get("role_name"),
+        is_system: row.get("is_system"),
+        perms: row.get("perms"),
+    }
+}
+
+pub fn perm_from_row(row: Row) -> Perm {
+    Perm {
+        perm_id: row.get("perm_id"),
+        perm: row.get("perm"),
+        description: row.get("description"),
+        is_system: row.get("is_system"),
+        implies: row.get("implies"),
+    }
+}
+
+const ROLE_COLUMNS: &str = "
+    r.role_id,
+    r.role_name,
+    r.is_system,
+    ARRAY(
+        SELECT p.perm
+        FROM public.Perms p
+        JOIN public.Role_Perms rp ON rp.perm_id = p.perm_id
+        WHERE rp.role_id = r.role_id
+        ORDER BY p.perm
+    ) as perms";
+
+const PERM_COLUMNS: &str = "
+    p.perm_id,
+    p.perm,
+    p.description,
+    p.is_system,
+    ARRAY(
+        SELECT c.perm
+        FROM public.Perms c
+        JOIN public.Perm_Implies pi ON pi.implied_perm_id = c.perm_id
+        WHERE pi.perm_id = p.perm_id
+        ORDER BY c.perm
+    ) as implies";
+
+pub async fn list_roles() -> napi::Result<Vec<Role>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT {ROLE_COLUMNS} FROM public.Roles r ORDER BY r.role_name"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let rows = client
+        .query(&stmt, &[])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    Ok(rows.into_iter().map(role_from_row).collect())
+}
+
+pub async fn get_role(role_name: impl AsRef<str>) -> napi::Result<Role> {
+    let role_name = role_name.as_ref();
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT {ROLE_COLUMNS} FROM public.Roles r WHERE r.role_name = $1"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .query_opt(&stmt, &[&role_name])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .map(role_from_row)
+        .ok_or(napi::Error::from_reason("Role not found"))
+}
+
+pub async fn create_role(role_name: String, perms: Option<Vec<String>>) -> napi::Result<Role> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let role_id: i32 = tx
+        .query_one(
+            "INSERT INTO public.Roles (role_name) VALUES ($1) RETURNING role_id",
+            &[&role_name],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Insert failed: {e}")))?
+        .get(0);
+
+    let mut perms = perms.unwrap_or_default();
+    perms.sort();
+    perms.dedup();
+    for perm in perms {
+        let granted = tx
+            .execute(
+                "INSERT INTO public.Role_Perms (role_id, perm_id)
+                 SELECT $1, perm_id FROM public.Perms WHERE perm = $2",
+                &[&role_id, &perm],
+            )
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        if granted == 0 {
+            return Err(napi::Error::from_reason(format!(
+                "Permission not found: {perm}"
+            )));
+        }
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    get_role(&role_name).await
+}
+
+pub async fn rename_role(role_name: String, new_name: String) -> napi::Result<Role> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "UPDATE public.Roles SET role_name = $2
+             WHERE role_name = $1 AND NOT is_system",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let updated = client
+        .execute(&stmt, &[&role_name, &new_name])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Rename failed: {e}")))?;
+
+    if updated == 0 {
+        // Tell a missing role apart from a protected one
+        let role = get_role(&role_name).await?;
+        if role.is_system {
+            return Err(napi::Error::from_reason(format!(
+                "Role {role_name} is a system role and cannot be renamed"
+            )));
+        }
+    }
+
+    get_role(&new_name).await
+}
+
+pub async fn delete_role(role_name: String) -> napi::Result<Role> {
+    let role = get_role(&role_name).await?;
+    if role.is_system {
+        return Err(napi::Error::from_reason(format!(
+            "Role {role_name} is a system role and cannot be deleted"
+        )));
+    }
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    // Role_Perms and User_Roles rows go with it through ON DELETE CASCADE
+    client
+        .execute(
+            "DELETE FROM public.Roles WHERE role_id = $1 AND NOT is_system",
+            &[&role.role_id],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Delete failed: {e}")))?;
+
+    Ok(role)
+}
+
+pub async fn list_perms() -> napi::Result<Vec<Perm>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT {PERM_COLUMNS} FROM public.Perms p ORDER BY p.perm"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let rows = client
+        .query(&stmt, &[])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    Ok(rows.into_iter().map(perm_from_row).collect())
+}
+
+pub async fn get_perm(perm: impl AsRef<str>) -> napi::Result<Perm> {
+    let perm = perm.as_ref();
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT {PERM_COLUMNS} FROM public.Perms p WHERE p.perm = $1"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .query_opt(&stmt, &[&perm])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .map(perm_from_row)
+        .ok_or(napi::Error::from_reason("Permission not found"))
+}
+
+pub async fn create_perm(perm: String, description: Option<String>) -> napi::Result<Perm> {
+    if !is_valid_pattern(&perm) {
+        return Err(napi::Error::from_reason(format!(
+            "Invalid permission pattern: {perm}"
+        )));
+    }
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached("INSERT INTO public.Perms (perm, description) VALUES ($1, $2)")
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(&stmt, &[&perm, &description])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Insert failed: {e}")))?;
+
+    get_perm(&perm).await
+}
+
+pub async fn update_perm(
+    perm: String,
+    new_name: Option<String>,
+    description: Option<String>,
+) -> napi::Result<Perm> {
+    if new_name.as_ref().is_some_and(|n| !is_valid_pattern(n)) {
+        return Err(napi::Error::from_reason("Invalid permission pattern"));
+    }
+
+    let existing = get_perm(&perm).await?;
+    if existing.is_system && new_name.as_ref().is_some_and(|n| *n != perm) {
+        return Err(napi::Error::from_reason(format!(
+            "Permission {perm} is a system permission and cannot be renamed"
+        )));
+    }
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "UPDATE public.Perms
+             SET perm = COALESCE($2, perm), description = COALESCE($3, description)
+             WHERE perm_id = $1",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(&stmt, &[&existing.perm_id, &new_name, &description])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Update failed: {e}")))?;
+
+    get_perm(new_name.as_deref().unwrap_or(&perm)).await
+}
+
+pub async fn delete_perm(perm: String) -> napi::Result<Perm> {
+    let existing = get_perm(&perm).await?;
+    if existing.is_system {
+        return Err(napi::Error::from_reason(format!(
+            "Permission {perm} is a system permission and cannot be deleted"
+        )));
+    }
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    // Role_Perms and User_Perms rows go with it through ON DELETE CASCADE
+    client
+        .execute(
+            "DELETE FROM public.Perms WHERE perm_id = $1 AND NOT is_system",
+            &[&existing.perm_id],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Delete failed: {e}")))?;
+
+    Ok(existing)
+}
+
+pub async fn grant_role_perm(role_name: String, perm: String) -> napi::Result<Role> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "INSERT INTO public.Role_Perms (role_id, perm_id)
+             SELECT r.role_id, p.perm_id
+             FROM public.Roles r, public.Perms p
+             WHERE r.role_name = $1 AND p.perm = $2
+             ON CONFLICT DO NOTHING",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(&stmt, &[&role_name, &perm])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Grant failed: {e}")))?;
+
+    let role = get_role(&role_name).await?;
+    if !role.perms.contains(&perm) {
+        return Err(napi::Error::from_reason(format!(
+            "Permission not found: {perm}"
+        )));
+    }
+    Ok(role)
+}
+
+pub async fn revoke_role_perm(role_name: String, perm: String) -> napi::Result<Role> {
+    let role = get_role(&role_name).await?;
+    check_required_perms(&role, |p| p != perm)?;
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "DELETE FROM public.Role_Perms rp
+             USING public.Roles r, public.Perms p
+             WHERE rp.role_id = r.role_id AND rp.perm_id = p.perm_id
+             AND r.role_name = $1 AND p.perm = $2",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(&stmt, &[&role_name, &perm])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Revoke failed: {e}")))?;
+
+    get_role(&role_name).await
+}
+
+/// Replaces every perm a role grants with `perms`
+pub async fn set_role_perms(role_name: String, mut perms: Vec<String>) -> napi::Result<Role> {
+    perms.sort();
+    perms.dedup();
+    let role = get_role(&role_name).await?;
+    check_required_perms(&role, |p| perms.iter().any(|kept| kept == p))?;
+
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    tx.execute(
+        "DELETE FROM public.Role_Perms WHERE role_id = $1",
+        &[&role.role_id],
+    )
+    .await
+    .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    for perm in &perms {
+        let granted = tx
+            .execute(
+                "INSERT INTO public.Role_Perms (role_id, perm_id)
+                 SELECT $1, perm_id FROM public.Perms WHERE perm = $2",
+                &[&role.role_id, perm],
+            )
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        if granted == 0 {
+            return Err(napi::Error::from_reason(format!(
+                "Permission not found: {perm}"
+            )));
+        }
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    get_role(&role_name).await
+}
+
+/// Makes holding `perm` also grant `implied` (and everything `implied` grants)
+pub async fn add_perm_implication(perm: String, implied: String) -> napi::Result<Perm> {
+    if perm == implied {
+        return Err(napi::Error::from_reason("A permission cannot imply itself"));
+    }
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "INSERT INTO public.Perm_Implies (perm_id, implied_perm_id)
+             SELECT p.perm_id, c.perm_id
+             FROM public.Perms p, public.Perms c
+             WHERE p.perm = $1 AND c.perm = $2
+             ON CONFLICT DO NOTHING",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(&stmt, &[&perm, &implied])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Insert failed: {e}")))?;
+
+    let updated = get_perm(&perm).await?;
+    if !updated.implies.contains(&implied) {
+        return Err(napi::Error::from_reason(format!(
+            "Permission not found: {implied}"
+        )));
+    }
+    Ok(updated)
+}
+
+pub async fn remove_perm_implication(perm: String, implied: String) -> napi::Result<Perm> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "DELETE FROM public.Perm_Implies pi
+             USING public.Perms p, public.Perms c
+             WHERE pi.perm_id = p.perm_id AND pi.implied_perm_id = c.perm_id
+             AND p.perm = $1 AND c.perm = $2",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(&stmt, &[&perm, &implied])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Delete failed: {e}")))?;
+
+    get_perm(&perm).await
+}
+
+/// Every perm a user ends up with once roles, direct grants, denies and the hierarchy
+/// are applied, for many users at once
+pub async fn effective_perms_for(uids: &[String]) -> napi::Result<HashMap<String, Vec<String>>> {
+    if uids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let graph = load_perm_graph().await?;
+    Ok(load_rules(uids)
+        .await?
+        .into_iter()
+        .map(|(uid, rules)| {
+            let perms = rules.effective(&graph);
+            (uid, perms)
+        })
+        .collect())
+}
+
+/// Users `has_perm` allows `perm`, each decided the same way it would be. Only users
+/// whose grants could cover it are loaded.
+pub async fn users_with_perm(perm: &str) -> napi::Result<Vec<String>> {
+    let graph = load_perm_graph().await?;
+    let grantors = graph.grantors(perm);
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT u.uid::text as uid FROM public.Users u WHERE {GRANTED_PERMS} && $1"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    let candidates: Vec<String> = client
+        .query(&stmt, &[&grantors])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .iter()
+        .map(|row| row.get("uid"))
+        .collect();
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(load_rules(&candidates)
+        .await?
+        .into_iter()
+        .filter(|(_, rules)| rules.decide(&graph, perm, None).allowed)
+        .map(|(uid, _)| uid)
+        .collect())
+}
+
+pub async fn effective_perms(uid: impl AsRef<str>) -> napi::Result<Vec<String>> {
+    let uid = uid.as_ref().to_string();
+    Ok(effective_perms_for(std::slice::from_ref(&uid))
+        .await?
+        .remove(&uid)
+        .unwrap_or_default())
+}
+
+/// Decides a check and reports which role, grant or deny settled it
+pub async fn explain_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+) -> napi::Result<PermDecision> {
+    let graph = load_perm_graph().await?;
+    let rules = load_rules(std::slice::from_ref(&uid))
+        .await?
+        .remove(&uid)
+        .unwrap_or_default();
+    Ok(rules.decide(&graph, &perm, resource.as_deref()))
+}
+
+/// Checks a perm such as `users:edit` or `grids:edit:42`, optionally against one resource,
+/// with wildcards, the hierarchy and denies all applied
+pub async fn has_perm(
+    uid: impl AsRef<str>,
+    perm: impl AsRef<str>,
+    resource: Option<String>,
+) -> napi::Result<bool> {
+    Ok(explain_perm(
+        uid.as_ref().to_string(),
+        perm.as_ref().to_string(),
+        resource,
+    )
+    .await?
+    .allowed)
+}
+
+/// Stops a user from using a perm, whatever their roles grant, optionally for one resource
+pub async fn deny_user_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    reason: Option<String>,
+    audit: AuditContext,
+) -> napi::Result<PermDecision> {
+    let resource = resource.unwrap_or_default();
+    if !resource.is_empty() && !is_valid_resource(&resource) {
+        return Err(napi::Error::from_reason(format!(
+            "Invalid resource id: {resource}"
+        )));
+    }
+
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "INSERT INTO public.User_Perm_Denies (user_uid, perm_id, resource, reason)
+             SELECT CAST($1 AS TEXT)::uuid, perm_id, $3, $4 FROM public.Perms WHERE perm = $2
+             ON CONFLICT (user_uid, perm_id, resource) DO UPDATE SET reason = EXCLUDED.reason",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let inserted = tx
+        .execute(&stmt, &[&uid, &perm, &resource, &reason])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Deny failed: {e}")))?;
+    if inserted == 0 {
+        return Err(napi::Error::from_reason(format!(
+            "Permission not found: {perm}"
+        )));
+    }
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::PermDeny,
+        Some(&uid),
+        audit::diff(
+            &Value::Null,
+            &json!({ "perm": perm, "resource": resource, "reason": reason }),
+        ),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    explain_perm(uid, perm, (!resource.is_empty()).then_some(resource)).await
+}
+
+pub async fn remove_user_deny(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    audit: AuditContext,
+) -> napi::Result<PermDecision> {
+    let resource = resource.unwrap_or_default();
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "DELETE FROM public.User_Perm_Denies d
+             USING public.Perms p
+             WHERE d.perm_id = p.perm_id
+             AND d.user_uid = CAST($1 AS TEXT)::uuid AND p.perm = $2 AND d.resource = $3",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let removed = tx
+        .execute(&stmt, &[&uid, &perm, &resource])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Delete failed: {e}")))?;
+    if removed > 0 {
+        audit::record(
+            &tx,
+            &audit,
+            AuditAction::DenyRemove,
+            Some(&uid),
+            audit::diff(&json!({ "perm": perm, "resource": resource }), &Value::Null),
+        )
+        .await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    explain_perm(uid, perm, (!resource.is_empty()).then_some(resource)).await
+}
+
+/// Roles a user holds right now, leaving out grants outside their validity window
+pub async fn active_roles(uid: impl AsRef<str>) -> napi::Result<Vec<String>> {
+    let uid = uid.as_ref();
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(concat!(
+            "SELECT r.role_name FROM public.Roles r
+             JOIN public.User_Roles ur ON ur.role_id = r.role_id
+             WHERE ur.user_uid = CAST($1 AS TEXT)::uuid AND ",
+            grant_active!("ur"),
+            "
+             ORDER BY r.role_name"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let rows = client
+        .query(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(rows.into_iter().map(|r| r.get("role_name")).collect())
+}
+
+/// Gives a user a role, for good or only within `window`. Assigning a role the user
+/// already has replaces its window.
+pub async fn assign_user_role(
+    uid: String,
+    role_name: String,
+    window: Option<GrantWindow>,
+    audit: AuditContext,
+) -> napi::Result<Vec<String>> {
+    let window = window.unwrap_or_default();
+    window.validate()?;
+    get_role(&role_name).await?;
+
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "INSERT INTO public.User_Roles (user_uid, role_id, valid_from, valid_until)
+             SELECT CAST($1 AS TEXT)::uuid, role_id, to_timestamp($3), to_timestamp($4)
+             FROM public.Roles WHERE role_name = $2
+             ON CONFLICT (user_uid, role_id)
+             DO UPDATE SET valid_from = EXCLUDED.valid_from, valid_until = EXCLUDED.valid_until",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    tx.execute(
+        &stmt,
+        &[&uid, &role_name, &window.valid_from, &window.valid_until],
+    )
+    .await
+    .map_err(|e| napi::Error::from_reason(format!("Assign failed: {e}")))?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::RoleAssign,
+        Some(&uid),
+        audit::diff(
+            &Value::Null,
+            &json!({
+                "role": role_name,
+                "valid_from": window.valid_from,
+                "valid_until": window.valid_until,
+            }),
+        ),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    active_roles(&uid).await
+}
+
+pub async fn remove_user_role(
+    uid: String,
+    role_name: String,
+    audit: AuditContext,
+) -> napi::Result<Vec<String>> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "DELETE FROM public.User_Roles ur
+             USING public.Roles r
+             WHERE ur.role_id = r.role_id
+             AND ur.user_uid = CAST($1 AS TEXT)::uuid AND r.role_name = $2",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let removed = tx
+        .execute(&stmt, &[&uid, &role_name])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Remove failed: {e}")))?;
+    if removed > 0 {
+        audit::record(
+            &tx,
+            &audit,
+            AuditAction::RoleRemove,
+            Some(&uid),
+            audit::diff(&json!({ "role": role_name }), &Value::Null),
+        )
+        .await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    active_roles(&uid).await
+}
+
+/// Grants a perm directly to a user, optionally scoped to a single resource and limited
+/// to `window`. Granting it again replaces the window.
+pub async fn grant_user_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    window: Option<GrantWindow>,
+    audit: AuditContext,
+) -> napi::Result<Vec<String>> {
+    let resource = resource.unwrap_or_default();
+    if !resource.is_empty() && !is_valid_resource(&resource) {
+        return Err(napi::Error::from_reason(format!(
+            "Invalid resource id: {resource}"
+        )));
+    }
+    let window = window.unwrap_or_default();
+    window.validate()?;
+
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "INSERT INTO public.User_Perms (user_uid, perm_id, resource, valid_from, valid_until)
+             SELECT CAST($1 AS TEXT)::uuid, perm_id, $3, to_timestamp($4), to_timestamp($5)
+             FROM public.Perms WHERE perm = $2
+             ON CONFLICT (user_uid, perm_id, resource)
+             DO UPDATE SET valid_from = EXCLUDED.valid_from, valid_until = EXCLUDED.valid_until",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let granted = tx
+        .execute(
+            &stmt,
+            &[
+                &uid,
+                &perm,
+                &resource,
+                &window.valid_from,
+                &window.valid_until,
+            ],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Grant failed: {e}")))?;
+
+    // The insert is a no-op for an unknown perm, and a deny may hide the grant from
+    // the effective set, so look the perm up directly
+    get_perm(&perm).await?;
+    if granted > 0 {
+        audit::record(
+            &tx,
+            &audit,
+            AuditAction::PermGrant,
+            Some(&uid),
+            audit::diff(
+                &Value::Null,
+                &json!({
+                    "perm": perm,
+                    "resource": resource,
+                    "valid_from": window.valid_from,
+                    "valid_until": window.valid_until,
+                }),
+            ),
+        )
+        .await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    effective_perms(&uid).await
+}
+
+pub async fn revoke_user_perm(
+    uid: String,
+    perm: String,
+    resource: Option<String>,
+    audit: AuditContext,
+) -> napi::Result<Vec<String>> {
+    let resource = resource.unwrap_or_default();
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "DELETE FROM public.User_Perms up
+             USING public.Perms p
+             WHERE up.perm_id = p.perm_id
+             AND up.user_uid = CAST($1 AS TEXT)::uuid AND p.perm = $2 AND up.resource = $3",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let revoked = tx
+        .execute(&stmt, &[&uid, &perm, &resource])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Revoke failed: {e}")))?;
+    if revoked > 0 {
+        audit::record(
+            &tx,
+            &audit,
+            AuditAction::PermRevoke,
+            Some(&uid),
+            audit::diff(&json!({ "perm": perm, "resource": resource }), &Value::Null),
+        )
+        .await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    effective_perms(&uid).await
+}
@@ -0,0 +1,205 @@
+// Permission patterns are `:` separated segments, e.g. `users:edit`, `grids:edit:42`.
+//
+// Matching rules, applied by segment:
+//   - a literal segment only matches the same literal
+//   - `*` matches exactly one segment (`class:*:manage` grants `class:7:manage`)
+//   - a trailing `*` matches one or more segments (`users:*` grants `users:edit` and
+//     `users:edit:42`, but not `users` on its own)
+//   - there is no implicit prefix match, `grids:edit` does not grant `grids:edit:42`
+//
+// When several grants match, the most specific one wins (see `specificity`).
+
+pub const WILDCARD: &str = "*";
+const SEPARATOR: char = ':';
+/// Bounds a perm so its `generalizations` stay few, there are 2^segments of them
+pub const MAX_PERM_SEGMENTS: usize = 6;
+pub const MAX_PERM_LEN: usize = 128;
+
+pub fn is_valid_pattern(perm: &str) -> bool {
+    !perm.is_empty()
+        && perm.len() <= MAX_PERM_LEN
+        && perm.split(SEPARATOR).count() <= MAX_PERM_SEGMENTS
+        && perm
+            .split(SEPARATOR)
+            .all(|seg| !seg.is_empty() && (seg == WILDCARD || !seg.contains('*')))
+}
+
+pub fn perm_matches(granted: &str, required: &str) -> bool {
+    let granted: Vec<&str> = granted.split(SEPARATOR).collect();
+    let required: Vec<&str> = required.split(SEPARATOR).collect();
+
+    for (i, seg) in granted.iter().enumerate() {
+        let is_last = i == granted.len() - 1;
+        if *seg == WILDCARD && is_last {
+            return required.len() > i;
+        }
+        match required.get(i) {
+            Some(req) if *seg == WILDCARD || seg == req => continue,
+            _ => return false,
+        }
+    }
+    granted.len() == required.len()
+}
+
+/// Orders matching grants from least to most specific: more literal segments first,
+/// then a fixed length over a trailing wildcard
+pub fn specificity(perm: &str) -> (usize, bool) {
+    let segments: Vec<&str> = perm.split(SEPARATOR).collect();
+    let literals = segments.iter().filter(|s| **s != WILDCARD).count();
+    let open_ended = segments.last() == Some(&WILDCARD);
+    (literals, !open_ended)
+}
+
+/// Binds a resource to a perm: it fills the first `*`, or is appended when there is none
+pub fn scope_perm(perm: &str, resource: &str) -> String {
+    if resource.is_empty() {
+        return perm.to_string();
+    }
+    let mut bound = false;
+    let segments: Vec<&str> = perm
+        .split(SEPARATOR)
+        .map(|seg| {
+            if !bound && seg == WILDCARD {
+                bound = true;
+                resource
+            } else {
+                seg
+            }
+        })
+        .collect();
+    if bound {
+        segments.join(":")
+    } else {
+        format!("{perm}{SEPARATOR}{resource}")
+    }
+}
+
+/// Every pattern that could grant `required`, `required` itself included. Only `required`
+/// when it's longer than a valid pattern can be, check with `is_valid_pattern` first.
+pub fn generalizations(required: &str) -> Vec<String> {
+    if !is_valid_pattern(required) {
+        return vec![required.to_string()];
+    }
+    let segments: Vec<&str> = required.split(SEPARATOR).collect();
+    let mut out = Vec::new();
+    // Each segment is either kept or swapped for `*`
+    for mask in 0u32..(1 << segments.len()) {
+        let variant: Vec<&str> = segments
+            .iter()
+            .enumerate()
+            .map(|(i, s)| if mask & (1 << i) != 0 { WILDCARD } else { *s })
+            .collect();
+        out.push(variant.join(":"));
+        // And any prefix followed by an open-ended `*`
+        for k in 0..variant.len() {
+            let mut prefix = variant[..k].to_vec();
+            prefix.push(WILDCARD);
+            out.push(prefix.join(":"));
+        }
+    }
+    out.sort();
+    out.dedup();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generalizations_cover_every_grantor() {
+        let found = generalizations("grids:edit:42");
+        for grantor in ["grids:edit:42", "grids:*:42", "*:edit:*", "grids:*", "*"] {
+            assert!(found.iter().any(|g| g == grantor), "{grantor} missing");
+        }
+        assert!(found.iter().all(|g| perm_matches(g, "grids:edit:42")));
+    }
+
+    #[test]
+    fn generalizations_are_bounded() {
+        let longest = ["a"; MAX_PERM_SEGMENTS].join(":");
+        assert!(is_valid_pattern(&longest));
+        // 2^6 masks, each with up to 6 open-ended prefixes, before dedup
+        assert!(generalizations(&longest).len() <= (1 << MAX_PERM_SEGMENTS) * 7);
+
+        let too_many = ["a"; MAX_PERM_SEGMENTS + 1].join(":");
+        assert!(!is_valid_pattern(&too_many));
+        assert_eq!(generalizations(&too_many), vec![too_many.clone()]);
+
+        let too_long = "a".repeat(MAX_PERM_LEN + 1);
+        assert!(!is_valid_pattern(&too_long));
+        assert_eq!(generalizations(&too_long), vec![too_long.clone()]);
+
+        let huge = ["x"; 64].join(":");
+        assert_eq!(generalizations(&huge).len(), 1);
+    }
+
+    #[test]
+    fn wildcards_match_by_segment() {
+        assert!(perm_matches("*", "users"));
+        assert!(perm_matches("*", "grids:edit:42"));
+        assert!(perm_matches("users:*", "users:edit"));
+        assert!(perm_matches("users:*", "users:edit:42"));
+        assert!(!perm_matches("users:*", "users"));
+        assert!(!perm_matches("users:*", "roles:edit"));
+        assert!(perm_matches("class:*:manage", "class:7:manage"));
+        assert!(!perm_matches("class:*:manage", "class:7:manage:x"));
+        assert!(!perm_matches("class:*:manage", "class:manage"));
+    }
+
+    #[test]
+    fn there_is_no_prefix_match() {
+        assert!(perm_matches("users:edit", "users:edit"));
+        assert!(!perm_matches("users:edit", "users:edit:42"));
+        assert!(!perm_matches("users:edit:42", "users:edit"));
+    }
+
+    #[test]
+    fn scoped_grants_only_cover_their_resource() {
+        let grant = scope_perm("users:edit", "42");
+        assert_eq!(grant, "users:edit:42");
+        assert!(perm_matches(&grant, &scope_perm("users:edit", "42")));
+        assert!(!perm_matches(&grant, &scope_perm("users:edit", "43")));
+        // A check without a resource needs the unscoped perm
+        assert!(!perm_matches(&grant, "users:edit"));
+
+        assert_eq!(scope_perm("class:*:manage", "7"), "class:7:manage");
+        assert_eq!(scope_perm("users:edit", ""), "users:edit");
+    }
+
+    #[test]
+    fn more_literals_are_more_specific() {
+        let mut perms = vec![
+            "*",
+            "users:edit:42",
+            "users:*",
+            "*:edit",
+            "users:edit",
+            "users:*:42",
+        ];
+        perms.sort_by_key(|p| specificity(p));
+        assert_eq!(
+            perms,
+            [
+                "*",
+                "users:*",
+                "*:edit",
+                "users:edit",
+                "users:*:42",
+                "users:edit:42"
+            ]
+        );
+        // Same literals: a fixed length beats a trailing wildcard
+        assert!(specificity("users:edit") > specificity("users:edit:*"));
+        assert!(specificity("*:edit") > specificity("users:*"));
+    }
+
+    #[test]
+    fn validates_patterns() {
+        assert!(is_valid_pattern("users:*"));
+        assert!(is_valid_pattern("class:*:manage"));
+        assert!(!is_valid_pattern(""));
+        assert!(!is_valid_pattern("users::edit"));
+        assert!(!is_valid_pattern("users:ed*"));
+    }
+}
@@ -0,0 +1,69 @@
+use napi_derive::napi;
+use std::sync::OnceLock;
+
+/// How accounts whose email isn't verified yet are treated. `Restrict` ignores their role
+/// and direct grants, `Block` also refuses their logins.
+#[napi(string_enum = "snake_case")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnverifiedEmailPolicy {
+    Allow,
+    Restrict,
+    Block,
+}
+
+impl UnverifiedEmailPolicy {
+    /// Whether a user may log in, by password, passkey or provider alike
+    pub fn allows_login(self, email_verified: bool) -> bool {
+        self != UnverifiedEmailPolicy::Block || email_verified
+    }
+}
+
+static POLICY: OnceLock<UnverifiedEmailPolicy> = OnceLock::new();
+
+/// Sets the policy, only once and before the first check
+pub fn configure_unverified_email_policy(policy: UnverifiedEmailPolicy) -> napi::Result<()> {
+    POLICY
+        .set(policy)
+        .map_err(|_| napi::Error::from_reason("Unverified email policy is already configured"))
+}
+
+/// The configured policy, else `UNVERIFIED_EMAIL_POLICY` (`allow`, `restrict` or `block`),
+/// else `Allow`
+pub fn unverified_email_policy() -> UnverifiedEmailPolicy {
+    *POLICY.get_or_init(|| {
+        match std::env::var("UNVERIFIED_EMAIL_POLICY")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "restrict" => UnverifiedEmailPolicy::Restrict,
+            "block" => UnverifiedEmailPolicy::Block,
+            _ => UnverifiedEmailPolicy::Allow,
+        }
+    })
+}
+
+/// Whether grants only count for users with a verified email. Blocked users can't log in,
+/// but sessions from before they were blocked are restricted too.
+pub(crate) fn grants_need_verified_email() -> bool {
+    unverified_email_policy() != UnverifiedEmailPolicy::Allow
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_block_refuses_unverified_logins() {
+        for policy in [
+            UnverifiedEmailPolicy::Allow,
+            UnverifiedEmailPolicy::Restrict,
+            UnverifiedEmailPolicy::Block,
+        ] {
+            assert!(policy.allows_login(true));
+        }
+        assert!(UnverifiedEmailPolicy::Allow.allows_login(false));
+        assert!(UnverifiedEmailPolicy::Restrict.allows_login(false));
+        assert!(!UnverifiedEmailPolicy::Block.allows_login(false));
+    }
+}
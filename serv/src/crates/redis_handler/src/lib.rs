@@ -23,6 +23,11 @@ pub enum RedisHandlerError {
     DeserializationError(String),
     #[error("IO error: {0}")]
     IoError(String),
+    #[error("Account locked, retry in {retry_after} seconds")]
+    AccountLocked {
+        retry_after: u64,
+        failed_attempts: u32,
+    },
 }
 
 impl From<deadpool_redis::CreatePoolError> for RedisHandlerError {
@@ -73,6 +78,18 @@ pub struct RateLimitConfig {
     pub identifier: String,
 }
 
+/// Failed login tracking for one account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[napi(object)]
+pub struct LockoutStatus {
+    /// Failures since the last successful login, forgotten after a day without any
+    pub failed_attempts: u32,
+    pub locked: bool,
+    /// Unix seconds, set while locked
+    pub locked_until: Option<i64>,
+    pub retry_after_seconds: u32,
+}
+
 impl RefreshTokenData {
     pub fn is_expired(&self) -> bool {
         let now = SystemTime::now()
@@ -344,6 +361,439 @@ pub async fn reset_rate_limit(identifier: String) -> Result<bool, RedisHandlerEr
     Ok(deleted > 0)
 }
 
+// Account lockout: the first FREE_LOGIN_ATTEMPTS failures are free, the next one locks the
+// account for BASE_LOCKOUT_SECONDS and every further failure for twice as long as the
+// last, up to MAX_LOCKOUT_SECONDS. A successful login or an admin unlock starts over.
+const LOCKOUT_PREFIX: &str = "login_failures:";
+const FREE_LOGIN_ATTEMPTS: u32 = 5;
+const BASE_LOCKOUT_SECONDS: u64 = 30;
+const MAX_LOCKOUT_SECONDS: u64 = 60 * 60;
+const FAILURE_MEMORY_SECONDS: u64 = 24 * 60 * 60;
+
+fn lockout_key(account: &str) -> String {
+    // Emails are case-insensitive in uidb, so the lock must be too
+    format!("{LOCKOUT_PREFIX}{}", account.trim().to_lowercase())
+}
+
+fn lockout_seconds(failed_attempts: u32) -> u64 {
+    if failed_attempts <= FREE_LOGIN_ATTEMPTS {
+        return 0;
+    }
+    let doublings = (failed_attempts - FREE_LOGIN_ATTEMPTS - 1).min(32);
+    BASE_LOCKOUT_SECONDS
+        .saturating_mul(1u64 << doublings)
+        .min(MAX_LOCKOUT_SECONDS)
+}
+
+/// Lock durations for the 1st, 2nd, ... failure, ending with the first one at the cap
+fn lockout_schedule() -> Vec<u64> {
+    let mut schedule = Vec::new();
+    for failed_attempts in 1.. {
+        let lock_for = lockout_seconds(failed_attempts);
+        schedule.push(lock_for);
+        if lock_for >= MAX_LOCKOUT_SECONDS {
+            break;
+        }
+    }
+    schedule
+}
+
+// Counts the failure and locks the account in one step, so concurrent failures or a login
+// clearing the key in between can't leave a count and a lock that disagree.
+// ARGV: now, how long to remember failures, then the lock schedule from `lockout_schedule`.
+const RECORD_FAILURE_SCRIPT: &str = r"
+local failed = redis.call('HINCRBY', KEYS[1], 'failed_attempts', 1)
+local now = tonumber(ARGV[1])
+local lock_for = tonumber(ARGV[2 + math.min(failed, #ARGV - 2)])
+local locked_until = false
+if lock_for > 0 then
+    locked_until = now + lock_for
+    redis.call('HSET', KEYS[1], 'locked_until', locked_until)
+end
+redis.call('EXPIRE', KEYS[1], math.max(tonumber(ARGV[2]), lock_for))
+return {failed, locked_until}
+";
+
+fn lockout_status_from(failed_attempts: u32, locked_until: Option<i64>, now: i64) -> LockoutStatus {
+    let locked_until = locked_until.filter(|until| *until > now);
+    LockoutStatus {
+        failed_attempts,
+        locked: locked_until.is_some(),
+        locked_until,
+        retry_after_seconds: locked_until.map_or(0, |until| (until - now) as u32),
+    }
+}
+
+pub async fn lockout_status(account: String) -> Result<LockoutStatus, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let (failed_attempts, locked_until): (Option<u32>, Option<i64>) = redis::cmd("HMGET")
+        .arg(lockout_key(&account))
+        .arg("failed_attempts")
+        .arg("locked_until")
+        .query_async(&mut conn)
+        .await?;
+
+    Ok(lockout_status_from(
+        failed_attempts.unwrap_or(0),
+        locked_until,
+        now,
+    ))
+}
+
+/// Fails with `AccountLocked` while the account is locked, so no password check happens
+pub async fn ensure_unlocked(account: String) -> Result<LockoutStatus, RedisHandlerError> {
+    let status = lockout_status(account).await?;
+    if status.locked {
+        return Err(RedisHandlerError::AccountLocked {
+            retry_after: status.retry_after_seconds as u64,
+            failed_attempts: status.failed_attempts,
+        });
+    }
+    Ok(status)
+}
+
+pub async fn record_login_failure(account: String) -> Result<LockoutStatus, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let key = lockout_key(&account);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    let (failed_attempts, locked_until): (u32, Option<i64>) = redis::cmd("EVAL")
+        .arg(RECORD_FAILURE_SCRIPT)
+        .arg(1)
+        .arg(&key)
+        .arg(now)
+        .arg(FAILURE_MEMORY_SECONDS)
+        .arg(lockout_schedule())
+        .query_async(&mut conn)
+        .await?;
+
+    Ok(lockout_status_from(failed_attempts, locked_until, now))
+}
+
+/// Forgets past failures, after a successful login
+pub async fn clear_login_failures(account: String) -> Result<(), RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: u64 = conn.del(lockout_key(&account)).await?;
+    Ok(())
+}
+
+/// Lifts a lock early. Returns whether the account had any failures on record.
+pub async fn unlock_account(account: String) -> Result<bool, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let deleted: u64 = conn.del(lockout_key(&account)).await?;
+    Ok(deleted > 0)
+}
+
+/// Single-use tokens handed to users, mostly in mailed links. They are stored by their
+/// hash, so reading Redis doesn't hand out working links, and each user has at most one
+/// live token of a kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserTokenKind {
+    PasswordReset,
+    EmailVerification,
+    /// A password login waiting for its second factor
+    LoginChallenge,
+}
+
+impl UserTokenKind {
+    fn prefix(&self) -> &'static str {
+        match self {
+            UserTokenKind::PasswordReset => "password_reset",
+            UserTokenKind::EmailVerification => "email_verification",
+            UserTokenKind::LoginChallenge => "login_challenge",
+        }
+    }
+
+    fn token_key(&self, token_hash: &str) -> String {
+        format!("{}:{token_hash}", self.prefix())
+    }
+
+    fn user_key(&self, user_id: &str) -> String {
+        format!("{}_user:{user_id}", self.prefix())
+    }
+}
+
+/// Stores a token for `user_id`, replacing the user's earlier token of the same kind
+pub async fn store_user_token(
+    kind: UserTokenKind,
+    token_hash: String,
+    user_id: String,
+    expires_in_seconds: u64,
+) -> Result<(), RedisHandlerError> {
+    revoke_user_token(kind, user_id.clone()).await?;
+
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: () = conn
+        .set_ex(kind.token_key(&token_hash), &user_id, expires_in_seconds)
+        .await?;
+    let _: () = conn
+        .set_ex(kind.user_key(&user_id), &token_hash, expires_in_seconds)
+        .await?;
+    Ok(())
+}
+
+/// The user a token belongs to, leaving the token in place
+pub async fn peek_user_token(
+    kind: UserTokenKind,
+    token_hash: String,
+) -> Result<Option<String>, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    Ok(conn.get(kind.token_key(&token_hash)).await?)
+}
+
+/// Takes a token, returning its user. Atomic, so a token is only ever consumed once.
+pub async fn consume_user_token(
+    kind: UserTokenKind,
+    token_hash: String,
+) -> Result<Option<String>, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let user_id: Option<String> = conn.get_del(kind.token_key(&token_hash)).await?;
+    if let Some(user_id) = &user_id {
+        let user_key = kind.user_key(user_id);
+        let current: Option<String> = conn.get(&user_key).await?;
+        if current.as_deref() == Some(token_hash.as_str()) {
+            let _: u64 = conn.del(&user_key).await?;
+        }
+    }
+    Ok(user_id)
+}
+
+/// Invalidates the user's live token of a kind, if any. Returns whether there was one.
+pub async fn revoke_user_token(
+    kind: UserTokenKind,
+    user_id: String,
+) -> Result<bool, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let token_hash: Option<String> = conn.get_del(kind.user_key(&user_id)).await?;
+    if let Some(token_hash) = &token_hash {
+        let _: u64 = conn.del(kind.token_key(token_hash)).await?;
+    }
+    Ok(token_hash.is_some())
+}
+
+/// Marks a TOTP time step as used by `user_id`. Returns false if it already was, so a
+/// code can't be replayed while it is still accepted.
+pub async fn claim_totp_step(
+    user_id: String,
+    step: u64,
+    expires_in_seconds: u64,
+) -> Result<bool, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let set: Option<String> = redis::cmd("SET")
+        .arg(format!("totp_used:{user_id}:{step}"))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(expires_in_seconds)
+        .query_async(&mut conn)
+        .await?;
+    Ok(set.is_some())
+}
+
+/// Remembers a WebAuthn challenge and what the ceremony is for until the response
+/// comes back. Passkey logins start before anyone is known, so it is keyed by the
+/// challenge rather than a user.
+pub async fn store_webauthn_challenge(
+    challenge: String,
+    ceremony: String,
+    expires_in_seconds: u64,
+) -> Result<(), RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: () = conn
+        .set_ex(
+            format!("webauthn_challenge:{challenge}"),
+            ceremony,
+            expires_in_seconds,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Takes a challenge, returning its ceremony. Atomic, so each challenge is answered once.
+pub async fn take_webauthn_challenge(
+    challenge: String,
+) -> Result<Option<String>, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    Ok(conn
+        .get_del(format!("webauthn_challenge:{challenge}"))
+        .await?)
+}
+
+/// Remembers what an OpenID Connect sign-in needs when the browser comes back from the
+/// provider (nonce, PKCE verifier and so on), keyed by the state it carries
+pub async fn store_oidc_state(
+    state: String,
+    pending: String,
+    expires_in_seconds: u64,
+) -> Result<(), RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: () = conn
+        .set_ex(format!("oidc_state:{state}"), pending, expires_in_seconds)
+        .await?;
+    Ok(())
+}
+
+/// Takes a pending sign-in by its state. Atomic, so each state is redeemed once.
+pub async fn take_oidc_state(state: String) -> Result<Option<String>, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    Ok(conn.get_del(format!("oidc_state:{state}")).await?)
+}
+
+/// Marks a sealed OAuth state as redeemed. Returns false if it already was, so a callback
+/// can't be replayed while the state is still valid.
+pub async fn claim_oauth_state(
+    state_id: String,
+    expires_in_seconds: u64,
+) -> Result<bool, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let set: Option<String> = redis::cmd("SET")
+        .arg(format!("oauth_state_used:{state_id}"))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(expires_in_seconds)
+        .query_async(&mut conn)
+        .await?;
+    Ok(set.is_some())
+}
+
+// Outgoing mail waits in a sorted set scored by when it is next due. Claiming leases an
+// entry by pushing its score past the lease instead of removing it, so with several
+// workers each one is only picked up once, and mail a crashed worker never got to is
+// picked up again once the lease runs out. Sent mail is acked, mail that can't be
+// delivered ends up in a capped list for inspection.
+const MAIL_QUEUE_KEY: &str = "mail_queue";
+const MAIL_DEAD_KEY: &str = "mail_dead";
+const MAIL_DEAD_LIMIT: isize = 1000;
+const MAIL_LEASE_SECONDS: i64 = 5 * 60;
+
+const CLAIM_MAIL_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, payload in ipairs(due) do
+    redis.call('ZADD', KEYS[1], 'XX', ARGV[3], payload)
+end
+return due
+";
+
+/// Queues a serialized mail to be sent at `due_at`, unix seconds. Payloads must be unique.
+pub async fn queue_mail(payload: String, due_at: i64) -> Result<(), RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: u64 = conn.zadd(MAIL_QUEUE_KEY, payload, due_at).await?;
+    Ok(())
+}
+
+/// Leases up to `limit` mails that are due. Each one must be settled with `ack_mail`,
+/// `requeue_mail` or `dead_letter_mail`, or it comes due again after the lease.
+pub async fn claim_due_mail(limit: u32) -> Result<Vec<String>, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    Ok(redis::cmd("EVAL")
+        .arg(CLAIM_MAIL_SCRIPT)
+        .arg(1)
+        .arg(MAIL_QUEUE_KEY)
+        .arg(now)
+        .arg(limit)
+        .arg(now + MAIL_LEASE_SECONDS)
+        .query_async(&mut conn)
+        .await?)
+}
+
+/// Drops a claimed mail from the queue once it was sent
+pub async fn ack_mail(claimed: String) -> Result<(), RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: u64 = conn.zrem(MAIL_QUEUE_KEY, claimed).await?;
+    Ok(())
+}
+
+/// Swaps a claimed mail for its updated `payload`, due again at `due_at`
+pub async fn requeue_mail(
+    claimed: String,
+    payload: String,
+    due_at: i64,
+) -> Result<(), RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: () = redis::pipe()
+        .atomic()
+        .zrem(MAIL_QUEUE_KEY, claimed)
+        .ignore()
+        .zadd(MAIL_QUEUE_KEY, payload, due_at)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn mail_queue_len() -> Result<u64, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    Ok(conn.zcard(MAIL_QUEUE_KEY).await?)
+}
+
+/// Moves a claimed mail that won't be retried to the failed list as `payload`, dropping the
+/// oldest past the cap
+pub async fn dead_letter_mail(claimed: String, payload: String) -> Result<(), RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    let _: () = redis::pipe()
+        .atomic()
+        .zrem(MAIL_QUEUE_KEY, claimed)
+        .ignore()
+        .lpush(MAIL_DEAD_KEY, payload)
+        .ignore()
+        .ltrim(MAIL_DEAD_KEY, 0, MAIL_DEAD_LIMIT - 1)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Undeliverable mail, newest first
+pub async fn list_dead_mail(limit: u32) -> Result<Vec<String>, RedisHandlerError> {
+    let pool = get_redis_pool()?;
+    let mut conn = pool.get().await?;
+
+    Ok(conn.lrange(MAIL_DEAD_KEY, 0, limit as isize - 1).await?)
+}
+
 pub async fn get_rate_limit_stats(identifier: String) -> Result<(u32, u32), RedisHandlerError> {
     let pool = get_redis_pool()?;
     let mut conn = pool.get().await?;
@@ -471,3 +921,33 @@ pub async fn cleanup_rate_limit_keys() -> Result<u32, RedisHandlerError> {
 
     Ok(cleaned)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_attempts_do_not_lock() {
+        for failed_attempts in 0..=FREE_LOGIN_ATTEMPTS {
+            assert_eq!(lockout_seconds(failed_attempts), 0);
+        }
+        assert_eq!(
+            lockout_seconds(FREE_LOGIN_ATTEMPTS + 1),
+            BASE_LOCKOUT_SECONDS
+        );
+        assert_eq!(
+            lockout_seconds(FREE_LOGIN_ATTEMPTS + 2),
+            BASE_LOCKOUT_SECONDS * 2
+        );
+        assert_eq!(lockout_seconds(u32::MAX), MAX_LOCKOUT_SECONDS);
+    }
+
+    #[test]
+    fn schedule_matches_lockout_seconds() {
+        let schedule = lockout_schedule();
+        assert_eq!(schedule.last(), Some(&MAX_LOCKOUT_SECONDS));
+        for (i, lock_for) in schedule.iter().enumerate() {
+            assert_eq!(*lock_for, lockout_seconds(i as u32 + 1));
+        }
+    }
+}
@@ -12,11 +12,21 @@ pub struct User {
     pub uid: String,
     pub email: String,
     pub pwd_hash: Option<String>,
-    pub oauth_provider: Option<String>,
-    pub oauth_provider_id: Option<String>,
+    /// Providers the user can sign in with, e.g. `google`
+    pub providers: Vec<String>,
     pub create_time: f64,
+    /// Set while the account is soft deleted and can still be restored
+    pub deleted_at: Option<f64>,
+    /// Unset until the user follows a verification link, or signs up through a provider
+    /// that vouches for the address
+    pub email_verified_at: Option<f64>,
+    /// Whether logins need a TOTP code as well
+    pub totp_enabled: bool,
     pub roles: Vec<String>,
+    /// Everything the user may do, roles, denies and the hierarchy applied
     pub perms: Vec<String>,
+    /// Permanent perms granted to the user directly, the ones `update_user` replaces
+    pub direct_perms: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,387 @@
+use crate::{audit_snapshot, insert_user, user_from_uid};
+use audit::{AuditAction, AuditContext};
+use db::get_uidb_pool;
+use deadpool_postgres::GenericClient;
+use napi_derive::napi;
+use serde_json::Value;
+use shared_types::{Row, User};
+
+// Sign-ins through a provider find the user by identity first. Falling back to the
+// email only links an existing account when both the provider and the account have
+// verified it, otherwise whoever registered the address first could take over the
+// other's account. Anything else has to be linked explicitly while signed in.
+
+/// An account at a provider, linked to a user
+#[napi(object)]
+pub struct Identity {
+    pub provider: String,
+    pub provider_user_id: String,
+    /// As the provider reported it, may differ from the user's
+    pub email: Option<String>,
+    pub linked_at: f64,
+    pub last_login_at: Option<f64>,
+}
+
+/// Who a provider says signed in
+#[napi(object)]
+pub struct ProviderProfile {
+    /// e.g. `google`
+    pub provider: String,
+    /// The provider's stable id for the account, such as the `sub` claim
+    pub provider_user_id: String,
+    pub email: String,
+    /// Whether the provider vouches that the user controls `email`
+    pub email_verified: bool,
+}
+
+fn identity_from_row(row: Row) -> Identity {
+    Identity {
+        provider: row.get("provider"),
+        provider_user_id: row.get("provider_user_id"),
+        email: row.get("email"),
+        linked_at: row.get("linked_at"),
+        last_login_at: row.get("last_login_at"),
+    }
+}
+
+/// Locks the user's row and counts the ways they can log in: a password, passkeys and
+/// linked identities. Whatever removes one takes the lock first, so two removals can't
+/// each leave the other as the last method and both go through.
+pub(crate) async fn lock_login_methods(
+    client: &impl GenericClient,
+    uid: &str,
+) -> napi::Result<i64> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT (u.password_hash IS NOT NULL)::int
+                + (SELECT count(*) FROM public.User_Passkeys k WHERE k.user_uid = u.uid)
+                + (SELECT count(*) FROM public.User_Identities i WHERE i.user_uid = u.uid)
+             FROM public.Users u WHERE u.uid = CAST($1 AS TEXT)::uuid
+             FOR UPDATE",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(client
+        .query_opt(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .ok_or_else(|| napi::Error::from_reason("User not found"))?
+        .get(0))
+}
+
+async fn insert_identity(
+    client: &impl GenericClient,
+    uid: &str,
+    profile: &ProviderProfile,
+    signing_in: bool,
+) -> napi::Result<()> {
+    let stmt = client
+        .prepare_cached(
+            "INSERT INTO public.User_Identities
+                (provider, provider_user_id, user_uid, email, last_login_at)
+             VALUES ($1, $2, CAST($3 AS TEXT)::uuid, $4, CASE WHEN $5 THEN now() END)",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    client
+        .execute(
+            &stmt,
+            &[
+                &profile.provider,
+                &profile.provider_user_id,
+                &uid,
+                &profile.email,
+                &signing_in,
+            ],
+        )
+        .await
+        .map_err(|e| match e.as_db_error().and_then(|db| db.constraint()) {
+            Some("one_identity_per_provider") => napi::Error::from_reason(format!(
+                "A different {} account is already linked, unlink it first",
+                profile.provider
+            )),
+            Some("user_identities_pkey") => napi::Error::from_reason(format!(
+                "This {} account is linked to another user",
+                profile.provider
+            )),
+            _ => napi::Error::from_reason(format!("Failed to link identity: {e}")),
+        })?;
+    Ok(())
+}
+
+/// Signs a user in through a provider: by a linked identity, else by linking an account
+/// with the same verified email, else by creating one. Existing accounts whose email
+/// either side hasn't verified are refused, the user has to sign in and link instead.
+/// Under `UnverifiedEmailPolicy::Block` so is a user whose email still isn't verified.
+pub async fn provider_sign_in(profile: ProviderProfile, audit: AuditContext) -> napi::Result<User> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "UPDATE public.User_Identities i SET last_login_at = now(), email = $3
+             FROM public.Users u
+             WHERE u.uid = i.user_uid AND i.provider = $1 AND i.provider_user_id = $2
+             RETURNING u.uid::text, u.deleted_at IS NOT NULL",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let linked: Option<(String, bool)> = tx
+        .query_opt(
+            &stmt,
+            &[&profile.provider, &profile.provider_user_id, &profile.email],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .map(|row| (row.get(0), row.get(1)));
+
+    let uid = match linked {
+        Some((_, true)) => {
+            return Err(napi::Error::from_reason(
+                "Account is deleted, restore it instead",
+            ));
+        }
+        Some((uid, false)) => uid,
+        None => {
+            let stmt = tx
+                .prepare_cached(
+                    "SELECT uid::text, deleted_at IS NOT NULL, email_verified_at IS NOT NULL
+                     FROM public.Users WHERE email = $1
+                     FOR UPDATE",
+                )
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+            let existing: Option<(String, bool, bool)> = tx
+                .query_opt(&stmt, &[&profile.email])
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?
+                .map(|row| (row.get(0), row.get(1), row.get(2)));
+
+            match existing {
+                // Only told to whoever the provider vouches owns the email
+                Some((_, true, _)) if profile.email_verified => {
+                    return Err(napi::Error::from_reason(
+                        "Account is deleted, restore it instead",
+                    ));
+                }
+                Some((_, true, _)) => {
+                    return Err(napi::Error::from_reason(format!(
+                        "An account with this email already exists, sign in to it and link {} from there",
+                        profile.provider
+                    )));
+                }
+                Some((uid, false, account_verified)) => {
+                    if !(profile.email_verified && account_verified) {
+                        return Err(napi::Error::from_reason(format!(
+                            "An account with this email already exists, sign in to it and link {} from there",
+                            profile.provider
+                        )));
+                    }
+                    let before = audit_snapshot(&tx, &uid).await?;
+                    insert_identity(&tx, &uid, &profile, true).await?;
+                    let after = audit_snapshot(&tx, &uid).await?;
+                    audit::record(
+                        &tx,
+                        &audit,
+                        AuditAction::IdentityLink,
+                        Some(&uid),
+                        audit::diff(&before, &after),
+                    )
+                    .await?;
+                    uid
+                }
+                None => {
+                    let uid = insert_user(
+                        &tx,
+                        &profile.email,
+                        None,
+                        profile.email_verified,
+                        None,
+                        None,
+                    )
+                    .await?;
+                    insert_identity(&tx, &uid, &profile, true).await?;
+                    let after = audit_snapshot(&tx, &uid).await?;
+                    audit::record(
+                        &tx,
+                        &audit,
+                        AuditAction::UserCreate,
+                        Some(&uid),
+                        audit::diff(&Value::Null, &after),
+                    )
+                    .await?;
+                    uid
+                }
+            }
+        }
+    };
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    // The account stays created or linked, it's only the sign-in that's refused
+    let user = user_from_uid(&uid).await?;
+    if !rbac::unverified_email_policy().allows_login(user.email_verified_at.is_some()) {
+        return Err(napi::Error::from_reason("Email address is not verified"));
+    }
+    Ok(user)
+}
+
+/// Links a provider account to a signed-in user. Linking one that already is linked to
+/// them changes nothing.
+pub async fn link_identity(
+    uid: String,
+    profile: ProviderProfile,
+    audit: AuditContext,
+) -> napi::Result<User> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let before = audit_snapshot(&tx, &uid).await?;
+    if before.is_null() || !before["deleted_at"].is_null() {
+        return Err(napi::Error::from_reason("User not found"));
+    }
+
+    let stmt = tx
+        .prepare_cached(
+            "SELECT user_uid::text FROM public.User_Identities
+             WHERE provider = $1 AND provider_user_id = $2",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let owner: Option<String> = tx
+        .query_opt(&stmt, &[&profile.provider, &profile.provider_user_id])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .map(|row| row.get(0));
+
+    if owner.is_none() {
+        insert_identity(&tx, &uid, &profile, false).await?;
+        let after = audit_snapshot(&tx, &uid).await?;
+        audit::record(
+            &tx,
+            &audit,
+            AuditAction::IdentityLink,
+            Some(&uid),
+            audit::diff(&before, &after),
+        )
+        .await?;
+        tx.commit()
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    } else if owner.as_deref() != Some(uid.as_str()) {
+        return Err(napi::Error::from_reason(format!(
+            "This {} account is linked to another user",
+            profile.provider
+        )));
+    }
+
+    user_from_uid(&uid).await
+}
+
+/// Unlinks the user's identity at `provider`. Refused if it is their last way to log in.
+pub async fn unlink_identity(
+    uid: String,
+    provider: String,
+    audit: AuditContext,
+) -> napi::Result<User> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let login_methods = lock_login_methods(&tx, &uid).await?;
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
+        .prepare_cached(
+            "DELETE FROM public.User_Identities
+             WHERE user_uid = CAST($1 AS TEXT)::uuid AND provider = $2",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let deleted = tx
+        .execute(&stmt, &[&uid, &provider])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to unlink identity: {e}")))?;
+    if deleted == 0 {
+        return Err(napi::Error::from_reason(format!(
+            "No {provider} account is linked"
+        )));
+    }
+    if login_methods <= 1 {
+        return Err(napi::Error::from_reason(
+            "Can't unlink the last way to log in, set a password or add a passkey first",
+        ));
+    }
+
+    let after = audit_snapshot(&tx, &uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::IdentityUnlink,
+        Some(&uid),
+        audit::diff(&before, &after),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    user_from_uid(&uid).await
+}
+
+/// The user's linked identities, oldest first
+pub async fn list_identities(uid: String) -> napi::Result<Vec<Identity>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "SELECT provider, provider_user_id, email,
+                date_part('epoch', linked_at) as linked_at,
+                date_part('epoch', last_login_at) as last_login_at
+             FROM public.User_Identities
+             WHERE user_uid = CAST($1 AS TEXT)::uuid
+             ORDER BY linked_at",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(client
+        .query(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .into_iter()
+        .map(identity_from_row)
+        .collect())
+}
@@ -1,21 +1,226 @@
-use argon2::{
-    Argon2, PasswordHash, PasswordVerifier,
-    password_hash::{PasswordHasher, SaltString},
+mod identity;
+mod login;
+mod oauth_state;
+mod openid;
+mod passkey;
+mod password;
+mod policy;
+mod reset;
+mod tokens;
+mod totp;
+mod verification;
+
+pub use identity::{
+    Identity, ProviderProfile, link_identity, list_identities, provider_sign_in, unlink_identity,
 };
+pub use login::{
+    LoginOutcome, LoginResult, authenticate, begin_second_factor, verify_second_factor,
+};
+pub use oauth_state::{OAuthState, mint_oauth_state, verify_oauth_state};
+pub use openid::{
+    OidcAuthorization, OidcProviderConfig, OidcSignIn, begin_oidc_sign_in, configure_oidc,
+    finish_oidc_sign_in, oidc_providers,
+};
+pub use passkey::{
+    Passkey, WebauthnConfig, begin_passkey_authentication, begin_passkey_registration,
+    configure_webauthn, delete_passkey, finish_passkey_authentication, finish_passkey_registration,
+    list_passkeys,
+};
+pub use password::{HashConfig, configure_hashing};
+pub use policy::{
+    PasswordCheck, PasswordPolicy, PolicyViolation, PolicyViolationCode, check_password,
+    configure_password_policy,
+};
+pub use reset::{request_password_reset, reset_password};
+pub use totp::{
+    TotpEnrolment, TotpStatus, begin_totp_enrolment, confirm_totp_enrolment, disable_totp,
+    regenerate_recovery_codes, totp_status, verify_totp,
+};
+pub use verification::{request_email_verification, verify_email};
+
+use audit::{AuditAction, AuditContext, REDACTED};
 use db::get_uidb_pool;
+use deadpool_postgres::GenericClient;
+use napi_derive::napi;
+use rbac::effective_perms_for;
+use redis_handler::UserTokenKind;
+use serde_json::{Value, json};
 use shared_types::{Row, User};
+use std::sync::LazyLock;
 
 pub fn user_from_row(row: Row) -> User {
     User {
         uid: row.get("uid"),
         email: row.get("email"),
         pwd_hash: row.get("password_hash"),
-        oauth_provider: row.get("oauth_provider"),
-        oauth_provider_id: row.get("oauth_provider_id"),
+        providers: row.get("providers"),
         create_time: row.get::<_, f64>("creation_time"),
+        deleted_at: row.get("deleted_at"),
+        email_verified_at: row.get("email_verified_at"),
+        totp_enabled: row.get("totp_enabled"),
         roles: row.get("roles"),
-        perms: row.get("perms"),
+        // Resolved by `expand_perms`, with the same rules `rbac::has_perm` applies
+        perms: Vec::new(),
+        direct_perms: row.get("direct_perms"),
+    }
+}
+
+// Shared select list so every lookup returns users shaped the same way.
+// Perms aren't selected, `expand_perms` fills them in.
+static USER_COLUMNS: LazyLock<String> = LazyLock::new(|| {
+    format!(
+        "u.uid::text as uid,
+        u.email,
+        u.password_hash,
+        date_part('epoch', u.creation_time) as creation_time,
+        date_part('epoch', u.deleted_at) as deleted_at,
+        date_part('epoch', u.email_verified_at) as email_verified_at,
+        EXISTS (
+            SELECT 1 FROM public.User_Totp t
+            WHERE t.user_uid = u.uid AND t.confirmed_at IS NOT NULL
+        ) as totp_enabled,
+        ARRAY(
+            SELECT i.provider FROM public.User_Identities i
+            WHERE i.user_uid = u.uid ORDER BY 1
+        ) as providers,
+        ARRAY(
+            SELECT r.role_name
+            FROM public.Roles r
+            JOIN public.User_Roles ur ON r.role_id = ur.role_id
+            WHERE ur.user_uid = u.uid AND {}
+        ) as roles,
+        ARRAY(
+            SELECT p.perm
+            FROM public.Perms p
+            JOIN public.User_Perms up ON up.perm_id = p.perm_id
+            WHERE up.user_uid = u.uid AND up.resource = ''
+            AND up.valid_from IS NULL AND up.valid_until IS NULL
+            ORDER BY 1
+        ) as direct_perms",
+        rbac::grant_active!("ur")
+    )
+});
+
+/// Fills in each user's perms: everything their grants imply, minus what is denied
+async fn expand_perms(users: &mut [User]) -> napi::Result<()> {
+    let uids: Vec<String> = users.iter().map(|u| u.uid.clone()).collect();
+    let mut effective = effective_perms_for(&uids).await?;
+    for user in users.iter_mut() {
+        user.perms = effective.remove(&user.uid).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// What the audit log records of a user. Password hashes are left out, only whether one is set.
+async fn audit_snapshot(client: &impl GenericClient, uid: &str) -> napi::Result<Value> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT json_build_object(
+                'email', u.email,
+                'has_password', u.password_hash IS NOT NULL,
+                'identities', ARRAY(
+                    SELECT i.provider || ':' || i.provider_user_id FROM public.User_Identities i
+                    WHERE i.user_uid = u.uid ORDER BY 1
+                ),
+                'deleted_at', date_part('epoch', u.deleted_at),
+                'email_verified', u.email_verified_at IS NOT NULL,
+                'totp_enabled', EXISTS (
+                    SELECT 1 FROM public.User_Totp t
+                    WHERE t.user_uid = u.uid AND t.confirmed_at IS NOT NULL
+                ),
+                'recovery_codes_left', (
+                    SELECT count(*) FROM public.User_Recovery_Codes c
+                    WHERE c.user_uid = u.uid AND c.used_at IS NULL
+                ),
+                'passkeys', ARRAY(
+                    SELECT k.name FROM public.User_Passkeys k
+                    WHERE k.user_uid = u.uid ORDER BY k.created_at
+                ),
+                'roles', ARRAY(
+                    SELECT r.role_name FROM public.Roles r
+                    JOIN public.User_Roles ur ON ur.role_id = r.role_id
+                    WHERE ur.user_uid = u.uid ORDER BY 1
+                ),
+                'perms', ARRAY(
+                    SELECT p.perm FROM public.Perms p
+                    JOIN public.User_Perms up ON up.perm_id = p.perm_id
+                    WHERE up.user_uid = u.uid AND up.resource = '' ORDER BY 1
+                )
+            )::text
+            FROM public.Users u WHERE u.uid = CAST($1 AS TEXT)::uuid",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let row = client
+        .query_opt(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(row
+        .and_then(|r| serde_json::from_str(r.get(0)).ok())
+        .unwrap_or(Value::Null))
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 25;
+const DEFAULT_RESTORE_GRACE_DAYS: i32 = 30;
+
+/// How long a soft deleted account can be restored before it is purged
+fn restore_grace_days() -> i32 {
+    std::env::var("USER_RESTORE_GRACE_DAYS")
+        .ok()
+        .and_then(|d| d.parse().ok())
+        .unwrap_or(DEFAULT_RESTORE_GRACE_DAYS)
+}
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// `text` as a literal in a LIKE pattern with `ESCAPE '\'`
+fn like_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
+}
+
+#[napi(string_enum = "snake_case")]
+pub enum UserSortField {
+    Email,
+    CreationTime,
+}
+
+#[napi(object)]
+pub struct UserListQuery {
+    /// Case-insensitive substring match on the email
+    pub email: Option<String>,
+    pub role: Option<String>,
+    /// Users `has_perm` allows it, through roles, direct grants or the hierarchy
+    pub perm: Option<String>,
+    /// Users with an identity at this provider linked
+    pub oauth_provider: Option<String>,
+    /// Unix seconds, inclusive
+    pub created_after: Option<f64>,
+    /// Unix seconds, exclusive
+    pub created_before: Option<f64>,
+    pub sort_by: Option<UserSortField>,
+    pub descending: Option<bool>,
+    pub limit: Option<u32>,
+    /// `next_cursor` from the previous page. Fails if that user was deleted or restored
+    /// since.
+    pub cursor: Option<String>,
+    /// List only soft deleted users, which are otherwise left out
+    pub deleted: Option<bool>,
+}
+
+#[napi(object)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    pub next_cursor: Option<String>,
+    /// Number of users matching the filters across all pages
+    pub total: i64,
 }
 
 pub async fn user_from_uid(uid: impl AsRef<str>) -> napi::Result<User> {
@@ -26,31 +231,10 @@ pub async fn user_from_uid(uid: impl AsRef<str>) -> napi::Result<User> {
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
     let stmt = client
-        .prepare_cached(
-            "SELECT
-            u.uid::text as uid,
-            u.email,
-            u.password_hash,
-            u.oauth_provider,
-            u.oauth_provider_id,
-            date_part('epoch', u.creation_time) as creation_time,
-            ARRAY(
-                SELECT r.role_name
-                FROM public.Roles r
-                JOIN public.User_Roles ur ON r.role_id = ur.role_id
-                WHERE ur.user_uid = u.uid
-            ) as roles,
-            ARRAY(
-                SELECT DISTINCT p.perm 
-                FROM public.Perms p
-                LEFT JOIN public.Role_Perms rp ON p.perm_id = rp.perm_id
-                LEFT JOIN public.User_Roles ur ON rp.role_id = ur.role_id
-                LEFT JOIN public.User_Perms up ON p.perm_id = up.perm_id
-                WHERE ur.user_uid = u.uid OR up.user_uid = u.uid
-            ) as perms
-            FROM public.Users u 
-            WHERE u.uid::text ILIKE $1",
-        )
+        .prepare_cached(&format!(
+            "SELECT {} FROM public.Users u WHERE u.uid::text ILIKE $1 AND u.deleted_at IS NULL",
+            *USER_COLUMNS
+        ))
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
@@ -58,127 +242,259 @@ pub async fn user_from_uid(uid: impl AsRef<str>) -> napi::Result<User> {
         .query(&stmt, &[&uid])
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
-    rows.into_iter()
+    let mut user = rows
+        .into_iter()
         .map(user_from_row)
         .next()
-        .ok_or(napi::Error::from_reason("No users returned"))
+        .ok_or(napi::Error::from_reason("No users returned"))?;
+    expand_perms(std::slice::from_mut(&mut user)).await?;
+    Ok(user)
 }
 
-pub async fn search_users(email_str: impl AsRef<str>) -> napi::Result<Vec<User>> {
-    let email_str = email_str.as_ref();
+pub async fn user_from_email(email: impl AsRef<str>) -> napi::Result<Option<User>> {
+    let email = email.as_ref();
     let client = get_uidb_pool()
         .get()
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
     let stmt = client
-        .prepare_cached(
-            "SELECT 
-                u.uid::text as uid, 
-                u.email, 
-                u.password_hash, 
-                u.oauth_provider, 
-                u.oauth_provider_id,
-                date_part('epoch', u.creation_time) as creation_time,
-                -- Handle roles
-                ARRAY(
-                    SELECT r.role_name 
-                    FROM public.Roles r
-                    JOIN public.User_Roles ur ON r.role_id = ur.role_id
-                    WHERE ur.user_uid = u.uid
-                ) as roles,
-                -- Handle permissions
-                ARRAY(
-                    SELECT DISTINCT p.perm 
-                    FROM public.Perms p
-                    LEFT JOIN public.Role_Perms rp ON p.perm_id = rp.perm_id
-                    LEFT JOIN public.User_Roles ur ON rp.role_id = ur.role_id
-                    LEFT JOIN public.User_Perms up ON p.perm_id = up.perm_id
-                    WHERE ur.user_uid = u.uid OR up.user_uid = u.uid
-                ) as perms
-             FROM public.Users u 
-             WHERE u.email ILIKE $1",
-        )
+        .prepare_cached(&format!(
+            "SELECT {} FROM public.Users u WHERE u.email = $1 AND u.deleted_at IS NULL",
+            *USER_COLUMNS
+        ))
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
-    let rows = client
-        .query(&stmt, &[&format!("%{}%", email_str)])
+    let row = client
+        .query_opt(&stmt, &[&email])
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
-    Ok(rows.into_iter().map(user_from_row).collect())
+    let mut users: Vec<User> = row.into_iter().map(user_from_row).collect();
+    expand_perms(&mut users).await?;
+    Ok(users.pop())
 }
 
-pub async fn add_user(
-    email: String,
-    pass: Option<String>,
-    oauth_provider: Option<String>,
-    oauth_provider_id: Option<String>,
-    roles: Option<Vec<String>>,
-    perms: Option<Vec<String>>,
-) -> napi::Result<User> {
-    let mut client = get_uidb_pool()
+pub async fn list_users(query: UserListQuery) -> napi::Result<UserPage> {
+    let client = get_uidb_pool()
         .get()
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
-    // Start transaction so if role assignment fails, the user wont be created.
-    let tx = client
-        .transaction()
+    let mut filters = vec![if query.deleted.unwrap_or(false) {
+        "u.deleted_at IS NOT NULL".to_string()
+    } else {
+        "u.deleted_at IS NULL".to_string()
+    }];
+    let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = Vec::new();
+    let mut param_counter = 1;
+
+    let email_pattern = query
+        .email
+        .as_ref()
+        .map(|e| format!("%{}%", like_escape(e)));
+    if let Some(pattern) = &email_pattern {
+        filters.push(format!("u.email ILIKE ${} ESCAPE '\\'", param_counter));
+        params.push(pattern);
+        param_counter += 1;
+    }
+
+    if let Some(role) = &query.role {
+        filters.push(format!(
+            "EXISTS (
+                SELECT 1 FROM public.User_Roles ur
+                JOIN public.Roles r ON r.role_id = ur.role_id
+                WHERE ur.user_uid = u.uid AND r.role_name = ${} AND {}
+            )",
+            param_counter,
+            rbac::grant_active!("ur")
+        ));
+        params.push(role);
+        param_counter += 1;
+    }
+
+    // Decided per user by `rbac::has_perm`'s rules, denies and the hierarchy included
+    let perm_holders = match &query.perm {
+        Some(perm) if !rbac::is_valid_pattern(perm) => {
+            return Err(napi::Error::from_reason(format!(
+                "Invalid permission filter, at most {} segments and {} bytes",
+                rbac::MAX_PERM_SEGMENTS,
+                rbac::MAX_PERM_LEN
+            )));
+        }
+        Some(perm) => Some(rbac::users_with_perm(perm).await?),
+        None => None,
+    };
+    if let Some(holders) = &perm_holders {
+        filters.push(format!("u.uid::text = ANY(${})", param_counter));
+        params.push(holders);
+        param_counter += 1;
+    }
+
+    if let Some(provider) = &query.oauth_provider {
+        filters.push(format!(
+            "EXISTS (SELECT 1 FROM public.User_Identities i WHERE i.user_uid = u.uid AND i.provider = ${})",
+            param_counter
+        ));
+        params.push(provider);
+        param_counter += 1;
+    }
+
+    if let Some(after) = &query.created_after {
+        filters.push(format!(
+            "u.creation_time >= to_timestamp(${})",
+            param_counter
+        ));
+        params.push(after);
+        param_counter += 1;
+    }
+
+    if let Some(before) = &query.created_before {
+        filters.push(format!(
+            "u.creation_time < to_timestamp(${})",
+            param_counter
+        ));
+        params.push(before);
+        param_counter += 1;
+    }
+
+    let where_clause = format!("WHERE {}", filters.join(" AND "));
+
+    let total: i64 = client
+        .query_one(
+            &format!("SELECT count(*) FROM public.Users u {where_clause}"),
+            &params,
+        )
         .await
-        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+        .map_err(|e| napi::Error::from_reason(format!("Failed to count users: {e}")))?
+        .get(0);
 
-    // Check if user already exists
-    let existing_stmt = tx
-        .prepare_cached(
-            "SELECT uid::text FROM public.Users 
-         WHERE (oauth_provider = $1 AND oauth_provider_id = $2) OR email = $3",
+    let sort_column = match query.sort_by {
+        Some(UserSortField::Email) => "u.email",
+        Some(UserSortField::CreationTime) | None => "u.creation_time",
+    };
+    let descending = query.descending.unwrap_or(false);
+    let (direction, comparison) = if descending {
+        ("DESC", "<")
+    } else {
+        ("ASC", ">")
+    };
+
+    // Keyset pagination: the cursor is the uid of the last row on the previous page, and
+    // the uid breaks ties so rows with equal sort keys are never skipped or repeated
+    let mut page_filters = filters;
+    if let Some(cursor) = &query.cursor {
+        // Comparing against a row that isn't there matches nothing, which would pass for
+        // the last page
+        let stmt = client
+            .prepare_cached(
+                "SELECT 1 FROM public.Users
+                 WHERE uid::text = $1 AND (deleted_at IS NOT NULL) = $2",
+            )
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        if client
+            .query_opt(&stmt, &[cursor, &query.deleted.unwrap_or(false)])
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?
+            .is_none()
+        {
+            return Err(napi::Error::from_reason(
+                "Unknown cursor, start again from the first page",
+            ));
+        }
+
+        page_filters.push(format!(
+            "({sort_column}, u.uid) {comparison} (
+                SELECT {}, c.uid FROM public.Users c WHERE c.uid::text = ${}
+            )",
+            sort_column.replacen("u.", "c.", 1),
+            param_counter
+        ));
+        params.push(cursor);
+        param_counter += 1;
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as i64;
+    // Fetch one extra row to know whether another page exists
+    let fetch = limit + 1;
+    params.push(&fetch);
+
+    let page_where = format!("WHERE {}", page_filters.join(" AND "));
+
+    let rows = client
+        .query(
+            &format!(
+                "SELECT {} FROM public.Users u {page_where}
+                 ORDER BY {sort_column} {direction}, u.uid {direction}
+                 LIMIT ${param_counter}",
+                *USER_COLUMNS
+            ),
+            &params,
         )
         .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to list users: {e}")))?;
+
+    let mut users: Vec<User> = rows.into_iter().map(user_from_row).collect();
+    let next_cursor = if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        users.last().map(|u| u.uid.clone())
+    } else {
+        None
+    };
+    expand_perms(&mut users).await?;
+
+    Ok(UserPage {
+        users,
+        next_cursor,
+        total,
+    })
+}
+
+/// Inserts a new user inside `tx`, returning the uid. Fails if the email is taken,
+/// existing accounts are never merged into.
+pub(crate) async fn insert_user(
+    tx: &impl GenericClient,
+    email: &str,
+    pass: Option<String>,
+    email_verified: bool,
+    roles: Option<Vec<String>>,
+    perms: Option<Vec<String>>,
+) -> napi::Result<String> {
+    // Deleted accounts keep their email until purged. Same error as a live one, this is
+    // reachable from registration and shouldn't tell the two apart.
+    let existing_stmt = tx
+        .prepare_cached("SELECT 1 FROM public.Users WHERE email = $1")
+        .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
-    let existing_uid: Option<String> = tx
-        .query_opt(
-            &existing_stmt,
-            &[&oauth_provider, &oauth_provider_id, &email],
-        )
+    if tx
+        .query_opt(&existing_stmt, &[&email])
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?
-        .map(|row| row.get(0));
-
-    if let Some(uid) = existing_uid {
-        // If user exists, we perform an update
-        tx.rollback().await.ok();
-        return update_user(
-            uid,
-            Some(email),
-            pass,
-            oauth_provider,
-            oauth_provider_id,
-            roles,
-            perms,
-        )
-        .await;
+        .is_some()
+    {
+        return Err(napi::Error::from_reason("User already exists"));
     }
 
     // Hash password
-    let pwd_hash = if let Some(p) = pass {
-        let salt = SaltString::generate(&mut rand_core::OsRng);
-        Some(
-            Argon2::default()
-                .hash_password(p.as_bytes(), &salt)
-                .map_err(|e| napi::Error::from_reason(format!("Hashing failed: {e}")))?
-                .to_string(),
-        )
-    } else {
-        None
+    let (pwd_hash, pepper_id) = match pass {
+        Some(p) => {
+            policy::enforce_password(&p, Some(email)).await?;
+            let stored = password::hash_password(&p)?;
+            (Some(stored.hash), stored.pepper_id)
+        }
+        None => (None, None),
     };
 
     // Insert New User
     let insert_stmt = tx
         .prepare_cached(
-            "INSERT INTO public.Users (email, password_hash, oauth_provider, oauth_provider_id) 
-         VALUES ($1, $2, $3, $4) RETURNING uid::text",
+            "INSERT INTO public.Users (email, password_hash, password_pepper_id, email_verified_at) 
+         VALUES ($1, $2, $3, CASE WHEN $4 THEN now() END) RETURNING uid::text",
         )
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
@@ -186,7 +502,7 @@ pub async fn add_user(
     let row = tx
         .query_one(
             &insert_stmt,
-            &[&email, &pwd_hash, &oauth_provider, &oauth_provider_id],
+            &[&email, &pwd_hash, &pepper_id, &email_verified],
         )
         .await
         .map_err(|e| napi::Error::from_reason(format!("Insert failed: {e}")))?;
@@ -218,6 +534,41 @@ pub async fn add_user(
         }
     }
 
+    Ok(new_uid)
+}
+
+/// Creates a user with an unverified email. Sign-ups through a provider go through
+/// `provider_sign_in` instead, which links the identity as well.
+pub async fn add_user(
+    email: String,
+    pass: Option<String>,
+    roles: Option<Vec<String>>,
+    perms: Option<Vec<String>>,
+    audit: AuditContext,
+) -> napi::Result<User> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    // Start transaction so if role assignment fails, the user wont be created.
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let new_uid = insert_user(&tx, &email, pass, false, roles, perms).await?;
+
+    let after = audit_snapshot(&tx, &new_uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::UserCreate,
+        Some(&new_uid),
+        audit::diff(&Value::Null, &after),
+    )
+    .await?;
+
     tx.commit()
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
@@ -225,6 +576,8 @@ pub async fn add_user(
     user_from_uid(&new_uid).await
 }
 
+/// Whether `pass` is the user's password. Fails when the hash is peppered with a key no
+/// longer in `PASSWORD_PEPPERS`.
 pub async fn validate_pass(email: String, pass: String) -> napi::Result<bool> {
     let client = get_uidb_pool()
         .get()
@@ -234,84 +587,268 @@ pub async fn validate_pass(email: String, pass: String) -> napi::Result<bool> {
     let stmt = client
         .prepare_cached(
             "SELECT 
-                password_hash
+                password_hash, password_pepper_id
              FROM users 
-             WHERE email ILIKE $1",
+             WHERE email = $1 AND deleted_at IS NULL",
         )
         .await
         .map_err(|e| napi::Error::from_reason(format!("Failed to prepare cached: {e}")))?;
 
-    let rows = client
-        .query(&stmt, &[&email])
+    let (password_hash, pepper_id): (Option<String>, Option<String>) = client
+        .query_opt(&stmt, &[&email])
         .await
-        .map_err(|e| napi::Error::from_reason(format!("Failed to execute query: {e}")))?;
-
-    // If no user found or multiple users found (shouldn't happen with unique email), return false
-    if rows.len() != 1 {
-        return Ok(false);
+        .map_err(|e| napi::Error::from_reason(format!("Failed to execute query: {e}")))?
+        .map(|row| (row.get("password_hash"), row.get("password_pepper_id")))
+        .unwrap_or_default();
+
+    // Missing users and users without a password still cost one verification
+    let verification =
+        password::verify_password(password_hash.as_deref(), pepper_id.as_deref(), &pass)?;
+    if let Some(id) = verification.unknown_pepper {
+        return Err(napi::Error::from_reason(format!(
+            "Password hash uses unknown pepper key {id}"
+        )));
+    }
+    if let Some(old_hash) = &password_hash
+        && verification.valid
+        && verification.needs_rehash
+    {
+        password::upgrade_hash(&client, &email, old_hash, &pass).await;
     }
+    Ok(verification.valid)
+}
 
-    let row = &rows[0];
+/// Soft deletes a user and ends their sessions. They can no longer log in or be found, but
+/// `restore_user` brings the account back until `purge_deleted_users` removes it for good.
+pub async fn delete_user(email: String, audit: AuditContext) -> napi::Result<User> {
+    let mut user = user_from_email(&email)
+        .await?
+        .ok_or(napi::Error::from_reason("User not found"))?;
 
-    let password_hash: Option<String> = row.get("password_hash");
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to get client from pool: {e}")))?;
 
-    match password_hash {
-        Some(hash) => {
-            let parsed_hash = PasswordHash::new(&hash).map_err(|e| {
-                napi::Error::from_reason(format!("Failed to parse password hash: {e}"))
-            })?;
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
 
-            // Use Argon2 to verify the password
-            let argon2 = Argon2::default();
-            let is_valid = argon2
-                .verify_password(pass.as_bytes(), &parsed_hash)
-                .is_ok();
+    let before = audit_snapshot(&tx, &user.uid).await?;
 
-            Ok(is_valid)
-        }
-        None => Ok(false),
+    let stmt = tx
+        .prepare_cached(
+            "UPDATE users SET deleted_at = now()
+             WHERE uid = CAST($1 AS TEXT)::uuid AND deleted_at IS NULL
+             RETURNING date_part('epoch', deleted_at) as deleted_at",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to prepare cached: {e}")))?;
+
+    let row = tx.query_opt(&stmt, &[&user.uid]).await.map_err(|e| {
+        napi::Error::from_reason(format!("Failed to execute delete statement: {e}"))
+    })?;
+    let Some(row) = row else {
+        return Err(napi::Error::from_reason("User not found"));
+    };
+    user.deleted_at = row.get("deleted_at");
+
+    let after = audit_snapshot(&tx, &user.uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::UserDelete,
+        Some(&user.uid),
+        audit::diff(&before, &after),
+    )
+    .await?;
+
+    // Deleted users can't log in, so their sessions end too. Done before the commit so a
+    // failed revoke leaves the account as it was instead of deleted with live sessions.
+    redis_handler::delete_user_refresh_tokens(user.uid.clone())
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to revoke sessions: {e}")))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(user)
+}
+
+/// Brings back a soft deleted user, as long as the grace period has not run out
+pub async fn restore_user(uid: String, audit: AuditContext) -> napi::Result<User> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
+        .prepare_cached(
+            "UPDATE public.Users SET deleted_at = NULL
+             WHERE uid = CAST($1 AS TEXT)::uuid
+             AND deleted_at > now() - make_interval(days => $2)",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let restored = tx
+        .execute(&stmt, &[&uid, &restore_grace_days()])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Restore failed: {e}")))?;
+    if restored == 0 {
+        return Err(napi::Error::from_reason(
+            "No deleted user to restore, or the grace period has ended",
+        ));
     }
+
+    let after = audit_snapshot(&tx, &uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::UserRestore,
+        Some(&uid),
+        audit::diff(&before, &after),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    user_from_uid(&uid).await
 }
 
-pub async fn delete_user(email: String) -> napi::Result<User> {
-    let client = get_uidb_pool()
+/// Sets a password hash carried over from another system. Argon2, bcrypt (`$2a$`, `$2b$`,
+/// `$2x$`, `$2y$`) and scrypt PHC hashes are accepted, anything not Argon2id with the
+/// configured parameters is rehashed on the user's next successful login.
+pub async fn import_password_hash(
+    uid: String,
+    hash: String,
+    audit: AuditContext,
+) -> napi::Result<User> {
+    if !password::is_supported_hash(&hash) {
+        return Err(napi::Error::from_reason("Unsupported password hash format"));
+    }
+
+    let mut client = get_uidb_pool()
         .get()
         .await
-        .map_err(|e| napi::Error::from_reason(format!("Failed to get client from pool: {e}")))?;
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
-    let stmt = client
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
         .prepare_cached(
-            "DELETE FROM users 
-             WHERE email = $1
-             RETURNING 
-                uid::text as uid, 
-                email, 
-                password_hash, 
-                oauth_provider, 
-                oauth_provider_id,
-                date_part('epoch', creation_time) as creation_time",
+            "UPDATE public.Users SET password_hash = $1, password_pepper_id = NULL
+             WHERE uid = CAST($2 AS TEXT)::uuid AND deleted_at IS NULL",
         )
         .await
-        .map_err(|e| napi::Error::from_reason(format!("Failed to prepare cached: {e}")))?;
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
-    let row = client.query_opt(&stmt, &[&email]).await.map_err(|e| {
-        napi::Error::from_reason(format!("Failed to execute delete statement: {e}"))
-    })?;
+    let updated = tx
+        .execute(&stmt, &[&hash, &uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Import failed: {e}")))?;
+    if updated == 0 {
+        return Err(napi::Error::from_reason("User not found"));
+    }
 
-    match row {
-        Some(row) => Ok(user_from_row(row)),
-        None => Err(napi::Error::from_reason("User not found")),
+    let after = audit_snapshot(&tx, &uid).await?;
+    let mut changes = audit::diff(&before, &after);
+    if let Some(changes) = changes.as_object_mut() {
+        changes.insert(
+            "password".to_string(),
+            json!({ "before": REDACTED, "after": REDACTED }),
+        );
     }
+    audit::record(&tx, &audit, AuditAction::UserUpdate, Some(&uid), changes).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    user_from_uid(&uid).await
+}
+
+/// Hard deletes users whose grace period has ended, along with their roles and perms.
+/// Returns the uids removed.
+pub async fn purge_deleted_users() -> napi::Result<Vec<String>> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let expired_stmt = tx
+        .prepare_cached(
+            "SELECT uid::text FROM public.Users
+             WHERE deleted_at <= now() - make_interval(days => $1)
+             FOR UPDATE",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let uids: Vec<String> = tx
+        .query(&expired_stmt, &[&restore_grace_days()])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .into_iter()
+        .map(|r| r.get(0))
+        .collect();
+
+    // The scheduled job has no actor, the entry only marks that the account is gone for good
+    let system = AuditContext::default();
+    for uid in &uids {
+        let before = audit_snapshot(&tx, uid).await?;
+        audit::record(
+            &tx,
+            &system,
+            AuditAction::UserPurge,
+            Some(uid),
+            audit::diff(&before, &Value::Null),
+        )
+        .await?;
+    }
+
+    tx.execute(
+        "DELETE FROM public.Users WHERE uid::text = ANY($1)",
+        &[&uids],
+    )
+    .await
+    .map_err(|e| napi::Error::from_reason(format!("Purge failed: {e}")))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(uids)
 }
 
 pub async fn update_user(
     uid: String,
     email: Option<String>,
     pass: Option<String>,
-    oauth_provider: Option<String>,
-    oauth_provider_id: Option<String>,
     roles: Option<Vec<String>>,
     perms: Option<Vec<String>>,
+    audit: AuditContext,
 ) -> napi::Result<User> {
     let mut client = get_uidb_pool()
         .get()
@@ -323,38 +860,41 @@ pub async fn update_user(
         .await
         .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
 
+    let before = audit_snapshot(&tx, &uid).await?;
+    if before.is_null() || !before["deleted_at"].is_null() {
+        return Err(napi::Error::from_reason("User not found"));
+    }
+
     let mut updates = Vec::new();
     let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = Vec::new();
     let mut param_counter = 1;
 
     // Handle metadata updates
+    let email_changed = email.as_deref().is_some_and(|e| {
+        !before["email"]
+            .as_str()
+            .is_some_and(|current| current.eq_ignore_ascii_case(e))
+    });
     if let Some(e) = &email {
         updates.push(format!("email = ${}", param_counter));
         params.push(e);
         param_counter += 1;
     }
+    if email_changed {
+        // A new address has to be verified again
+        updates.push("email_verified_at = NULL".to_string());
+    }
 
     let password_hash_owned;
     if let Some(p) = &pass {
-        let salt = SaltString::generate(&mut rand_core::OsRng);
-        password_hash_owned = Argon2::default()
-            .hash_password(p.as_bytes(), &salt)
-            .map_err(|e| napi::Error::from_reason(format!("Hashing failed: {e}")))?
-            .to_string();
+        let target_email = email.as_deref().or(before["email"].as_str());
+        policy::enforce_password(p, target_email).await?;
+        password_hash_owned = password::hash_password(p)?;
         updates.push(format!("password_hash = ${}", param_counter));
-        params.push(&password_hash_owned);
+        params.push(&password_hash_owned.hash);
         param_counter += 1;
-    }
-
-    if let Some(provider) = &oauth_provider {
-        updates.push(format!("oauth_provider = ${}", param_counter));
-        params.push(provider);
-        param_counter += 1;
-    }
-
-    if let Some(provider_id) = &oauth_provider_id {
-        updates.push(format!("oauth_provider_id = ${}", param_counter));
-        params.push(provider_id);
+        updates.push(format!("password_pepper_id = ${}", param_counter));
+        params.push(&password_hash_owned.pepper_id);
         param_counter += 1;
     }
 
@@ -370,10 +910,11 @@ pub async fn update_user(
             .map_err(|e| napi::Error::from_reason(format!("Metadata update failed: {e}")))?;
     }
 
-    // Handle roles
+    // Handle roles. Time-limited ones are managed through rbac, listing one here keeps its window.
     if let Some(role_list) = roles {
         tx.execute(
-            "DELETE FROM public.User_Roles WHERE user_uid = CAST($1 AS text)::uuid",
+            "DELETE FROM public.User_Roles WHERE user_uid = CAST($1 AS text)::uuid
+             AND valid_from IS NULL AND valid_until IS NULL",
             &[&uid],
         )
         .await
@@ -382,7 +923,8 @@ pub async fn update_user(
         for role_name in role_list {
             tx.execute(
                 "INSERT INTO public.User_Roles (user_uid, role_id) 
-                 SELECT CAST($1 AS TEXT)::uuid, role_id FROM public.Roles WHERE role_name = $2",
+                 SELECT CAST($1 AS TEXT)::uuid, role_id FROM public.Roles WHERE role_name = $2
+                 ON CONFLICT (user_uid, role_id) DO NOTHING",
                 &[&uid, &role_name],
             )
             .await
@@ -390,10 +932,12 @@ pub async fn update_user(
         }
     }
 
-    // Handle direct permissions
+    // Handle direct permissions, grants scoped to a resource or limited in time are managed through
+    // rbac and kept as they are
     if let Some(perm_list) = perms {
         tx.execute(
-            "DELETE FROM public.User_Perms WHERE user_uid = CAST($1 AS text)::uuid",
+            "DELETE FROM public.User_Perms WHERE user_uid = CAST($1 AS text)::uuid AND resource = ''
+             AND valid_from IS NULL AND valid_until IS NULL",
             &[&uid],
         )
         .await
@@ -402,7 +946,8 @@ pub async fn update_user(
         for perm_slug in perm_list {
             tx.execute(
                 "INSERT INTO public.User_Perms (user_uid, perm_id) 
-                 SELECT CAST($1 AS TEXT)::uuid, perm_id FROM public.Perms WHERE perm = $2",
+                 SELECT CAST($1 AS TEXT)::uuid, perm_id FROM public.Perms WHERE perm = $2
+                 ON CONFLICT (user_uid, perm_id, resource) DO NOTHING",
                 &[&uid, &perm_slug],
             )
             .await
@@ -410,10 +955,44 @@ pub async fn update_user(
         }
     }
 
+    let after = audit_snapshot(&tx, &uid).await?;
+    let mut changes = audit::diff(&before, &after);
+    if pass.is_some()
+        && let Some(changes) = changes.as_object_mut()
+    {
+        changes.insert(
+            "password".to_string(),
+            json!({ "before": REDACTED, "after": REDACTED }),
+        );
+    }
+    if changes.as_object().is_some_and(|c| !c.is_empty()) {
+        audit::record(&tx, &audit, AuditAction::UserUpdate, Some(&uid), changes).await?;
+    }
+
     tx.commit()
         .await
         .map_err(|e| napi::Error::from_reason(e.to_string()))?;
 
+    if email_changed {
+        // A link mailed to the old address must not verify the new one
+        redis_handler::revoke_user_token(UserTokenKind::EmailVerification, uid.clone())
+            .await
+            .map_err(tokens::token_error)?;
+    }
+
     // Return user
     user_from_uid(&uid).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_escape_makes_wildcards_literal() {
+        assert_eq!(like_escape("a_b"), "a\\_b");
+        assert_eq!(like_escape("100%"), "100\\%");
+        assert_eq!(like_escape("a\\b"), "a\\\\b");
+        assert_eq!(like_escape("plain@example.com"), "plain@example.com");
+    }
+}
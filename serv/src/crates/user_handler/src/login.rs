@@ -0,0 +1,242 @@
+use crate::{
+    USER_COLUMNS, expand_perms,
+    password::{self, verify_password},
+    tokens::{hash_token, new_token, token_error, ttl_from_env},
+    totp, user_from_row, user_from_uid,
+};
+use db::get_uidb_pool;
+use napi_derive::napi;
+use redis_handler::{RedisHandlerError, UserTokenKind};
+use shared_types::User;
+
+const DEFAULT_CHALLENGE_TTL_SECONDS: u64 = 5 * 60;
+
+// Every login attempt that reaches the password check pays for exactly one Argon2
+// verification, whether or not the account exists or has a password, so response
+// times don't reveal which emails are registered.
+
+#[napi(string_enum = "snake_case")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginOutcome {
+    Valid,
+    /// Unknown email, no password set or wrong password, deliberately not told apart
+    Invalid,
+    Locked,
+    /// Right password, but the email isn't verified and the policy blocks such logins
+    Unverified,
+    /// Right password, now `verify_second_factor` needs a code for the challenge
+    SecondFactorRequired,
+}
+
+#[napi(object)]
+pub struct LoginResult {
+    pub outcome: LoginOutcome,
+    /// Only set when the login is valid
+    pub user: Option<User>,
+    pub failed_attempts: u32,
+    /// Set when locked, including a lock the failed attempt just triggered
+    pub retry_after_seconds: u32,
+    /// Only set when a second factor is required, pass it to `verify_second_factor`
+    pub challenge: Option<String>,
+    /// On an invalid login whose password hash is peppered with a key no longer in
+    /// `PASSWORD_PEPPERS`, that key's id. For the operator to log, the user can only reset.
+    pub unknown_pepper: Option<String>,
+}
+
+impl LoginResult {
+    pub(crate) fn rejected(
+        outcome: LoginOutcome,
+        failed_attempts: u32,
+        retry_after_seconds: u32,
+    ) -> Self {
+        LoginResult {
+            outcome,
+            user: None,
+            failed_attempts,
+            retry_after_seconds,
+            challenge: None,
+            unknown_pepper: None,
+        }
+    }
+
+    pub(crate) fn valid(user: User) -> Self {
+        LoginResult {
+            outcome: LoginOutcome::Valid,
+            user: Some(user),
+            failed_attempts: 0,
+            retry_after_seconds: 0,
+            challenge: None,
+            unknown_pepper: None,
+        }
+    }
+}
+
+pub(crate) fn lockout_error(e: RedisHandlerError) -> napi::Error {
+    napi::Error::from_reason(format!("Account lockout failed: {e}"))
+}
+
+/// Checks the account isn't locked, turning a lock into the login result to return
+pub(crate) async fn check_unlocked(email: &str) -> napi::Result<Option<LoginResult>> {
+    match redis_handler::ensure_unlocked(email.to_string()).await {
+        Ok(_) => Ok(None),
+        Err(RedisHandlerError::AccountLocked {
+            retry_after,
+            failed_attempts,
+        }) => Ok(Some(LoginResult::rejected(
+            LoginOutcome::Locked,
+            failed_attempts,
+            retry_after as u32,
+        ))),
+        Err(e) => Err(lockout_error(e)),
+    }
+}
+
+/// Counts a failed attempt towards the account's lock
+pub(crate) async fn record_failure(email: &str) -> napi::Result<LoginResult> {
+    let status = redis_handler::record_login_failure(email.to_string())
+        .await
+        .map_err(lockout_error)?;
+    let outcome = if status.locked {
+        LoginOutcome::Locked
+    } else {
+        LoginOutcome::Invalid
+    };
+    Ok(LoginResult::rejected(
+        outcome,
+        status.failed_attempts,
+        status.retry_after_seconds,
+    ))
+}
+
+/// Starts the second half of a login for a user with TOTP enabled. Also for sign-ins that
+/// skip the password, such as through a provider. The challenge replaces any earlier one.
+pub async fn begin_second_factor(uid: String) -> napi::Result<String> {
+    let (challenge, challenge_hash) = new_token();
+    // How long a login may wait for its code
+    let ttl = ttl_from_env("LOGIN_CHALLENGE_TTL_SECONDS", DEFAULT_CHALLENGE_TTL_SECONDS);
+    redis_handler::store_user_token(UserTokenKind::LoginChallenge, challenge_hash, uid, ttl)
+        .await
+        .map_err(token_error)?;
+    Ok(challenge)
+}
+
+/// Completes a login that returned `SecondFactorRequired`, with a TOTP or recovery code.
+/// Wrong codes count towards the same lock as wrong passwords, an unknown or expired
+/// challenge is simply invalid.
+pub async fn verify_second_factor(challenge: String, code: String) -> napi::Result<LoginResult> {
+    let challenge_hash = hash_token(&challenge);
+
+    let Some(uid) =
+        redis_handler::peek_user_token(UserTokenKind::LoginChallenge, challenge_hash.clone())
+            .await
+            .map_err(token_error)?
+    else {
+        return Ok(LoginResult::rejected(LoginOutcome::Invalid, 0, 0));
+    };
+    let user = user_from_uid(&uid).await?;
+
+    if let Some(locked) = check_unlocked(&user.email).await? {
+        return Ok(locked);
+    }
+
+    if !totp::verify_totp(uid, code).await? {
+        let result = record_failure(&user.email).await?;
+        if result.outcome == LoginOutcome::Locked {
+            // Start over with the password once the lock is up
+            redis_handler::consume_user_token(UserTokenKind::LoginChallenge, challenge_hash)
+                .await
+                .map_err(token_error)?;
+        }
+        return Ok(result);
+    }
+
+    // Of two attempts racing with the same challenge only one logs in
+    if redis_handler::consume_user_token(UserTokenKind::LoginChallenge, challenge_hash)
+        .await
+        .map_err(token_error)?
+        .is_none()
+    {
+        return Ok(LoginResult::rejected(LoginOutcome::Invalid, 0, 0));
+    }
+
+    redis_handler::clear_login_failures(user.email.clone())
+        .await
+        .map_err(lockout_error)?;
+    Ok(LoginResult::valid(user))
+}
+
+/// Logs a user in by email and password. A locked account is refused before the password
+/// is looked at, failures count towards the next lock and success clears them.
+/// Users with TOTP enabled get a challenge for `verify_second_factor` instead of a login.
+pub async fn authenticate(email: String, pass: String) -> napi::Result<LoginResult> {
+    if let Some(locked) = check_unlocked(&email).await? {
+        return Ok(locked);
+    }
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT {}, u.password_pepper_id FROM public.Users u
+             WHERE u.email = $1 AND u.deleted_at IS NULL",
+            *USER_COLUMNS
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let (user, pepper_id) = match client
+        .query_opt(&stmt, &[&email])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+    {
+        Some(row) => {
+            let pepper_id: Option<String> = row.get("password_pepper_id");
+            (Some(user_from_row(row)), pepper_id)
+        }
+        None => (None, None),
+    };
+
+    let hash = user.as_ref().and_then(|u| u.pwd_hash.as_deref());
+    let verification = verify_password(hash, pepper_id.as_deref(), &pass)?;
+    if !verification.valid {
+        return Ok(LoginResult {
+            unknown_pepper: verification.unknown_pepper,
+            ..record_failure(&email).await?
+        });
+    }
+
+    if let Some(old_hash) = hash
+        && verification.needs_rehash
+    {
+        password::upgrade_hash(&client, &email, old_hash, &pass).await;
+    }
+
+    let Some(mut user) = user else {
+        return Ok(LoginResult::rejected(LoginOutcome::Invalid, 0, 0));
+    };
+
+    if !rbac::unverified_email_policy().allows_login(user.email_verified_at.is_some()) {
+        redis_handler::clear_login_failures(email)
+            .await
+            .map_err(lockout_error)?;
+        return Ok(LoginResult::rejected(LoginOutcome::Unverified, 0, 0));
+    }
+
+    // Failures keep counting until the second factor is in as well
+    if user.totp_enabled {
+        return Ok(LoginResult {
+            challenge: Some(begin_second_factor(user.uid).await?),
+            ..LoginResult::rejected(LoginOutcome::SecondFactorRequired, 0, 0)
+        });
+    }
+
+    redis_handler::clear_login_failures(email)
+        .await
+        .map_err(lockout_error)?;
+
+    expand_perms(std::slice::from_mut(&mut user)).await?;
+    Ok(LoginResult::valid(user))
+}
@@ -0,0 +1,66 @@
+use crate::tokens::{token_error, ttl_from_env};
+use napi_derive::napi;
+use oidc::StateKey;
+use serde_json::Value;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_STATE_TTL_SECONDS: u64 = 10 * 60;
+
+// The state rides through the provider in the URL, sealed so nobody on the way can read
+// or change it. It opens only with the binding kept in a cookie of the browser that
+// started the flow, which stops a callback URL from working in anyone else's browser,
+// and only once.
+
+/// A sealed `state` for an authorization URL
+#[napi(object)]
+pub struct OAuthState {
+    pub state: String,
+    /// Keep it in a cookie, the state only verifies together with it
+    pub binding: String,
+}
+
+static STATE_KEY: OnceLock<StateKey> = OnceLock::new();
+
+/// Derived from `OAUTH_STATE_SECRET`. Unset, a random key is used, and states don't
+/// survive a restart or verify on other instances.
+fn state_key() -> napi::Result<&'static StateKey> {
+    if let Some(key) = STATE_KEY.get() {
+        return Ok(key);
+    }
+    let key = match std::env::var("OAUTH_STATE_SECRET") {
+        Ok(secret) if !secret.is_empty() => StateKey::from_secret(secret.as_bytes()),
+        _ => StateKey::generate(),
+    }?;
+    Ok(STATE_KEY.get_or_init(|| key))
+}
+
+/// Seals `payload` into a state that expires after `OAUTH_STATE_TTL_SECONDS`
+pub fn mint_oauth_state(payload: Value) -> napi::Result<OAuthState> {
+    let ttl = ttl_from_env("OAUTH_STATE_TTL_SECONDS", DEFAULT_STATE_TTL_SECONDS);
+    let sealed = state_key()?.seal(payload, ttl)?;
+    Ok(OAuthState {
+        state: sealed.state,
+        binding: sealed.binding,
+    })
+}
+
+/// Opens a state the provider sent back and returns its payload. Fails if it was
+/// tampered with, expired, came with another browser's binding or was used before.
+pub async fn verify_oauth_state(state: String, binding: String) -> napi::Result<Value> {
+    let opened = state_key()?.open(&state, &binding)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // Remembered for as long as the state would still open
+    let first =
+        redis_handler::claim_oauth_state(opened.id, opened.expires_at.saturating_sub(now) + 1)
+            .await
+            .map_err(token_error)?;
+    if !first {
+        return Err(napi::Error::from_reason("Invalid state: already used"));
+    }
+    Ok(opened.payload)
+}
@@ -0,0 +1,244 @@
+use crate::{
+    identity::{ProviderProfile, link_identity, provider_sign_in},
+    tokens::{token_error, ttl_from_env},
+};
+use audit::AuditContext;
+use napi_derive::napi;
+use oidc::{Provider, ProviderConfig};
+use serde_json::{Value, json};
+use shared_types::User;
+use std::sync::OnceLock;
+use tokio::sync::OnceCell;
+
+const DEFAULT_STATE_TTL_SECONDS: u64 = 10 * 60;
+const DEFAULT_SCOPES: [&str; 2] = ["email", "profile"];
+
+// The state in the authorization URL is the only thing the browser brings back, so
+// everything else about a sign-in (who started a link, the nonce, the PKCE verifier)
+// waits in Redis under it. Binding the state to the browser that started the sign-in
+// is up to the caller.
+
+/// A provider to sign in with over OpenID Connect. Any compliant one works, e.g.
+/// Keycloak (`https://host/realms/<realm>`), Authentik
+/// (`https://host/application/o/<slug>/`) or Microsoft
+/// (`https://login.microsoftonline.com/<tenant>/v2.0`).
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct OidcProviderConfig {
+    /// Names the provider in routes and on linked identities, e.g. `keycloak`
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    /// Unset for public clients, which rely on PKCE alone
+    pub client_secret: Option<String>,
+    /// Requested besides `openid`, `email` and `profile` by default
+    pub scopes: Option<Vec<String>>,
+}
+
+/// Where to send the browser to sign in at a provider
+#[napi(object)]
+pub struct OidcAuthorization {
+    pub url: String,
+    /// Comes back with the browser, tie it to the browser to tell who started the sign-in
+    pub state: String,
+}
+
+/// How a sign-in at a provider ended
+#[napi(object)]
+pub struct OidcSignIn {
+    pub user: User,
+    /// The provider account was linked to a signed-in user rather than signed in with
+    pub linked: bool,
+    /// The name the provider has for the user, if it shared one
+    pub name: Option<String>,
+}
+
+struct RegisteredProvider {
+    config: OidcProviderConfig,
+    /// Discovered on first use, and retried on the next one if that failed
+    provider: OnceCell<Provider>,
+}
+
+static PROVIDERS: OnceLock<Vec<RegisteredProvider>> = OnceLock::new();
+
+/// `OIDC_PROVIDERS` lists the names (comma separated), each configured by
+/// `OIDC_<NAME>_ISSUER`, `_CLIENT_ID`, `_CLIENT_SECRET` and `_SCOPES`
+fn providers_from_env() -> Vec<OidcProviderConfig> {
+    let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    env("OIDC_PROVIDERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|n| n.trim().to_lowercase())
+        .filter(|n| !n.is_empty())
+        .map(|name| {
+            let prefix = format!("OIDC_{}", name.to_uppercase().replace('-', "_"));
+            let var = |suffix: &str| env(&format!("{prefix}_{suffix}"));
+            OidcProviderConfig {
+                issuer: var("ISSUER").unwrap_or_default(),
+                client_id: var("CLIENT_ID").unwrap_or_default(),
+                client_secret: var("CLIENT_SECRET"),
+                scopes: var("SCOPES").map(|s| {
+                    s.split([' ', ','])
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }),
+                name,
+            }
+        })
+        .collect()
+}
+
+fn register(configs: Vec<OidcProviderConfig>) -> Vec<RegisteredProvider> {
+    configs
+        .into_iter()
+        .map(|config| RegisteredProvider {
+            config,
+            provider: OnceCell::new(),
+        })
+        .collect()
+}
+
+/// Sets the providers up front, otherwise the environment is read on first use
+pub fn configure_oidc(providers: Vec<OidcProviderConfig>) -> napi::Result<()> {
+    for (i, p) in providers.iter().enumerate() {
+        if p.name.is_empty() || p.issuer.is_empty() || p.client_id.is_empty() {
+            return Err(napi::Error::from_reason(
+                "OIDC providers need a name, an issuer and a client id",
+            ));
+        }
+        if providers[..i].iter().any(|other| other.name == p.name) {
+            return Err(napi::Error::from_reason(format!(
+                "OIDC provider {} is configured twice",
+                p.name
+            )));
+        }
+    }
+    PROVIDERS
+        .set(register(providers))
+        .map_err(|_| napi::Error::from_reason("OIDC is already configured"))
+}
+
+fn registered() -> &'static [RegisteredProvider] {
+    PROVIDERS.get_or_init(|| register(providers_from_env()))
+}
+
+/// Names of the providers that can be signed in with
+pub fn oidc_providers() -> Vec<String> {
+    registered().iter().map(|p| p.config.name.clone()).collect()
+}
+
+async fn provider(name: &str) -> napi::Result<&'static Provider> {
+    let registered = registered()
+        .iter()
+        .find(|p| p.config.name == name)
+        .ok_or_else(|| napi::Error::from_reason(format!("Unknown provider: {name}")))?;
+    let config = &registered.config;
+    if config.issuer.is_empty() || config.client_id.is_empty() {
+        return Err(napi::Error::from_reason(format!(
+            "Provider {name} needs an issuer and a client id"
+        )));
+    }
+
+    registered
+        .provider
+        .get_or_try_init(|| {
+            Provider::discover(ProviderConfig {
+                issuer: config.issuer.clone(),
+                client_id: config.client_id.clone(),
+                client_secret: config.client_secret.clone(),
+                scopes: config
+                    .scopes
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SCOPES.iter().map(|s| s.to_string()).collect()),
+            })
+        })
+        .await
+        .map_err(napi::Error::from)
+}
+
+/// Starts a sign-in at a provider, or with `link_uid` linking a provider account to that
+/// signed-in user. The provider sends the browser back to `redirect_uri`.
+pub async fn begin_oidc_sign_in(
+    provider_name: String,
+    redirect_uri: String,
+    link_uid: Option<String>,
+) -> napi::Result<OidcAuthorization> {
+    let request = provider(&provider_name)
+        .await?
+        .authorization_request(&redirect_uri);
+
+    let pending = json!({
+        "provider": provider_name,
+        "redirect_uri": redirect_uri,
+        "nonce": request.nonce,
+        "code_verifier": request.code_verifier,
+        "link_uid": link_uid,
+    });
+    // How long the user may take at the provider
+    let ttl = ttl_from_env("OIDC_STATE_TTL_SECONDS", DEFAULT_STATE_TTL_SECONDS);
+    redis_handler::store_oidc_state(request.state.clone(), pending.to_string(), ttl)
+        .await
+        .map_err(token_error)?;
+
+    Ok(OidcAuthorization {
+        url: request.url,
+        state: request.state,
+    })
+}
+
+/// Finishes a sign-in with what the provider sent the browser back with. Signs the user
+/// in the way `provider_sign_in` does, or links the account if the sign-in was started
+/// for that. Each state is good for one try.
+pub async fn finish_oidc_sign_in(
+    state: String,
+    code: String,
+    audit: AuditContext,
+) -> napi::Result<OidcSignIn> {
+    let pending: Value = redis_handler::take_oidc_state(state)
+        .await
+        .map_err(token_error)?
+        .and_then(|p| serde_json::from_str(&p).ok())
+        .ok_or_else(|| napi::Error::from_reason("Sign-in expired, start over"))?;
+    let field = |name: &str| pending[name].as_str().unwrap_or_default();
+
+    let provider_name = field("provider");
+    let claims = provider(provider_name)
+        .await?
+        .sign_in(
+            &code,
+            field("redirect_uri"),
+            field("code_verifier"),
+            field("nonce"),
+        )
+        .await?;
+
+    let email = claims.email.ok_or_else(|| {
+        napi::Error::from_reason(format!(
+            "{provider_name} didn't share an email address, request the email scope"
+        ))
+    })?;
+    let profile = ProviderProfile {
+        provider: provider_name.to_string(),
+        provider_user_id: claims.subject,
+        email,
+        email_verified: claims.email_verified,
+    };
+
+    let (user, linked) = match pending["link_uid"].as_str() {
+        Some(uid) => {
+            let audit = AuditContext {
+                actor_uid: audit.actor_uid.or_else(|| Some(uid.to_string())),
+                ..audit
+            };
+            (link_identity(uid.to_string(), profile, audit).await?, true)
+        }
+        None => (provider_sign_in(profile, audit).await?, false),
+    };
+
+    Ok(OidcSignIn {
+        user,
+        linked,
+        name: claims.name,
+    })
+}
@@ -0,0 +1,427 @@
+use crate::{
+    audit_snapshot,
+    identity::lock_login_methods,
+    login::{LoginOutcome, LoginResult, check_unlocked, lockout_error, record_failure},
+    tokens::{token_error, ttl_from_env},
+    user_from_uid,
+};
+use audit::{AuditAction, AuditContext};
+use db::get_uidb_pool;
+use deadpool_postgres::GenericClient;
+use napi_derive::napi;
+use serde_json::Value;
+use shared_types::Row;
+use std::sync::OnceLock;
+use webauthn::{
+    AuthenticationResponse, CredentialDescriptor, RegistrationResponse, RelyingParty,
+    base64url_encode, new_challenge, response_challenge,
+};
+
+const DEFAULT_CHALLENGE_TTL_SECONDS: u64 = 5 * 60;
+const DEFAULT_PASSKEY_NAME: &str = "Passkey";
+const MAX_NAME_CHARS: usize = 64;
+
+// Challenges wait in Redis under what they were issued for: a registration for one
+// user, or a login that doesn't know its user until the passkey says so.
+const AUTHENTICATION_CEREMONY: &str = "authentication";
+const REGISTRATION_CEREMONY: &str = "registration:";
+
+/// The relying party passkeys are registered with. Unset fields fall back to the
+/// `WEBAUTHN_RP_ID`, `WEBAUTHN_RP_NAME` and `WEBAUTHN_ORIGINS` (comma separated) env vars.
+/// Changing the id later orphans every registered passkey.
+#[derive(Debug, Clone, Default)]
+#[napi(object)]
+pub struct WebauthnConfig {
+    pub rp_id: Option<String>,
+    pub rp_name: Option<String>,
+    pub origins: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct Passkey {
+    /// base64url, as the browser reports it
+    pub credential_id: String,
+    pub name: String,
+    pub transports: Vec<String>,
+    /// Synced between devices, e.g. through a password manager
+    pub backed_up: bool,
+    pub created_at: f64,
+    pub last_used_at: Option<f64>,
+}
+
+static RELYING_PARTY: OnceLock<RelyingParty> = OnceLock::new();
+
+fn relying_party_from(config: WebauthnConfig) -> RelyingParty {
+    let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    RelyingParty {
+        id: config
+            .rp_id
+            .or_else(|| env("WEBAUTHN_RP_ID"))
+            .unwrap_or_else(|| "localhost".to_string()),
+        name: config
+            .rp_name
+            .or_else(|| env("WEBAUTHN_RP_NAME"))
+            .unwrap_or_else(|| "deadlock".to_string()),
+        origins: config.origins.unwrap_or_else(|| {
+            env("WEBAUTHN_ORIGINS")
+                .unwrap_or_else(|| "http://localhost:8888".to_string())
+                .split(',')
+                .map(|o| o.trim().to_string())
+                .filter(|o| !o.is_empty())
+                .collect()
+        }),
+    }
+}
+
+/// Sets the relying party up front, otherwise the environment is read on first use
+pub fn configure_webauthn(config: WebauthnConfig) -> napi::Result<()> {
+    RELYING_PARTY
+        .set(relying_party_from(config))
+        .map_err(|_| napi::Error::from_reason("WebAuthn is already configured"))
+}
+
+fn relying_party() -> &'static RelyingParty {
+    RELYING_PARTY.get_or_init(|| relying_party_from(WebauthnConfig::default()))
+}
+
+fn passkey_from_row(row: Row) -> Passkey {
+    Passkey {
+        credential_id: row.get("credential_id"),
+        name: row.get("name"),
+        transports: row.get("transports"),
+        backed_up: row.get("backed_up"),
+        created_at: row.get("created_at"),
+        last_used_at: row.get("last_used_at"),
+    }
+}
+
+const PASSKEY_COLUMNS: &str = "credential_id, name, transports, backed_up,
+    date_part('epoch', created_at) as created_at,
+    date_part('epoch', last_used_at) as last_used_at";
+
+async fn issue_challenge(ceremony: String) -> napi::Result<String> {
+    let challenge = new_challenge();
+    // How long the browser may take to answer
+    let ttl = ttl_from_env(
+        "WEBAUTHN_CHALLENGE_TTL_SECONDS",
+        DEFAULT_CHALLENGE_TTL_SECONDS,
+    );
+    redis_handler::store_webauthn_challenge(challenge.clone(), ceremony, ttl)
+        .await
+        .map_err(token_error)?;
+    Ok(challenge)
+}
+
+/// Takes the challenge a response answers, with the ceremony it was issued for
+async fn take_challenge(client_data_json: &str) -> napi::Result<(String, Option<String>)> {
+    let challenge = response_challenge(client_data_json)?;
+    let ceremony = redis_handler::take_webauthn_challenge(challenge.clone())
+        .await
+        .map_err(token_error)?;
+    Ok((challenge, ceremony))
+}
+
+fn malformed_response(e: serde_json::Error) -> napi::Error {
+    napi::Error::from_reason(format!("Malformed passkey response: {e}"))
+}
+
+/// Options for `navigator.credentials.create()`. Passkeys the user already has are
+/// excluded, so an authenticator isn't registered twice.
+pub async fn begin_passkey_registration(uid: String) -> napi::Result<Value> {
+    let user = user_from_uid(&uid).await?;
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "SELECT credential_id, transports FROM public.User_Passkeys
+             WHERE user_uid = CAST($1 AS TEXT)::uuid",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let existing: Vec<CredentialDescriptor> = client
+        .query(&stmt, &[&user.uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .into_iter()
+        .map(|row| CredentialDescriptor {
+            id: row.get("credential_id"),
+            transports: row.get("transports"),
+        })
+        .collect();
+
+    let challenge = issue_challenge(format!("{REGISTRATION_CEREMONY}{}", user.uid)).await?;
+    Ok(relying_party().creation_options(&challenge, user.uid.as_bytes(), &user.email, &existing))
+}
+
+/// Verifies the browser's answer to `begin_passkey_registration` and stores the passkey
+pub async fn finish_passkey_registration(
+    uid: String,
+    response: Value,
+    name: Option<String>,
+    audit: AuditContext,
+) -> napi::Result<Passkey> {
+    let response: RegistrationResponse =
+        serde_json::from_value(response).map_err(malformed_response)?;
+
+    let name = name
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| DEFAULT_PASSKEY_NAME.to_string());
+    if name.chars().count() > MAX_NAME_CHARS {
+        return Err(napi::Error::from_reason(format!(
+            "Passkey names are at most {MAX_NAME_CHARS} characters"
+        )));
+    }
+
+    let (challenge, ceremony) = take_challenge(&response.response.client_data_json).await?;
+    if ceremony
+        .as_deref()
+        .and_then(|c| c.strip_prefix(REGISTRATION_CEREMONY))
+        != Some(&uid)
+    {
+        return Err(napi::Error::from_reason(
+            "Passkey registration expired or was not started",
+        ));
+    }
+    let credential = relying_party().verify_registration(&challenge, &response)?;
+
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
+        .prepare_cached(&format!(
+            "INSERT INTO public.User_Passkeys
+                (credential_id, user_uid, public_key, sign_count, transports, name, backed_up)
+             VALUES ($1, CAST($2 AS TEXT)::uuid, $3, $4, $5, $6, $7)
+             ON CONFLICT (credential_id) DO NOTHING
+             RETURNING {PASSKEY_COLUMNS}"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let passkey = tx
+        .query_opt(
+            &stmt,
+            &[
+                &credential.id,
+                &uid,
+                &credential.public_key,
+                &(credential.sign_count as i64),
+                &credential.transports,
+                &name,
+                &credential.backed_up,
+            ],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to store passkey: {e}")))?
+        .map(passkey_from_row)
+        .ok_or_else(|| napi::Error::from_reason("Passkey is already registered"))?;
+
+    let after = audit_snapshot(&tx, &uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::PasskeyAdd,
+        Some(&uid),
+        audit::diff(&before, &after),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(passkey)
+}
+
+/// Options for `navigator.credentials.get()`. No credentials are listed, the user picks
+/// one of their passkeys and it tells who they are, so no email is needed up front.
+pub async fn begin_passkey_authentication() -> napi::Result<Value> {
+    let challenge = issue_challenge(AUTHENTICATION_CEREMONY.to_string()).await?;
+    Ok(relying_party().request_options(&challenge, &[]))
+}
+
+/// Logs in with the browser's answer to `begin_passkey_authentication`. Passkeys are
+/// user verified, so they count as both factors and TOTP isn't asked for. Failed
+/// assertions count towards the account's lock like wrong passwords.
+pub async fn finish_passkey_authentication(response: Value) -> napi::Result<LoginResult> {
+    let response: AuthenticationResponse =
+        serde_json::from_value(response).map_err(malformed_response)?;
+
+    let (challenge, ceremony) = take_challenge(&response.response.client_data_json).await?;
+    if ceremony.as_deref() != Some(AUTHENTICATION_CEREMONY) {
+        return Ok(LoginResult::rejected(LoginOutcome::Invalid, 0, 0));
+    }
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "SELECT k.user_uid::text as uid, u.email, u.email_verified_at IS NOT NULL as verified,
+                k.public_key, k.sign_count
+             FROM public.User_Passkeys k
+             JOIN public.Users u ON u.uid = k.user_uid
+             WHERE k.credential_id = $1 AND u.deleted_at IS NULL",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let Some(row) = client
+        .query_opt(&stmt, &[&response.id])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+    else {
+        return Ok(LoginResult::rejected(LoginOutcome::Invalid, 0, 0));
+    };
+    let uid: String = row.get("uid");
+    let email: String = row.get("email");
+
+    // The handle is the uid given at registration, a mismatch means a confused client
+    if let Some(handle) = &response.response.user_handle
+        && *handle != base64url_encode(uid.as_bytes())
+    {
+        return Ok(LoginResult::rejected(LoginOutcome::Invalid, 0, 0));
+    }
+
+    if let Some(locked) = check_unlocked(&email).await? {
+        return Ok(locked);
+    }
+
+    let public_key: Vec<u8> = row.get("public_key");
+    let stored_sign_count: i64 = row.get("sign_count");
+    let used = match relying_party().verify_authentication(
+        &challenge,
+        &response,
+        &public_key,
+        stored_sign_count as u32,
+    ) {
+        Ok(used) => used,
+        Err(_) => return record_failure(&email).await,
+    };
+
+    let stmt = client
+        .prepare_cached(
+            "UPDATE public.User_Passkeys
+             SET sign_count = $2, backed_up = $3, last_used_at = now()
+             WHERE credential_id = $1",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    client
+        .execute(
+            &stmt,
+            &[&response.id, &(used.sign_count as i64), &used.backed_up],
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to update passkey: {e}")))?;
+
+    redis_handler::clear_login_failures(email)
+        .await
+        .map_err(lockout_error)?;
+
+    if !rbac::unverified_email_policy().allows_login(row.get("verified")) {
+        return Ok(LoginResult::rejected(LoginOutcome::Unverified, 0, 0));
+    }
+
+    Ok(LoginResult::valid(user_from_uid(&uid).await?))
+}
+
+/// The user's passkeys, oldest first
+pub async fn list_passkeys(uid: String) -> napi::Result<Vec<Passkey>> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(&format!(
+            "SELECT {PASSKEY_COLUMNS} FROM public.User_Passkeys
+             WHERE user_uid = CAST($1 AS TEXT)::uuid
+             ORDER BY created_at"
+        ))
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(client
+        .query(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .into_iter()
+        .map(passkey_from_row)
+        .collect())
+}
+
+/// Removes one of the user's passkeys. Returns false if they have no such passkey,
+/// refused if it is their last way to log in.
+pub async fn delete_passkey(
+    uid: String,
+    credential_id: String,
+    audit: AuditContext,
+) -> napi::Result<bool> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let login_methods = lock_login_methods(&tx, &uid).await?;
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
+        .prepare_cached(
+            "DELETE FROM public.User_Passkeys
+             WHERE credential_id = $1 AND user_uid = CAST($2 AS TEXT)::uuid",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let deleted = tx
+        .execute(&stmt, &[&credential_id, &uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to delete passkey: {e}")))?;
+    if deleted == 0 {
+        return Ok(false);
+    }
+    if login_methods <= 1 {
+        return Err(napi::Error::from_reason(
+            "Can't remove the last way to log in, set a password or link a provider first",
+        ));
+    }
+
+    let after = audit_snapshot(&tx, &uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::PasskeyRemove,
+        Some(&uid),
+        audit::diff(&before, &after),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(true)
+}
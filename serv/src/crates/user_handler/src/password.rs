@@ -0,0 +1,323 @@
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version,
+    password_hash::{PasswordHasher, SaltString},
+};
+use deadpool_postgres::GenericClient;
+use hmac::{Hmac, Mac};
+use napi_derive::napi;
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+/// Argon2id cost parameters for new hashes. Unset fields fall back to the
+/// `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS` and `ARGON2_PARALLELISM` env vars, then to
+/// the argon2 crate defaults.
+#[derive(Debug, Clone, Copy, Default)]
+#[napi(object)]
+pub struct HashConfig {
+    pub memory_kib: Option<u32>,
+    pub iterations: Option<u32>,
+    pub parallelism: Option<u32>,
+}
+
+static HASH_PARAMS: OnceLock<Params> = OnceLock::new();
+
+fn env_u32(name: &str) -> Option<u32> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+fn params_from(config: HashConfig) -> napi::Result<Params> {
+    Params::new(
+        config
+            .memory_kib
+            .or_else(|| env_u32("ARGON2_MEMORY_KIB"))
+            .unwrap_or(Params::DEFAULT_M_COST),
+        config
+            .iterations
+            .or_else(|| env_u32("ARGON2_ITERATIONS"))
+            .unwrap_or(Params::DEFAULT_T_COST),
+        config
+            .parallelism
+            .or_else(|| env_u32("ARGON2_PARALLELISM"))
+            .unwrap_or(Params::DEFAULT_P_COST),
+        None,
+    )
+    .map_err(|e| napi::Error::from_reason(format!("Invalid Argon2 parameters: {e}")))
+}
+
+/// Sets the parameters for new hashes. Only possible before the first password is hashed
+/// or verified, later calls fail rather than silently mixing parameters. Also builds the
+/// dummy hash, so a broken pepper setup fails here rather than on the first login.
+pub fn configure_hashing(config: HashConfig) -> napi::Result<()> {
+    HASH_PARAMS
+        .set(params_from(config)?)
+        .map_err(|_| napi::Error::from_reason("Password hashing is already configured"))?;
+    dummy_hash().map(|_| ())
+}
+
+fn hash_params() -> napi::Result<&'static Params> {
+    if let Some(params) = HASH_PARAMS.get() {
+        return Ok(params);
+    }
+    let params = params_from(HashConfig::default())?;
+    Ok(HASH_PARAMS.get_or_init(|| params))
+}
+
+fn hasher() -> napi::Result<Argon2<'static>> {
+    Ok(Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        hash_params()?.clone(),
+    ))
+}
+
+/// A secret mixed into passwords with HMAC-SHA256 before hashing, so a leaked database
+/// alone isn't enough to crack them
+struct Pepper {
+    id: String,
+    key: Vec<u8>,
+}
+
+static PEPPERS: OnceLock<Vec<Pepper>> = OnceLock::new();
+
+/// Pepper keys from `PASSWORD_PEPPERS` as comma separated `id:secret` pairs. The first one
+/// peppers new hashes, the rest only verify old ones and should stay listed until none of
+/// their hashes are left. Unset means no pepper.
+fn peppers() -> napi::Result<&'static [Pepper]> {
+    if let Some(peppers) = PEPPERS.get() {
+        return Ok(peppers);
+    }
+
+    let mut peppers: Vec<Pepper> = Vec::new();
+    let configured = std::env::var("PASSWORD_PEPPERS").unwrap_or_default();
+    for entry in configured
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let Some((id, secret)) = entry
+            .split_once(':')
+            .filter(|(id, secret)| !id.is_empty() && id.len() <= 64 && !secret.is_empty())
+        else {
+            return Err(napi::Error::from_reason(
+                "Invalid PASSWORD_PEPPERS entry, expected id:secret",
+            ));
+        };
+        if peppers.iter().any(|p| p.id == id) {
+            return Err(napi::Error::from_reason(format!(
+                "Duplicate PASSWORD_PEPPERS key: {id}"
+            )));
+        }
+        peppers.push(Pepper {
+            id: id.to_string(),
+            key: secret.as_bytes().to_vec(),
+        });
+    }
+    Ok(PEPPERS.get_or_init(|| peppers))
+}
+
+fn current_pepper_id() -> napi::Result<Option<&'static str>> {
+    Ok(peppers()?.first().map(|p| p.id.as_str()))
+}
+
+fn apply_pepper(pass: &str, pepper_id: Option<&str>) -> napi::Result<Vec<u8>> {
+    let Some(id) = pepper_id else {
+        return Ok(pass.as_bytes().to_vec());
+    };
+    let pepper = peppers()?
+        .iter()
+        .find(|p| p.id == id)
+        .ok_or_else(|| napi::Error::from_reason(format!("Unknown password pepper key: {id}")))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&pepper.key)
+        .map_err(|e| napi::Error::from_reason(format!("Invalid pepper key: {e}")))?;
+    mac.update(pass.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// What goes in `Users.Password_Hash` and `Users.Password_Pepper_Id`
+pub(crate) struct StoredHash {
+    pub hash: String,
+    pub pepper_id: Option<String>,
+}
+
+pub(crate) fn hash_password(pass: &str) -> napi::Result<StoredHash> {
+    let pepper_id = current_pepper_id()?;
+    let salt = SaltString::generate(&mut rand_core::OsRng);
+    let hash = hasher()?
+        .hash_password(&apply_pepper(pass, pepper_id)?, &salt)
+        .map_err(|e| napi::Error::from_reason(format!("Hashing failed: {e}")))?
+        .to_string();
+    Ok(StoredHash {
+        hash,
+        pepper_id: pepper_id.map(str::to_string),
+    })
+}
+
+static DUMMY_HASH: OnceLock<StoredHash> = OnceLock::new();
+
+/// Verified against when there is no real hash, made with the same parameters and pepper
+/// as new hashes. It is always Argon2, so an account still on a legacy bcrypt or scrypt
+/// hash answers in a different time than a missing one. That leak is accepted, legacy
+/// hashes only last until their owner's next login.
+fn dummy_hash() -> napi::Result<&'static StoredHash> {
+    if let Some(dummy) = DUMMY_HASH.get() {
+        return Ok(dummy);
+    }
+    let dummy = hash_password("dummy password for missing accounts")?;
+    Ok(DUMMY_HASH.get_or_init(|| dummy))
+}
+
+pub(crate) struct Verification {
+    pub valid: bool,
+    /// The hash is from a legacy scheme, weaker than the configured parameters or not
+    /// peppered with the current key
+    pub needs_rehash: bool,
+    /// The hash is peppered with a key no longer in `PASSWORD_PEPPERS`, so it couldn't be
+    /// checked and `valid` is false. For the caller to report, the user can only reset.
+    pub unknown_pepper: Option<String>,
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn is_weaker_argon2(parsed: &PasswordHash, wanted: &Params) -> bool {
+    let Ok(params) = Params::try_from(parsed) else {
+        return true;
+    };
+    parsed.algorithm != Algorithm::Argon2id.ident()
+        || parsed.version != Some(Version::V0x13.into())
+        || params.m_cost() < wanted.m_cost()
+        || params.t_cost() < wanted.t_cost()
+        || params.p_cost() < wanted.p_cost()
+}
+
+/// Checks `pass` against an Argon2, bcrypt or scrypt hash, peppered with `pepper_id` if
+/// set. Without a hash it burns the same time on the dummy hash and fails, so missing
+/// accounts can't be told apart.
+pub(crate) fn verify_password(
+    hash: Option<&str>,
+    pepper_id: Option<&str>,
+    pass: &str,
+) -> napi::Result<Verification> {
+    let Some(hash) = hash else {
+        let dummy = dummy_hash()?;
+        let parsed = PasswordHash::new(&dummy.hash)
+            .map_err(|e| napi::Error::from_reason(format!("Invalid dummy hash: {e}")))?;
+        let input = apply_pepper(pass, dummy.pepper_id.as_deref())?;
+        let _ = hasher()?.verify_password(&input, &parsed);
+        return Ok(Verification {
+            valid: false,
+            needs_rehash: false,
+            unknown_pepper: None,
+        });
+    };
+
+    // Imported from legacy systems and never peppered, replaced with a peppered Argon2
+    // hash on the next successful login
+    if is_bcrypt(hash) {
+        let valid = bcrypt::verify(pass, hash)
+            .map_err(|e| napi::Error::from_reason(format!("Failed to parse password hash: {e}")))?;
+        return Ok(Verification {
+            valid,
+            needs_rehash: true,
+            unknown_pepper: None,
+        });
+    }
+
+    let parsed = PasswordHash::new(hash)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to parse password hash: {e}")))?;
+
+    if parsed.algorithm == scrypt::ALG_ID {
+        return Ok(Verification {
+            valid: scrypt::Scrypt
+                .verify_password(pass.as_bytes(), &parsed)
+                .is_ok(),
+            needs_rehash: true,
+            unknown_pepper: None,
+        });
+    }
+
+    if let Some(id) = pepper_id
+        && !peppers()?.iter().any(|p| p.id == id)
+    {
+        // Its key was dropped from PASSWORD_PEPPERS while hashes still used it
+        return Ok(Verification {
+            valid: false,
+            needs_rehash: false,
+            unknown_pepper: Some(id.to_string()),
+        });
+    }
+
+    // Verification reads the parameters from the hash itself, the configured ones only
+    // decide whether it's due for an upgrade
+    let input = apply_pepper(pass, pepper_id)?;
+    Ok(Verification {
+        valid: hasher()?.verify_password(&input, &parsed).is_ok(),
+        needs_rehash: is_weaker_argon2(&parsed, hash_params()?)
+            || pepper_id != current_pepper_id()?,
+        unknown_pepper: None,
+    })
+}
+
+/// Replaces a legacy, weak or stale peppered hash after `pass` was verified against it. Only swaps if the
+/// stored hash is still `old_hash`, so a password changed in the meantime is kept. Failing
+/// to upgrade doesn't affect the login, it's retried on the next one.
+pub(crate) async fn upgrade_hash(
+    client: &impl GenericClient,
+    email: &str,
+    old_hash: &str,
+    pass: &str,
+) {
+    let Ok(new) = hash_password(pass) else {
+        return;
+    };
+    let Ok(stmt) = client
+        .prepare_cached(
+            "UPDATE public.Users SET password_hash = $1, password_pepper_id = $2
+             WHERE email = $3 AND password_hash = $4",
+        )
+        .await
+    else {
+        return;
+    };
+    let _ = client
+        .execute(&stmt, &[&new.hash, &new.pepper_id, &email, &old_hash])
+        .await;
+}
+
+/// Whether a hash is in a format `verify_password` understands, for imports
+pub(crate) fn is_supported_hash(hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        return hash.parse::<bcrypt::HashParts>().is_ok();
+    }
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        parsed.algorithm == scrypt::ALG_ID
+            || [Algorithm::Argon2id, Algorithm::Argon2i, Algorithm::Argon2d]
+                .iter()
+                .any(|alg| parsed.algorithm == alg.ident())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_hashes_with_an_unknown_pepper() {
+        // No PASSWORD_PEPPERS in tests, so new hashes are unpeppered
+        let stored = hash_password("correct horse").unwrap();
+        assert_eq!(stored.pepper_id, None);
+
+        let plain = verify_password(Some(&stored.hash), None, "correct horse").unwrap();
+        assert!(plain.valid);
+        assert_eq!(plain.unknown_pepper, None);
+
+        let gone = verify_password(Some(&stored.hash), Some("2019"), "correct horse").unwrap();
+        assert!(!gone.valid);
+        assert!(!gone.needs_rehash);
+        assert_eq!(gone.unknown_pepper.as_deref(), Some("2019"));
+    }
+}
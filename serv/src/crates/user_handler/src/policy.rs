@@ -0,0 +1,702 @@
+use aws_lc_rs::digest::{SHA1_FOR_LEGACY_USE_ONLY, digest};
+use napi_derive::napi;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::sync::OnceLock;
+
+const DEFAULT_MIN_LENGTH: u32 = 8;
+const DEFAULT_MAX_LENGTH: u32 = 128;
+const DEFAULT_MIN_SCORE: u32 = 2;
+/// Longest stretch `find_patterns` tries as a word, well past every built in pattern
+const MAX_PATTERN_LEN: usize = 12;
+
+/// Rules new passwords must meet. Unset fields use the defaults: 8 to 128 characters,
+/// no required character classes, a strength score of at least 2, and the breached
+/// password file from `BREACHED_PASSWORDS_FILE` if that is set.
+#[derive(Debug, Clone, Default)]
+#[napi(object)]
+pub struct PasswordPolicy {
+    pub min_length: Option<u32>,
+    pub max_length: Option<u32>,
+    pub require_lowercase: Option<bool>,
+    pub require_uppercase: Option<bool>,
+    pub require_digit: Option<bool>,
+    pub require_symbol: Option<bool>,
+    /// 0 to 4, see `PasswordCheck::score`
+    pub min_score: Option<u32>,
+    /// Uppercase SHA-1 hashes or hash prefixes one per line, sorted, optionally followed by
+    /// `:count` as in the Have I Been Pwned downloads. Searched in place, never loaded.
+    pub breached_file: Option<String>,
+}
+
+#[napi(string_enum = "snake_case")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyViolationCode {
+    TooShort,
+    TooLong,
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    ContainsEmail,
+    TooWeak,
+    Breached,
+}
+
+#[napi(object)]
+pub struct PolicyViolation {
+    pub code: PolicyViolationCode,
+    pub message: String,
+}
+
+#[napi(object)]
+pub struct PasswordCheck {
+    pub ok: bool,
+    pub violations: Vec<PolicyViolation>,
+    /// Strength from 0 (guessable in under a thousand tries) to 4 (over ten billion)
+    pub score: u32,
+    pub guesses_log10: f64,
+}
+
+static POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+
+/// Sets the policy for every later check. Like the hashing config, only once.
+pub fn configure_password_policy(policy: PasswordPolicy) -> napi::Result<()> {
+    POLICY
+        .set(policy)
+        .map_err(|_| napi::Error::from_reason("Password policy is already configured"))
+}
+
+fn policy() -> &'static PasswordPolicy {
+    POLICY.get_or_init(PasswordPolicy::default)
+}
+
+fn violation(code: PolicyViolationCode, message: impl Into<String>) -> PolicyViolation {
+    PolicyViolation {
+        code,
+        message: message.into(),
+    }
+}
+
+/// Checks `pass` against the configured policy. `email` is the account's, if known, and
+/// is both forbidden in the password and counted as an easy guess by the estimator.
+pub async fn check_password(pass: String, email: Option<String>) -> napi::Result<PasswordCheck> {
+    let policy = policy();
+    let mut violations = Vec::new();
+
+    let length = pass.chars().count() as u32;
+    let min_length = policy.min_length.unwrap_or(DEFAULT_MIN_LENGTH);
+    let max_length = policy.max_length.unwrap_or(DEFAULT_MAX_LENGTH);
+    if length < min_length {
+        violations.push(violation(
+            PolicyViolationCode::TooShort,
+            format!("Must be at least {min_length} characters"),
+        ));
+    }
+    if length > max_length {
+        // Rejected either way, and nothing below needs to run over an oversized input
+        return Ok(PasswordCheck {
+            ok: false,
+            violations: vec![violation(
+                PolicyViolationCode::TooLong,
+                format!("Must be at most {max_length} characters"),
+            )],
+            score: 0,
+            guesses_log10: 0.0,
+        });
+    }
+
+    let classes = [
+        (
+            policy.require_lowercase,
+            pass.chars().any(char::is_lowercase),
+            PolicyViolationCode::MissingLowercase,
+            "Must contain a lowercase letter",
+        ),
+        (
+            policy.require_uppercase,
+            pass.chars().any(char::is_uppercase),
+            PolicyViolationCode::MissingUppercase,
+            "Must contain an uppercase letter",
+        ),
+        (
+            policy.require_digit,
+            pass.chars().any(|c| c.is_ascii_digit()),
+            PolicyViolationCode::MissingDigit,
+            "Must contain a digit",
+        ),
+        (
+            policy.require_symbol,
+            pass.chars().any(|c| !c.is_alphanumeric()),
+            PolicyViolationCode::MissingSymbol,
+            "Must contain a symbol",
+        ),
+    ];
+    for (required, present, code, message) in classes {
+        if required.unwrap_or(false) && !present {
+            violations.push(violation(code, message));
+        }
+    }
+
+    let lowered = pass.to_lowercase();
+    let email = email.map(|e| e.to_lowercase());
+    let local_part = email
+        .as_deref()
+        .and_then(|e| e.split('@').next())
+        .filter(|l| l.chars().count() >= 3);
+    if let Some(email) = &email
+        && (lowered.contains(email.as_str()) || local_part.is_some_and(|l| lowered.contains(l)))
+    {
+        violations.push(violation(
+            PolicyViolationCode::ContainsEmail,
+            "Must not contain the email address",
+        ));
+    }
+
+    let estimated = pass.clone();
+    let user_inputs: Vec<String> = local_part.map(str::to_string).into_iter().collect();
+    let guesses_log10 = tokio::task::spawn_blocking(move || {
+        let user_inputs: Vec<&str> = user_inputs.iter().map(String::as_str).collect();
+        estimate_guesses_log10(&estimated, &user_inputs)
+    })
+    .await
+    .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    let score = score_for(guesses_log10);
+    let min_score = policy.min_score.unwrap_or(DEFAULT_MIN_SCORE);
+    if score < min_score {
+        violations.push(violation(
+            PolicyViolationCode::TooWeak,
+            "Too easy to guess, try a longer password or an uncommon phrase",
+        ));
+    }
+
+    let breached_file = policy
+        .breached_file
+        .clone()
+        .or_else(|| std::env::var("BREACHED_PASSWORDS_FILE").ok());
+    if let Some(path) = breached_file {
+        let hash = sha1_hex(&pass);
+        let found = tokio::task::spawn_blocking(move || is_breached(&path, &hash))
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))??;
+        if found {
+            violations.push(violation(
+                PolicyViolationCode::Breached,
+                "Appears in a known data breach",
+            ));
+        }
+    }
+
+    Ok(PasswordCheck {
+        ok: violations.is_empty(),
+        violations,
+        score,
+        guesses_log10,
+    })
+}
+
+/// `check_password` as an error, for the Rust side paths that set passwords
+pub(crate) async fn enforce_password(pass: &str, email: Option<&str>) -> napi::Result<()> {
+    let check = check_password(pass.to_string(), email.map(str::to_string)).await?;
+    if check.ok {
+        return Ok(());
+    }
+    let reasons: Vec<String> = check.violations.into_iter().map(|v| v.message).collect();
+    Err(napi::Error::from_reason(format!(
+        "Password rejected: {}",
+        reasons.join(", ")
+    )))
+}
+
+fn sha1_hex(pass: &str) -> String {
+    digest(&SHA1_FOR_LEGACY_USE_ONLY, pass.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect()
+}
+
+/// Compares a file line's hash, possibly a prefix, against the full hash
+fn compare_line(line: &str, hash: &str) -> Ordering {
+    let stored = line.split(':').next().unwrap_or("").trim();
+    if stored.is_empty() {
+        // Stray blank lines, usually trailing, sort after everything
+        return Ordering::Greater;
+    }
+    let wanted = hash.get(..stored.len()).unwrap_or(hash);
+    stored.to_ascii_uppercase().as_str().cmp(wanted)
+}
+
+/// Binary search over byte offsets, reading one line per step
+fn is_breached(path: &str, hash: &str) -> napi::Result<bool> {
+    let file = File::open(path)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to open {path}: {e}")))?;
+    let len = file
+        .metadata()
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .len();
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+
+    // Reads the first line starting at or after `offset`, returning where it starts. Reading
+    // on from the byte before lands exactly on `offset` when a line starts there.
+    let mut line_at = |offset: u64, line: &mut String| -> napi::Result<u64> {
+        let from = offset.saturating_sub(1);
+        reader
+            .seek(SeekFrom::Start(from))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        let mut start = from;
+        if offset > 0 {
+            line.clear();
+            start += reader
+                .read_line(line)
+                .map_err(|e| napi::Error::from_reason(e.to_string()))? as u64;
+        }
+        line.clear();
+        reader
+            .read_line(line)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        Ok(start)
+    };
+
+    // The line we want, if present, starts in [low, high)
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let start = line_at(mid, &mut line)?;
+        if start >= high || line.is_empty() {
+            high = mid;
+            continue;
+        }
+        match compare_line(&line, hash) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => low = start + line.len() as u64,
+            Ordering::Greater => high = mid,
+        }
+    }
+    Ok(false)
+}
+
+// A small take on zxcvbn: the password is split into the cheapest sequence of known
+// patterns and brute forced characters, and the guesses for each piece multiplied.
+
+/// Most common first, the rank is the guess count
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "123456",
+    "qwerty",
+    "letmein",
+    "welcome",
+    "admin",
+    "iloveyou",
+    "monkey",
+    "dragon",
+    "football",
+    "baseball",
+    "sunshine",
+    "princess",
+    "master",
+    "shadow",
+    "superman",
+    "trustno1",
+    "login",
+    "abc123",
+    "starwars",
+    "hello",
+    "freedom",
+    "whatever",
+    "charlie",
+    "batman",
+    "access",
+    "secret",
+    "summer",
+    "winter",
+    "spring",
+    "autumn",
+    "love",
+    "changeme",
+    "default",
+    "test",
+    "guest",
+    "root",
+    "user",
+    "pass",
+    "computer",
+    "internet",
+    "soccer",
+    "hockey",
+    "killer",
+    "pepper",
+    "ginger",
+    "cheese",
+    "cookie",
+    "flower",
+    "orange",
+    "banana",
+    "apple",
+    "chocolate",
+    "london",
+    "google",
+    "samsung",
+    "mustang",
+    "jordan",
+    "harley",
+    "ranger",
+    "buster",
+    "tigger",
+    "hunter",
+    "thomas",
+    "robert",
+    "michael",
+    "daniel",
+    "jennifer",
+    "jessica",
+    "ashley",
+    "amanda",
+    "andrew",
+    "joshua",
+    "matthew",
+    "maggie",
+    "taylor",
+    "secure",
+    "system",
+    "server",
+    "database",
+    "account",
+    "family",
+    "friend",
+    "money",
+    "happy",
+    "music",
+    "silver",
+    "golden",
+    "purple",
+    "yellow",
+    "hello123",
+];
+
+const KEYBOARD_ROWS: &[&str] = &["qwertyuiop", "asdfghjkl", "zxcvbnm", "1234567890"];
+
+fn unleet(c: char) -> char {
+    match c {
+        '@' | '4' => 'a',
+        '0' => 'o',
+        '1' | '!' => 'i',
+        '3' => 'e',
+        '$' | '5' => 's',
+        '7' => 't',
+        _ => c,
+    }
+}
+
+fn pool_size(pass: &[char]) -> f64 {
+    let mut pool = 0.0;
+    if pass.iter().any(|c| c.is_ascii_lowercase()) {
+        pool += 26.0;
+    }
+    if pass.iter().any(|c| c.is_ascii_uppercase()) {
+        pool += 26.0;
+    }
+    if pass.iter().any(|c| c.is_ascii_digit()) {
+        pool += 10.0;
+    }
+    if pass.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        pool += 33.0;
+    }
+    if pass.iter().any(|c| !c.is_ascii()) {
+        pool += 100.0;
+    }
+    f64::max(pool, 10.0)
+}
+
+/// Every pattern found as `(start, end, guesses)` over char indices, `end` exclusive
+fn find_patterns(pass: &[char], user_inputs: &[&str]) -> Vec<(usize, usize, f64)> {
+    let mut found = Vec::new();
+    let lower: Vec<char> = pass.iter().flat_map(|c| c.to_lowercase()).collect();
+    // Lowercasing can change the length for some scripts, then only exact patterns apply
+    let lower = if lower.len() == pass.len() {
+        lower
+    } else {
+        pass.to_vec()
+    };
+    let unleeted: Vec<char> = lower.iter().map(|c| unleet(*c)).collect();
+
+    let capitalised = |start: usize, end: usize| {
+        if pass[start..end].iter().any(|c| c.is_uppercase()) {
+            2.0
+        } else {
+            1.0
+        }
+    };
+
+    // User inputs can be longer than the other patterns, so they're searched for directly
+    for input in user_inputs {
+        let input: Vec<char> = input.chars().collect();
+        if input.len() < 3 || input.len() > pass.len() {
+            continue;
+        }
+        for start in 0..=pass.len() - input.len() {
+            let end = start + input.len();
+            if lower[start..end] == input[..] || unleeted[start..end] == input[..] {
+                found.push((start, end, capitalised(start, end)));
+            }
+        }
+    }
+
+    for start in 0..pass.len() {
+        for end in start + 3..=pass.len().min(start + MAX_PATTERN_LEN) {
+            let word: String = lower[start..end].iter().collect();
+            let plain: String = unleeted[start..end].iter().collect();
+            let capitalised = capitalised(start, end);
+
+            let ranked = COMMON_PASSWORDS
+                .iter()
+                .position(|w| *w == word)
+                .map(|rank| rank as f64 + 1.0)
+                .or_else(|| {
+                    COMMON_PASSWORDS
+                        .iter()
+                        .position(|w| *w == plain)
+                        .map(|rank| (rank as f64 + 1.0) * 2.0)
+                });
+            if let Some(guesses) = ranked {
+                found.push((start, end, guesses * capitalised));
+            }
+
+            let len = end - start;
+            if len >= 4 {
+                let reversed: String = word.chars().rev().collect();
+                if KEYBOARD_ROWS.iter().any(|row| row.contains(word.as_str())) {
+                    found.push((start, end, 40.0 * len as f64));
+                } else if KEYBOARD_ROWS
+                    .iter()
+                    .any(|row| row.contains(reversed.as_str()))
+                {
+                    found.push((start, end, 80.0 * len as f64));
+                }
+
+                if len == 4
+                    && let Ok(year) = word.parse::<u32>()
+                    && (1900..=2039).contains(&year)
+                {
+                    found.push((start, end, 140.0));
+                }
+            }
+        }
+    }
+
+    // Runs of one character and steady sequences such as `abcd` or `9876`
+    let mut start = 0;
+    while start < pass.len() {
+        let mut end = start + 1;
+        while end < pass.len() && lower[end] == lower[start] {
+            end += 1;
+        }
+        if end - start >= 3 {
+            found.push((
+                start,
+                end,
+                pool_size(&pass[start..=start]) * (end - start) as f64,
+            ));
+        }
+        start = end;
+    }
+
+    let mut start = 0;
+    while start + 1 < lower.len() {
+        let delta = lower[start + 1] as i64 - lower[start] as i64;
+        let mut end = start + 2;
+        while end < lower.len() && lower[end] as i64 - lower[end - 1] as i64 == delta {
+            end += 1;
+        }
+        if delta.abs() == 1 && end - start >= 3 {
+            let first = lower[start];
+            let base = if matches!(first, 'a' | 'z' | '0' | '1' | '9') {
+                4.0
+            } else if first.is_ascii_digit() {
+                10.0
+            } else {
+                26.0
+            };
+            let direction = if delta < 0 { 2.0 } else { 1.0 };
+            found.push((start, end, base * direction * (end - start) as f64));
+        }
+        start = end - 1;
+    }
+
+    found
+}
+
+fn estimate_guesses_log10(pass: &str, user_inputs: &[&str]) -> f64 {
+    let chars: Vec<char> = pass.chars().collect();
+    if chars.is_empty() {
+        return 0.0;
+    }
+    let per_char = pool_size(&chars).log10();
+    let mut ending_at: Vec<Vec<(usize, f64)>> = vec![Vec::new(); chars.len() + 1];
+    for (start, end, guesses) in find_patterns(&chars, user_inputs) {
+        ending_at[end].push((start, guesses));
+    }
+
+    // cheapest[i] is the fewest guesses, as log10, to get the first i characters right
+    let mut cheapest = vec![f64::INFINITY; chars.len() + 1];
+    cheapest[0] = 0.0;
+    for end in 1..=chars.len() {
+        cheapest[end] = cheapest[end - 1] + per_char;
+        for (start, guesses) in &ending_at[end] {
+            cheapest[end] = cheapest[end].min(cheapest[*start] + guesses.log10());
+        }
+    }
+    cheapest[chars.len()]
+}
+
+fn score_for(guesses_log10: f64) -> u32 {
+    match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(pass: &str, user_inputs: &[&str]) -> u32 {
+        score_for(estimate_guesses_log10(pass, user_inputs))
+    }
+
+    #[test]
+    fn common_and_keyboard_passwords_score_low() {
+        for pass in ["password", "P@ssw0rd", "qwertyuiop", "abcdefgh", "aaaaaaaa"] {
+            assert_eq!(score(pass, &[]), 0, "{pass}");
+        }
+    }
+
+    #[test]
+    fn random_and_long_passwords_score_high() {
+        for pass in ["correct horse battery staple", "xK9#mQ2$vL7@"] {
+            assert_eq!(score(pass, &[]), 4, "{pass}");
+        }
+    }
+
+    #[test]
+    fn user_inputs_are_easy_guesses() {
+        assert_eq!(score("jdoe1990", &["jdoe"]), 0);
+        assert!(score("jdoe1990", &[]) >= 2);
+        // Longer than any other pattern, still found
+        let name = "averyveryverylongname";
+        assert!(name.len() > MAX_PATTERN_LEN);
+        assert_eq!(score("averyveryverylongname!", &[name]), 0);
+    }
+
+    #[test]
+    fn patterns_stay_within_the_length_cap() {
+        let pass: Vec<char> = "password".repeat(100).chars().collect();
+        let patterns = find_patterns(&pass, &[]);
+        assert!(!patterns.is_empty());
+        assert!(
+            patterns
+                .iter()
+                .all(|(start, end, _)| end - start <= MAX_PATTERN_LEN)
+        );
+    }
+
+    #[tokio::test]
+    async fn over_max_length_is_rejected_without_scoring() {
+        let pass = "xK9#mQ2$".repeat(DEFAULT_MAX_LENGTH as usize);
+        let check = check_password(pass, None).await.unwrap();
+        assert!(!check.ok);
+        assert_eq!(check.violations.len(), 1);
+        assert_eq!(check.violations[0].code, PolicyViolationCode::TooLong);
+        assert_eq!(check.score, 0);
+    }
+
+    /// A sorted breached file in the temp dir, removed on drop
+    struct BreachedFile(std::path::PathBuf);
+
+    impl BreachedFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("breached-{}-{name}.txt", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            BreachedFile(path)
+        }
+
+        fn contains(&self, hash: &str) -> bool {
+            is_breached(self.0.to_str().unwrap(), hash).unwrap()
+        }
+    }
+
+    impl Drop for BreachedFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn sorted_hashes(count: usize) -> Vec<String> {
+        let mut hashes: Vec<String> = (0..count).map(|i| sha1_hex(&format!("pw{i}"))).collect();
+        hashes.sort();
+        hashes
+    }
+
+    #[test]
+    fn finds_every_line_of_a_breached_file() {
+        let hashes = sorted_hashes(200);
+        let lines: Vec<String> = hashes
+            .iter()
+            .enumerate()
+            .map(|(i, h)| format!("{h}:{}", i + 1))
+            .collect();
+        let file = BreachedFile::new("counts", &(lines.join("\n") + "\n"));
+        for hash in &hashes {
+            assert!(file.contains(hash), "{hash}");
+        }
+        assert!(!file.contains(&sha1_hex("not in the file")));
+        assert!(!file.contains(&"0".repeat(40)));
+        assert!(!file.contains(&"F".repeat(40)));
+    }
+
+    #[test]
+    fn handles_crlf_blank_lines_and_no_counts() {
+        let hashes = sorted_hashes(50);
+        let (first, middle, last) = (&hashes[0], &hashes[25], &hashes[49]);
+        let missing = sha1_hex("not in the file");
+        for (name, contents) in [
+            ("crlf", hashes.join("\r\n") + "\r\n"),
+            ("blank", hashes.join("\n") + "\n\n\n"),
+            ("crlf-blank", hashes.join("\r\n") + "\r\n\r\n"),
+            ("unterminated", hashes.join("\n")),
+            ("lowercase", hashes.join("\n").to_lowercase()),
+        ] {
+            let file = BreachedFile::new(name, &contents);
+            for hash in [first, middle, last] {
+                assert!(file.contains(hash), "{name}: {hash}");
+            }
+            assert!(!file.contains(&missing), "{name}");
+        }
+    }
+
+    #[test]
+    fn prefix_lines_match_every_hash_they_start() {
+        let file = BreachedFile::new("prefixes", "0A1B2:3\n5BAA6\n5BAA61E4:7\nFFFFF:1\n");
+        assert!(file.contains("0A1B2C3D4E5F60718293A4B5C6D7E8F901234567"));
+        // `password`, under both its 5 and 8 character prefix
+        assert!(file.contains("5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8"));
+        assert!(file.contains("5BAA6FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"));
+        assert!(file.contains(&"F".repeat(40)));
+        assert!(!file.contains("5BAA5FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"));
+        assert!(!file.contains("0A1B1FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"));
+    }
+
+    #[test]
+    fn single_line_and_empty_files() {
+        let hash = sha1_hex("password");
+        assert!(BreachedFile::new("single", &format!("{hash}:1")).contains(&hash));
+        assert!(!BreachedFile::new("empty", "").contains(&hash));
+        assert!(!BreachedFile::new("blank-only", "\n\n").contains(&hash));
+    }
+}
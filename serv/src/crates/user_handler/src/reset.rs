@@ -0,0 +1,78 @@
+use crate::{
+    policy,
+    tokens::{hash_token, link_vars, mail_error, new_token, token_error, ttl_from_env},
+    update_user, user_from_email, user_from_uid, verification,
+};
+use audit::AuditContext;
+use redis_handler::UserTokenKind;
+use shared_types::User;
+
+const DEFAULT_RESET_TTL_SECONDS: u64 = 60 * 60;
+
+/// Mails a single-use reset link, `reset_url` with the token added as a `token` query
+/// parameter. Unknown and deleted accounts get nothing but the call still succeeds, so it
+/// doesn't tell anyone which emails have accounts. `locale` picks the mail's language.
+pub async fn request_password_reset(
+    email: String,
+    reset_url: String,
+    locale: Option<String>,
+) -> napi::Result<()> {
+    let Some(user) = user_from_email(&email).await? else {
+        return Ok(());
+    };
+
+    let (token, token_hash) = new_token();
+    // How long a reset link works
+    let ttl = ttl_from_env("PASSWORD_RESET_TTL_SECONDS", DEFAULT_RESET_TTL_SECONDS);
+    redis_handler::store_user_token(UserTokenKind::PasswordReset, token_hash, user.uid, ttl)
+        .await
+        .map_err(token_error)?;
+
+    mailer::send_template(
+        "password_reset",
+        locale.as_deref(),
+        &user.email,
+        &link_vars(&reset_url, &token, ttl),
+    )
+    .await
+    .map_err(mail_error)
+}
+
+/// Sets a new password with a token from `request_password_reset` and logs the user out
+/// everywhere. The token is only used up once the new password passes the policy.
+pub async fn reset_password(
+    token: String,
+    pass: String,
+    audit: AuditContext,
+) -> napi::Result<User> {
+    let token_hash = hash_token(&token);
+    let invalid = || napi::Error::from_reason("Invalid or expired reset token");
+
+    let uid = redis_handler::peek_user_token(UserTokenKind::PasswordReset, token_hash.clone())
+        .await
+        .map_err(token_error)?
+        .ok_or_else(invalid)?;
+    let user = user_from_uid(&uid).await?;
+    policy::enforce_password(&pass, Some(&user.email)).await?;
+
+    // Of two resets racing with the same token only one gets it
+    let consumed = redis_handler::consume_user_token(UserTokenKind::PasswordReset, token_hash)
+        .await
+        .map_err(token_error)?;
+    if consumed.as_deref() != Some(uid.as_str()) {
+        return Err(invalid());
+    }
+
+    let user = update_user(uid.clone(), None, Some(pass), None, None, audit.clone()).await?;
+
+    redis_handler::delete_user_refresh_tokens(uid.clone())
+        .await
+        .map_err(token_error)?;
+    // A lock guarded the old password, the owner shouldn't have to wait it out
+    redis_handler::clear_login_failures(user.email.clone())
+        .await
+        .map_err(token_error)?;
+
+    // The link reached them, which is all verifying the email would prove
+    verification::mark_email_verified(uid, audit).await
+}
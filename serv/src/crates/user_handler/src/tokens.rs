@@ -0,0 +1,72 @@
+use mailer::MailError;
+use rand_core::RngCore;
+use redis_handler::RedisHandlerError;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// A fresh random token to mail out, and the hash to store for it
+pub(crate) fn new_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    let token = to_hex(&bytes);
+    let hash = hash_token(&token);
+    (token, hash)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// `url` with the token added as a `token` query parameter
+fn token_link(url: &str, token: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{url}{separator}token={token}")
+}
+
+/// Template variables for a mailed token link: `link`, `ttl_minutes` and `ttl_hours`,
+/// both rounded down so a link never dies sooner than the mail claims, but at least 1
+pub(crate) fn link_vars(url: &str, token: &str, ttl: u64) -> HashMap<String, String> {
+    HashMap::from([
+        ("link".to_string(), token_link(url, token)),
+        ("ttl_minutes".to_string(), (ttl / 60).max(1).to_string()),
+        ("ttl_hours".to_string(), (ttl / 3600).max(1).to_string()),
+    ])
+}
+
+/// Reads a TTL in seconds from the environment
+pub(crate) fn ttl_from_env(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+pub(crate) fn token_error(e: RedisHandlerError) -> napi::Error {
+    napi::Error::from_reason(format!("Token store failed: {e}"))
+}
+
+pub(crate) fn mail_error(e: MailError) -> napi::Error {
+    napi::Error::from_reason(format!("Failed to send mail: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_ttls_round_down_to_at_least_one() {
+        let vars = link_vars("https://example.com/verify", "abc", 90 * 60 + 59);
+        assert_eq!(vars["link"], "https://example.com/verify?token=abc");
+        assert_eq!(vars["ttl_minutes"], "90");
+        assert_eq!(vars["ttl_hours"], "1");
+
+        let vars = link_vars("https://example.com/reset?lang=en", "abc", 30);
+        assert_eq!(vars["link"], "https://example.com/reset?lang=en&token=abc");
+        assert_eq!(vars["ttl_minutes"], "1");
+        assert_eq!(vars["ttl_hours"], "1");
+    }
+}
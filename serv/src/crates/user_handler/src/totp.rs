@@ -0,0 +1,597 @@
+use crate::{
+    audit_snapshot,
+    tokens::{to_hex, token_error},
+    user_from_uid,
+};
+use audit::{AuditAction, AuditContext};
+use aws_lc_rs::{constant_time::verify_slices_are_equal, hmac};
+use db::get_uidb_pool;
+use deadpool_postgres::GenericClient;
+use napi_derive::napi;
+use rand_core::RngCore;
+use redis_handler::UserTokenKind;
+use shared_types::User;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// RFC 6238 with the parameters every authenticator app supports: SHA-1, six digits,
+// thirty second steps. One step either side is accepted for clock drift.
+const SECRET_BYTES: usize = 20;
+const STEP_SECONDS: u64 = 30;
+const DIGITS: usize = 6;
+const SKEW_STEPS: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+const DEFAULT_ISSUER: &str = "deadlock";
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[napi(object)]
+pub struct TotpEnrolment {
+    /// Base32, for typing into an authenticator by hand
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code
+    pub otpauth_uri: String,
+}
+
+#[napi(object)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    /// An enrolment was started but not confirmed with a code yet
+    pub pending: bool,
+    pub recovery_codes_left: u32,
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// RFC 4226 HOTP value for one counter
+fn hotp(key: &hmac::Key, counter: u64) -> String {
+    let tag = hmac::sign(key, &counter.to_be_bytes());
+    let digest = tag.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!("{:0DIGITS$}", value % 10u32.pow(DIGITS as u32))
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / STEP_SECONDS
+}
+
+/// The time step `code` belongs to, if it is one of those accepted at step `now`
+fn matching_step(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &base32_decode(secret)?);
+    (now.saturating_sub(SKEW_STEPS)..=now + SKEW_STEPS)
+        .find(|&step| verify_slices_are_equal(hotp(&key, step).as_bytes(), code.as_bytes()).is_ok())
+}
+
+/// Checks a TOTP code and uses it up, so the same code can't log in twice
+async fn check_code(uid: &str, secret: &str, code: &str) -> napi::Result<bool> {
+    let Some(step) = matching_step(secret, code, current_step()) else {
+        return Ok(false);
+    };
+    // Long enough to outlive every window the step is accepted in
+    let ttl = STEP_SECONDS * (2 * SKEW_STEPS + 1);
+    redis_handler::claim_totp_step(uid.to_string(), step, ttl)
+        .await
+        .map_err(token_error)
+}
+
+fn is_totp_code(code: &str) -> bool {
+    code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+static RECOVERY_KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+/// HMAC key from `RECOVERY_CODE_SECRET`. Fifty bits are quick to brute force against a
+/// plain hash, keyed ones are useless without the secret. Changing it invalidates every
+/// stored code.
+fn recovery_key() -> napi::Result<&'static hmac::Key> {
+    if let Some(key) = RECOVERY_KEY.get() {
+        return Ok(key);
+    }
+    let secret = std::env::var("RECOVERY_CODE_SECRET")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| napi::Error::from_reason("RECOVERY_CODE_SECRET not set"))?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    Ok(RECOVERY_KEY.get_or_init(|| key))
+}
+
+/// Recovery codes are compared without case, spaces or dashes
+fn recovery_code_hash(key: &hmac::Key, code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    to_hex(hmac::sign(key, normalized.as_bytes()).as_ref())
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// The name authenticator apps list the account under, `TOTP_ISSUER` or `deadlock`
+fn issuer() -> String {
+    std::env::var("TOTP_ISSUER").unwrap_or_else(|_| DEFAULT_ISSUER.to_string())
+}
+
+fn otpauth_uri(email: &str, secret: &str) -> String {
+    let issuer = percent_encode(&issuer());
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        percent_encode(email)
+    )
+}
+
+/// Replaces the user's recovery codes with fresh ones, returned in the clear this once
+async fn replace_recovery_codes(
+    client: &impl GenericClient,
+    uid: &str,
+) -> napi::Result<Vec<String>> {
+    let delete = client
+        .prepare_cached(
+            "DELETE FROM public.User_Recovery_Codes WHERE user_uid = CAST($1 AS TEXT)::uuid",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    client
+        .execute(&delete, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let insert = client
+        .prepare_cached(
+            "INSERT INTO public.User_Recovery_Codes (user_uid, code_hash)
+             VALUES (CAST($1 AS TEXT)::uuid, $2)",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let key = recovery_key()?;
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        // Fifty random bits, shown as two groups of five
+        let mut bytes = [0u8; 7];
+        rand_core::OsRng.fill_bytes(&mut bytes);
+        let raw = base32_encode(&bytes).to_lowercase();
+        let code = format!("{}-{}", &raw[..5], &raw[5..10]);
+        client
+            .execute(&insert, &[&uid, &recovery_code_hash(key, &code)])
+            .await
+            .map_err(|e| napi::Error::from_reason(format!("Failed to store recovery code: {e}")))?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+/// Starts TOTP enrolment with a new secret. Nothing is enforced until
+/// `confirm_totp_enrolment` sees a code from it, and starting again replaces the secret.
+pub async fn begin_totp_enrolment(uid: String) -> napi::Result<TotpEnrolment> {
+    let user = user_from_uid(&uid).await?;
+    if user.totp_enabled {
+        return Err(napi::Error::from_reason(
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    let secret = base32_encode(&bytes);
+
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "INSERT INTO public.User_Totp AS t (user_uid, secret)
+             VALUES (CAST($1 AS TEXT)::uuid, $2)
+             ON CONFLICT (user_uid) DO UPDATE SET secret = EXCLUDED.secret, created_at = now()
+             WHERE t.confirmed_at IS NULL",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stored = client
+        .execute(&stmt, &[&uid, &secret])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Enrolment failed: {e}")))?;
+    // Confirmed by a concurrent request since the check above
+    if stored == 0 {
+        return Err(napi::Error::from_reason(
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    Ok(TotpEnrolment {
+        otpauth_uri: otpauth_uri(&user.email, &secret),
+        secret,
+    })
+}
+
+/// Turns TOTP on once the user proves their authenticator works. Returns the recovery
+/// codes, which are never shown again.
+pub async fn confirm_totp_enrolment(
+    uid: String,
+    code: String,
+    audit: AuditContext,
+) -> napi::Result<Vec<String>> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "SELECT secret FROM public.User_Totp
+             WHERE user_uid = CAST($1 AS TEXT)::uuid AND confirmed_at IS NULL
+             FOR UPDATE",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let secret: String = tx
+        .query_opt(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .ok_or_else(|| napi::Error::from_reason("No two-factor enrolment in progress"))?
+        .get("secret");
+
+    if !check_code(&uid, &secret, code.trim()).await? {
+        return Err(napi::Error::from_reason("Invalid code"));
+    }
+
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
+        .prepare_cached(
+            "UPDATE public.User_Totp SET confirmed_at = now() WHERE user_uid = CAST($1 AS TEXT)::uuid",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    tx.execute(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Enrolment failed: {e}")))?;
+
+    let codes = replace_recovery_codes(&tx, &uid).await?;
+
+    let after = audit_snapshot(&tx, &uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::TotpEnable,
+        Some(&uid),
+        audit::diff(&before, &after),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(codes)
+}
+
+/// Removes the second factor and recovery codes, including an unconfirmed enrolment.
+/// Self-service callers should check a code with `verify_totp` first.
+pub async fn disable_totp(uid: String, audit: AuditContext) -> napi::Result<User> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
+        .prepare_cached(
+            "WITH codes AS (
+                DELETE FROM public.User_Recovery_Codes WHERE user_uid = CAST($1 AS TEXT)::uuid
+             )
+             DELETE FROM public.User_Totp WHERE user_uid = CAST($1 AS TEXT)::uuid",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    tx.execute(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Failed to disable two-factor: {e}")))?;
+
+    let after = audit_snapshot(&tx, &uid).await?;
+    let diff = audit::diff(&before, &after);
+    if diff.as_object().is_some_and(|d| !d.is_empty()) {
+        audit::record(&tx, &audit, AuditAction::TotpDisable, Some(&uid), diff).await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    // A login already waiting for a code would otherwise still need one that can't be made
+    redis_handler::revoke_user_token(UserTokenKind::LoginChallenge, uid.clone())
+        .await
+        .map_err(token_error)?;
+
+    user_from_uid(&uid).await
+}
+
+/// Replaces all recovery codes, used or not, and returns the new ones
+pub async fn regenerate_recovery_codes(
+    uid: String,
+    audit: AuditContext,
+) -> napi::Result<Vec<String>> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let stmt = tx
+        .prepare_cached(
+            "SELECT 1 FROM public.User_Totp
+             WHERE user_uid = CAST($1 AS TEXT)::uuid AND confirmed_at IS NOT NULL
+             FOR UPDATE",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    if tx
+        .query_opt(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .is_none()
+    {
+        return Err(napi::Error::from_reason(
+            "Two-factor authentication is not enabled",
+        ));
+    }
+
+    let before = audit_snapshot(&tx, &uid).await?;
+    let codes = replace_recovery_codes(&tx, &uid).await?;
+    let after = audit_snapshot(&tx, &uid).await?;
+    audit::record(
+        &tx,
+        &audit,
+        AuditAction::RecoveryCodesRegenerate,
+        Some(&uid),
+        audit::diff(&before, &after),
+    )
+    .await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    Ok(codes)
+}
+
+pub async fn totp_status(uid: String) -> napi::Result<TotpStatus> {
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let stmt = client
+        .prepare_cached(
+            "SELECT
+                EXISTS (
+                    SELECT 1 FROM public.User_Totp t
+                    WHERE t.user_uid = u.uid AND t.confirmed_at IS NOT NULL
+                ) as enabled,
+                EXISTS (
+                    SELECT 1 FROM public.User_Totp t
+                    WHERE t.user_uid = u.uid AND t.confirmed_at IS NULL
+                ) as pending,
+                (
+                    SELECT count(*) FROM public.User_Recovery_Codes c
+                    WHERE c.user_uid = u.uid AND c.used_at IS NULL
+                ) as recovery_codes_left
+             FROM public.Users u
+             WHERE u.uid = CAST($1 AS TEXT)::uuid AND u.deleted_at IS NULL",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let row = client
+        .query_opt(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        .ok_or_else(|| napi::Error::from_reason("No users returned"))?;
+
+    Ok(TotpStatus {
+        enabled: row.get("enabled"),
+        pending: row.get("pending"),
+        recovery_codes_left: row.get::<_, i64>("recovery_codes_left") as u32,
+    })
+}
+
+/// Checks a second factor for a user with TOTP enabled: a current code from the
+/// authenticator or an unused recovery code. Either is used up by a successful check.
+pub async fn verify_totp(uid: String, code: String) -> napi::Result<bool> {
+    let code = code.trim();
+    let client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    if is_totp_code(code) {
+        let stmt = client
+            .prepare_cached(
+                "SELECT secret FROM public.User_Totp
+                 WHERE user_uid = CAST($1 AS TEXT)::uuid AND confirmed_at IS NOT NULL",
+            )
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+        let Some(row) = client
+            .query_opt(&stmt, &[&uid])
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?
+        else {
+            return Ok(false);
+        };
+        return check_code(&uid, row.get("secret"), code).await;
+    }
+
+    let stmt = client
+        .prepare_cached(
+            "UPDATE public.User_Recovery_Codes SET used_at = now()
+             WHERE user_uid = CAST($1 AS TEXT)::uuid AND code_hash = $2 AND used_at IS NULL",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let used = client
+        .execute(&stmt, &[&uid, &recovery_code_hash(recovery_key()?, code)])
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    Ok(used > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4226 Appendix D and RFC 6238 Appendix B share this SHA-1 secret
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn rfc_key() -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, RFC_SECRET)
+    }
+
+    #[test]
+    fn hotp_matches_rfc4226() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        let key = rfc_key();
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(&key, counter as u64), *code, "counter {counter}");
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc6238() {
+        // The RFC lists eight digits, six digit codes are their last six
+        let expected = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+        let key = rfc_key();
+        for (time, code) in expected {
+            assert_eq!(hotp(&key, time / STEP_SECONDS), code[2..], "time {time}");
+        }
+    }
+
+    #[test]
+    fn base32_matches_rfc4648() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY======"),
+            ("fo", "MZXQ===="),
+            ("foo", "MZXW6==="),
+            ("foob", "MZXW6YQ="),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI======"),
+        ];
+        for (plain, encoded) in vectors {
+            assert_eq!(
+                base32_encode(plain.as_bytes()),
+                encoded.trim_end_matches('=')
+            );
+            assert_eq!(base32_decode(encoded).unwrap(), plain.as_bytes());
+            assert_eq!(
+                base32_decode(&encoded.to_lowercase()).unwrap(),
+                plain.as_bytes()
+            );
+        }
+        assert!(base32_decode("MZXW1").is_none());
+    }
+
+    #[test]
+    fn accepts_codes_within_the_skew_window() {
+        let secret = base32_encode(RFC_SECRET);
+        let key = rfc_key();
+        let now = 1_000;
+        for step in now - SKEW_STEPS..=now + SKEW_STEPS {
+            assert_eq!(matching_step(&secret, &hotp(&key, step), now), Some(step));
+        }
+        for step in [now - SKEW_STEPS - 1, now + SKEW_STEPS + 1] {
+            assert_eq!(matching_step(&secret, &hotp(&key, step), now), None);
+        }
+        assert_eq!(matching_step(&secret, &hotp(&key, 0), 0), Some(0));
+    }
+
+    #[test]
+    fn recovery_codes_hash_with_the_key() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let hash = recovery_code_hash(&key, "abcde-fghij");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(recovery_code_hash(&key, " ABCDE FGHIJ "), hash);
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other secret");
+        assert_ne!(recovery_code_hash(&other, "abcde-fghij"), hash);
+    }
+}
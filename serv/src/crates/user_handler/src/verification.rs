@@ -0,0 +1,104 @@
+use crate::{
+    audit_snapshot,
+    tokens::{hash_token, link_vars, mail_error, new_token, token_error, ttl_from_env},
+    user_from_email, user_from_uid,
+};
+use audit::{AuditAction, AuditContext};
+use db::get_uidb_pool;
+use redis_handler::UserTokenKind;
+use shared_types::User;
+
+const DEFAULT_VERIFY_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+/// Mails a single-use link that verifies the account's email, `verify_url` with the token
+/// added as a `token` query parameter. Like password resets it succeeds without sending
+/// anything for unknown accounts, and also for already verified ones.
+pub async fn request_email_verification(
+    email: String,
+    verify_url: String,
+    locale: Option<String>,
+) -> napi::Result<()> {
+    let Some(user) = user_from_email(&email).await? else {
+        return Ok(());
+    };
+    if user.email_verified_at.is_some() {
+        return Ok(());
+    }
+
+    let (token, token_hash) = new_token();
+    // How long a verification link works
+    let ttl = ttl_from_env("EMAIL_VERIFICATION_TTL_SECONDS", DEFAULT_VERIFY_TTL_SECONDS);
+    redis_handler::store_user_token(UserTokenKind::EmailVerification, token_hash, user.uid, ttl)
+        .await
+        .map_err(token_error)?;
+
+    mailer::send_template(
+        "email_verification",
+        locale.as_deref(),
+        &user.email,
+        &link_vars(&verify_url, &token, ttl),
+    )
+    .await
+    .map_err(mail_error)
+}
+
+/// Verifies the email of the user a token from `request_email_verification` was sent to
+pub async fn verify_email(token: String, audit: AuditContext) -> napi::Result<User> {
+    let uid =
+        redis_handler::consume_user_token(UserTokenKind::EmailVerification, hash_token(&token))
+            .await
+            .map_err(token_error)?
+            .ok_or_else(|| napi::Error::from_reason("Invalid or expired verification token"))?;
+
+    mark_email_verified(uid, audit).await
+}
+
+/// Marks the email verified unless it already is, and drops any verification link still out
+pub(crate) async fn mark_email_verified(uid: String, audit: AuditContext) -> napi::Result<User> {
+    let mut client = get_uidb_pool()
+        .get()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Transaction error: {e}")))?;
+
+    let before = audit_snapshot(&tx, &uid).await?;
+
+    let stmt = tx
+        .prepare_cached(
+            "UPDATE public.Users SET email_verified_at = now()
+             WHERE uid = CAST($1 AS TEXT)::uuid
+             AND deleted_at IS NULL AND email_verified_at IS NULL",
+        )
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    let verified = tx
+        .execute(&stmt, &[&uid])
+        .await
+        .map_err(|e| napi::Error::from_reason(format!("Verification failed: {e}")))?;
+    if verified > 0 {
+        let after = audit_snapshot(&tx, &uid).await?;
+        audit::record(
+            &tx,
+            &audit,
+            AuditAction::EmailVerify,
+            Some(&uid),
+            audit::diff(&before, &after),
+        )
+        .await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+    redis_handler::revoke_user_token(UserTokenKind::EmailVerification, uid.clone())
+        .await
+        .map_err(token_error)?;
+
+    user_from_uid(&uid).await
+}
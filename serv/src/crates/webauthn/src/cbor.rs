@@ -0,0 +1,226 @@
+//! The subset of CBOR (RFC 8949) WebAuthn uses: attestation objects and COSE keys.
+//! Only definite lengths, which is all CTAP2 canonical encoding allows.
+
+use crate::WebauthnError;
+
+/// Deep enough for any attestation object, shallow enough that hostile input can't
+/// exhaust the stack
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Int(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    /// Looks up an integer key, as COSE keys use
+    pub(crate) fn get_int(&self, key: i128) -> Option<&Value> {
+        self.get(&Value::Int(key))
+    }
+
+    /// Looks up a text key, as attestation objects use
+    pub(crate) fn get_text(&self, key: &str) -> Option<&Value> {
+        self.get(&Value::Text(key.to_string()))
+    }
+
+    fn get(&self, key: &Value) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_int(&self) -> Option<i128> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+fn malformed(what: &str) -> WebauthnError {
+    WebauthnError::Malformed(format!("CBOR: {what}"))
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], WebauthnError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.input.len())
+            .ok_or_else(|| malformed("unexpected end of input"))?;
+        let bytes = &self.input[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// The argument following an initial byte's additional information
+    fn argument(&mut self, info: u8) -> Result<u64, WebauthnError> {
+        Ok(match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap_or_default()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap_or_default()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap_or_default()),
+            31 => return Err(malformed("indefinite lengths are not supported")),
+            _ => return Err(malformed("reserved additional information")),
+        })
+    }
+
+    fn length(&mut self, info: u8) -> Result<usize, WebauthnError> {
+        let length = self.argument(info)?;
+        // Every item takes at least a byte, so longer counts can't be honest
+        if length > (self.input.len() - self.pos) as u64 {
+            return Err(malformed("length exceeds input"));
+        }
+        Ok(length as usize)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, WebauthnError> {
+        if depth > MAX_DEPTH {
+            return Err(malformed("nested too deeply"));
+        }
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        Ok(match major {
+            0 => Value::Int(self.argument(info)? as i128),
+            1 => Value::Int(-1 - self.argument(info)? as i128),
+            2 => {
+                let length = self.length(info)?;
+                Value::Bytes(self.take(length)?.to_vec())
+            }
+            3 => {
+                let length = self.length(info)?;
+                let text = std::str::from_utf8(self.take(length)?)
+                    .map_err(|_| malformed("text is not UTF-8"))?;
+                Value::Text(text.to_string())
+            }
+            4 => {
+                let length = self.length(info)?;
+                let mut items = Vec::with_capacity(length);
+                for _ in 0..length {
+                    items.push(self.value(depth + 1)?);
+                }
+                Value::Array(items)
+            }
+            5 => {
+                let length = self.length(info)?;
+                let mut entries = Vec::with_capacity(length);
+                for _ in 0..length {
+                    let key = self.value(depth + 1)?;
+                    if entries.iter().any(|(k, _)| *k == key) {
+                        return Err(malformed("duplicate map key"));
+                    }
+                    entries.push((key, self.value(depth + 1)?));
+                }
+                Value::Map(entries)
+            }
+            7 => match info {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                22 => Value::Null,
+                _ => return Err(malformed("unsupported simple value")),
+            },
+            _ => return Err(malformed("tags are not supported")),
+        })
+    }
+}
+
+/// Decodes one item from the start of `input`, returning it and the bytes it took.
+/// Authenticator data carries a COSE key followed by more data, hence the length.
+pub(crate) fn decode_prefix(input: &[u8]) -> Result<(Value, usize), WebauthnError> {
+    let mut decoder = Decoder { input, pos: 0 };
+    let value = decoder.value(0)?;
+    Ok((value, decoder.pos))
+}
+
+/// Decodes exactly one item, trailing bytes are an error
+pub(crate) fn decode(input: &[u8]) -> Result<Value, WebauthnError> {
+    let (value, used) = decode_prefix(input)?;
+    if used != input.len() {
+        return Err(malformed("trailing bytes"));
+    }
+    Ok(value)
+}
+
+fn header(out: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    match argument {
+        0..=23 => out.push(major | argument as u8),
+        24..=0xff => out.extend([major | 24, argument as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((argument as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(argument.to_be_bytes());
+        }
+    }
+}
+
+/// Encodes in the order given, callers list map keys canonically themselves
+pub(crate) fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(&mut out, value);
+    out
+}
+
+fn encode_into(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(i) if *i >= 0 => header(out, 0, *i as u64),
+        Value::Int(i) => header(out, 1, (-1 - *i) as u64),
+        Value::Bytes(b) => {
+            header(out, 2, b.len() as u64);
+            out.extend(b);
+        }
+        Value::Text(t) => {
+            header(out, 3, t.len() as u64);
+            out.extend(t.as_bytes());
+        }
+        Value::Array(items) => {
+            header(out, 4, items.len() as u64);
+            for item in items {
+                encode_into(out, item);
+            }
+        }
+        Value::Map(entries) => {
+            header(out, 5, entries.len() as u64);
+            for (k, v) in entries {
+                encode_into(out, k);
+                encode_into(out, v);
+            }
+        }
+        Value::Bool(b) => out.push(0xf4 | *b as u8),
+        Value::Null => out.push(0xf6),
+    }
+}
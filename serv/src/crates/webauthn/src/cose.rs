@@ -0,0 +1,136 @@
+//! COSE public keys (RFC 9053) for the three algorithms authenticators actually use
+
+use crate::WebauthnError;
+use crate::cbor::{self, Value};
+use aws_lc_rs::signature::{
+    ECDSA_P256_SHA256_ASN1, ED25519, RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents,
+    UnparsedPublicKey,
+};
+
+pub const ES256: i64 = -7;
+pub const EDDSA: i64 = -8;
+pub const RS256: i64 = -257;
+
+/// Offered to authenticators in order of preference
+pub const SUPPORTED_ALGORITHMS: [i64; 3] = [ES256, EDDSA, RS256];
+
+// Labels from the COSE registries
+const KTY: i128 = 1;
+const ALG: i128 = 3;
+const CRV: i128 = -1;
+const X: i128 = -2;
+const Y: i128 = -3;
+const RSA_N: i128 = -1;
+const RSA_E: i128 = -2;
+const KTY_OKP: i128 = 1;
+const KTY_EC2: i128 = 2;
+const KTY_RSA: i128 = 3;
+const CRV_P256: i128 = 1;
+const CRV_ED25519: i128 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PublicKey {
+    /// Uncompressed SEC1 point
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+}
+
+fn unsupported(what: &str) -> WebauthnError {
+    WebauthnError::Unsupported(what.to_string())
+}
+
+fn field<'a>(key: &'a Value, label: i128, name: &str) -> Result<&'a [u8], WebauthnError> {
+    key.get_int(label)
+        .and_then(Value::as_bytes)
+        .ok_or_else(|| WebauthnError::Malformed(format!("COSE key without {name}")))
+}
+
+impl PublicKey {
+    pub(crate) fn from_cose(key: &Value) -> Result<Self, WebauthnError> {
+        let kty = key.get_int(KTY).and_then(Value::as_int);
+        let alg = key.get_int(ALG).and_then(Value::as_int).map(|a| a as i64);
+        let crv = key.get_int(CRV).and_then(Value::as_int);
+
+        match (kty, alg) {
+            (Some(KTY_EC2), Some(ES256)) => {
+                if crv != Some(CRV_P256) {
+                    return Err(unsupported("ES256 key on a curve other than P-256"));
+                }
+                let (x, y) = (field(key, X, "x")?, field(key, Y, "y")?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(WebauthnError::Malformed("P-256 coordinates".to_string()));
+                }
+                Ok(PublicKey::Es256([&[0x04], x, y].concat()))
+            }
+            (Some(KTY_OKP), Some(EDDSA)) => {
+                if crv != Some(CRV_ED25519) {
+                    return Err(unsupported("EdDSA key on a curve other than Ed25519"));
+                }
+                Ok(PublicKey::Ed25519(field(key, X, "x")?.to_vec()))
+            }
+            (Some(KTY_RSA), Some(RS256)) => Ok(PublicKey::Rs256 {
+                n: field(key, RSA_N, "n")?.to_vec(),
+                e: field(key, RSA_E, "e")?.to_vec(),
+            }),
+            _ => Err(unsupported("key type or algorithm")),
+        }
+    }
+
+    pub(crate) fn from_cose_bytes(bytes: &[u8]) -> Result<Self, WebauthnError> {
+        Self::from_cose(&cbor::decode(bytes)?)
+    }
+
+    pub(crate) fn algorithm(&self) -> i64 {
+        match self {
+            PublicKey::Es256(_) => ES256,
+            PublicKey::Ed25519(_) => EDDSA,
+            PublicKey::Rs256 { .. } => RS256,
+        }
+    }
+
+    /// The canonical COSE encoding, what gets stored
+    pub(crate) fn to_cose(&self) -> Vec<u8> {
+        let alg = Value::Int(self.algorithm() as i128);
+        let entries = match self {
+            PublicKey::Es256(point) => vec![
+                (Value::Int(KTY), Value::Int(KTY_EC2)),
+                (Value::Int(ALG), alg),
+                (Value::Int(CRV), Value::Int(CRV_P256)),
+                (Value::Int(X), Value::Bytes(point[1..33].to_vec())),
+                (Value::Int(Y), Value::Bytes(point[33..].to_vec())),
+            ],
+            PublicKey::Ed25519(x) => vec![
+                (Value::Int(KTY), Value::Int(KTY_OKP)),
+                (Value::Int(ALG), alg),
+                (Value::Int(CRV), Value::Int(CRV_ED25519)),
+                (Value::Int(X), Value::Bytes(x.clone())),
+            ],
+            PublicKey::Rs256 { n, e } => vec![
+                (Value::Int(KTY), Value::Int(KTY_RSA)),
+                (Value::Int(ALG), alg),
+                (Value::Int(RSA_N), Value::Bytes(n.clone())),
+                (Value::Int(RSA_E), Value::Bytes(e.clone())),
+            ],
+        };
+        cbor::encode(&Value::Map(entries))
+    }
+
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), WebauthnError> {
+        let verified = match self {
+            PublicKey::Es256(point) => {
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point).verify(message, signature)
+            }
+            PublicKey::Ed25519(x) => UnparsedPublicKey::new(&ED25519, x).verify(message, signature),
+            PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }.verify(
+                &RSA_PKCS1_2048_8192_SHA256,
+                message,
+                signature,
+            ),
+        };
+        verified.map_err(|_| WebauthnError::Rejected("signature doesn't verify".to_string()))
+    }
+}
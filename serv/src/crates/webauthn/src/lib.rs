@@ -0,0 +1,570 @@
+//! A WebAuthn relying party for passkeys: builds the options handed to
+//! `navigator.credentials.create()`/`get()` and verifies what comes back. Storage and
+//! challenge bookkeeping are left to the caller.
+
+mod cbor;
+mod cose;
+#[cfg(test)]
+mod soft;
+
+pub use cose::{EDDSA, ES256, RS256, SUPPORTED_ALGORITHMS};
+
+use aws_lc_rs::constant_time::verify_slices_are_equal;
+use aws_lc_rs::digest::{SHA256, digest};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use cbor::Value;
+use cose::PublicKey;
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+const CHALLENGE_BYTES: usize = 32;
+/// Longest credential id the spec allows
+const MAX_CREDENTIAL_ID_BYTES: usize = 1023;
+const CEREMONY_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+
+// Authenticator data flags
+const FLAG_UP: u8 = 0x01;
+const FLAG_UV: u8 = 0x04;
+const FLAG_BE: u8 = 0x08;
+const FLAG_BS: u8 = 0x10;
+const FLAG_AT: u8 = 0x40;
+
+#[derive(Error, Debug)]
+pub enum WebauthnError {
+    #[error("Malformed WebAuthn data: {0}")]
+    Malformed(String),
+    /// Well formed, but not acceptable: wrong challenge or origin, bad signature and so on
+    #[error("WebAuthn response rejected: {0}")]
+    Rejected(String),
+    #[error("Unsupported WebAuthn feature: {0}")]
+    Unsupported(String),
+}
+
+impl From<WebauthnError> for napi::Error {
+    fn from(err: WebauthnError) -> Self {
+        napi::Error::from_reason(err.to_string())
+    }
+}
+
+pub fn base64url_encode(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn base64url_decode(text: &str) -> Result<Vec<u8>, WebauthnError> {
+    // Some clients pad, the spec doesn't
+    URL_SAFE_NO_PAD
+        .decode(text.trim_end_matches('='))
+        .map_err(|_| WebauthnError::Malformed("invalid base64url".to_string()))
+}
+
+/// A fresh random challenge, base64url encoded
+pub fn new_challenge() -> String {
+    let mut bytes = [0u8; CHALLENGE_BYTES];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    base64url_encode(&bytes)
+}
+
+/// `PublicKeyCredential.toJSON()` of a new credential. Binary fields are base64url.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationResponse {
+    pub id: String,
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+}
+
+/// `PublicKeyCredential.toJSON()` of an assertion. Binary fields are base64url.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationResponse {
+    pub id: String,
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    /// The `user.id` given at registration, always set for passkeys
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+    #[serde(default)]
+    cross_origin: Option<bool>,
+}
+
+/// The challenge a response answers, for finding the ceremony it belongs to. Nothing
+/// about the response is verified yet.
+pub fn response_challenge(client_data_json: &str) -> Result<String, WebauthnError> {
+    let client_data: ClientData = serde_json::from_slice(&base64url_decode(client_data_json)?)
+        .map_err(|e| WebauthnError::Malformed(format!("client data: {e}")))?;
+    Ok(client_data.challenge)
+}
+
+/// A credential as registered, to be stored with its user
+#[derive(Debug, Clone)]
+pub struct NewCredential {
+    /// base64url
+    pub id: String,
+    /// COSE_Key, pass it back to `verify_authentication`
+    pub public_key: Vec<u8>,
+    pub algorithm: i64,
+    pub sign_count: u32,
+    pub transports: Vec<String>,
+    /// Whether the passkey is synced, e.g. through a password manager
+    pub backed_up: bool,
+}
+
+/// What a successful assertion changes about the stored credential
+#[derive(Debug, Clone, Copy)]
+pub struct CredentialUse {
+    pub sign_count: u32,
+    pub backed_up: bool,
+}
+
+/// A credential the user already has, for `excludeCredentials`/`allowCredentials`
+#[derive(Debug, Clone)]
+pub struct CredentialDescriptor {
+    /// base64url
+    pub id: String,
+    pub transports: Vec<String>,
+}
+
+fn descriptors(credentials: &[CredentialDescriptor]) -> serde_json::Value {
+    credentials
+        .iter()
+        .map(|c| json!({ "type": "public-key", "id": c.id, "transports": c.transports }))
+        .collect()
+}
+
+struct AttestedCredential {
+    id: Vec<u8>,
+    public_key: PublicKey,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    attested: Option<AttestedCredential>,
+}
+
+impl AuthenticatorData {
+    fn parse(bytes: &[u8]) -> Result<Self, WebauthnError> {
+        let malformed = || WebauthnError::Malformed("authenticator data".to_string());
+        if bytes.len() < 37 {
+            return Err(malformed());
+        }
+        let flags = bytes[32];
+        let sign_count = u32::from_be_bytes(bytes[33..37].try_into().map_err(|_| malformed())?);
+
+        let attested = if flags & FLAG_AT != 0 {
+            // 16 byte AAGUID, then a two byte length and the credential id
+            let rest = bytes.get(37 + 16..).ok_or_else(malformed)?;
+            let id_len = u16::from_be_bytes(
+                rest.get(..2)
+                    .ok_or_else(malformed)?
+                    .try_into()
+                    .map_err(|_| malformed())?,
+            ) as usize;
+            if id_len > MAX_CREDENTIAL_ID_BYTES {
+                return Err(malformed());
+            }
+            let id = rest.get(2..2 + id_len).ok_or_else(malformed)?.to_vec();
+            // Extensions may follow the key, so only its own length is taken
+            let (key, _) = cbor::decode_prefix(&rest[2 + id_len..])?;
+            Some(AttestedCredential {
+                id,
+                public_key: PublicKey::from_cose(&key)?,
+            })
+        } else {
+            None
+        };
+
+        Ok(AuthenticatorData {
+            rp_id_hash: bytes[..32].to_vec(),
+            flags,
+            sign_count,
+            attested,
+        })
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+fn rejected(reason: &str) -> WebauthnError {
+    WebauthnError::Rejected(reason.to_string())
+}
+
+/// The site credentials are scoped to
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    /// A registrable domain, e.g. `example.com`
+    pub id: String,
+    /// Shown by the browser and authenticator
+    pub name: String,
+    /// Origins the ceremonies may run on, e.g. `https://example.com`
+    pub origins: Vec<String>,
+}
+
+impl RelyingParty {
+    /// `PublicKeyCredentialCreationOptionsJSON` for a discoverable, user verified passkey.
+    /// `user_id` is what comes back as the user handle, it must not contain personal data.
+    pub fn creation_options(
+        &self,
+        challenge: &str,
+        user_id: &[u8],
+        user_name: &str,
+        exclude: &[CredentialDescriptor],
+    ) -> serde_json::Value {
+        json!({
+            "rp": { "id": self.id, "name": self.name },
+            "user": {
+                "id": base64url_encode(user_id),
+                "name": user_name,
+                "displayName": user_name,
+            },
+            "challenge": challenge,
+            "pubKeyCredParams": SUPPORTED_ALGORITHMS
+                .iter()
+                .map(|alg| json!({ "type": "public-key", "alg": alg }))
+                .collect::<Vec<_>>(),
+            "timeout": CEREMONY_TIMEOUT_MS,
+            "excludeCredentials": descriptors(exclude),
+            "authenticatorSelection": {
+                "residentKey": "required",
+                "requireResidentKey": true,
+                "userVerification": "required",
+            },
+            "attestation": "none",
+        })
+    }
+
+    /// `PublicKeyCredentialRequestOptionsJSON`. An empty `allow` lets the user pick any
+    /// passkey they have for this site.
+    pub fn request_options(
+        &self,
+        challenge: &str,
+        allow: &[CredentialDescriptor],
+    ) -> serde_json::Value {
+        json!({
+            "rpId": self.id,
+            "challenge": challenge,
+            "timeout": CEREMONY_TIMEOUT_MS,
+            "allowCredentials": descriptors(allow),
+            "userVerification": "required",
+        })
+    }
+
+    fn check_client_data(
+        &self,
+        client_data_json: &[u8],
+        kind: &str,
+        challenge: &str,
+    ) -> Result<(), WebauthnError> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json)
+            .map_err(|e| WebauthnError::Malformed(format!("client data: {e}")))?;
+        if client_data.kind != kind {
+            return Err(rejected("wrong ceremony type"));
+        }
+        if verify_slices_are_equal(client_data.challenge.as_bytes(), challenge.as_bytes()).is_err()
+        {
+            return Err(rejected("challenge mismatch"));
+        }
+        if !self.origins.contains(&client_data.origin) {
+            return Err(WebauthnError::Rejected(format!(
+                "origin {} not allowed",
+                client_data.origin
+            )));
+        }
+        if client_data.cross_origin == Some(true) {
+            return Err(rejected("cross-origin ceremony"));
+        }
+        Ok(())
+    }
+
+    fn check_authenticator_data(&self, data: &AuthenticatorData) -> Result<(), WebauthnError> {
+        let expected = digest(&SHA256, self.id.as_bytes());
+        if data.rp_id_hash != expected.as_ref() {
+            return Err(rejected("credential is for another relying party"));
+        }
+        if !data.has(FLAG_UP) {
+            return Err(rejected("user wasn't present"));
+        }
+        // Passkeys replace the password, so the authenticator must have checked the user
+        if !data.has(FLAG_UV) {
+            return Err(rejected("user wasn't verified"));
+        }
+        if data.has(FLAG_BS) && !data.has(FLAG_BE) {
+            return Err(rejected("backed up but not backup eligible"));
+        }
+        Ok(())
+    }
+
+    /// Verifies a new credential against the challenge its options were made with.
+    /// Only `none` attestation and `packed` self attestation are accepted, which is what
+    /// browsers send when asked for no attestation.
+    pub fn verify_registration(
+        &self,
+        challenge: &str,
+        response: &RegistrationResponse,
+    ) -> Result<NewCredential, WebauthnError> {
+        if response.kind != "public-key" || response.id != response.raw_id {
+            return Err(WebauthnError::Malformed(
+                "credential type or id".to_string(),
+            ));
+        }
+        let client_data_json = base64url_decode(&response.response.client_data_json)?;
+        self.check_client_data(&client_data_json, "webauthn.create", challenge)?;
+
+        let attestation = cbor::decode(&base64url_decode(&response.response.attestation_object)?)?;
+        let auth_data_bytes = attestation
+            .get_text("authData")
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| WebauthnError::Malformed("attestation without authData".to_string()))?;
+        let auth_data = AuthenticatorData::parse(auth_data_bytes)?;
+        self.check_authenticator_data(&auth_data)?;
+
+        let credential = auth_data
+            .attested
+            .as_ref()
+            .ok_or_else(|| WebauthnError::Malformed("no attested credential".to_string()))?;
+        if base64url_decode(&response.raw_id)? != credential.id {
+            return Err(rejected("credential id mismatch"));
+        }
+
+        let statement = attestation.get_text("attStmt");
+        match attestation.get_text("fmt").and_then(Value::as_text) {
+            Some("none") => {
+                if statement != Some(&Value::Map(Vec::new())) {
+                    return Err(WebauthnError::Malformed(
+                        "none attestation with a statement".to_string(),
+                    ));
+                }
+            }
+            Some("packed") => {
+                let statement = statement.unwrap_or(&Value::Null);
+                if statement.get_text("x5c").is_some() {
+                    return Err(WebauthnError::Unsupported(
+                        "packed attestation with certificates".to_string(),
+                    ));
+                }
+                let alg = statement.get_text("alg").and_then(Value::as_int);
+                if alg != Some(credential.public_key.algorithm() as i128) {
+                    return Err(rejected("self attestation algorithm mismatch"));
+                }
+                let signature = statement
+                    .get_text("sig")
+                    .and_then(Value::as_bytes)
+                    .ok_or_else(|| WebauthnError::Malformed("packed without sig".to_string()))?;
+                let signed =
+                    [auth_data_bytes, digest(&SHA256, &client_data_json).as_ref()].concat();
+                credential.public_key.verify(&signed, signature)?;
+            }
+            Some(other) => {
+                return Err(WebauthnError::Unsupported(format!("{other} attestation")));
+            }
+            None => {
+                return Err(WebauthnError::Malformed(
+                    "attestation without fmt".to_string(),
+                ));
+            }
+        }
+
+        Ok(NewCredential {
+            id: base64url_encode(&credential.id),
+            public_key: credential.public_key.to_cose(),
+            algorithm: credential.public_key.algorithm(),
+            sign_count: auth_data.sign_count,
+            transports: response.response.transports.clone(),
+            backed_up: auth_data.has(FLAG_BS),
+        })
+    }
+
+    /// Verifies an assertion made with a stored credential. Which credential and user it
+    /// is, from `response.id` and the user handle, is for the caller to look up.
+    pub fn verify_authentication(
+        &self,
+        challenge: &str,
+        response: &AuthenticationResponse,
+        public_key: &[u8],
+        stored_sign_count: u32,
+    ) -> Result<CredentialUse, WebauthnError> {
+        if response.kind != "public-key" || response.id != response.raw_id {
+            return Err(WebauthnError::Malformed(
+                "credential type or id".to_string(),
+            ));
+        }
+        let client_data_json = base64url_decode(&response.response.client_data_json)?;
+        self.check_client_data(&client_data_json, "webauthn.get", challenge)?;
+
+        let auth_data_bytes = base64url_decode(&response.response.authenticator_data)?;
+        let auth_data = AuthenticatorData::parse(&auth_data_bytes)?;
+        self.check_authenticator_data(&auth_data)?;
+
+        let signed = [
+            auth_data_bytes.as_slice(),
+            digest(&SHA256, &client_data_json).as_ref(),
+        ]
+        .concat();
+        PublicKey::from_cose_bytes(public_key)?
+            .verify(&signed, &base64url_decode(&response.response.signature)?)?;
+
+        // Authenticators that count must count up, otherwise the key was probably copied.
+        // Synced passkeys usually report zero throughout.
+        if (auth_data.sign_count != 0 || stored_sign_count != 0)
+            && auth_data.sign_count <= stored_sign_count
+        {
+            return Err(rejected("signature counter went backwards"));
+        }
+
+        Ok(CredentialUse {
+            sign_count: auth_data.sign_count,
+            backed_up: auth_data.has(FLAG_BS),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soft::SoftAuthenticator;
+
+    const ORIGIN: &str = "https://example.com";
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "example.com".to_string(),
+            name: "Example".to_string(),
+            origins: vec![ORIGIN.to_string()],
+        }
+    }
+
+    fn register(authenticator: &mut SoftAuthenticator) -> NewCredential {
+        let challenge = new_challenge();
+        let options = rp().creation_options(&challenge, b"user-1", "a@example.com", &[]);
+        let response = authenticator.create(&options).unwrap();
+        rp().verify_registration(&challenge, &response).unwrap()
+    }
+
+    fn assert_rejected<T: std::fmt::Debug>(result: Result<T, WebauthnError>, reason: &str) {
+        match result {
+            Err(WebauthnError::Rejected(r)) => assert!(r.contains(reason), "{r}"),
+            other => panic!("expected a rejection for {reason}, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn registers_and_authenticates() {
+        let mut authenticator = SoftAuthenticator::new(ORIGIN);
+        let credential = register(&mut authenticator);
+        assert_eq!(credential.algorithm, ES256);
+        assert_eq!(credential.sign_count, 0);
+
+        let challenge = new_challenge();
+        let allow = [CredentialDescriptor {
+            id: credential.id.clone(),
+            transports: credential.transports.clone(),
+        }];
+        let response = authenticator
+            .get(&rp().request_options(&challenge, &allow))
+            .unwrap();
+        assert_eq!(response.id, credential.id);
+        assert_eq!(
+            response.response.user_handle.as_deref(),
+            Some(base64url_encode(b"user-1").as_str())
+        );
+
+        let used = rp()
+            .verify_authentication(&challenge, &response, &credential.public_key, 0)
+            .unwrap();
+        assert_eq!(used.sign_count, 1);
+    }
+
+    #[test]
+    fn rejects_another_challenge() {
+        let mut authenticator = SoftAuthenticator::new(ORIGIN);
+        let options = rp().creation_options(&new_challenge(), b"user-1", "a@example.com", &[]);
+        let response = authenticator.create(&options).unwrap();
+        assert_rejected(
+            rp().verify_registration(&new_challenge(), &response),
+            "challenge mismatch",
+        );
+
+        let credential = register(&mut authenticator);
+        let response = authenticator
+            .get(&rp().request_options(&new_challenge(), &[]))
+            .unwrap();
+        assert_rejected(
+            rp().verify_authentication(&new_challenge(), &response, &credential.public_key, 0),
+            "challenge mismatch",
+        );
+    }
+
+    #[test]
+    fn rejects_a_counter_going_backwards() {
+        let mut authenticator = SoftAuthenticator::new(ORIGIN);
+        let credential = register(&mut authenticator);
+
+        let challenge = new_challenge();
+        let response = authenticator
+            .get(&rp().request_options(&challenge, &[]))
+            .unwrap();
+        // The stored count is already past what the authenticator reports, as with a clone
+        assert_rejected(
+            rp().verify_authentication(&challenge, &response, &credential.public_key, 5),
+            "counter went backwards",
+        );
+    }
+
+    #[test]
+    fn rejects_a_wrong_origin() {
+        let mut authenticator = SoftAuthenticator::new("https://example.com.evil.test");
+        let challenge = new_challenge();
+        let options = rp().creation_options(&challenge, b"user-1", "a@example.com", &[]);
+        let response = authenticator.create(&options).unwrap();
+        assert_rejected(rp().verify_registration(&challenge, &response), "origin");
+    }
+
+    #[test]
+    fn rejects_an_unverified_user() {
+        let mut authenticator = SoftAuthenticator::new(ORIGIN).without_user_verification();
+        let challenge = new_challenge();
+        let options = rp().creation_options(&challenge, b"user-1", "a@example.com", &[]);
+        let response = authenticator.create(&options).unwrap();
+        assert_rejected(
+            rp().verify_registration(&challenge, &response),
+            "user wasn't verified",
+        );
+    }
+}
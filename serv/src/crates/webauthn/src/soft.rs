@@ -0,0 +1,190 @@
+use crate::cbor::{self, Value};
+use crate::cose::PublicKey;
+use crate::{
+    AssertionResponse, AttestationResponse, AuthenticationResponse, FLAG_AT, FLAG_UP, FLAG_UV,
+    RegistrationResponse, WebauthnError, base64url_decode, base64url_encode,
+};
+use aws_lc_rs::digest::{SHA256, digest};
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, KeyPair};
+use rand_core::RngCore;
+use serde_json::{Value as Json, json};
+
+struct SoftCredential {
+    id: Vec<u8>,
+    key: EcdsaKeyPair,
+    rp_id: String,
+    user_handle: String,
+    sign_count: u32,
+}
+
+/// An ES256 authenticator in memory, standing in for a security key or platform
+/// authenticator in tests. Takes the options JSON the relying party produces and
+/// answers the way a browser's `toJSON()` would.
+pub struct SoftAuthenticator {
+    origin: String,
+    user_verification: bool,
+    credentials: Vec<SoftCredential>,
+}
+
+fn field<'a>(options: &'a Json, path: &[&str]) -> Result<&'a str, WebauthnError> {
+    path.iter()
+        .try_fold(options, |value, key| value.get(key))
+        .and_then(Json::as_str)
+        .ok_or_else(|| WebauthnError::Malformed(format!("options without {}", path.join("."))))
+}
+
+fn listed_ids(options: &Json, key: &str) -> Vec<String> {
+    options
+        .get(key)
+        .and_then(Json::as_array)
+        .map(|list| {
+            list.iter()
+                .filter_map(|c| c.get("id").and_then(Json::as_str).map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn signing_error(_: aws_lc_rs::error::Unspecified) -> WebauthnError {
+    WebauthnError::Unsupported("software key operation failed".to_string())
+}
+
+impl SoftAuthenticator {
+    /// Runs its ceremonies as if on `origin`, e.g. `https://example.com`
+    pub fn new(origin: impl Into<String>) -> Self {
+        Self {
+            origin: origin.into(),
+            user_verification: true,
+            credentials: Vec::new(),
+        }
+    }
+
+    /// Reports the user as present but not verified, like a security key without a PIN
+    pub fn without_user_verification(mut self) -> Self {
+        self.user_verification = false;
+        self
+    }
+
+    fn client_data(&self, kind: &str, challenge: &str) -> Vec<u8> {
+        json!({
+            "type": kind,
+            "challenge": challenge,
+            "origin": self.origin,
+            "crossOrigin": false,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn flags(&self) -> u8 {
+        if self.user_verification {
+            FLAG_UP | FLAG_UV
+        } else {
+            FLAG_UP
+        }
+    }
+
+    /// Answers `navigator.credentials.create()` with a new key and `none` attestation
+    pub fn create(&mut self, options: &Json) -> Result<RegistrationResponse, WebauthnError> {
+        let challenge = field(options, &["challenge"])?;
+        let rp_id = field(options, &["rp", "id"])?.to_string();
+        let user_handle = field(options, &["user", "id"])?.to_string();
+
+        let excluded = listed_ids(options, "excludeCredentials");
+        if self
+            .credentials
+            .iter()
+            .any(|c| c.rp_id == rp_id && excluded.contains(&base64url_encode(&c.id)))
+        {
+            return Err(WebauthnError::Rejected(
+                "authenticator already registered".to_string(),
+            ));
+        }
+
+        let key = EcdsaKeyPair::generate(&ECDSA_P256_SHA256_ASN1_SIGNING).map_err(signing_error)?;
+        let mut id = vec![0u8; 16];
+        rand_core::OsRng.fill_bytes(&mut id);
+
+        let public_key = PublicKey::Es256(key.public_key().as_ref().to_vec()).to_cose();
+        let mut auth_data = digest(&SHA256, rp_id.as_bytes()).as_ref().to_vec();
+        auth_data.push(self.flags() | FLAG_AT);
+        auth_data.extend(0u32.to_be_bytes());
+        auth_data.extend([0u8; 16]);
+        auth_data.extend((id.len() as u16).to_be_bytes());
+        auth_data.extend(&id);
+        auth_data.extend(public_key);
+
+        let attestation_object = cbor::encode(&Value::Map(vec![
+            (
+                Value::Text("fmt".to_string()),
+                Value::Text("none".to_string()),
+            ),
+            (Value::Text("attStmt".to_string()), Value::Map(Vec::new())),
+            (Value::Text("authData".to_string()), Value::Bytes(auth_data)),
+        ]));
+
+        let encoded_id = base64url_encode(&id);
+        self.credentials.push(SoftCredential {
+            id,
+            key,
+            rp_id,
+            user_handle,
+            sign_count: 0,
+        });
+
+        Ok(RegistrationResponse {
+            id: encoded_id.clone(),
+            raw_id: encoded_id,
+            kind: "public-key".to_string(),
+            response: AttestationResponse {
+                client_data_json: base64url_encode(&self.client_data("webauthn.create", challenge)),
+                attestation_object: base64url_encode(&attestation_object),
+                transports: vec!["internal".to_string()],
+            },
+        })
+    }
+
+    /// Answers `navigator.credentials.get()` with the newest matching credential
+    pub fn get(&mut self, options: &Json) -> Result<AuthenticationResponse, WebauthnError> {
+        let challenge = field(options, &["challenge"])?;
+        let rp_id = field(options, &["rpId"])?;
+        let allowed: Vec<Vec<u8>> = listed_ids(options, "allowCredentials")
+            .iter()
+            .map(|id| base64url_decode(id))
+            .collect::<Result<_, _>>()?;
+
+        let flags = self.flags();
+        let client_data = self.client_data("webauthn.get", challenge);
+        let credential = self
+            .credentials
+            .iter_mut()
+            .rev()
+            .find(|c| c.rp_id == rp_id && (allowed.is_empty() || allowed.contains(&c.id)))
+            .ok_or_else(|| WebauthnError::Rejected("no matching credential".to_string()))?;
+        credential.sign_count += 1;
+
+        let mut auth_data = digest(&SHA256, rp_id.as_bytes()).as_ref().to_vec();
+        auth_data.push(flags);
+        auth_data.extend(credential.sign_count.to_be_bytes());
+
+        let signed = [auth_data.as_slice(), digest(&SHA256, &client_data).as_ref()].concat();
+        let signature = credential
+            .key
+            .sign(&SystemRandom::new(), &signed)
+            .map_err(signing_error)?;
+
+        let id = base64url_encode(&credential.id);
+        Ok(AuthenticationResponse {
+            id: id.clone(),
+            raw_id: id,
+            kind: "public-key".to_string(),
+            response: AssertionResponse {
+                client_data_json: base64url_encode(&client_data),
+                authenticator_data: base64url_encode(&auth_data),
+                signature: base64url_encode(signature.as_ref()),
+                user_handle: Some(credential.user_handle.clone()),
+            },
+        })
+    }
+}
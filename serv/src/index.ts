@@ -3,13 +3,22 @@ import { createExpressMiddleware } from "@trpc/server/adapters/express";
 import { appRouter, Ctx } from "./trpc";
 import oauthRouter from "./oauth";
 import path from "path";
-import { initDbs, initRedis, uidLookup } from "./rlibs";
+import {
+  configurePasswordHashing,
+  initDbs,
+  initRedis,
+  uidLookup,
+} from "./rlibs";
 import cookieParser from "cookie-parser";
 import helmet from "helmet";
 import {
+  accessJwksJson,
   cleanupExpiredTokens,
   cleanupRateLimitKeys,
   checkAccessJwt,
+  purgeExpiredGrants,
+  purgeDeletedUsers,
+  processMailQueue,
 } from "./rlibs/index";
 
 const app = express();
@@ -62,6 +71,18 @@ app.use((_, res, next) => {
   next();
 });
 
+// Lets other services verify access tokens when they're signed with a private key
+app.get("/.well-known/jwks.json", (_, res) => {
+  accessJwksJson()
+    .then((jwks) => {
+      res.type("application/jwk-set+json").send(jwks);
+    })
+    .catch((error) => {
+      console.error("Failed to serve JWKS:", error);
+      res.sendStatus(500);
+    });
+});
+
 app.get(/^\/(?!trpc).*/, (_, res) => {
   res.sendFile(path.join(cdp, "index.html"));
 });
@@ -79,6 +100,14 @@ async function initializeScheduledCleanups() {
 
       const tokenCleaned = await cleanupExpiredTokens();
       console.log(`Cleaned up ${tokenCleaned} expired refresh tokens`);
+
+      // Time-limited roles and perms, each removal is recorded in Expired_Grants
+      const grantsPurged = await purgeExpiredGrants();
+      console.log(`Purged ${grantsPurged.length} expired grants`);
+
+      // Soft deleted users past their grace period
+      const usersPurged = await purgeDeletedUsers();
+      console.log(`Purged ${usersPurged.length} deleted users`);
     } catch (error) {
       console.error("Cleanup failed:", error);
     }
@@ -95,6 +124,25 @@ async function initializeScheduledCleanups() {
   console.log(`Scheduled cleanup every ${CLEANUP_INTERVAL_MS / 60000} minutes`);
 }
 
+function initializeMailQueue() {
+  const MAIL_QUEUE_INTERVAL_MS = 30 * 1000; // 30 seconds
+
+  // Retries are scheduled by the queue itself, this only picks up whatever is due
+  setInterval(() => {
+    processMailQueue()
+      .then(({ sent, retried, failed }) => {
+        if (retried > 0 || failed > 0) {
+          console.log(
+            `Mail queue: ${sent} sent, ${retried} to retry, ${failed} failed`,
+          );
+        }
+      })
+      .catch((error) => {
+        console.error("Mail queue failed:", error);
+      });
+  }, MAIL_QUEUE_INTERVAL_MS);
+}
+
 async function initializeServer() {
   try {
     console.log("Initializing database pools...");
@@ -102,6 +150,8 @@ async function initializeServer() {
     console.log("Database pools initialized successfully");
     await initRedis();
     console.log("Redis initialized successfully");
+    // Argon2 parameters from the env, and a broken pepper setup fails here
+    configurePasswordHashing({});
 
     app.listen(port, () => {
       console.log(`Server listening on port ${port}`);
@@ -109,6 +159,7 @@ async function initializeServer() {
       console.log(`http://localhost:${port}`);
     });
 
+    initializeMailQueue();
     await initializeScheduledCleanups();
   } catch (error) {
     console.error("Failed to initialize database pools:", error);
@@ -3,11 +3,12 @@ import { OAuth2Client } from "google-auth-library";
 import { URLSearchParams } from "url";
 import * as Rapi from "./rlibs/index";
 
+// Sealed into the state Google sends back, see `mintOauthState`
 interface OAuthState {
-  redirect_uri?: string;
-  frontend_origin?: string;
-  timestamp?: number;
-  nonce?: string;
+  redirect_uri: string;
+  frontend_origin: string;
+  // Link the Google account to this signed-in user instead of signing in with it
+  link_uid?: string;
 }
 
 const router: ReturnType<typeof express.Router> = express.Router();
@@ -89,11 +90,67 @@ const COOKIE_OPTS = {
   priority: "high" as const,
 };
 
+// Holds the binding the sealed state only opens with, so a callback URL someone else
+// started can't sign in or link an account in whichever browser follows it
+const STATE_COOKIE = "__Host-oauthState";
+const STATE_COOKIE_OPTS = {
+  ...COOKIE_OPTS,
+  maxAge: 10 * 60 * 1000,
+};
+
 const createOAuth2Client = (callbackUrl: string) => {
   return new OAuth2Client(GOOGLE_CLIENT_ID, GOOGLE_CLIENT_SECRET, callbackUrl);
 };
 
-router.get("/auth/google", (req, res) => {
+// Issues the session for a user a provider vouched for, or hands over to the second
+// factor if they have one
+const completeSignIn = async (
+  res: express.Response,
+  user: Rapi.User,
+  frontendOrigin: string,
+  redirectPath: string,
+  name: string,
+) => {
+  // The provider only stands in for the password, a second factor is still needed
+  if (user.totpEnabled) {
+    const challenge = await Rapi.beginSecondFactor(user.uid);
+    const params = new URLSearchParams({
+      second_factor: challenge,
+      redirect_uri: redirectPath,
+    });
+    res.redirect(`${frontendOrigin}/login?${params.toString()}`);
+    return;
+  }
+
+  // Generate JWT tokens
+  const accessToken = await Rapi.genAccessJwt(user.uid, user.email);
+  const [refreshToken, jti] = await Rapi.genRefreshJwt(user.uid, user.email);
+
+  await Rapi.storeRefreshToken(
+    jti,
+    user.uid,
+    user.email,
+    REFRESH_TOKEN_MAX_AGE,
+  );
+
+  // Set HTTP-only cookies
+  res.cookie("__Host-accessToken", accessToken, COOKIE_OPTS);
+  res.cookie("__Host-refreshToken", refreshToken, {
+    ...COOKIE_OPTS,
+    maxAge: REFRESH_TOKEN_MAX_AGE,
+  });
+
+  // Redirect to frontend with success
+  const params = new URLSearchParams({
+    success: "true",
+    email: encodeURIComponent(user.email),
+    name: encodeURIComponent(name),
+  });
+
+  res.redirect(`${frontendOrigin}${redirectPath}?${params.toString()}`);
+};
+
+router.get("/auth/google", async (req, res) => {
   try {
     const frontendUrl = getFrontendUrl(req);
     const backendUrl = getServerUrl(req);
@@ -106,9 +163,20 @@ router.get("/auth/google", (req, res) => {
     const statePayload: OAuthState = {
       redirect_uri: validatedPath,
       frontend_origin: frontendUrl,
-      timestamp: Date.now(),
-      nonce: Math.random().toString(36).substring(2),
     };
+    if (req.query.link === "true") {
+      // The session cookies are strict and won't come along on the way back from
+      // Google, so the user is looked up now and sealed into the state
+      const signedCookies = req.signedCookies as Record<
+        string,
+        string | undefined
+      >;
+      const token = signedCookies["__Host-accessToken"];
+      if (!token) throw new Error("Sign in before linking a Google account");
+      statePayload.link_uid = (await Rapi.checkAccessJwt(token)).uid;
+    }
+    const { state, binding } = Rapi.mintOauthState(statePayload);
+    res.cookie(STATE_COOKIE, binding, STATE_COOKIE_OPTS);
 
     const redirectUrl = oauth2Client.generateAuthUrl({
       access_type: "offline",
@@ -117,7 +185,7 @@ router.get("/auth/google", (req, res) => {
         "https://www.googleapis.com/auth/userinfo.profile",
       ],
       prompt: "select_account",
-      state: JSON.stringify(statePayload),
+      state,
     });
 
     console.warn(
@@ -137,15 +205,20 @@ router.get("/auth/google/callback", async (req, res) => {
     if (!code || typeof code !== "string")
       throw new Error("No authorization code received");
 
-    let stateObj: OAuthState;
-    try {
-      stateObj = state ? (JSON.parse(state as string) as OAuthState) : {};
-    } catch {
-      stateObj = {};
+    const signedCookies = req.signedCookies as Record<
+      string,
+      string | undefined
+    >;
+    const binding = signedCookies[STATE_COOKIE];
+    res.clearCookie(STATE_COOKIE, STATE_COOKIE_OPTS);
+    if (!state || typeof state !== "string" || !binding) {
+      throw new Error("Sign-in was not started from this browser");
     }
+    // Tamper-proof, bound to this browser and good for one callback
+    const stateObj = (await Rapi.verifyOauthState(state, binding)) as OAuthState;
 
-    const frontendOrigin = stateObj.frontend_origin ?? DEFAULT_URL;
-    const redirectPath = stateObj.redirect_uri ?? "/";
+    const frontendOrigin = stateObj.frontend_origin;
+    const redirectPath = stateObj.redirect_uri;
 
     // Validate frontend origin
     if (!ALLOWED_ORIGINS.includes(frontendOrigin)) {
@@ -170,69 +243,140 @@ router.get("/auth/google/callback", async (req, res) => {
     if (!payload) throw new Error("Failed to get user payload");
 
     const email = payload.email ?? "";
-    const googleId = payload.sub;
     const name = payload.name ?? "";
+    const profile: Rapi.ProviderProfile = {
+      provider: "google",
+      providerUserId: payload.sub,
+      email,
+      emailVerified: payload.email_verified ?? false,
+    };
 
-    // Check if user exists by OAuth ID
-    const existingUsers = await Rapi.searchUsers(email);
-    let user;
-
-    if (existingUsers.length > 0) {
-      const existingUser = existingUsers[0];
-
-      if (
-        existingUser.oauthProvider === "google" &&
-        existingUser.oauthProviderId === googleId
-      ) {
-        user = existingUser;
-      } else if (!existingUser.oauthProvider && !existingUser.oauthProviderId) {
-        user = await Rapi.updateUser(
-          existingUser.uid,
-          undefined,
-          undefined,
-          "google",
-          googleId,
-        );
-        console.log(`Linked Google account to existing user: ${email}`);
-      } else {
-        throw new Error(
-          "Account already exists with different authentication method",
-        );
-      }
-    } else {
-      user = await Rapi.createUser(email, undefined, "google", googleId);
+    if (stateObj.link_uid) {
+      const uid = stateObj.link_uid;
+      await Rapi.linkIdentity(uid, profile, { actorUid: uid, ip: req.ip });
+      const params = new URLSearchParams({ linked: "google" });
+      res.redirect(`${frontendOrigin}${redirectPath}?${params.toString()}`);
+      return;
     }
 
-    // Generate JWT tokens
-    const accessToken = await Rapi.genAccessJwt(user.uid, user.email);
-    const [refreshToken, jti] = await Rapi.genRefreshJwt(user.uid, user.email);
+    // Links by email only when Google and the existing account have both verified it
+    const user = await Rapi.providerSignIn(profile, { ip: req.ip });
+    await completeSignIn(res, user, frontendOrigin, redirectPath, name);
+  } catch (error) {
+    console.error("Google OAuth callback error:", error);
+
+    // Redirect to default frontend on error
+    const params = new URLSearchParams({
+      error: "oauth_failed",
+      message:
+        error instanceof Error
+          ? encodeURIComponent(error.message)
+          : "Authentication failed",
+    });
+
+    res.redirect(`${DEFAULT_URL}/login?${params.toString()}`);
+  }
+});
+
+// Ties an OpenID Connect sign-in to the browser that started it, and remembers where to
+// send it afterwards. The state it holds is all that ties the callback to the sign-in.
+const OIDC_COOKIE = "__Host-oidcSignIn";
+
+interface OidcPending {
+  state: string;
+  frontend_origin: string;
+  redirect_uri: string;
+}
 
-    await Rapi.storeRefreshToken(
-      jti,
-      user.uid,
-      user.email,
-      REFRESH_TOKEN_MAX_AGE,
+router.get("/auth/oidc/:provider", async (req, res) => {
+  try {
+    const provider = req.params.provider;
+    const frontendUrl = getFrontendUrl(req);
+    const callbackUrl = `${getServerUrl(req)}/auth/oidc/${encodeURIComponent(provider)}/callback`;
+    const validatedPath = validateRedirectPath(
+      (req.query.redirect_uri as string) ?? "/",
     );
 
-    // Set HTTP-only cookies
-    res.cookie("__Host-accessToken", accessToken, COOKIE_OPTS);
-    res.cookie("__Host-refreshToken", refreshToken, {
-      ...COOKIE_OPTS,
-      maxAge: REFRESH_TOKEN_MAX_AGE,
-    });
+    let linkUid: string | undefined;
+    if (req.query.link === "true") {
+      const signedCookies = req.signedCookies as Record<
+        string,
+        string | undefined
+      >;
+      const token = signedCookies["__Host-accessToken"];
+      if (!token)
+        throw new Error(`Sign in before linking a ${provider} account`);
+      linkUid = (await Rapi.checkAccessJwt(token)).uid;
+    }
 
-    // Redirect to frontend with success
-    const params = new URLSearchParams({
-      success: "true",
-      email: encodeURIComponent(email),
-      name: encodeURIComponent(name),
-    });
+    const { url, state } = await Rapi.beginOidcSignIn(
+      provider,
+      callbackUrl,
+      linkUid,
+    );
+    const pending: OidcPending = {
+      state,
+      frontend_origin: frontendUrl,
+      redirect_uri: validatedPath,
+    };
+    res.cookie(OIDC_COOKIE, JSON.stringify(pending), STATE_COOKIE_OPTS);
+    res.redirect(url);
+  } catch (error) {
+    console.error("OIDC initiation error:", error);
+    res.redirect(`${DEFAULT_URL}/login?error=oauth_init_failed`);
+  }
+});
+
+router.get("/auth/oidc/:provider/callback", async (req, res) => {
+  try {
+    const { code, state, error, error_description } = req.query;
+
+    const signedCookies = req.signedCookies as Record<
+      string,
+      string | undefined
+    >;
+    let pending: OidcPending | undefined;
+    try {
+      pending = JSON.parse(signedCookies[OIDC_COOKIE] ?? "") as OidcPending;
+    } catch {
+      pending = undefined;
+    }
+    res.clearCookie(OIDC_COOKIE, STATE_COOKIE_OPTS);
+    if (!pending || typeof state !== "string" || pending.state !== state) {
+      throw new Error("Sign-in was not started from this browser");
+    }
+    // e.g. the user declined at the provider
+    if (typeof error === "string") {
+      throw new Error(
+        typeof error_description === "string" ? error_description : error,
+      );
+    }
+    if (!code || typeof code !== "string")
+      throw new Error("No authorization code received");
+    if (!ALLOWED_ORIGINS.includes(pending.frontend_origin)) {
+      throw new Error(
+        `Unauthorized frontend origin: ${pending.frontend_origin}`,
+      );
+    }
 
-    res.redirect(`${frontendOrigin}${redirectPath}?${params.toString()}`);
+    const result = await Rapi.finishOidcSignIn(state, code, { ip: req.ip });
+    if (result.linked) {
+      const params = new URLSearchParams({ linked: req.params.provider });
+      res.redirect(
+        `${pending.frontend_origin}${pending.redirect_uri}?${params.toString()}`,
+      );
+      return;
+    }
+    await completeSignIn(
+      res,
+      result.user,
+      pending.frontend_origin,
+      pending.redirect_uri,
+      result.name ?? "",
+    );
   } catch (error) {
-    console.error("Google OAuth callback error:", error);
+    console.error("OIDC callback error:", error);
 
-    // Redirect to default frontend on error
     const params = new URLSearchParams({
       error: "oauth_failed",
       message:
@@ -256,6 +400,7 @@ router.get("/auth/config", (req, res) => {
     frontendUrl,
     callbackUrl: `${backendUrl}/auth/google/callback`,
     hasGoogleConfig: !!GOOGLE_CLIENT_ID && !!GOOGLE_CLIENT_SECRET,
+    oidcProviders: Rapi.oidcProviders(),
   });
 });
 
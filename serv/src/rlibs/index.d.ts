@@ -5,6 +5,12 @@ export interface AccessTokenClaims {
   email: string
   iat: number
   exp: number
+  /** Missing on tokens issued before `nbf` was set, see `verify_access_token` */
+  nbf?: number
+  /** `JWT_ISSUER`, if set */
+  iss?: string
+  /** `JWT_AUDIENCE`, if set */
+  aud?: string
   tokenType: string
 }
 
@@ -16,10 +22,52 @@ export interface RefreshTokenClaims {
   tokenType: string
   jti: string
 }
+
+/**
+ * The public keys access tokens verify with as a JWKS document, for
+ * `/.well-known/jwks.json`. Keys still verifying tokens from before a rotation are included.
+ */
+export declare function accessJwksJson(): Promise<string>
+
+/**
+ * The PEM public key access tokens verify with, for other services. Null when they're
+ * signed with a shared secret (HS256).
+ */
+export declare function accessJwtPublicKey(): Promise<string | null>
+
+export declare function addPermImplication(perm: string, implied: string): Promise<Perm>
+
+export declare function assignUserRole(uid: string, roleName: string, window?: GrantWindow | undefined | null, audit?: AuditContext | undefined | null): Promise<Array<string>>
+
+export declare function authenticate(email: string, pass: string): Promise<LoginResult>
+
+/**
+ * Where to send the browser to sign in at an OpenID Connect provider. With `link_uid`
+ * the provider account is linked to that user instead.
+ */
+export declare function beginOidcSignIn(provider: string, redirectUri: string, linkUid?: string | undefined | null): Promise<OidcAuthorization>
+
+/** Options to pass to `navigator.credentials.get()` */
+export declare function beginPasskeyAuthentication(): Promise<any>
+
+/** Options to pass to `navigator.credentials.create()` */
+export declare function beginPasskeyRegistration(uid: string): Promise<any>
+
+/** Challenge for a sign-in that skipped the password, for users with TOTP enabled */
+export declare function beginSecondFactor(uid: string): Promise<string>
+
+export declare function beginTotpEnrolment(uid: string): Promise<TotpEnrolment>
+
 export declare function checkAccessJwt(token: string): Promise<AccessTokenClaims>
 
+/**
+ * Fails rather than answering false when the hash is peppered with a key no longer in
+ * `PASSWORD_PEPPERS`
+ */
 export declare function checkPass(email: string, pass: string): Promise<boolean>
 
+export declare function checkPassword(pass: string, email?: string | undefined | null): Promise<PasswordCheck>
+
 export declare function checkRateLimit(identifier: string, maxRequests: number, windowSeconds: number): Promise<[boolean, number, number]>
 
 export declare function checkRefreshJwt(token: string): Promise<RefreshTokenClaims>
@@ -28,47 +76,533 @@ export declare function cleanupExpiredTokens(): Promise<number>
 
 export declare function cleanupRateLimitKeys(): Promise<number>
 
-export declare function createUser(email: string, pass?: string | undefined | null, oauthProvider?: string | undefined | null, oauthProviderId?: string | undefined | null, roles?: Array<string> | undefined | null, perms?: Array<string> | undefined | null): Promise<User>
+/** Sets the OpenID Connect providers. Optional, the environment is read otherwise. */
+export declare function configureOidc(providers: Array<OidcProviderConfig>): void
+
+/**
+ * Overrides the Argon2 parameters for new hashes. Call once at startup, before any
+ * password is hashed or checked. Fails on an invalid `PASSWORD_PEPPERS` too.
+ */
+export declare function configurePasswordHashing(config: HashConfig): void
+
+/** Overrides the password policy. Call once at startup, before any password is checked. */
+export declare function configurePasswordPolicy(policy: PasswordPolicy): void
+
+/** Overrides `UNVERIFIED_EMAIL_POLICY`. Call once at startup, before any login. */
+export declare function configureUnverifiedEmailPolicy(policy: UnverifiedEmailPolicy): void
+
+/** Sets the passkey relying party. Optional, the environment is read otherwise. */
+export declare function configureWebauthn(config: WebauthnConfig): void
+
+/**
+ * Returns the recovery codes, which can't be shown again. They are stored hashed with
+ * `RECOVERY_CODE_SECRET`, which every instance has to share.
+ */
+export declare function confirmTotpEnrolment(uid: string, code: string, audit?: AuditContext | undefined | null): Promise<Array<string>>
+
+export declare function createPerm(perm: string, description?: string | undefined | null): Promise<Perm>
+
+export declare function createRole(roleName: string, perms?: Array<string> | undefined | null): Promise<Role>
+
+export declare function createUser(email: string, pass?: string | undefined | null, roles?: Array<string> | undefined | null, perms?: Array<string> | undefined | null, audit?: AuditContext | undefined | null): Promise<User>
+
+export declare function deletePasskey(uid: string, credentialId: string, audit?: AuditContext | undefined | null): Promise<boolean>
+
+export declare function deletePerm(perm: string): Promise<Perm>
 
 export declare function deleteRefreshToken(jti: string): Promise<boolean>
 
-export declare function deleteUser(email: string): Promise<User>
+export declare function deleteRole(roleName: string): Promise<Role>
+
+export declare function deleteUser(email: string, audit?: AuditContext | undefined | null): Promise<User>
 
 export declare function deleteUserRefreshTokens(userId: string): Promise<number>
 
+export declare function denyUserPerm(uid: string, perm: string, resource?: string | undefined | null, reason?: string | undefined | null, audit?: AuditContext | undefined | null): Promise<PermDecision>
+
+export declare function disableTotp(uid: string, audit?: AuditContext | undefined | null): Promise<User>
+
+export declare function effectivePerms(uid: string): Promise<Array<string>>
+
+export declare function emailLookup(email: string): Promise<User | null>
+
+export declare function explainPerm(uid: string, perm: string, resource?: string | undefined | null): Promise<PermDecision>
+
+export declare function exportAuditJsonl(query?: AuditQuery | undefined | null): Promise<AuditExport>
+
+export declare function finishOidcSignIn(state: string, code: string, audit?: AuditContext | undefined | null): Promise<OidcSignIn>
+
+/** `response` is the assertion's `toJSON()` */
+export declare function finishPasskeyAuthentication(response: any): Promise<LoginResult>
+
+/** `response` is the new credential's `toJSON()` */
+export declare function finishPasskeyRegistration(uid: string, response: any, name?: string | undefined | null, audit?: AuditContext | undefined | null): Promise<Passkey>
+
 export declare function flushRedis(): Promise<boolean>
 
 export declare function genAccessJwt(uid: string, email: string): Promise<string>
 
 export declare function genRefreshJwt(uid: string, email: string): Promise<[string, string]>
 
+export declare function genWorksheetHtml(gridId: number, options?: WorksheetOptions | undefined | null): Promise<string>
+
+export declare function genWorksheetPdf(gridId: number, options?: WorksheetOptions | undefined | null): Promise<Buffer>
+
 export declare function getAllRefreshTokens(): Promise<Array<RefreshTokenData>>
 
+export declare function getGrid(gridId: number): Promise<Grid>
+
 export declare function getRateLimitStats(identifier: string): Promise<[number, number]>
 
 export declare function getRedisInfo(): Promise<string>
 
 export declare function getRefreshToken(jti: string): Promise<RefreshTokenData | null>
 
+export declare function grantRolePerm(roleName: string, perm: string): Promise<Role>
+
+export declare function grantUserPerm(uid: string, perm: string, resource?: string | undefined | null, window?: GrantWindow | undefined | null, audit?: AuditContext | undefined | null): Promise<Array<string>>
+
+export declare function hasPerm(uid: string, perm: string, resource?: string | undefined | null): Promise<boolean>
+
+export declare function importPasswordHash(uid: string, hash: string, audit?: AuditContext | undefined | null): Promise<User>
+
 export declare function initDbs(): Promise<void>
 
 export declare function initRedis(): Promise<void>
 
+export declare function linkIdentity(uid: string, profile: ProviderProfile, audit?: AuditContext | undefined | null): Promise<User>
+
+export declare function listAudit(query: AuditQuery): Promise<AuditPage>
+
+export declare function listExpiredGrants(uid?: string | undefined | null, limit?: number | undefined | null): Promise<Array<ExpiredGrant>>
+
+export declare function listFailedMail(limit: number): Promise<Array<FailedMail>>
+
+export declare function listIdentities(uid: string): Promise<Array<Identity>>
+
+export declare function listPasskeys(uid: string): Promise<Array<Passkey>>
+
+export declare function listPerms(): Promise<Array<Perm>>
+
+export declare function listRoles(): Promise<Array<Role>>
+
+export declare function listUsers(query: UserListQuery): Promise<UserPage>
+
+export declare function lockoutStatus(email: string): Promise<LockoutStatus>
+
+/**
+ * Seals `payload` into an OAuth `state`, with a binding to keep in a cookie. The key is
+ * derived from `OAUTH_STATE_SECRET`, which every instance has to share.
+ */
+export declare function mintOauthState(payload: any): OAuthState
+
+/** Names of the OpenID Connect providers that can be signed in with */
+export declare function oidcProviders(): Array<string>
+
+/** Renders a template without sending anything */
+export declare function previewMail(template: string, vars: Record<string, string>, locale?: string | undefined | null): RenderedMail
+
+/** Sends due mail, 50 at a time by default. Meant to be called on an interval. */
+export declare function processMailQueue(limit?: number | undefined | null): Promise<MailQueueReport>
+
+/**
+ * Signs in through a provider, linking or creating the account as needed. Fails for an
+ * unverified email while the unverified email policy is `block`.
+ */
+export declare function providerSignIn(profile: ProviderProfile, audit?: AuditContext | undefined | null): Promise<User>
+
+export declare function purgeDeletedUsers(): Promise<Array<string>>
+
+export declare function purgeExpiredGrants(): Promise<Array<ExpiredGrant>>
+
 export declare function redisHealthCheck(): Promise<boolean>
 
+export declare function regenerateRecoveryCodes(uid: string, audit?: AuditContext | undefined | null): Promise<Array<string>>
+
+export declare function removePermImplication(perm: string, implied: string): Promise<Perm>
+
+export declare function removeUserDeny(uid: string, perm: string, resource?: string | undefined | null, audit?: AuditContext | undefined | null): Promise<PermDecision>
+
+export declare function removeUserRole(uid: string, roleName: string, audit?: AuditContext | undefined | null): Promise<Array<string>>
+
+export declare function renameRole(roleName: string, newName: string): Promise<Role>
+
+export declare function requestEmailVerification(email: string, verifyUrl: string, locale?: string | undefined | null): Promise<void>
+
+export declare function requestPasswordReset(email: string, resetUrl: string, locale?: string | undefined | null): Promise<void>
+
+export declare function resetPassword(token: string, pass: string, audit?: AuditContext | undefined | null): Promise<User>
+
 export declare function resetRateLimit(identifier: string): Promise<boolean>
 
+export declare function restoreUser(uid: string, audit?: AuditContext | undefined | null): Promise<User>
+
+export declare function revokeRolePerm(roleName: string, perm: string): Promise<Role>
+
+export declare function revokeUserPerm(uid: string, perm: string, resource?: string | undefined | null, audit?: AuditContext | undefined | null): Promise<Array<string>>
+
 export declare function rotateRefreshJwt(token: string): Promise<[string, string, string]>
 
-export declare function searchUsers(emailStr: string): Promise<Array<User>>
+/** Queues a mail, sent by the next `process_mail_queue` */
+export declare function sendMail(mail: Mail): Promise<void>
+
+export declare function sendTemplateMail(template: string, to: string, vars: Record<string, string>, locale?: string | undefined | null): Promise<void>
+
+export declare function setRolePerms(roleName: string, perms: Array<string>): Promise<Role>
 
 export declare function storeRefreshToken(jti: string, userId: string, email: string, expiresInSeconds: number): Promise<boolean>
 
+export declare function totpStatus(uid: string): Promise<TotpStatus>
+
 export declare function uidLookup(uid: string): Promise<User>
 
-export declare function updateUser(uid: string, email?: string | undefined | null, pass?: string | undefined | null, oauthProvider?: string | undefined | null, oauthProviderId?: string | undefined | null, roles?: Array<string> | undefined | null, perms?: Array<string> | undefined | null): Promise<User>
+export declare function unlinkIdentity(uid: string, provider: string, audit?: AuditContext | undefined | null): Promise<User>
+
+export declare function unlockAccount(email: string): Promise<boolean>
+
+export declare function unverifiedEmailPolicy(): UnverifiedEmailPolicy
+
+export declare function updatePerm(perm: string, newName?: string | undefined | null, description?: string | undefined | null): Promise<Perm>
+
+export declare function updateUser(uid: string, email?: string | undefined | null, pass?: string | undefined | null, roles?: Array<string> | undefined | null, perms?: Array<string> | undefined | null, audit?: AuditContext | undefined | null): Promise<User>
 
 export declare function validateRefreshToken(jti: string): Promise<boolean>
+
+export declare function verifyEmail(token: string, audit?: AuditContext | undefined | null): Promise<User>
+
+/** Returns the payload of a state minted with `mint_oauth_state`, once */
+export declare function verifyOauthState(state: string, binding: string): Promise<any>
+
+export declare function verifySecondFactor(challenge: string, code: string): Promise<LoginResult>
+
+/** Checks and uses up a TOTP or recovery code, e.g. before a sensitive change */
+export declare function verifyTotp(uid: string, code: string): Promise<boolean>
+export declare enum AuditAction {
+  UserCreate = 'user_create',
+  UserUpdate = 'user_update',
+  UserDelete = 'user_delete',
+  UserRestore = 'user_restore',
+  UserPurge = 'user_purge',
+  RoleAssign = 'role_assign',
+  RoleRemove = 'role_remove',
+  PermGrant = 'perm_grant',
+  PermRevoke = 'perm_revoke',
+  PermDeny = 'perm_deny',
+  DenyRemove = 'deny_remove',
+  EmailVerify = 'email_verify',
+  TotpEnable = 'totp_enable',
+  TotpDisable = 'totp_disable',
+  RecoveryCodesRegenerate = 'recovery_codes_regenerate',
+  PasskeyAdd = 'passkey_add',
+  PasskeyRemove = 'passkey_remove',
+  IdentityLink = 'identity_link',
+  IdentityUnlink = 'identity_unlink'
+}
+
+/** Who made a change and from where, passed down from the request */
+export interface AuditContext {
+  /** Unset for changes nobody was logged in for, e.g. registration */
+  actorUid?: string
+  ip?: string
+}
+
+export interface AuditEntry {
+  auditId: number
+  /** Epoch seconds */
+  at: number
+  actorUid?: string
+  targetUid?: string
+  action: AuditAction
+  /** Changed fields as `{ field: { before, after } }` */
+  diff: any
+  ip?: string
+}
+
+export interface AuditExport {
+  /** One entry per line, newest first */
+  jsonl: string
+  /** Pass back as `cursor` for the next chunk, unset after the last one */
+  nextCursor?: number
+}
+
+export interface AuditPage {
+  /** Newest first */
+  entries: Array<AuditEntry>
+  nextCursor?: number
+}
+
+export interface AuditQuery {
+  actorUid?: string
+  targetUid?: string
+  action?: AuditAction
+  /** Epoch seconds, inclusive */
+  since?: number
+  /** Epoch seconds, exclusive */
+  until?: number
+  /** Page size, 50 by default. The export always pages by 5000. */
+  limit?: number
+  /** `next_cursor` from the previous page */
+  cursor?: number
+}
+
+/** A grant removed by `purge_expired_grants` */
+export interface ExpiredGrant {
+  expiredId: number
+  uid: string
+  kind: GrantKind
+  /** Role name or perm */
+  name: string
+  /** Resource the perm was scoped to, empty for roles and unscoped perms */
+  resource: string
+  validFrom?: number
+  validUntil: number
+  removedAt: number
+}
+
+export interface FailedMail {
+  id: string
+  to: string
+  subject: string
+  attempts: number
+  lastError?: string
+}
+
+export declare enum GrantKind {
+  Role = 'role',
+  Perm = 'perm'
+}
+
+/** When a user role or perm grant applies, as epoch seconds. Unset bounds are open. */
+export interface GrantWindow {
+  validFrom?: number
+  validUntil?: number
+}
+
+export interface Grid {
+  title: string
+  description: string
+  phrases: Array<Phrase>
+}
+
+/**
+ * Argon2id cost parameters for new hashes. Unset fields fall back to the
+ * `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS` and `ARGON2_PARALLELISM` env vars, then to
+ * the argon2 crate defaults.
+ */
+export interface HashConfig {
+  memoryKib?: number
+  iterations?: number
+  parallelism?: number
+}
+
+/** An account at a provider, linked to a user */
+export interface Identity {
+  provider: string
+  providerUserId: string
+  /** As the provider reported it, may differ from the user's */
+  email?: string
+  linkedAt: number
+  lastLoginAt?: number
+}
+
+/** Failed login tracking for one account */
+export interface LockoutStatus {
+  /** Failures since the last successful login, forgotten after a day without any */
+  failedAttempts: number
+  locked: boolean
+  /** Unix seconds, set while locked */
+  lockedUntil?: number
+  retryAfterSeconds: number
+}
+
+export declare enum LoginOutcome {
+  Valid = 'valid',
+  /** Unknown email, no password set or wrong password, deliberately not told apart */
+  Invalid = 'invalid',
+  Locked = 'locked',
+  /** Right password, but the email isn't verified and the policy blocks such logins */
+  Unverified = 'unverified',
+  /** Right password, now `verify_second_factor` needs a code for the challenge */
+  SecondFactorRequired = 'second_factor_required'
+}
+
+export interface LoginResult {
+  outcome: LoginOutcome
+  /** Only set when the login is valid */
+  user?: User
+  failedAttempts: number
+  /** Set when locked, including a lock the failed attempt just triggered */
+  retryAfterSeconds: number
+  /** Only set when a second factor is required, pass it to `verify_second_factor` */
+  challenge?: string
+  /**
+   * On an invalid login whose password hash is peppered with a key no longer in
+   * `PASSWORD_PEPPERS`, that key's id. For the operator to log, the user can only reset.
+   */
+  unknownPepper?: string
+}
+
+/** One message to one recipient. The sender is configured on the transport. */
+export interface Mail {
+  to: string
+  subject: string
+  text: string
+  /** Sent as an alternative to `text` for clients that show HTML */
+  html?: string
+}
+
+export interface MailQueueReport {
+  sent: number
+  /** Failed for now, queued again with a backoff */
+  retried: number
+  /** Given up on and moved to the failed list */
+  failed: number
+}
+
+/** A sealed `state` for an authorization URL */
+export interface OAuthState {
+  state: string
+  /** Keep it in a cookie, the state only verifies together with it */
+  binding: string
+}
+
+/** Where to send the browser to sign in at a provider */
+export interface OidcAuthorization {
+  url: string
+  /** Comes back with the browser, tie it to the browser to tell who started the sign-in */
+  state: string
+}
+
+/**
+ * A provider to sign in with over OpenID Connect. Any compliant one works, e.g.
+ * Keycloak (`https://host/realms/<realm>`), Authentik
+ * (`https://host/application/o/<slug>/`) or Microsoft
+ * (`https://login.microsoftonline.com/<tenant>/v2.0`).
+ */
+export interface OidcProviderConfig {
+  /** Names the provider in routes and on linked identities, e.g. `keycloak` */
+  name: string
+  issuer: string
+  clientId: string
+  /** Unset for public clients, which rely on PKCE alone */
+  clientSecret?: string
+  /** Requested besides `openid`, `email` and `profile` by default */
+  scopes?: Array<string>
+}
+
+/** How a sign-in at a provider ended */
+export interface OidcSignIn {
+  user: User
+  /** The provider account was linked to a signed-in user rather than signed in with */
+  linked: boolean
+  /** The name the provider has for the user, if it shared one */
+  name?: string
+}
+
+export interface Passkey {
+  /** base64url, as the browser reports it */
+  credentialId: string
+  name: string
+  transports: Array<string>
+  /** Synced between devices, e.g. through a password manager */
+  backedUp: boolean
+  createdAt: number
+  lastUsedAt?: number
+}
+
+export interface PasswordCheck {
+  ok: boolean
+  violations: Array<PolicyViolation>
+  /** Strength from 0 (guessable in under a thousand tries) to 4 (over ten billion) */
+  score: number
+  guessesLog10: number
+}
+
+/**
+ * Rules new passwords must meet. Unset fields use the defaults: 8 to 128 characters,
+ * no required character classes, a strength score of at least 2, and the breached
+ * password file from `BREACHED_PASSWORDS_FILE` if that is set.
+ */
+export interface PasswordPolicy {
+  minLength?: number
+  maxLength?: number
+  requireLowercase?: boolean
+  requireUppercase?: boolean
+  requireDigit?: boolean
+  requireSymbol?: boolean
+  /** 0 to 4, see `PasswordCheck::score` */
+  minScore?: number
+  /**
+   * Uppercase SHA-1 hashes or hash prefixes one per line, sorted, optionally followed by
+   * `:count` as in the Have I Been Pwned downloads. Searched in place, never loaded.
+   */
+  breachedFile?: string
+}
+
+export interface Perm {
+  permId: number
+  perm: string
+  description?: string
+  /** Seeded by db/init.sh, cannot be renamed or deleted */
+  isSystem: boolean
+  /** Perms this one directly implies */
+  implies: Array<string>
+}
+
+export interface PermDecision {
+  allowed: boolean
+  perm: string
+  resource?: string
+  /** The rule that settled it, unset when nothing matched */
+  decidedBy?: PermRule
+  /** Every rule that matched, most specific first */
+  matched: Array<PermRule>
+}
+
+export interface PermRule {
+  /** The perm as stored, bound to its resource when scoped */
+  perm: string
+  source: RuleSource
+  /** Role that granted it, for role rules */
+  role?: string
+  /** Why the deny was put in place, for deny rules */
+  reason?: string
+  /** When a timed grant applies, unset for grants that always do and denies */
+  window?: GrantWindow
+}
+
+export interface Phrase {
+  sections: Array<Section>
+}
+
+export interface PolicyViolation {
+  code: PolicyViolationCode
+  message: string
+}
+
+export declare enum PolicyViolationCode {
+  TooShort = 'too_short',
+  TooLong = 'too_long',
+  MissingLowercase = 'missing_lowercase',
+  MissingUppercase = 'missing_uppercase',
+  MissingDigit = 'missing_digit',
+  MissingSymbol = 'missing_symbol',
+  ContainsEmail = 'contains_email',
+  TooWeak = 'too_weak',
+  Breached = 'breached'
+}
+
+/** Who a provider says signed in */
+export interface ProviderProfile {
+  /** e.g. `google` */
+  provider: string
+  /** The provider's stable id for the account, such as the `sub` claim */
+  providerUserId: string
+  email: string
+  /** Whether the provider vouches that the user controls `email` */
+  emailVerified: boolean
+}
+
 export interface RateLimitConfig {
   maxRequests: number
   windowSeconds: number
@@ -82,13 +616,142 @@ export interface RefreshTokenData {
   expiresAt: number
   createdAt: number
 }
+
+export interface RenderedMail {
+  subject: string
+  text: string
+  html?: string
+}
+
+export interface Role {
+  roleId: number
+  roleName: string
+  /** Seeded by db/init.sh, cannot be renamed or deleted */
+  isSystem: boolean
+  perms: Array<string>
+}
+
+export declare enum RuleSource {
+  Role = 'role',
+  Direct = 'direct',
+  Deny = 'deny'
+}
+
+export interface Section {
+  terms: Array<Term>
+}
+
+export interface Sentence {
+  en: string
+  de: string
+}
+
+export interface Term {
+  en: string
+  de: string
+}
+
+export interface TotpEnrolment {
+  /** Base32, for typing into an authenticator by hand */
+  secret: string
+  /** `otpauth://` URI, usually shown as a QR code */
+  otpauthUri: string
+}
+
+export interface TotpStatus {
+  enabled: boolean
+  /** An enrolment was started but not confirmed with a code yet */
+  pending: boolean
+  recoveryCodesLeft: number
+}
+
+/**
+ * How accounts whose email isn't verified yet are treated. `Restrict` ignores their role
+ * and direct grants, `Block` also refuses their logins.
+ */
+export declare enum UnverifiedEmailPolicy {
+  Allow = 'allow',
+  Restrict = 'restrict',
+  Block = 'block'
+}
+
 export interface User {
   uid: string
   email: string
   pwdHash?: string
-  oauthProvider?: string
-  oauthProviderId?: string
+  /** Providers the user can sign in with, e.g. `google` */
+  providers: Array<string>
   createTime: number
+  /** Set while the account is soft deleted and can still be restored */
+  deletedAt?: number
+  /**
+   * Unset until the user follows a verification link, or signs up through a provider
+   * that vouches for the address
+   */
+  emailVerifiedAt?: number
+  /** Whether logins need a TOTP code as well */
+  totpEnabled: boolean
   roles: Array<string>
+  /** Everything the user may do, roles, denies and the hierarchy applied */
   perms: Array<string>
+  /** Permanent perms granted to the user directly, the ones `update_user` replaces */
+  directPerms: Array<string>
+}
+
+export interface UserListQuery {
+  /** Case-insensitive substring match on the email */
+  email?: string
+  role?: string
+  /** Users `has_perm` allows it, through roles, direct grants or the hierarchy */
+  perm?: string
+  /** Users with an identity at this provider linked */
+  oauthProvider?: string
+  /** Unix seconds, inclusive */
+  createdAfter?: number
+  /** Unix seconds, exclusive */
+  createdBefore?: number
+  sortBy?: UserSortField
+  descending?: boolean
+  limit?: number
+  /**
+   * `next_cursor` from the previous page. Fails if that user was deleted or restored
+   * since.
+   */
+  cursor?: string
+  /** List only soft deleted users, which are otherwise left out */
+  deleted?: boolean
+}
+
+export interface UserPage {
+  users: Array<User>
+  nextCursor?: string
+  /** Number of users matching the filters across all pages */
+  total: number
+}
+
+export declare enum UserSortField {
+  Email = 'email',
+  CreationTime = 'creation_time'
+}
+
+/**
+ * The relying party passkeys are registered with. Unset fields fall back to the
+ * `WEBAUTHN_RP_ID`, `WEBAUTHN_RP_NAME` and `WEBAUTHN_ORIGINS` (comma separated) env vars.
+ * Changing the id later orphans every registered passkey.
+ */
+export interface WebauthnConfig {
+  rpId?: string
+  rpName?: string
+  origins?: Array<string>
+}
+
+export interface WorksheetOptions {
+  /** Number of exercises on the sheet, capped by how many sentences the grid can realise */
+  sentenceCount?: number
+  /** Section indices to blank in every sentence, a random section is blanked when unset */
+  blankSections?: Array<number>
+  /** Fixed seed so the same sheet can be printed again */
+  seed?: number
+  /** Print the English sentence under each exercise as a hint (defaults to true) */
+  showTranslation?: boolean
 }
@@ -9,7 +9,9 @@ const ebp = path.join(utildirn, "napi_exports.node");
 const ebinding = req(ebp);
 export const {
   initDbs,
-  searchUsers,
+  listUsers,
+  emailLookup,
+  UserSortField,
   createUser,
   deleteUser,
   checkPass,
@@ -18,9 +20,11 @@ export const {
   genAccessJwt,
   genRefreshJwt,
   rotateRefreshJwt,
+  accessJwtPublicKey,
+  accessJwksJson,
   cleanupExpiredTokens,
   deleteRefreshToken,
-  deleteUserRefreshToken,
+  deleteUserRefreshTokens,
   getRefreshToken,
   initRedis,
   storeRefreshToken,
@@ -33,4 +37,84 @@ export const {
   cleanupRateLimitKeys,
   updateUser,
   uidLookup,
+  listRoles,
+  createRole,
+  renameRole,
+  deleteRole,
+  listPerms,
+  createPerm,
+  updatePerm,
+  deletePerm,
+  grantRolePerm,
+  revokeRolePerm,
+  setRolePerms,
+  getGrid,
+  genWorksheetHtml,
+  genWorksheetPdf,
+  addPermImplication,
+  removePermImplication,
+  effectivePerms,
+  hasPerm,
+  grantUserPerm,
+  revokeUserPerm,
+  explainPerm,
+  denyUserPerm,
+  removeUserDeny,
+  RuleSource,
+  assignUserRole,
+  removeUserRole,
+  purgeExpiredGrants,
+  listExpiredGrants,
+  GrantKind,
+  listAudit,
+  exportAuditJsonl,
+  AuditAction,
+  restoreUser,
+  purgeDeletedUsers,
+  authenticate,
+  lockoutStatus,
+  unlockAccount,
+  LoginOutcome,
+  configurePasswordHashing,
+  importPasswordHash,
+  configurePasswordPolicy,
+  checkPassword,
+  PolicyViolationCode,
+  requestPasswordReset,
+  resetPassword,
+  requestEmailVerification,
+  verifyEmail,
+  configureUnverifiedEmailPolicy,
+  unverifiedEmailPolicy,
+  UnverifiedEmailPolicy,
+  sendMail,
+  sendTemplateMail,
+  previewMail,
+  processMailQueue,
+  listFailedMail,
+  beginSecondFactor,
+  verifySecondFactor,
+  beginTotpEnrolment,
+  confirmTotpEnrolment,
+  disableTotp,
+  regenerateRecoveryCodes,
+  totpStatus,
+  verifyTotp,
+  configureWebauthn,
+  beginPasskeyRegistration,
+  finishPasskeyRegistration,
+  beginPasskeyAuthentication,
+  finishPasskeyAuthentication,
+  listPasskeys,
+  deletePasskey,
+  providerSignIn,
+  linkIdentity,
+  unlinkIdentity,
+  listIdentities,
+  configureOidc,
+  oidcProviders,
+  beginOidcSignIn,
+  finishOidcSignIn,
+  mintOauthState,
+  verifyOauthState,
 } = ebinding;
@@ -4,7 +4,16 @@ import { z } from "zod";
 import * as Rapi from "./rlibs/index";
 
 const emailSchema = z.email().min(3).max(255);
-const passSchema = z.string().min(8);
+// Length and strength rules live in the Rust password policy, this only bounds the input
+const passSchema = z.string().min(1).max(1024);
+// Six digit TOTP codes or recovery codes like `abcde-fghij`
+const codeSchema = z.string().trim().min(1).max(32);
+
+const PASSWORD_RESET_URL =
+  process.env.PASSWORD_RESET_URL ?? "http://localhost:8888/reset-password";
+
+const VERIFY_EMAIL_URL =
+  process.env.VERIFY_EMAIL_URL ?? "http://localhost:8888/verify-email";
 
 const ACCESS_TOKEN_MAX_AGE = 15 * 60 * 1000; // 15 minutes
 const REFRESH_TOKEN_MAX_AGE = 30 * 24 * 60 * 60 * 1000; // 30 days
@@ -23,6 +32,16 @@ export interface Ctx {
   ip?: string;
 }
 
+// Who is making a change, recorded in the audit log
+const auditCtx = (ctx: Ctx): Rapi.AuditContext => ({
+  actorUid: ctx.user?.uid,
+  ip: ctx.ip,
+});
+
+// Mails go out in the first language the browser asks for, when there is a template for it
+const mailLocale = (ctx: Ctx): string | undefined =>
+  ctx.req.acceptsLanguages()[0];
+
 export const t = initTRPC.context<Ctx>().create({
   errorFormatter({ shape, error }) {
     return {
@@ -38,32 +57,14 @@ export const t = initTRPC.context<Ctx>().create({
   },
 });
 
-const PERM_MAP: Record<string, string[]> = {
-  "users:manage": [
-    "users:create",
-    "users:edit",
-    "users:delete",
-    "users:search",
-  ],
-  "admin:access": ["users:manage"],
-};
-
-function hasEffectivePerm(userPerms: string[], required: string): boolean {
-  if (userPerms.includes(required)) return true;
-
-  return Object.entries(PERM_MAP).some(
-    ([parent, children]) =>
-      userPerms.includes(parent) && children.includes(required),
-  );
-}
-
 const checkPerms = (required: string) =>
   t.middleware(async ({ ctx, next }) => {
     if (!ctx.user) {
       throw new TRPCError({ code: "UNAUTHORIZED" });
     }
 
-    if (!hasEffectivePerm(ctx.user.perms, required)) {
+    // Rust resolves the permission hierarchy, so this is the only check needed
+    if (!(await Rapi.hasPerm(ctx.user.uid, required))) {
       throw new TRPCError({
         code: "FORBIDDEN",
         message: `Missing permission: ${required}`,
@@ -90,9 +91,7 @@ const authMiddleware = t.middleware(async ({ ctx, next, path }) => {
     const claims = await Rapi.checkAccessJwt(ctx.token);
 
     // Fetch the full user for all roles/perms access
-    const usrs = await Rapi.searchUsers(claims.email);
-    if (usrs.length === 0) throw new Error("User no longer exists");
-    const fullUser = usrs[0];
+    const fullUser = await Rapi.uidLookup(claims.uid);
 
     if (!claims.uid || !claims.email) {
       throw new TRPCError({
@@ -204,12 +203,73 @@ const COOKIE_OPTS = {
   priority: "high" as const,
 } as const;
 
+// Issues the token pair for a finished login and sets it as cookies
+async function startSession(ctx: Ctx, usr: Rapi.User) {
+  const accessToken = await Rapi.genAccessJwt(usr.uid, usr.email);
+  const [refreshToken, jti] = await Rapi.genRefreshJwt(usr.uid, usr.email);
+
+  await Rapi.storeRefreshToken(jti, usr.uid, usr.email, REFRESH_TOKEN_MAX_AGE);
+
+  ctx.res.cookie("__Host-accessToken", accessToken, COOKIE_OPTS);
+  ctx.res.cookie("__Host-refreshToken", refreshToken, {
+    ...COOKIE_OPTS,
+    maxAge: REFRESH_TOKEN_MAX_AGE,
+  });
+}
+
+const currentUid = (ctx: Ctx): string => {
+  if (!ctx.user) {
+    throw new TRPCError({ code: "UNAUTHORIZED" });
+  }
+  return ctx.user.uid;
+};
+
+// Sensitive two-factor changes need a fresh code, a stolen session alone isn't enough
+async function requireTotpCode(uid: string, code: string) {
+  if (!(await Rapi.verifyTotp(uid, code))) {
+    throw new TRPCError({
+      code: "FORBIDDEN",
+      message: "Invalid two-factor code",
+      cause: "INVALID_TOTP",
+    });
+  }
+}
+
 export const appRouter = t.router({
-  searchUsers: protectedProcedure
+  listUsers: protectedProcedure
     .use(checkPerms("users:search"))
-    .input(z.object({ email: z.string() }))
+    .input(
+      z.object({
+        email: z.string().optional(),
+        role: z.string().optional(),
+        perm: z.string().optional(),
+        oauthProvider: z.string().optional(),
+        createdAfter: z.number().optional(),
+        createdBefore: z.number().optional(),
+        sortBy: z.enum(Rapi.UserSortField).optional(),
+        descending: z.boolean().optional(),
+        limit: z.number().int().min(1).max(100).optional(),
+        cursor: z.string().nullish(),
+        deleted: z.boolean().optional(),
+      }),
+    )
+    .query(async ({ input }) => {
+      return await Rapi.listUsers({
+        ...input,
+        cursor: input.cursor ?? undefined,
+      });
+    }),
+  explainPerm: protectedProcedure
+    .use(checkPerms("admin:access"))
+    .input(
+      z.object({
+        uid: z.string(),
+        perm: z.string(),
+        resource: z.string().optional(),
+      }),
+    )
     .query(async ({ input }) => {
-      return await Rapi.searchUsers(input.email);
+      return await Rapi.explainPerm(input.uid, input.perm, input.resource);
     }),
   checkPass: protectedProcedure
     .input(z.object({ email: z.email(), pass: z.string() }))
@@ -222,56 +282,283 @@ export const appRouter = t.router({
       z.object({
         email: z.email(),
         pass: z.string().optional(),
-        oauthProvider: z.string().optional(),
       }),
     )
-    .mutation(async ({ input }) => {
+    .mutation(async ({ ctx, input }) => {
       return await Rapi.createUser(
         input.email,
         input.pass,
-        input.oauthProvider,
+        null,
+        null,
+        auditCtx(ctx),
       );
     }),
   deleteUser: protectedProcedure
     .use(checkPerms("users:delete"))
     .input(z.object({ email: z.email() }))
+    .mutation(async ({ ctx, input }) => {
+      // Also ends the user's sessions, failing as a whole if that doesn't work
+      return await Rapi.deleteUser(input.email, auditCtx(ctx));
+    }),
+  lockoutStatus: protectedProcedure
+    .use(checkPerms("users:edit"))
+    .input(z.object({ email: z.email() }))
+    .query(async ({ input }) => {
+      return await Rapi.lockoutStatus(input.email);
+    }),
+  unlockAccount: protectedProcedure
+    .use(checkPerms("users:edit"))
+    .input(z.object({ email: z.email() }))
     .mutation(async ({ input }) => {
-      return await Rapi.deleteUser(input.email);
+      return await Rapi.unlockAccount(input.email);
+    }),
+  restoreUser: protectedProcedure
+    .use(checkPerms("users:delete"))
+    .input(z.object({ uid: z.string() }))
+    .mutation(async ({ ctx, input }) => {
+      return await Rapi.restoreUser(input.uid, auditCtx(ctx));
+    }),
+  requestEmailVerification: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ email: emailSchema }))
+    .mutation(async ({ input, ctx }) => {
+      // Same answer whether or not the account exists or is already verified
+      await Rapi.requestEmailVerification(
+        input.email,
+        VERIFY_EMAIL_URL,
+        mailLocale(ctx),
+      );
+      return { success: true };
+    }),
+  verifyEmail: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ token: z.string().min(1) }))
+    .mutation(async ({ input, ctx }) => {
+      const user = await Rapi.verifyEmail(input.token, auditCtx(ctx));
+      return { user };
+    }),
+  requestPasswordReset: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ email: emailSchema }))
+    .mutation(async ({ input, ctx }) => {
+      // Same answer whether or not the account exists
+      await Rapi.requestPasswordReset(
+        input.email,
+        PASSWORD_RESET_URL,
+        mailLocale(ctx),
+      );
+      return { success: true };
+    }),
+  resetPassword: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ token: z.string().min(1), pass: passSchema }))
+    .mutation(async ({ input, ctx }) => {
+      const user = await Rapi.resetPassword(
+        input.token,
+        input.pass,
+        auditCtx(ctx),
+      );
+      return { user };
+    }),
+  checkPassword: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ pass: passSchema, email: emailSchema.optional() }))
+    .mutation(async ({ input }) => {
+      return await Rapi.checkPassword(input.pass, input.email);
     }),
   login: t.procedure
     .use(rateLimitMiddleware.authEndpoint)
     .input(z.object({ email: emailSchema, pass: passSchema }))
     .mutation(async ({ input, ctx }) => {
-      const isValid = await Rapi.checkPass(input.email, input.pass);
-      if (!isValid) {
+      // One call so unknown emails take as long as wrong passwords
+      const result = await Rapi.authenticate(input.email, input.pass);
+      if (result.unknownPepper) {
+        console.error(
+          `Password hash uses pepper key ${result.unknownPepper}, which is missing from PASSWORD_PEPPERS`,
+        );
+      }
+      if (result.outcome === Rapi.LoginOutcome.Locked) {
+        throw new TRPCError({
+          code: "TOO_MANY_REQUESTS",
+          message: `Account locked after too many failed logins, try again in ${result.retryAfterSeconds} seconds`,
+          cause: "ACCOUNT_LOCKED",
+        });
+      }
+      if (result.outcome === Rapi.LoginOutcome.Unverified) {
+        throw new TRPCError({
+          code: "FORBIDDEN",
+          message: "Verify your email address before logging in",
+          cause: "EMAIL_NOT_VERIFIED",
+        });
+      }
+      // No session yet, the client sends a code with the challenge to verifySecondFactor
+      if (result.outcome === Rapi.LoginOutcome.SecondFactorRequired) {
+        return { secondFactorRequired: true, challenge: result.challenge };
+      }
+      const usr = result.user;
+      if (result.outcome !== Rapi.LoginOutcome.Valid || !usr) {
         throw new TRPCError({
           code: "UNAUTHORIZED",
           message: "Invalid email or password",
         });
       }
 
-      const usrs = await Rapi.searchUsers(input.email);
-      if (usrs.length === 0) {
-        throw new TRPCError({ code: "NOT_FOUND", message: "User not found" });
+      await startSession(ctx, usr);
+      return { user: usr };
+    }),
+  verifySecondFactor: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ challenge: z.string().min(1), code: codeSchema }))
+    .mutation(async ({ input, ctx }) => {
+      const result = await Rapi.verifySecondFactor(input.challenge, input.code);
+      if (result.outcome === Rapi.LoginOutcome.Locked) {
+        throw new TRPCError({
+          code: "TOO_MANY_REQUESTS",
+          message: `Account locked after too many failed logins, try again in ${result.retryAfterSeconds} seconds`,
+          cause: "ACCOUNT_LOCKED",
+        });
+      }
+      const usr = result.user;
+      if (result.outcome !== Rapi.LoginOutcome.Valid || !usr) {
+        throw new TRPCError({
+          code: "UNAUTHORIZED",
+          message: "Invalid or expired two-factor code",
+        });
       }
 
-      const usr = usrs[0];
-      const accessToken = await Rapi.genAccessJwt(usr.uid, usr.email);
-      const [refreshToken, jti] = await Rapi.genRefreshJwt(usr.uid, usr.email);
-
-      await Rapi.storeRefreshToken(
-        jti,
-        usr.uid,
-        usr.email,
-        REFRESH_TOKEN_MAX_AGE,
+      await startSession(ctx, usr);
+      return { user: usr };
+    }),
+  totpStatus: rateLimitedProcedure.query(async ({ ctx }) => {
+    return await Rapi.totpStatus(currentUid(ctx));
+  }),
+  beginTotpEnrolment: rateLimitedProcedure.mutation(async ({ ctx }) => {
+    return await Rapi.beginTotpEnrolment(currentUid(ctx));
+  }),
+  confirmTotpEnrolment: protectedProcedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ code: codeSchema }))
+    .mutation(async ({ input, ctx }) => {
+      const recoveryCodes = await Rapi.confirmTotpEnrolment(
+        currentUid(ctx),
+        input.code,
+        auditCtx(ctx),
       );
+      return { recoveryCodes };
+    }),
+  disableTotp: protectedProcedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ code: codeSchema }))
+    .mutation(async ({ input, ctx }) => {
+      const uid = currentUid(ctx);
+      await requireTotpCode(uid, input.code);
+      return { user: await Rapi.disableTotp(uid, auditCtx(ctx)) };
+    }),
+  regenerateRecoveryCodes: protectedProcedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ code: codeSchema }))
+    .mutation(async ({ input, ctx }) => {
+      const uid = currentUid(ctx);
+      await requireTotpCode(uid, input.code);
+      const recoveryCodes = await Rapi.regenerateRecoveryCodes(
+        uid,
+        auditCtx(ctx),
+      );
+      return { recoveryCodes };
+    }),
+  // For users who lost both their authenticator and recovery codes
+  resetUserTotp: protectedProcedure
+    .use(checkPerms("users:edit"))
+    .input(z.object({ uid: z.string() }))
+    .mutation(async ({ input, ctx }) => {
+      return await Rapi.disableTotp(input.uid, auditCtx(ctx));
+    }),
+  listPasskeys: rateLimitedProcedure.query(async ({ ctx }) => {
+    return await Rapi.listPasskeys(currentUid(ctx));
+  }),
+  beginPasskeyRegistration: rateLimitedProcedure.mutation(async ({ ctx }) => {
+    return await Rapi.beginPasskeyRegistration(currentUid(ctx));
+  }),
+  finishPasskeyRegistration: protectedProcedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(
+      z.object({
+        // The credential's toJSON(), checked in full on the Rust side
+        response: z.record(z.string(), z.unknown()),
+        name: z.string().max(64).optional(),
+      }),
+    )
+    .mutation(async ({ input, ctx }) => {
+      const passkey = await Rapi.finishPasskeyRegistration(
+        currentUid(ctx),
+        input.response,
+        input.name,
+        auditCtx(ctx),
+      );
+      return { passkey };
+    }),
+  deletePasskey: protectedProcedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ credentialId: z.string().min(1) }))
+    .mutation(async ({ input, ctx }) => {
+      const deleted = await Rapi.deletePasskey(
+        currentUid(ctx),
+        input.credentialId,
+        auditCtx(ctx),
+      );
+      if (!deleted) {
+        throw new TRPCError({ code: "NOT_FOUND", message: "No such passkey" });
+      }
+      return { deleted };
+    }),
+  // Linking happens through the provider's sign-in flow, e.g. /auth/google?link=true
+  listIdentities: rateLimitedProcedure.query(async ({ ctx }) => {
+    return await Rapi.listIdentities(currentUid(ctx));
+  }),
+  unlinkIdentity: protectedProcedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ provider: z.string().min(1) }))
+    .mutation(async ({ input, ctx }) => {
+      const user = await Rapi.unlinkIdentity(
+        currentUid(ctx),
+        input.provider,
+        auditCtx(ctx),
+      );
+      return { user };
+    }),
+  beginPasskeyLogin: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .mutation(async () => {
+      return await Rapi.beginPasskeyAuthentication();
+    }),
+  finishPasskeyLogin: t.procedure
+    .use(rateLimitMiddleware.authEndpoint)
+    .input(z.object({ response: z.record(z.string(), z.unknown()) }))
+    .mutation(async ({ input, ctx }) => {
+      const result = await Rapi.finishPasskeyAuthentication(input.response);
+      if (result.outcome === Rapi.LoginOutcome.Locked) {
+        throw new TRPCError({
+          code: "TOO_MANY_REQUESTS",
+          message: `Account locked after too many failed logins, try again in ${result.retryAfterSeconds} seconds`,
+          cause: "ACCOUNT_LOCKED",
+        });
+      }
+      if (result.outcome === Rapi.LoginOutcome.Unverified) {
+        throw new TRPCError({
+          code: "FORBIDDEN",
+          message: "Verify your email address before logging in",
+          cause: "EMAIL_NOT_VERIFIED",
+        });
+      }
+      const usr = result.user;
+      if (result.outcome !== Rapi.LoginOutcome.Valid || !usr) {
+        throw new TRPCError({
+          code: "UNAUTHORIZED",
+          message: "Passkey not recognised",
+        });
+      }
 
-      ctx.res.cookie("__Host-accessToken", accessToken, COOKIE_OPTS);
-      ctx.res.cookie("__Host-refreshToken", refreshToken, {
-        ...COOKIE_OPTS,
-        maxAge: REFRESH_TOKEN_MAX_AGE,
-      });
-
+      await startSession(ctx, usr);
       return { user: usr };
     }),
 
@@ -286,8 +573,8 @@ export const appRouter = t.router({
       }),
     )
     .mutation(async ({ input, ctx }) => {
-      const existingUsers = await Rapi.searchUsers(input.email);
-      if (existingUsers.length > 0) {
+      const existingUser = await Rapi.emailLookup(input.email);
+      if (existingUser) {
         throw new TRPCError({
           code: "CONFLICT",
           message: "User already exists",
@@ -298,12 +585,23 @@ export const appRouter = t.router({
       const user = await Rapi.createUser(
         input.email,
         input.pass,
-        null,
-        null,
         input.roles,
         input.perms,
+        auditCtx(ctx),
       );
 
+      await Rapi.requestEmailVerification(
+        user.email,
+        VERIFY_EMAIL_URL,
+        mailLocale(ctx),
+      );
+      // Logging in would be refused until the link is followed, so don't start a session
+      if (
+        Rapi.unverifiedEmailPolicy() === Rapi.UnverifiedEmailPolicy.Block
+      ) {
+        return { user, verificationRequired: true };
+      }
+
       const accessToken = await Rapi.genAccessJwt(user.uid, user.email);
       const [refreshToken, jti] = await Rapi.genRefreshJwt(
         user.uid,
@@ -327,7 +625,7 @@ export const appRouter = t.router({
         });
       }
 
-      return { user };
+      return { user, verificationRequired: false };
     }),
 
   refresh: t.procedure
@@ -385,9 +683,7 @@ export const appRouter = t.router({
           maxAge: REFRESH_TOKEN_MAX_AGE,
         });
 
-        const usrs = await Rapi.searchUsers(claims.email);
-        if (usrs.length === 0) throw new Error("User not found");
-        const fullUser = usrs[0];
+        const fullUser = await Rapi.uidLookup(claims.uid);
 
         return { user: fullUser, claims };
       } catch (error) {
@@ -450,10 +746,15 @@ export const appRouter = t.router({
     .mutation(async () => {
       const rateLimitCleaned = await Rapi.cleanupRateLimitKeys();
       const tokenCleaned = await Rapi.cleanupExpiredTokens();
+      const grantsPurged = (await Rapi.purgeExpiredGrants()).length;
+      const usersPurged = (await Rapi.purgeDeletedUsers()).length;
       return {
         rateLimitCleaned,
         tokenCleaned,
-        totalCleaned: rateLimitCleaned + tokenCleaned,
+        grantsPurged,
+        usersPurged,
+        totalCleaned:
+          rateLimitCleaned + tokenCleaned + grantsPurged + usersPurged,
         success: true,
       };
     }),
@@ -470,7 +771,7 @@ export const appRouter = t.router({
     .mutation(async ({ ctx, input }) => {
       const isTargetUser = ctx.user?.uid === input.uid;
       const hasEditPermission = ctx.user
-        ? hasEffectivePerm(ctx.user.perms, "users:edit")
+        ? await Rapi.hasPerm(ctx.user.uid, "users:edit", input.uid)
         : false;
 
       if (!isTargetUser && !hasEditPermission) {
@@ -496,12 +797,48 @@ export const appRouter = t.router({
         input.uid,
         input.email,
         input.pass,
-        null,
-        null,
         input.roles,
         input.perms,
+        auditCtx(ctx),
       );
     }),
+  listAudit: protectedProcedure
+    .use(checkPerms("admin:access"))
+    .input(
+      z.object({
+        actorUid: z.string().optional(),
+        targetUid: z.string().optional(),
+        action: z.enum(Rapi.AuditAction).optional(),
+        since: z.number().optional(),
+        until: z.number().optional(),
+        limit: z.number().int().min(1).max(500).optional(),
+        cursor: z.number().nullish(),
+      }),
+    )
+    .query(async ({ input }) => {
+      return await Rapi.listAudit({
+        ...input,
+        cursor: input.cursor ?? undefined,
+      });
+    }),
+  exportAudit: protectedProcedure
+    .use(checkPerms("admin:access"))
+    .input(
+      z.object({
+        actorUid: z.string().optional(),
+        targetUid: z.string().optional(),
+        action: z.enum(Rapi.AuditAction).optional(),
+        since: z.number().optional(),
+        until: z.number().optional(),
+        cursor: z.number().nullish(),
+      }),
+    )
+    .query(async ({ input }) => {
+      return await Rapi.exportAuditJsonl({
+        ...input,
+        cursor: input.cursor ?? undefined,
+      });
+    }),
 });
 
 export type AppRouter = typeof appRouter;
@@ -10,3 +10,27 @@ check_installed_warn "openssl" "Cannot generate secure keys for .env; using defa
 export JWT_ACCESS_SECRET="$(openssl rand -base64 32)"
 export JWT_REFRESH_SECRET="$(openssl rand -base64 32)"
 export COOKIE_SECRET="$(openssl rand -base64 32)"
+
+# Generates a secret only when it isn't set yet and keeps it in .env, for secrets that
+# can't change on every run without breaking stored data
+function generate_once() {
+  local name=$1
+  local value=$2
+
+  if [ -n "${!name:-}" ]; then
+    return 0
+  fi
+  export "$name=$value"
+  if [ -s "$PR/.env" ] && [ -n "$(tail -c1 "$PR/.env")" ]; then
+    echo >>"$PR/.env"
+  fi
+  echo "$name=$value" >>"$PR/.env"
+  echo "Generated $name and saved it to .env"
+}
+
+# Rotate by prepending a new id:secret pair, keeping the old ones until their hashes are upgraded
+generate_once PASSWORD_PEPPERS "$(date +%Y%m%d):$(openssl rand -base64 32)"
+# OAuth sign-ins in flight fail when it changes, and every instance needs the same one
+generate_once OAUTH_STATE_SECRET "$(openssl rand -base64 32)"
+# Stored recovery codes stop working when it changes
+generate_once RECOVERY_CODE_SECRET "$(openssl rand -base64 32)"
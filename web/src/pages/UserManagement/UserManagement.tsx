@@ -9,24 +9,34 @@ export const UserManagement = () => {
   const [editingUser, setEditingUser] = useState<User | null>(null);
 
   const utils = trpc.useUtils();
-  const { data: users } = trpc.searchUsers.useQuery({ email: emailSearch });
+  const {
+    data: userPages,
+    fetchNextPage,
+    hasNextPage,
+    isFetchingNextPage,
+  } = trpc.listUsers.useInfiniteQuery(
+    { email: emailSearch || undefined, limit: 25 },
+    { getNextPageParam: (lastPage) => lastPage.nextCursor },
+  );
+  const users = userPages?.pages.flatMap((page) => page.users);
+  const totalUsers = userPages?.pages[0]?.total ?? 0;
 
   const upsertMutation = trpc.updateUser.useMutation({
     onSuccess: () => {
-      utils.searchUsers.invalidate();
+      utils.listUsers.invalidate();
       closeModal();
     },
   });
 
   const createMutation = trpc.addUser.useMutation({
     onSuccess: () => {
-      utils.searchUsers.invalidate();
+      utils.listUsers.invalidate();
       closeModal();
     },
   });
 
   const deleteMutation = trpc.deleteUser.useMutation({
-    onSuccess: () => utils.searchUsers.invalidate(),
+    onSuccess: () => utils.listUsers.invalidate(),
   });
 
   const closeModal = () => {
@@ -114,6 +124,19 @@ export const UserManagement = () => {
             ))}
           </tbody>
         </table>
+        <footer className="table-footer">
+          <span>
+            Showing {users?.length ?? 0} of {totalUsers} users
+          </span>
+          {hasNextPage && (
+            <button
+              onClick={() => fetchNextPage()}
+              disabled={isFetchingNextPage}
+            >
+              {isFetchingNextPage ? "Loading..." : "Load more"}
+            </button>
+          )}
+        </footer>
       </main>
 
       {isModalOpen && (
@@ -141,10 +164,10 @@ export const UserManagement = () => {
                 placeholder="admin, user"
               />
 
-              <label>Permissions (comma separated)</label>
+              <label>Direct permissions (comma separated)</label>
               <input
                 name="perms"
-                defaultValue={editingUser?.perms.join(", ")}
+                defaultValue={editingUser?.directPerms.join(", ")}
                 placeholder="users:manage, admin:access"
               />
 